@@ -1,20 +1,135 @@
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossterm::event::{Event, EventStream, KeyCode, KeyModifiers};
+use crossterm::event::{Event, EventStream, KeyCode};
 use crossterm::{cursor, queue, style, terminal};
 use futures::stream::Fuse;
 use futures::StreamExt;
 use futures_timer::Delay;
 use libp2p::gossipsub::error::PublishError;
+use libp2p::gossipsub::MessageId;
 use libp2p::PeerId;
 use tokio::select;
+use tokio::sync::mpsc::UnboundedReceiver;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use p2p_chat::protocol::{ChannelIdentifier, MessageType};
-use p2p_chat::{Client, ClientEvent, Error};
+use p2p_chat::protocol::{
+    parse_formatted, Attachment, ChannelIdentifier, MessageType, Permission,
+    Role, Span, TimestampVerdict, MAX_NICK_LENGTH,
+};
+use p2p_chat::{Client, ClientEvent, Error, SessionEntry, SpamKind};
+
+use crate::commands::{self, Command};
+use crate::completion::{completion_kind, Completion, CompletionKind};
+use crate::input::InputLine;
+use crate::keys::{Action, Keymap};
+use crate::theme::Theme;
+
+/// How long invitations sent with `/invite` may be used for.
+const INVITE_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most messages of earlier sessions shown upon joining a channel.
+const SCROLLBACK_LENGTH: usize = 100;
+
+/// How old the messages of earlier sessions shown upon joining a channel may
+/// be.
+const SCROLLBACK_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How narrow the terminal may be before the sidebar is hidden to leave room
+/// for messages.
+const SIDEBAR_MIN_COLUMNS: u16 = 60;
+
+/// How many log records are shown in the system buffer within each
+/// [`LOG_WINDOW`], beyond which they are only counted.
+const LOG_LIMIT: usize = 5;
+
+/// How long the rate limit of log records shown in the system buffer counts
+/// them for.
+const LOG_WINDOW: Duration = Duration::from_secs(30);
+
+/// How the times of messages are shown before them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// The time of day, such as `[13:37]`.
+    #[default]
+    Short,
+    /// The date and time of day, such as `[2022-03-14 13:37]`.
+    Full,
+}
+
+impl TimestampFormat {
+    fn format(self, time: SystemTime) -> String {
+        let format = match self {
+            TimestampFormat::Short => "[%H:%M] ",
+            TimestampFormat::Full => "[%Y-%m-%d %H:%M] ",
+        };
+        chrono::DateTime::<chrono::Local>::from(time)
+            .format(format)
+            .to_string()
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "short" => Ok(TimestampFormat::Short),
+            "full" => Ok(TimestampFormat::Full),
+            _ => Err(anyhow::anyhow!("unknown timestamp format {s}")),
+        }
+    }
+}
+
+/// The log records fed to the system buffer, which are shown at a limited
+/// rate so that a burst of them does not flood it.
+struct LogFeed {
+    receiver: UnboundedReceiver<String>,
+    /// When the current window of the rate limit began.
+    window_start: Instant,
+    /// How many records were shown within the current window.
+    shown: usize,
+    /// How many records were left out within the current window.
+    dropped: usize,
+}
+
+impl LogFeed {
+    /// Begin a new window if the current one has ended, returning a notice of
+    /// how many records were left out within it, if any were.
+    fn roll_window(&mut self, now: Instant) -> Option<String> {
+        if now.duration_since(self.window_start) < LOG_WINDOW {
+            return None;
+        }
+
+        let dropped = std::mem::take(&mut self.dropped);
+        self.window_start = now;
+        self.shown = 0;
+        (dropped > 0).then(|| {
+            format!(
+                "({dropped} more log records were left out, see current.log)"
+            )
+        })
+    }
+
+    /// Take a log record, returning the messages to show for it.
+    fn admit(&mut self, record: String, now: Instant) -> Vec<String> {
+        let mut messages: Vec<_> = self.roll_window(now).into_iter().collect();
+        if self.shown < LOG_LIMIT {
+            self.shown += 1;
+            messages.push(record);
+        } else {
+            self.dropped += 1;
+        }
+        messages
+    }
+}
 
 #[derive(Clone, Debug)]
 struct Buffer {
@@ -23,8 +138,13 @@ struct Buffer {
     /// Whether there are any messages in the buffer that have not been read by
     /// the user.
     has_unread: bool,
+    /// Whether any of the unread messages mention the user, or are addressed
+    /// to them directly.
+    has_mention: bool,
     /// The type of buffer this is (system or channel).
     buffer_type: BufferType,
+    /// How many lines the buffer is scrolled up from its latest message.
+    scroll: usize,
 }
 
 impl Buffer {
@@ -32,34 +152,66 @@ impl Buffer {
         Buffer {
             history: VecDeque::new(),
             has_unread: false,
+            has_mention: false,
             buffer_type,
+            scroll: 0,
         }
     }
 
-    pub fn name(&self) -> &str {
+    pub fn name(&self, client: &mut Client) -> String {
         match &self.buffer_type {
-            BufferType::System => "*system*",
-            BufferType::Channel(ident) => ident,
+            BufferType::System => "*system*".to_owned(),
+            BufferType::Channel(ident) => ident.clone(),
+            BufferType::Direct(peer) => {
+                format!("@{}", display_name(client, peer))
+            }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum BufferType {
     /// A buffer in which system-wide messages are read.
     System,
     /// A channel in which users communicate with one another.
     Channel(ChannelIdentifier),
+    /// A direct conversation with a single peer.
+    Direct(PeerId),
 }
 
 #[derive(Clone, Debug)]
 enum HistoryEntry {
     Message {
+        /// The message ID, if the message was published.
+        id: Option<MessageId>,
         sender: PeerId,
         contents: String,
         message_type: MessageType,
+        /// The message ID of the message being replied to, if any.
+        in_reply_to: Option<MessageId>,
+        /// Whether or not the contents were edited by the sender.
+        edited: bool,
+        /// Whether or not the sender mentioned the user in the message.
+        mentioned: bool,
+        /// The time the message was sent, as claimed by its sender.
+        sent_at: SystemTime,
     },
-    Log(String),
+    /// A message which was retracted by its sender.
+    Deleted { sender: PeerId, sent_at: SystemTime },
+    Log {
+        message: String,
+        logged_at: SystemTime,
+    },
+}
+
+impl HistoryEntry {
+    /// Create a log entry of the current time.
+    fn log(message: impl Into<String>) -> Self {
+        HistoryEntry::Log {
+            message: message.into(),
+            logged_at: SystemTime::now(),
+        }
+    }
 }
 
 /// A TUI implementation of p2p-chat.
@@ -67,7 +219,9 @@ pub struct App {
     /// The underlying client which this app represents.
     client: Fuse<Client>,
     /// The message/command input buffer.
-    input_buffer: String,
+    input_buffer: InputLine,
+    /// The completion of the word before the cursor, if tab was pressed.
+    completion: Option<Completion>,
     /// The list of currently open buffers (system or channels). In practice,
     /// this should consist of a single system buffer, followed by an arbitrary
     /// number of channels.
@@ -76,26 +230,83 @@ pub struct App {
     current_buffer: Rc<RefCell<Buffer>>,
     /// The primary system buffer.
     system_buffer: Rc<RefCell<Buffer>>,
+    /// Messages sent by the user which have not been published yet, and the
+    /// channels they were sent to.
+    outgoing: HashMap<MessageId, ChannelIdentifier>,
+    /// The last attachment received in each channel, which may be saved.
+    attachments: HashMap<ChannelIdentifier, Attachment>,
+    /// The last channel invitation received, which may be accepted.
+    invite: Option<Vec<u8>>,
+    /// The last poll posted to each channel, which may be voted on.
+    polls: HashMap<ChannelIdentifier, MessageId>,
     /// The index of the currently focused buffer.
+    /// The colors of the user interface.
+    theme: Theme,
+    /// The actions bound to keys.
+    keymap: Keymap,
+    /// Whether the times of messages are shown before them.
+    show_timestamps: bool,
+    /// How the times of messages are shown, when they are.
+    timestamp_format: TimestampFormat,
+    /// Whether the members of the current channel are listed beside it.
+    show_sidebar: bool,
+    /// The number of peers connected to, kept up to date as they connect and
+    /// disconnect.
+    connected_peers: usize,
+    /// The log records to show in the system buffer, if any.
+    logs: Option<LogFeed>,
     /// Whether or not the user has requested to exit the program.
     wants_to_exit: bool,
+    /// The reason given for quitting, which is passed along to peers.
+    quit_reason: Option<String>,
 }
 
 impl App {
-    pub fn new(client: Client) -> Self {
+    /// Create an app, showing the times of messages in the given format, if
+    /// any.
+    pub fn new(
+        client: Client,
+        theme: Theme,
+        keymap: Keymap,
+        timestamps: Option<TimestampFormat>,
+    ) -> Self {
         let system_buffer =
             Rc::new(RefCell::new(Buffer::new(BufferType::System)));
 
         App {
             client: client.fuse(),
-            input_buffer: String::with_capacity(256),
+            input_buffer: InputLine::default(),
+            completion: None,
             buffers: vec![system_buffer.clone()],
             current_buffer: system_buffer.clone(),
             system_buffer,
+            outgoing: HashMap::new(),
+            attachments: HashMap::new(),
+            invite: None,
+            polls: HashMap::new(),
+            theme,
+            keymap,
+            show_timestamps: timestamps.is_some(),
+            timestamp_format: timestamps.unwrap_or_default(),
+            show_sidebar: true,
+            connected_peers: 0,
+            logs: None,
             wants_to_exit: false,
+            quit_reason: None,
         }
     }
 
+    /// Show the log records received from a logger in the system buffer, such
+    /// as warnings about connection problems.
+    pub fn show_logs(&mut self, receiver: UnboundedReceiver<String>) {
+        self.logs = Some(LogFeed {
+            receiver,
+            window_start: Instant::now(),
+            shown: 0,
+            dropped: 0,
+        });
+    }
+
     /// Run the app, blocking until it finishes executing.
     pub async fn run<W: Write>(
         &mut self,
@@ -112,18 +323,120 @@ impl App {
 
             select! {
                 _ = redraw_tick => {
+                    self.flush_log_records();
                     self.draw(writer, true)?;
                 }
-                Some(event) = self.client.select_next_some() => {
+                event = self.client.select_next_some() => {
                     match event {
-                        ClientEvent::Message { contents, channel, timestamp: _, message_type, sender } => {
-                            self.push_channel_message(sender, contents, &channel, message_type);
+                        // synced history may include messages already shown
+                        // from the scrollback of earlier sessions
+                        ClientEvent::Message { id, channel, historical: true, .. } if self.has_channel_message(&channel, &id) => {}
+                        ClientEvent::Message { muted: true, .. } => {}
+                        ClientEvent::Message { id, contents, channel, timestamp, message_type, in_reply_to, attachment, timestamp_verdict, sender, historical, .. } => {
+                            let contents = match attachment {
+                                Some(_) => format!("[attachment] {contents}"),
+                                None => contents,
+                            };
+                            let contents = if historical { format!("[history] {contents}") } else { contents };
+                            self.push_channel_message(&channel, HistoryEntry::Message {
+                                id: Some(id),
+                                sender,
+                                contents,
+                                message_type,
+                                in_reply_to,
+                                edited: false,
+                                mentioned: false,
+                                sent_at: from_millis(timestamp),
+                            });
+                            if let Some(buffer) = self.channel_by_ident(&channel) {
+                                self.mark_unread(buffer, false);
+                            }
+                            match timestamp_verdict {
+                                TimestampVerdict::Plausible => {}
+                                TimestampVerdict::Ahead => self.push_log_in(&channel, "(the message above claims to be from the future)"),
+                                TimestampVerdict::Ancient => self.push_log_in(&channel, "(the message above claims to be impossibly old)"),
+                            }
+                        }
+                        ClientEvent::AttachmentReceived { channel, attachment, .. } => {
+                            self.push_log_in(&channel, format!(
+                                "Received {} ({} bytes), use /save <path> to save it.",
+                                attachment.name, attachment.size
+                            ));
+                            self.attachments.insert(channel, attachment);
+                        }
+                        ClientEvent::Mentioned { id, channel, sender } => {
+                            self.mark_mentioned(&channel, &id);
+                            if let Some(buffer) = self.channel_by_ident(&channel) {
+                                self.mark_unread(buffer, true);
+                            }
+                            if !matches!(&self.current_buffer.borrow().buffer_type, BufferType::Channel(c) if *c == channel) {
+                                let name = display_name(self.client.get_mut(), &sender);
+                                self.push_system(format!("{name} mentioned you in {channel}"));
+                            }
+                        }
+                        ClientEvent::MessageEdited { id, channel, new_contents, .. } => {
+                            self.edit_channel_message(&channel, &id, new_contents);
+                        }
+                        ClientEvent::MessageDeleted { id, channel, .. } => {
+                            self.delete_channel_message(&channel, &id);
+                        }
+                        ClientEvent::SpamDetected { id, channel, sender, kind } => {
+                            self.remove_channel_message(&channel, &id);
+                            let name = display_name(self.client.get_mut(), &sender);
+                            let reason = match kind {
+                                SpamKind::Duplicate => "repeated",
+                                SpamKind::Burst => "one of too many",
+                                SpamKind::ExcessiveMentions => "mentioning too many people",
+                            };
+                            self.push_log_in(&channel, format!("(hid a message from {name}, {reason})"));
+                        }
+                        ClientEvent::MessageExpired { id, channel } => {
+                            self.remove_channel_message(&channel, &id);
+                        }
+                        ClientEvent::DirectMessage { muted: true, .. } => {}
+                        ClientEvent::DirectMessage { contents, timestamp, message_type, sender, encrypted, .. } => {
+                            self.push_direct_message(sender, sender, contents, message_type, from_millis(timestamp));
+                            let buffer = self.direct_buffer(sender);
+                            self.mark_unread(&buffer, true);
+                            if !encrypted {
+                                self.direct_buffer(sender).borrow_mut().history.push_back(
+                                    HistoryEntry::log("(the message above was not encrypted)"),
+                                );
+                            }
+                            if self.current_buffer.borrow().buffer_type != BufferType::Direct(sender) {
+                                let name = display_name(self.client.get_mut(), &sender);
+                                self.push_system(format!("new direct message from {name}"));
+                            }
+                        }
+                        ClientEvent::ChannelInvited { channel, inviter, token, .. } => {
+                            let name = display_name(self.client.get_mut(), &inviter);
+                            self.push_system(format!("{name} invited you to {channel}, /accept to join"));
+                            self.invite = Some(token);
+                        }
+                        ClientEvent::DirectMessageFailed { peer, error, .. } => {
+                            self.direct_buffer(peer).borrow_mut().history.push_back(
+                                HistoryEntry::log(format!("could not send direct message: {error}")),
+                            );
                         }
                         ClientEvent::PeerConnected(peer_id) => {
+                            self.connected_peers = self.client.get_ref().connected_peers().len();
                             self.push_system(format!("peer connected: {peer_id}"));
                         }
                         ClientEvent::PeerDisconnected(peer_id) => {
+                            self.connected_peers = self.client.get_ref().connected_peers().len();
                             self.push_system(format!("peer disconnected: {peer_id}"));
+                            if self.connected_peers == 0 {
+                                self.push_system("offline: the last peer disconnected");
+                            }
+                        }
+                        ClientEvent::PeerDiscovered { peer, .. } => {
+                            self.push_system(format!("peer discovered: {peer}"));
+                        }
+                        ClientEvent::ListeningOn(address) => {
+                            self.push_system(format!("listening on: {address}"));
+                        }
+                        ClientEvent::StoppedListeningOn(address) => {
+                            self.push_system(format!("stopped listening on: {address}"));
                         }
                         ClientEvent::Dialing(peer_id) => {
                             self.push_system(format!("dialing: {peer_id}"));
@@ -134,10 +447,148 @@ impl App {
                         } => {
                             self.push_system("failed to connect to peer");
                         }
+                        ClientEvent::PeerIncompatible { peer, protocol_version } => {
+                            self.push_system(format!(
+                                "disconnected from {peer}, which speaks incompatible protocol version {protocol_version}"
+                            ));
+                        }
+                        ClientEvent::PeerThrottled(peer) => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_system(format!("ignoring {name}, who is sending messages too quickly"));
+                        }
+                        ClientEvent::Resumed(SessionEntry::Channel(channel))
+                            if self.channel_by_ident(&channel).is_none() =>
+                        {
+                            self.open_channel(&channel);
+                            self.push_system(format!("Rejoined channel {channel}"));
+                        }
+                        ClientEvent::Resumed(SessionEntry::Direct(peer)) => {
+                            self.direct_buffer(peer);
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_system(format!("Resumed conversation with {name}"));
+                        }
+                        ClientEvent::PingFailure { peer, error } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_system(format!("ping to {name} failed: {error}"));
+                        }
+                        ClientEvent::PeerJoinedChannel { peer, channel } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_log_in(&channel, format!("{name} joined"));
+                        }
+                        ClientEvent::PeerLeft { peer, channel, reason } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            match reason {
+                                Some(reason) => self.push_log_in(&channel, format!("{name} left {channel} ({reason})")),
+                                None => self.push_log_in(&channel, format!("{name} left {channel}")),
+                            }
+                        }
+                        ClientEvent::PeerLeftChannel { peer, channel } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_log_in(&channel, format!("{name} left"));
+                        }
+                        ClientEvent::ChannelUpdated(channel) if channel.is_archived() => {
+                            self.push_system(format!("channel archived: {}", channel.identifier()));
+                        }
+                        ClientEvent::ChannelDiscovered(channel) => {
+                            self.push_system(format!("found public channel: {}", channel.identifier()));
+                        }
+                        ClientEvent::UndecryptableMessage { channel, sender, .. } => {
+                            let name = display_name(self.client.get_mut(), &sender);
+                            self.push_log_in(&channel, format!("could not decrypt a message from {name}"));
+                        }
+                        ClientEvent::PeerKicked { peer, channel } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_log_in(&channel, format!("{name} was kicked"));
+                        }
+                        ClientEvent::PeerBanned { peer, channel } => {
+                            let name = display_name(self.client.get_mut(), &peer);
+                            self.push_log_in(&channel, format!("{name} was banned"));
+                        }
+                        ClientEvent::ChannelWelcome { channel, message } => {
+                            self.push_log_in(&channel, format!("Welcome: {message}"));
+                        }
+                        ClientEvent::PollCreated { poll, channel, question, options, .. } => {
+                            self.push_poll(&channel, &question, &options);
+                            self.polls.insert(channel, poll);
+                        }
+                        ClientEvent::PollUpdated { poll, channel, tally } => {
+                            if let Some(options) = self.client.get_ref().poll(&poll).map(|p| p.options.clone()) {
+                                let results = options
+                                    .iter()
+                                    .zip(tally)
+                                    .map(|(option, votes)| format!("{option} ({votes})"))
+                                    .collect::<Vec<_>>()
+                                    .join(", ");
+                                self.push_log_in(&channel, format!("Poll {poll} results: {results}"));
+                            }
+                        }
+                        ClientEvent::PollClosed { poll, channel, options, tally } => {
+                            let results = options
+                                .iter()
+                                .zip(tally)
+                                .map(|(option, votes)| format!("{option} ({votes})"))
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            self.push_log_in(&channel, format!("Poll {poll} closed with results: {results}"));
+                            if self.polls.get(&channel) == Some(&poll) {
+                                self.polls.remove(&channel);
+                            }
+                        }
+                        ClientEvent::NicknameCollision { nick, peers } => {
+                            self.push_system(format!(
+                                "warning: nickname {nick} is used by {} peers, possible impersonation",
+                                peers.len()
+                            ));
+                        }
+                        ClientEvent::KeyChanged { nick, trusted, peer } => {
+                            self.push_system(format!(
+                                "warning: {nick} was first seen as {trusted}, but is now used by {peer}; compare fingerprints, then /trust {nick} {peer}",
+                            ));
+                        }
+                        ClientEvent::NickRegistered { nick, owner } => {
+                            let client = self.client.get_ref();
+                            if &nick == client.nick() && owner != client.peer_id() {
+                                self.push_system(format!(
+                                    "warning: your nickname {nick} is registered to {owner}, so others see you as {nick}(unverified)"
+                                ));
+                            }
+                        }
+                        ClientEvent::MessagePublished(id) => {
+                            self.outgoing.remove(&id);
+                        }
+                        ClientEvent::MessageRejected(id, err) => {
+                            if let Some(channel) = self.outgoing.remove(&id) {
+                                let reason = match err {
+                                    Error::PublishError(PublishError::InsufficientPeers) => "insufficient peers".to_owned(),
+                                    err => err.to_string(),
+                                };
+                                self.push_log_in(&channel, format!("Could not send message: {reason}."));
+                            }
+                        }
+                        ClientEvent::Error(err) => {
+                            self.push_system(format!("error: {err}"));
+                        }
+                        ClientEvent::ScheduledMessageSent { id, contents, channel, message_type } => {
+                            self.push_system(format!("sent scheduled message {id}"));
+                            self.push_own_message(
+                                None,
+                                contents,
+                                &channel,
+                                message_type,
+                                None,
+                            );
+                        }
+                        ClientEvent::ScheduledMessageFailed { id, error } => {
+                            self.push_system(format!("Could not send scheduled message {id}: {error}"));
+                        }
                         _ => {}
                     }
                     self.draw(writer, false)?;
                 }
+                Some(record) = recv_log(&mut self.logs) => {
+                    self.push_log_record(record);
+                    self.draw(writer, false)?;
+                }
                 event = term_events.select_next_some() => {
                     let event = event?;
 
@@ -146,6 +597,12 @@ impl App {
                 }
             }
         }
+
+        self.push_system("Shutting down...");
+        self.draw(writer, false)?;
+        let reason = self.quit_reason.take();
+        self.client.get_mut().shutdown_with_reason(reason).await?;
+
         Ok(())
     }
 
@@ -162,7 +619,12 @@ impl App {
             queue!(writer, terminal::Clear(terminal::ClearType::All))?;
         }
 
-        self.draw_current_buffer(writer, size)?;
+        let (cols, rows) = size;
+        let sidebar = self.sidebar_width(cols);
+        self.draw_current_buffer(writer, (cols - sidebar, rows))?;
+        if sidebar > 0 {
+            self.draw_sidebar(writer, size, sidebar)?;
+        }
         self.draw_status_line(writer, size)?;
         self.draw_input_buffer(writer, size)?;
 
@@ -177,7 +639,19 @@ impl App {
         (cols, rows): (u16, u16),
     ) -> anyhow::Result<()> {
         let buffer = self.current_buffer.borrow_mut();
+        let theme = self.theme.clone();
+        let (show_timestamps, timestamp_format) =
+            (self.show_timestamps, self.timestamp_format);
+        let stamp = |time| {
+            if show_timestamps {
+                timestamp_format.format(time)
+            } else {
+                String::new()
+            }
+        };
 
+        // each entry is drawn as one or more parts, listed top to bottom, each
+        // led by who it is from
         let lines = buffer
             .history
             .iter()
@@ -186,44 +660,220 @@ impl App {
                 HistoryEntry::Message {
                     sender,
                     contents,
-                    message_type: _,
+                    message_type,
+                    in_reply_to,
+                    edited,
+                    mentioned,
+                    sent_at,
+                    ..
                 } => {
-                    let nick = match self
-                        .client
-                        .get_mut()
-                        .fetch_nickname(sender)
-                        .unwrap()
-                    {
-                        Some(nick) => nick.to_owned(),
-                        None => sender
-                            .to_base58()
-                            .chars()
-                            .skip(16)
-                            .take(16)
-                            .collect(),
+                    let stamp = stamp(*sent_at);
+                    let mut parts = vec![];
+                    if let Some(target) = in_reply_to {
+                        let (prefix, excerpt) =
+                            match quote(&buffer.history, target) {
+                                Some((sender, excerpt)) => {
+                                    let nick = display_name(
+                                        self.client.get_mut(),
+                                        &sender,
+                                    );
+                                    let pad = " ".repeat(stamp.width());
+                                    (format!("{pad}  > {nick}: "), excerpt)
+                                }
+                                None => (
+                                    format!(
+                                        "{}  >: ",
+                                        " ".repeat(stamp.width())
+                                    ),
+                                    "(unknown message)".to_owned(),
+                                ),
+                            };
+                        parts.push((
+                            theme.muted,
+                            prefix,
+                            None,
+                            excerpt,
+                            vec![],
+                        ));
+                    }
+
+                    let nick = display_name(self.client.get_mut(), sender);
+                    // messages which fail to parse are shown as they are
+                    let (mut contents, mut styles) = match message_type {
+                        MessageType::Formatted => parse_formatted(contents)
+                            .map(|spans| flatten_spans(&spans))
+                            .unwrap_or_else(|| (contents.clone(), vec![])),
+                        _ => (contents.clone(), vec![]),
+                    };
+                    if *edited {
+                        let marker = " (edited)";
+                        contents.push_str(marker);
+                        if !styles.is_empty() {
+                            styles.extend(
+                                marker.chars().map(|_| TextStyle::default()),
+                            );
+                        }
+                    }
+                    // actions read as a sentence about their sender
+                    let (color, before_nick) = match message_type {
+                        MessageType::Me => (theme.action, format!("{stamp}* ")),
+                        _ => (theme.text, stamp),
+                    };
+                    let color = if *mentioned { theme.mention } else { color };
+                    let start = before_nick.chars().count();
+                    let nick_span = (
+                        start..start + nick.chars().count(),
+                        theme.nick_color(sender),
+                    );
+                    let lead = match message_type {
+                        MessageType::Me => format!("{before_nick}{nick} "),
+                        _ => format!("{before_nick}{nick}: "),
                     };
-                    (style::Color::White, nick, contents)
+                    parts.push((
+                        color,
+                        lead,
+                        Some(nick_span),
+                        contents,
+                        styles,
+                    ));
+                    parts
                 }
-                HistoryEntry::Log(message) => {
-                    (style::Color::White, "INFO".to_owned(), message)
+                HistoryEntry::Deleted { sender, sent_at } => {
+                    let nick = display_name(self.client.get_mut(), sender);
+                    let lead = format!("{}{nick}: ", stamp(*sent_at));
+                    let contents = "(deleted)".to_owned();
+                    vec![(theme.muted, lead, None, contents, vec![])]
                 }
+                HistoryEntry::Log { message, logged_at } => vec![(
+                    theme.text,
+                    format!("{}INFO: ", stamp(*logged_at)),
+                    None,
+                    message.clone(),
+                    vec![],
+                )],
             })
-            .flat_map(|(color, prefix, contents)| {
-                wrap(&prefix, contents, cols)
+            .flat_map(|parts| parts.into_iter().rev())
+            .flat_map(|(color, lead, nick_span, contents, styles)| {
+                let lines = wrap(&lead, &contents, cols);
+                let mut lines = style_lines(&lead, lines, &contents, &styles);
+                // the nickname is within the lead, on the first line
+                if let (Some((span, nick_color)), Some((line, styles))) =
+                    (nick_span, lines.first_mut())
+                {
+                    color_chars(line, styles, span, nick_color);
+                }
+                lines
                     .into_iter()
                     .rev()
-                    .map(move |line| (color, line))
+                    .map(move |(line, styles)| (color, line, styles))
             })
+            .skip(buffer.scroll)
             .take((rows - 2).into());
 
-        for (idx, (color, line)) in lines.enumerate() {
+        for (idx, (color, line, styles)) in lines.enumerate() {
             let idx: u16 = idx.try_into().unwrap();
             queue!(
                 writer,
                 cursor::MoveTo(0, rows - 3 - idx),
                 style::SetForegroundColor(color),
-                style::Print(line),
-                terminal::Clear(terminal::ClearType::UntilNewLine)
+            )?;
+            if styles.is_empty() {
+                queue!(writer, style::Print(line))?;
+            } else {
+                print_styled(writer, &line, &styles, color, theme.code)?;
+            }
+            queue!(writer, terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+
+        queue!(writer, style::ResetColor)?;
+
+        Ok(())
+    }
+
+    /// Get how many columns the sidebar takes up, which is none if it is
+    /// hidden or there is no room for it.
+    fn sidebar_width(&self, cols: u16) -> u16 {
+        let buffer_type = &self.current_buffer.borrow().buffer_type;
+        if !self.show_sidebar
+            || *buffer_type == BufferType::System
+            || cols < SIDEBAR_MIN_COLUMNS
+        {
+            return 0;
+        }
+        (cols / 5).clamp(14, 28)
+    }
+
+    /// Draw the members of the current channel down the right of the screen,
+    /// marking those which are connected and those which were verified, and
+    /// how long ago the others were last seen.
+    fn draw_sidebar<W: Write>(
+        &mut self,
+        writer: &mut W,
+        (cols, rows): (u16, u16),
+        width: u16,
+    ) -> anyhow::Result<()> {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let client = self.client.get_mut();
+        let own_id = client.peer_id();
+        let others = match buffer_type {
+            BufferType::Channel(channel) => client.channel_members(&channel),
+            BufferType::Direct(peer) => vec![peer],
+            BufferType::System => vec![],
+        };
+
+        let mut members = std::iter::once(own_id)
+            .chain(others)
+            .map(|peer| (display_name(client, &peer), peer))
+            .collect::<Vec<_>>();
+        members.sort_by_key(|(name, _)| name.to_lowercase());
+
+        let x = cols - width;
+        let height = usize::from(rows.saturating_sub(2));
+        let inner = usize::from(width - 1);
+        let now = SystemTime::now();
+        let mut entries =
+            vec![(self.theme.muted, format!(" {} members", members.len()))];
+        for (name, peer) in &members {
+            let present = *peer == own_id || client.is_connected(peer);
+            let marker = if present { '●' } else { '○' };
+            let badge = if client.is_verified(peer) { " ✓" } else { "" };
+            let age = match client.last_seen(peer) {
+                Some(time) if *peer != own_id => format_age(time, now),
+                _ => String::new(),
+            };
+            let name = truncate_to_width(
+                name,
+                inner.saturating_sub(3 + badge.width() + age.width()),
+            );
+            let entry = format!("{marker} {name}{badge}");
+            // the age is kept to the right edge
+            let padding = inner.saturating_sub(entry.width() + age.width());
+            entries.push((
+                self.theme.nick_color(peer),
+                format!("{entry}{}{age}", " ".repeat(padding)),
+            ));
+        }
+        // leave the last row to say how many did not fit
+        if height > 0 && entries.len() > height {
+            let hidden = entries.len() - (height - 1);
+            entries.truncate(height - 1);
+            entries.push((self.theme.muted, format!(" +{hidden} more")));
+        }
+
+        for row in 0..height {
+            let (color, entry) = entries
+                .get(row)
+                .cloned()
+                .unwrap_or((self.theme.text, String::new()));
+            let padding = inner.saturating_sub(entry.width());
+            queue!(
+                writer,
+                cursor::MoveTo(x, row as u16),
+                style::SetForegroundColor(self.theme.muted),
+                style::Print('│'),
+                style::SetForegroundColor(color),
+                style::Print(entry),
+                style::Print(" ".repeat(padding)),
             )?;
         }
 
@@ -240,26 +890,84 @@ impl App {
         queue!(
             writer,
             cursor::MoveTo(0, rows - 2),
-            style::SetBackgroundColor(style::Color::DarkGrey),
+            style::SetBackgroundColor(self.theme.status_line),
             style::Print("-".repeat(cols.into())),
         )?;
 
-        queue!(writer, cursor::MoveTo(0, rows - 2),)?;
-        match &self.current_buffer.borrow().buffer_type {
+        let header = match &self.current_buffer.borrow().buffer_type {
             BufferType::Channel(ident) => {
-                queue!(
-                    writer,
-                    style::Print("["),
-                    style::Print(ident),
-                    style::Print("]")
-                )?;
+                if self.client.get_ref().is_channel_archived(ident) {
+                    format!("[{ident}] (archived, read-only)")
+                } else {
+                    format!("[{ident}]")
+                }
             }
-            BufferType::System => {
-                queue!(writer, style::Print("*system*"))?;
+            BufferType::Direct(peer) => {
+                let client = self.client.get_mut();
+                let name = display_name(client, peer);
+                let badge = verified_badge(client.is_verified(peer));
+                format!("[@{name}{badge}]")
             }
-        }
+            BufferType::System => "*system*".to_owned(),
+        };
+        // how many peers are connected, and whether any of them receive the
+        // messages of the current channel
+        let (color, connection) = match self.connected_peers {
+            0 => (self.theme.offline, " [offline]".to_owned()),
+            peers => {
+                let mesh = match &self.current_buffer.borrow().buffer_type {
+                    BufferType::Channel(ident) => {
+                        Some(self.client.get_ref().mesh_peers(ident).len())
+                    }
+                    _ => None,
+                };
+                let plural = if peers == 1 { "" } else { "s" };
+                let connection = match mesh {
+                    Some(0) => format!(" [{peers} peer{plural}, no mesh]"),
+                    Some(mesh) => {
+                        format!(" [{peers} peer{plural}, {mesh} in mesh]")
+                    }
+                    None => format!(" [{peers} peer{plural}]"),
+                };
+                (self.theme.text, connection)
+            }
+        };
+        let mut width = header.width() + connection.width();
+        queue!(
+            writer,
+            cursor::MoveTo(0, rows - 2),
+            style::SetForegroundColor(self.theme.text),
+            style::Print(header),
+            style::SetForegroundColor(color),
+            style::Print(connection),
+        )?;
+
+        // list the buffers by the numbers they are switched to with, marking
+        // those with unread messages
+        for (idx, buffer) in self.buffers.clone().iter().enumerate() {
+            let buffer = buffer.borrow();
+            let (color, marker) = match (buffer.has_unread, buffer.has_mention)
+            {
+                (_, true) => (self.theme.mention, "!"),
+                (true, false) => (self.theme.activity, "+"),
+                (false, false) => (self.theme.text, ""),
+            };
+            let entry = format!(
+                " {}:{}{marker}",
+                idx + 1,
+                buffer.name(self.client.get_mut())
+            );
 
-        // TODO show rest of channels
+            width += entry.width();
+            if width > cols.into() {
+                break;
+            }
+            queue!(
+                writer,
+                style::SetForegroundColor(color),
+                style::Print(entry)
+            )?;
+        }
 
         queue!(writer, style::ResetColor)?;
 
@@ -271,127 +979,380 @@ impl App {
         writer: &mut W,
         (cols, rows): (u16, u16),
     ) -> anyhow::Result<()> {
-        let nick = self.client.get_ref().nick().clone();
+        let prompt = format!("{}: ", self.client.get_ref().nick());
 
         // the amount of space we have to render the input buffer
-        // takes into account the nickname and separator
-        let input_space = cols as isize - nick.len() as isize - 3;
+        // takes into account the nickname and separator, and the cursor
+        // after the last character
+        let input_space = usize::from(cols).saturating_sub(prompt.width() + 1);
 
-        // how many characters we will skip at the beginning of the message
-        let skip = isize::max(0, self.input_buffer.len() as isize - input_space) as usize;
+        // the line breaks of multi-line messages are shown as symbols, so
+        // that the input stays on one line
+        let text = self.input_buffer.as_str().replace('\n', "↵");
+        let mut graphemes = text.graphemes(true);
+
+        // how many columns we will skip at the beginning of the message, so
+        // that the cursor stays in view, without splitting any character
+        let column = self.input_buffer.cursor_column();
+        let mut skip = 0;
+        while column > skip + input_space {
+            match graphemes.next() {
+                Some(grapheme) => skip += grapheme.width(),
+                None => break,
+            }
+        }
+        let shown = truncate_to_width(graphemes.as_str(), input_space);
 
         queue!(
             writer,
             cursor::MoveTo(0, rows - 1),
-            style::Print(format!("{}: ", nick)),
-            style::Print(
-                &self
-                    .input_buffer
-                    .chars()
-                    .skip(skip)
-                    .collect::<String>()
-            ),
+            style::Print(&prompt),
+            style::Print(shown),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+            cursor::MoveTo((prompt.width() + column - skip) as u16, rows - 1),
             cursor::Show,
-            terminal::Clear(terminal::ClearType::UntilNewLine)
         )?;
 
         Ok(())
     }
 
     fn handle_event(&mut self, event: Event) -> anyhow::Result<bool> {
-        // TODO use a readline library
         if let Event::Key(event) = event {
-            match event.code {
-                KeyCode::Char('c' | 'd')
-                    if event.modifiers.contains(KeyModifiers::CONTROL) =>
-                {
+            match self.keymap.action(&event) {
+                Some(Action::Quit) => {
                     self.wants_to_exit = true;
+                    return Ok(false);
                 }
-                KeyCode::Char(c) => {
-                    self.input_buffer.push(c);
+                Some(Action::PageUp) => {
+                    self.scroll_up();
+                    return Ok(true);
                 }
-                KeyCode::Backspace => {
-                    self.input_buffer.pop();
+                Some(Action::PageDown) => {
+                    let page = page_height();
+                    let mut buffer = self.current_buffer.borrow_mut();
+                    buffer.scroll = buffer.scroll.saturating_sub(page);
+                    return Ok(true);
                 }
-                KeyCode::Enter => {
-                    let message = self.input_buffer.clone();
-                    self.input_buffer.clear();
-
-                    if let Some(command) = message.strip_prefix('/') {
-                        self.run_command(command)?;
-                        return Ok(true);
-                    } else {
-                        self.send_message(message);
+                Some(Action::CursorLeft) => self.input_buffer.move_left(),
+                Some(Action::CursorRight) => self.input_buffer.move_right(),
+                Some(Action::LineStart) => self.input_buffer.move_home(),
+                Some(Action::LineEnd) => self.input_buffer.move_end(),
+                Some(Action::WordLeft) => self.input_buffer.move_word_left(),
+                Some(Action::WordRight) => self.input_buffer.move_word_right(),
+                Some(Action::DeleteForward) => self.input_buffer.delete(),
+                Some(Action::KillWord) => self.input_buffer.kill_word(),
+                Some(Action::KillToEnd) => self.input_buffer.kill_to_end(),
+                Some(Action::KillToStart) => self.input_buffer.kill_to_start(),
+                Some(Action::Yank) => self.input_buffer.yank(),
+                Some(Action::HistoryPrev) => self.input_buffer.history_prev(),
+                Some(Action::HistoryNext) => self.input_buffer.history_next(),
+                Some(Action::Complete) => self.complete(),
+                Some(Action::NextBuffer) => {
+                    self.cycle_buffer(1);
+                    return Ok(true);
+                }
+                Some(Action::PrevBuffer) => {
+                    self.cycle_buffer(-1);
+                    return Ok(true);
+                }
+                Some(Action::GoToBuffer(n)) => {
+                    if let Some(buffer) = self.buffers.get(usize::from(n) - 1) {
+                        self.switch_to(buffer.clone());
                     }
+                    return Ok(true);
                 }
-                _ => {}
+                Some(Action::ToggleSidebar) => {
+                    self.show_sidebar = !self.show_sidebar;
+                    return Ok(true);
+                }
+                Some(Action::InsertNewline) => self.input_buffer.insert('\n'),
+                None => match event.code {
+                    KeyCode::Char(c) => self.input_buffer.insert(c),
+                    KeyCode::Backspace => self.input_buffer.backspace(),
+                    // the lines of pasted text are kept in one message
+                    KeyCode::Enter if is_pasting() => {
+                        self.input_buffer.insert('\n')
+                    }
+                    KeyCode::Enter => {
+                        let message = self.input_buffer.submit();
+
+                        if let Some(command) = message.strip_prefix('/') {
+                            if command.contains('\n') {
+                                self.push_system(
+                                    "Commands may not span several lines.",
+                                );
+                            } else {
+                                self.run_command(command)?;
+                            }
+                            return Ok(true);
+                        } else {
+                            self.send_message(message);
+                        }
+                    }
+                    _ => {}
+                },
             }
         }
 
         Ok(false)
     }
 
+    /// Change the user's nickname, which is shown in the prompt from then on.
+    fn change_nick(&mut self, nick: &str) {
+        if nick.len() > MAX_NICK_LENGTH {
+            self.push_system(format!(
+                "Nicknames may be at most {MAX_NICK_LENGTH} bytes long."
+            ));
+            return;
+        }
+
+        let old = self.client.get_ref().nick().clone();
+        match self.client.get_mut().set_nick(nick) {
+            Ok(()) => self.push_system(format!("{old} is now known as {nick}")),
+            Err(err) => {
+                self.push_system(format!("Could not change nickname: {err}"))
+            }
+        }
+    }
+
+    /// Switch to a buffer, marking it as read.
+    fn switch_to(&mut self, buffer: Rc<RefCell<Buffer>>) {
+        let mut state = buffer.borrow_mut();
+        state.has_unread = false;
+        state.has_mention = false;
+        drop(state);
+        self.current_buffer = buffer;
+    }
+
+    /// Mark a buffer as having unread messages, unless it is the one shown.
+    fn mark_unread(&self, buffer: &Rc<RefCell<Buffer>>, mention: bool) {
+        if Rc::ptr_eq(buffer, &self.current_buffer) {
+            return;
+        }
+        let mut buffer = buffer.borrow_mut();
+        buffer.has_unread = true;
+        buffer.has_mention |= mention;
+    }
+
+    /// Switch to the buffer a number of places after the current one in the
+    /// buffer list, wrapping around at either end.
+    fn cycle_buffer(&mut self, offset: isize) {
+        let len = self.buffers.len() as isize;
+        let current = self
+            .buffers
+            .iter()
+            .position(|b| Rc::ptr_eq(b, &self.current_buffer))
+            .unwrap_or(0) as isize;
+        let index = (current + offset).rem_euclid(len) as usize;
+        self.switch_to(self.buffers[index].clone());
+    }
+
+    /// Complete the word before the cursor, or fill in the next candidate if
+    /// the line was not edited since the last one was.
+    fn complete(&mut self) {
+        if let Some(completion) = &mut self.completion {
+            if completion.advance(&mut self.input_buffer) {
+                return;
+            }
+        }
+
+        let (start, word) = self.input_buffer.word_before_cursor();
+        let before = &self.input_buffer.as_str()[..start];
+        let kind = completion_kind(before, word);
+        // nicknames are completed as mentions if written as one, and
+        // addressed to at the start of a line
+        let (prefix, suffix) = match (word.starts_with('@'), before.is_empty())
+        {
+            (true, _) => ("@", " "),
+            (false, true) => ("", ": "),
+            (false, false) => ("", " "),
+        };
+
+        let candidates = match kind {
+            CompletionKind::Command => {
+                COMMANDS.iter().map(|c| format!("/{} ", c.name)).collect()
+            }
+            CompletionKind::Channel => {
+                let client = self.client.get_ref();
+                client
+                    .joined_channels()
+                    .chain(client.discovered_channels())
+                    .map(|c| format!("{c} "))
+                    .collect()
+            }
+            CompletionKind::Nick => self
+                .channel_nicks()
+                .into_iter()
+                .map(|nick| format!("{prefix}{nick}{suffix}"))
+                .collect::<Vec<_>>(),
+        };
+        self.completion = Completion::begin(&mut self.input_buffer, candidates);
+    }
+
+    /// Get the nicknames of the peers in the current channel or direct
+    /// conversation, if any.
+    fn channel_nicks(&mut self) -> Vec<String> {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let client = self.client.get_mut();
+        let peers = match buffer_type {
+            BufferType::Channel(channel) => client.channel_members(&channel),
+            BufferType::Direct(peer) => vec![peer],
+            BufferType::System => return Vec::new(),
+        };
+
+        peers
+            .iter()
+            .filter_map(|peer| client.fetch_nickname(peer).ok().cloned())
+            .flatten()
+            .collect()
+    }
+
+    /// Join a channel, as if the user had run the join command, unless it was
+    /// already joined, such as when resuming the previous session.
+    pub fn join_channel(
+        &mut self,
+        channel: &ChannelIdentifier,
+    ) -> anyhow::Result<()> {
+        if self
+            .client
+            .get_ref()
+            .joined_channels()
+            .any(|c| c == channel)
+        {
+            return Ok(());
+        }
+        self.run_command(&format!("join {channel}"))
+    }
+
     fn run_command(&mut self, command: &str) -> anyhow::Result<()> {
-        let args = command.split(char::is_whitespace).collect::<Vec<_>>();
+        let mut args = command.split(char::is_whitespace);
+        let name = args.next().unwrap_or_default();
+        let args = args.collect::<Vec<_>>();
 
-        match *args.as_slice() {
-            ["join", channel] => {
-                self.client
-                    .get_mut()
-                    .subscribe_channel(channel.to_owned())?;
-                self.buffers.push(Rc::new(RefCell::new(Buffer::new(
-                    BufferType::Channel(channel.to_owned()),
-                ))));
-                self.push_system(format!("Joined channel {channel}"));
+        match commands::find(COMMANDS, name) {
+            Some(command) if command.accepts(args.len()) => {
+                (command.run)(self, &args)?
             }
-            ["leave", channel] => {
-                self.client
-                    .get_mut()
-                    .unsubscribe_channel(channel.to_owned())?;
-                self.buffers.retain(|b| matches!(&b.borrow().buffer_type, BufferType::Channel(ident) if ident != channel) );
-                self.push_system(format!("Left channel {channel}"));
+            Some(command) => {
+                self.push_system(format!("Usage: {}", command.usage))
             }
-            ["go"] => {
-                self.current_buffer = self.buffers.first().unwrap().clone();
+            None => {
+                self.push_system(format!("Unknown command {name}, see /help"))
             }
-            ["go", channel] => {
-                if let Some(buffer) = self.channel_by_ident(channel) {
-                    self.current_buffer = buffer.clone();
+        }
+
+        Ok(())
+    }
+
+    /// List the commands, or describe one of them.
+    fn show_help(&self, name: Option<&str>) {
+        let name = match name {
+            Some(name) => name.trim_start_matches('/'),
+            None => {
+                self.push_system("Commands, see /help <command> for more:");
+                for command in COMMANDS {
+                    self.push_system(format!("- {}", command.usage));
                 }
+                return;
             }
-            ["list"] => {
-                self.push_system("Channels you are in:");
-                for buffer in &self.buffers {
-                    self.push_system(format!("- {}", buffer.borrow().name()));
-                }
+        };
+
+        match commands::find(COMMANDS, name) {
+            Some(command) => {
+                self.push_system(format!("Usage: {}", command.usage));
+                self.push_system(command.help);
             }
-            _ => self.push_system("Invalid command"),
+            None => self.push_system(format!("Unknown command {name}")),
         }
+    }
 
-        Ok(())
+    /// Show how a command is written, after it was given arguments it does
+    /// not understand.
+    fn show_usage(&self, name: &str) {
+        if let Some(command) = commands::find(COMMANDS, name) {
+            self.push_system(format!("Usage: {}", command.usage));
+        }
+    }
+
+    /// Apply something to each peer known by a nickname or peer ID.
+    fn for_each_peer(&mut self, target: &str, f: impl Fn(&mut Self, PeerId)) {
+        let peers = self.resolve_peers(target);
+        if peers.is_empty() {
+            self.push_system(format!("No known peer {target}"));
+        }
+        for peer in peers {
+            f(self, peer);
+        }
+    }
+
+    /// Set whether the current channel is encrypted or invite-only, which
+    /// only its owner may do.
+    fn set_channel_flag(&mut self, flag: &str, setting: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let on = setting == "on";
+        let client = self.client.get_mut();
+        let (result, name) = match flag {
+            "encrypt" => {
+                (client.set_channel_encrypted(channel, on), "encryption")
+            }
+            _ => (client.set_channel_invite_only(channel, on), "invite-only"),
+        };
+        match result {
+            Ok(_) => self.push_channel_log(format!("Turned {name} {setting}.")),
+            Err(Error::NotChannelOwner(_)) => {
+                self.push_channel_log("Only the channel owner may change this.")
+            }
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
     }
 
     fn send_message(&mut self, message: String) {
+        let message_type = message_type_of(&message);
+        self.send_message_as(message, message_type);
+    }
+
+    /// Send a message of a given type to the current channel or direct
+    /// conversation.
+    fn send_message_as(&mut self, message: String, message_type: MessageType) {
         let buffer_type = self.current_buffer.borrow().buffer_type.clone();
-        if let BufferType::Channel(channel) = &buffer_type {
+        if let BufferType::Direct(peer) = buffer_type {
+            self.send_direct(peer, message, message_type);
+        } else if let BufferType::Channel(channel) = &buffer_type {
             match self.client.get_mut().send_message(
                 &message,
-                MessageType::Normal,
+                message_type,
                 channel.clone(),
             ) {
-                Err(Error::PublishError(PublishError::InsufficientPeers)) => {
+                Err(Error::ChannelArchived(_)) => {
+                    self.push_channel_log(
+                        "Could not send message: channel is archived.",
+                    );
+                }
+                Err(Error::PolicyViolation(_)) => {
+                    self.push_channel_log(
+                        "Could not send message: not allowed by channel policy.",
+                    );
+                }
+                Err(Error::SlowMode(_)) => {
                     self.push_channel_log(
-                        "Could not send message: insufficient peers.",
+                        "Could not send message: channel is in slow mode.",
                     );
                 }
                 Err(err) => self.push_system(format!("{err:?}")),
-                Ok(_) => {
+                Ok(id) => {
                     let channel = channel.clone();
-                    self.push_channel_message(
-                        self.client.get_ref().peer_id(),
+                    self.outgoing.insert(id.clone(), channel.clone());
+                    self.push_own_message(
+                        Some(id),
                         message,
                         &channel,
-                        MessageType::Normal,
+                        message_type,
+                        None,
                     )
                 }
             }
@@ -400,54 +1361,2330 @@ impl App {
         }
     }
 
-    fn channel_by_ident(&self, channel: &str) -> Option<&Rc<RefCell<Buffer>>> {
-        self.buffers
-            .iter()
-            .find(|b| {
-                matches!(
-                    &b.borrow().buffer_type,
-                    BufferType::Channel(c) if c == channel
+    /// Send a file to the current channel.
+    fn attach(&mut self, path: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                return self.push_system(format!("Could not read file: {err}"))
+            }
+        };
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_owned());
+
+        match self.client.get_mut().send_attachment(
+            channel.clone(),
+            &name,
+            data,
+        ) {
+            Ok(id) => {
+                self.outgoing.insert(id.clone(), channel.clone());
+                self.push_own_message(
+                    Some(id),
+                    format!("[attachment] {name}"),
+                    &channel,
+                    MessageType::Normal,
+                    None,
                 )
-            })
+            }
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
     }
 
-    fn push_channel_message(
-        &mut self,
-        sender: PeerId,
-        contents: impl Into<String>,
-        channel: &ChannelIdentifier,
-        message_type: MessageType,
-    ) {
-        self.channel_by_ident(channel)
-            .unwrap() // TODO
-            .borrow_mut()
-            .history
-            .push_back(HistoryEntry::Message {
-                sender,
-                contents: contents.into(),
-                message_type,
-            });
-    }
+    /// Post a poll to the current channel, written as the question and its
+    /// options separated by `|`.
+    fn set_message_lifetime(&mut self, lifetime: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
 
-    fn push_channel_log(&self, contents: impl Into<String>) {
-        self.current_buffer
-            .borrow_mut()
-            .history
-            .push_back(HistoryEntry::Log(contents.into()));
+        let lifetime = match lifetime {
+            "off" => None,
+            lifetime => match lifetime.parse() {
+                Ok(seconds) => Some(Duration::from_secs(seconds)),
+                Err(_) => {
+                    return self.push_system("Usage: /disappear <seconds|off>")
+                }
+            },
+        };
+
+        let result = self
+            .client
+            .get_mut()
+            .set_message_lifetime(channel, lifetime);
+        match (result, lifetime) {
+            (Ok(_), Some(lifetime)) => self.push_channel_log(format!(
+                "Messages you send here will disappear after {} seconds.",
+                lifetime.as_secs()
+            )),
+            (Ok(_), None) => {
+                self.push_channel_log("Messages you send here will stay.")
+            }
+            (Err(err), _) => self.push_system(format!("{err:?}")),
+        }
     }
 
-    fn push_system(&self, message: impl Into<String>) {
-        self.system_buffer
-            .borrow_mut()
-            .history
-            .push_back(HistoryEntry::Log(message.into()));
+    fn show_policy(&mut self) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let policy = self
+            .client
+            .get_ref()
+            .channel(&channel)
+            .map(|channel| channel.policy().clone())
+            .unwrap_or_default();
+        let or_off = |limit: Option<u32>| {
+            limit.map_or_else(|| String::from("off"), |n| n.to_string())
+        };
+        let types = policy.allowed_types.map_or_else(
+            || String::from("all"),
+            |types| {
+                types
+                    .iter()
+                    .map(|t| format!("{t:?}").to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            },
+        );
+        self.push_channel_log(format!(
+            "Slow mode: {}, max length: {}, types: {types}",
+            or_off(policy.slow_mode),
+            or_off(policy.max_message_length),
+        ));
     }
-}
 
-fn wrap(prefix: &str, message: &str, columns: u16) -> Vec<String> {
-    let indent = " ".repeat(prefix.len() + 2);
+    fn set_policy(&mut self, setting: &str, value: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let mut policy = self
+            .client
+            .get_ref()
+            .channel(&channel)
+            .map(|channel| channel.policy().clone())
+            .unwrap_or_default();
+        let limit = match value {
+            "off" => Ok(None),
+            value => value.parse().map(Some),
+        };
+        match (setting, limit) {
+            ("slowmode", Ok(limit)) => policy.slow_mode = limit,
+            ("maxlength", Ok(limit)) => policy.max_message_length = limit,
+            ("types", _) if value == "all" => policy.allowed_types = None,
+            ("types", _) => {
+                let types = value
+                    .split(',')
+                    .map(|t| match t {
+                        "normal" => Some(MessageType::Normal),
+                        "me" => Some(MessageType::Me),
+                        "formatted" => Some(MessageType::Formatted),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>();
+                match types {
+                    Some(types) => policy.allowed_types = Some(types),
+                    None => {
+                        return self.push_system(
+                            "Message types are normal, me, and formatted",
+                        )
+                    }
+                }
+            }
+            _ => {
+                return self.push_system(
+                    "Usage: /policy <slowmode|maxlength> <n|off>, \
+                     /policy types <type,...|all>",
+                )
+            }
+        }
+
+        match self.client.get_mut().set_channel_policy(channel, policy) {
+            Ok(_) => self.push_channel_log("Updated policy."),
+            Err(Error::NotChannelOwner(_)) => self
+                .push_channel_log("Only the channel owner may set its policy."),
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    fn create_poll(&mut self, duration: &str, poll: &str) {
+        let duration = match duration.parse() {
+            Ok(duration) => Duration::from_secs(duration),
+            Err(_) => {
+                self.push_system("Invalid duration, expected seconds");
+                return;
+            }
+        };
+
+        let mut parts = poll.split('|').map(str::trim);
+        let question = parts.next().unwrap_or_default().to_owned();
+        let options = parts.map(str::to_owned).collect::<Vec<_>>();
+        if options.len() < 2 {
+            self.push_system(
+                "Usage: /poll <seconds> <question> | <option> | <option>...",
+            );
+            return;
+        }
+
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        match self.client.get_mut().create_poll(
+            channel.clone(),
+            &question,
+            options.clone(),
+            SystemTime::now() + duration,
+        ) {
+            Ok(id) => {
+                self.push_poll(&channel, &question, &options);
+                self.polls.insert(channel, id);
+            }
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    fn push_poll(
+        &mut self,
+        channel: &ChannelIdentifier,
+        question: &str,
+        options: &[String],
+    ) {
+        self.push_log_in(channel, format!("Poll: {question}"));
+        for (idx, option) in options.iter().enumerate() {
+            self.push_log_in(channel, format!("  [{idx}] {option}"));
+        }
+        self.push_log_in(channel, "Use /vote <number> to vote.");
+    }
+
+    /// Vote in the last poll posted to the current channel.
+    fn vote(&mut self, option: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let poll = match buffer_type {
+            BufferType::Channel(channel) => self.polls.get(&channel).cloned(),
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let (poll, option) = match (poll, option.parse()) {
+            (Some(poll), Ok(option)) => (poll, option),
+            (None, _) => {
+                return self.push_channel_log("There is no poll here.")
+            }
+            (_, Err(_)) => return self.push_system("Invalid option number"),
+        };
+
+        match self.client.get_mut().vote(&poll, option) {
+            Ok(_) => {
+                self.push_channel_log(format!("Voted for option {option}."))
+            }
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    /// Save the last attachment received in the current channel.
+    fn save_attachment(&mut self, path: &str) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let attachment = match buffer_type {
+            BufferType::Channel(channel) => self.attachments.get(&channel),
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let data = attachment
+            .and_then(|a| self.client.get_ref().attachment(&a.hash).cloned());
+        match data {
+            Some(data) => match std::fs::write(path, data) {
+                Ok(_) => self.push_channel_log(format!("Saved to {path}.")),
+                Err(err) => {
+                    self.push_channel_log(format!("Could not save file: {err}"))
+                }
+            },
+            None => self.push_channel_log("No attachment to save."),
+        }
+    }
+
+    /// Reply to the last message a peer sent to the current channel.
+    fn reply(&mut self, target: &str, message: String) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let peers = self.resolve_peers(target);
+        let in_reply_to =
+            self.current_buffer.borrow().history.iter().rev().find_map(
+                |entry| match entry {
+                    HistoryEntry::Message {
+                        id: Some(id),
+                        sender,
+                        ..
+                    } if peers.contains(sender) => Some(id.clone()),
+                    _ => None,
+                },
+            );
+        let in_reply_to = match in_reply_to {
+            Some(in_reply_to) => in_reply_to,
+            None => {
+                return self
+                    .push_channel_log(format!("No message from {target}."))
+            }
+        };
+
+        let message_type = message_type_of(&message);
+        match self.client.get_mut().send_reply(
+            &message,
+            message_type,
+            channel.clone(),
+            Some(in_reply_to.clone()),
+        ) {
+            Err(err) => self.push_system(format!("{err:?}")),
+            Ok(id) => {
+                self.outgoing.insert(id.clone(), channel.clone());
+                self.push_own_message(
+                    Some(id),
+                    message,
+                    &channel,
+                    message_type,
+                    Some(in_reply_to),
+                )
+            }
+        }
+    }
+
+    /// Edit the last message the user sent to the current channel.
+    fn edit_last_message(&mut self, new_contents: String) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let target = match self.last_own_message() {
+            Some(target) => target,
+            None => return self.push_channel_log("No message to edit."),
+        };
+
+        match self.client.get_mut().edit_message(&target, &new_contents) {
+            Ok(_) => self.edit_channel_message(&channel, &target, new_contents),
+            Err(err) => self.push_channel_log(format!("Could not edit: {err}")),
+        }
+    }
+
+    /// Delete the last message the user sent to the current channel.
+    fn delete_last_message(&mut self) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let target = match self.last_own_message() {
+            Some(target) => target,
+            None => return self.push_channel_log("No message to delete."),
+        };
+
+        match self.client.get_mut().delete_message(&target) {
+            Ok(_) => self.delete_channel_message(&channel, &target),
+            Err(err) => {
+                self.push_channel_log(format!("Could not delete: {err}"))
+            }
+        }
+    }
+
+    /// Find the last published message the user sent to the current buffer.
+    fn last_own_message(&self) -> Option<MessageId> {
+        let peer_id = self.client.get_ref().peer_id();
+        self.current_buffer
+            .borrow()
+            .history
+            .iter()
+            .rev()
+            .find_map(|entry| match entry {
+                HistoryEntry::Message {
+                    id: Some(id),
+                    sender,
+                    ..
+                } if *sender == peer_id => Some(id.clone()),
+                _ => None,
+            })
+    }
+
+    /// Switch to the buffer of the direct conversation with a peer, opening
+    /// it if it is not open yet.
+    fn open_direct(&mut self, target: &str) -> Option<PeerId> {
+        let peer = match *self.resolve_peers(target).as_slice() {
+            [peer] => peer,
+            [] => {
+                self.push_system(format!("No known peer {target}"));
+                return None;
+            }
+            _ => {
+                self.push_system(format!(
+                    "Nickname {target} is ambiguous, use a peer ID"
+                ));
+                return None;
+            }
+        };
+
+        let buffer = self.direct_buffer(peer);
+        self.switch_to(buffer);
+        Some(peer)
+    }
+
+    fn send_direct(
+        &mut self,
+        peer: PeerId,
+        message: String,
+        message_type: MessageType,
+    ) {
+        let client = self.client.get_mut();
+        match client.send_direct(&peer, &message, message_type) {
+            Ok(_) => {
+                let own_id = client.peer_id();
+                self.push_direct_message(
+                    peer,
+                    own_id,
+                    message,
+                    message_type,
+                    SystemTime::now(),
+                );
+            }
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    /// Get the buffer of the direct conversation with a peer, opening it in
+    /// the background if it is not open yet.
+    fn direct_buffer(&mut self, peer: PeerId) -> Rc<RefCell<Buffer>> {
+        let buffer_type = BufferType::Direct(peer);
+        if let Some(buffer) = self
+            .buffers
+            .iter()
+            .find(|b| b.borrow().buffer_type == buffer_type)
+        {
+            return buffer.clone();
+        }
+
+        let buffer = Rc::new(RefCell::new(Buffer::new(buffer_type)));
+        self.buffers.push(buffer.clone());
+        buffer
+    }
+
+    /// Add a message to the direct conversation with a peer, sent either by
+    /// the peer or by the user.
+    fn push_direct_message(
+        &mut self,
+        peer: PeerId,
+        sender: PeerId,
+        contents: String,
+        message_type: MessageType,
+        sent_at: SystemTime,
+    ) {
+        self.direct_buffer(peer).borrow_mut().history.push_back(
+            HistoryEntry::Message {
+                id: None,
+                sender,
+                contents,
+                message_type,
+                in_reply_to: None,
+                edited: false,
+                mentioned: false,
+                sent_at,
+            },
+        );
+    }
+
+    fn invite(&mut self, target: &str) {
+        let peer = match *self.resolve_peers(target).as_slice() {
+            [peer] => peer,
+            [] => return self.push_system(format!("No known peer {target}")),
+            _ => {
+                return self.push_system(format!(
+                    "Nickname {target} is ambiguous, use a peer ID"
+                ))
+            }
+        };
+
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        if let BufferType::Channel(channel) = buffer_type {
+            let client = self.client.get_mut();
+            match client.send_invite(channel, peer, INVITE_VALIDITY) {
+                Ok(_) => {
+                    let name = display_name(client, &peer);
+                    self.push_channel_log(format!("Invited {name}."));
+                }
+                Err(Error::NotChannelOwner(_)) => self.push_channel_log(
+                    "Only the channel owner may invite peers.",
+                ),
+                Err(err) => self.push_system(format!("{err:?}")),
+            }
+        } else {
+            self.push_system("You are not in a channel.");
+        }
+    }
+
+    fn remove_peer(&mut self, target: &str, ban: bool) {
+        let peer = match *self.resolve_peers(target).as_slice() {
+            [peer] => peer,
+            [] => return self.push_system(format!("No known peer {target}")),
+            _ => {
+                return self.push_system(format!(
+                    "Nickname {target} is ambiguous, use a peer ID"
+                ))
+            }
+        };
+
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let client = self.client.get_mut();
+        let permission = if ban {
+            Permission::Ban
+        } else {
+            Permission::Kick
+        };
+        let permitted = client
+            .channel(&channel)
+            .is_some_and(|c| c.role(&client.peer_id()).can(permission));
+        if !permitted {
+            return self.push_channel_log(
+                "Only the channel owner and moderators may remove peers.",
+            );
+        }
+
+        let name = display_name(client, &peer);
+        let result = if ban {
+            client.ban_peer(channel, peer)
+        } else {
+            client.kick_peer(channel, peer)
+        };
+        match result {
+            Ok(_) if ban => self.push_channel_log(format!("Banned {name}.")),
+            Ok(_) => self.push_channel_log(format!("Kicked {name}.")),
+            Err(Error::NotPermitted(_)) => self.push_channel_log(format!(
+                "You may not remove {name} from this channel."
+            )),
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    fn grant_role(&mut self, target: &str, role: Role) {
+        let peer = match *self.resolve_peers(target).as_slice() {
+            [peer] => peer,
+            [] => return self.push_system(format!("No known peer {target}")),
+            _ => {
+                return self.push_system(format!(
+                    "Nickname {target} is ambiguous, use a peer ID"
+                ))
+            }
+        };
+
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let client = self.client.get_mut();
+        let name = display_name(client, &peer);
+        match client.grant_role(channel, peer, role) {
+            Ok(_) => self.push_channel_log(format!("{name} is now {role:?}.")),
+            Err(Error::NotChannelOwner(_)) => self
+                .push_channel_log("Only the channel owner may change roles."),
+            Err(err) => self.push_system(format!("{err:?}")),
+        }
+    }
+
+    fn show_roles(&mut self) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let roles = self.client.get_ref().channel_roles(&channel);
+        for (peer, role) in roles {
+            let name = display_name(self.client.get_mut(), &peer);
+            self.push_channel_log(format!("- {name}: {role:?}"));
+        }
+    }
+
+    fn show_bans(&mut self) {
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        let channel = match buffer_type {
+            BufferType::Channel(channel) => channel,
+            _ => return self.push_system("You are not in a channel."),
+        };
+
+        let banned = self.client.get_ref().banned_peers(&channel);
+        if banned.is_empty() {
+            return self.push_channel_log("No one is banned.");
+        }
+        for peer in banned {
+            let name = display_name(self.client.get_mut(), &peer);
+            self.push_channel_log(format!("- {name}"));
+        }
+    }
+
+    fn list_public_channels(&mut self) {
+        let channels = match self.client.get_mut().list_public_channels() {
+            Ok(channels) => channels
+                .into_iter()
+                .map(|channel| {
+                    (
+                        channel.identifier().clone(),
+                        *channel.owner(),
+                        channel.peers().len(),
+                    )
+                })
+                .collect::<Vec<_>>(),
+            Err(err) => return self.push_system(format!("{err:?}")),
+        };
+
+        self.push_system("Searching the directory for public channels...");
+        for (channel, owner, peers) in channels {
+            let owner = display_name(self.client.get_mut(), &owner);
+            self.push_system(format!(
+                "- {channel}: {peers} peers, owned by {owner}"
+            ));
+        }
+    }
+
+    fn whois(&mut self, target: &str) {
+        let peers = self.resolve_peers(target);
+        if peers.is_empty() {
+            self.push_system(format!("No known peer {target}"));
+            return;
+        }
+
+        for peer in peers {
+            let client = self.client.get_mut();
+            let nick = client
+                .fetch_nickname(&peer)
+                .ok()
+                .cloned()
+                .flatten()
+                .unwrap_or_else(|| "(unknown)".to_owned());
+            let last_seen = match client.last_seen(&peer) {
+                Some(time) => format_time(time),
+                None => "never".to_owned(),
+            };
+            // there is no way to draw images here, so fall back to initials
+            let avatar = match client.fetch_avatar(&peer) {
+                Ok(Some(data)) => format!("{} bytes", data.len()),
+                _ => format!("none [{}]", initials(&nick)),
+            };
+            let profile = client.fetch_profile(&peer).ok().flatten().cloned();
+            let fingerprint = client.fingerprint(&peer);
+            let badge = verified_badge(client.is_verified(&peer));
+
+            self.push_system(format!("whois {nick}{badge}:"));
+            self.push_system(format!("- peer ID: {peer}"));
+            self.push_system(format!("- fingerprint: {fingerprint}"));
+            self.push_system(format!("- last seen: {last_seen}"));
+            self.push_system(format!("- avatar: {avatar}"));
+
+            match profile {
+                Some(profile) => {
+                    if let Some(pronouns) = profile.pronouns {
+                        self.push_system(format!("- pronouns: {pronouns}"));
+                    }
+                    if let Some(bio) = profile.bio {
+                        self.push_system(format!("- bio: {bio}"));
+                    }
+                }
+                None => self.push_system("- profile: not yet fetched"),
+            }
+
+            if let Some(info) = self.client.get_ref().whois(&peer).cloned() {
+                self.push_system(format!("- agent: {}", info.agent_version));
+                self.push_system(format!(
+                    "- observed us at: {}",
+                    info.observed_addr
+                ));
+                for address in info.listen_addrs {
+                    self.push_system(format!("- listening on: {address}"));
+                }
+                self.push_system(format!(
+                    "- protocols: {}",
+                    info.protocols.join(", ")
+                ));
+            }
+
+            let client = self.client.get_ref();
+            if let (Some(version), Some(capabilities)) =
+                (client.protocol_version(&peer), client.capabilities(&peer))
+            {
+                let capabilities = capabilities.join(", ");
+                self.push_system(format!("- chat protocol: v{version}"));
+                self.push_system(format!("- capabilities: {capabilities}"));
+            }
+
+            if let Some(session) = client.dm_session(&peer) {
+                let state = if session.confirmed {
+                    "established"
+                } else {
+                    "pending"
+                };
+                self.push_system(format!(
+                    "- encrypted session: {state}, {} ratchet steps",
+                    session.ratchet_steps
+                ));
+            }
+        }
+    }
+
+    /// Show the safety numbers shared with the peers matching a target, to be
+    /// compared out of band before marking them as verified.
+    fn show_safety_numbers(&mut self, target: &str) {
+        let peers = self.resolve_peers(target);
+        if peers.is_empty() {
+            self.push_system(format!("No known peer {target}"));
+            return;
+        }
+
+        for peer in peers {
+            let nick = display_name(self.client.get_mut(), &peer);
+            let number = match self.client.get_ref().safety_number(&peer) {
+                Ok(number) => number,
+                Err(err) => {
+                    self.push_system(format!(
+                        "Could not get safety number with {nick}: {err}"
+                    ));
+                    continue;
+                }
+            };
+            self.push_system(format!("Safety number with {nick} ({peer}):"));
+            self.push_system(format!("  {number}"));
+        }
+        self.push_system(format!(
+            "If theirs matches when compared in person or over another channel, run /verify {target} confirm"
+        ));
+    }
+
+    /// Find the peers referred to by a peer ID or nickname.
+    fn resolve_peers(&self, target: &str) -> Vec<PeerId> {
+        match target.parse() {
+            Ok(peer) => vec![peer],
+            Err(_) => self.client.get_ref().find_peers_by_nick(target),
+        }
+    }
+
+    fn schedule_message(&mut self, delay: &str, message: &str) {
+        let delay = match delay.parse() {
+            Ok(delay) => Duration::from_secs(delay),
+            Err(_) => {
+                self.push_system("Invalid delay, expected seconds");
+                return;
+            }
+        };
+
+        let buffer_type = self.current_buffer.borrow().buffer_type.clone();
+        if let BufferType::Channel(channel) = buffer_type {
+            match self.client.get_mut().schedule_message(
+                channel,
+                message,
+                MessageType::Normal,
+                SystemTime::now() + delay,
+            ) {
+                Ok(id) => self.push_channel_log(format!(
+                    "Scheduled message {id} to be sent in {}s.",
+                    delay.as_secs()
+                )),
+                Err(err) => self
+                    .push_system(format!("Could not schedule message: {err}")),
+            }
+        } else {
+            self.push_system("You are not in a channel.");
+        }
+    }
+
+    fn channel_by_ident(&self, channel: &str) -> Option<&Rc<RefCell<Buffer>>> {
+        self.buffers
+            .iter()
+            .find(|b| {
+                matches!(
+                    &b.borrow().buffer_type,
+                    BufferType::Channel(c) if c == channel
+                )
+            })
+    }
+
+    /// Add a buffer for a channel, showing the recent messages kept from
+    /// earlier sessions.
+    fn open_channel(&mut self, channel: &ChannelIdentifier) {
+        self.buffers.push(Rc::new(RefCell::new(Buffer::new(
+            BufferType::Channel(channel.clone()),
+        ))));
+
+        let since = SystemTime::now()
+            .checked_sub(SCROLLBACK_AGE)
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_millis() as u64);
+        let messages = match self.client.get_ref().history(channel, since..) {
+            Ok(messages) => messages,
+            Err(err) => {
+                self.push_system(format!("{err:?}"));
+                return;
+            }
+        };
+        let skip = messages.len().saturating_sub(SCROLLBACK_LENGTH);
+        for message in messages.into_iter().skip(skip) {
+            if self.client.get_ref().is_muted(&message.sender) {
+                continue;
+            }
+            self.push_channel_message(
+                channel,
+                HistoryEntry::Message {
+                    id: Some(message.id),
+                    sender: message.sender,
+                    contents: message.contents,
+                    message_type: message.message_type,
+                    in_reply_to: None,
+                    edited: false,
+                    mentioned: false,
+                    sent_at: from_millis(message.timestamp),
+                },
+            );
+        }
+    }
+
+    /// Scroll the current buffer up a page, loading older messages kept from
+    /// earlier sessions once its oldest messages come into view.
+    fn scroll_up(&mut self) {
+        let page = page_height();
+        let (channel, oldest) = {
+            let mut buffer = self.current_buffer.borrow_mut();
+            buffer.scroll += page;
+
+            // every entry takes up at least one line
+            if buffer.scroll + page < buffer.history.len() {
+                return;
+            }
+            let channel = match &buffer.buffer_type {
+                BufferType::Channel(channel) => channel.clone(),
+                _ => return,
+            };
+            let oldest = buffer.history.iter().find_map(|entry| match entry {
+                HistoryEntry::Message { id: Some(id), .. } => Some(id.clone()),
+                _ => None,
+            });
+            (channel, oldest)
+        };
+
+        let oldest = match oldest {
+            Some(oldest) => oldest,
+            None => return,
+        };
+        let messages = match self.client.get_ref().history_page(
+            &channel,
+            &oldest,
+            SCROLLBACK_LENGTH,
+        ) {
+            Ok(messages) => messages,
+            Err(err) => {
+                self.push_system(format!("{err:?}"));
+                return;
+            }
+        };
+
+        let client = self.client.get_ref();
+        let mut buffer = self.current_buffer.borrow_mut();
+        for message in messages.into_iter().rev() {
+            if client.is_muted(&message.sender) {
+                continue;
+            }
+            buffer.history.push_front(HistoryEntry::Message {
+                id: Some(message.id),
+                sender: message.sender,
+                contents: message.contents,
+                message_type: message.message_type,
+                in_reply_to: None,
+                edited: false,
+                mentioned: false,
+                sent_at: from_millis(message.timestamp),
+            });
+        }
+    }
+
+    /// Check whether a message is shown in a channel's history.
+    fn has_channel_message(
+        &self,
+        channel: &ChannelIdentifier,
+        target: &MessageId,
+    ) -> bool {
+        self.channel_by_ident(channel).is_some_and(|buffer| {
+            buffer.borrow().history.iter().any(|entry| {
+                matches!(entry, HistoryEntry::Message { id: Some(id), .. }
+                    if id == target)
+            })
+        })
+    }
+
+    fn push_channel_message(
+        &self,
+        channel: &ChannelIdentifier,
+        message: HistoryEntry,
+    ) {
+        self.channel_by_ident(channel)
+            .unwrap() // TODO
+            .borrow_mut()
+            .history
+            .push_back(message);
+    }
+
+    /// Add a message the user just sent to a channel's history.
+    fn push_own_message(
+        &mut self,
+        id: Option<MessageId>,
+        contents: impl Into<String>,
+        channel: &ChannelIdentifier,
+        message_type: MessageType,
+        in_reply_to: Option<MessageId>,
+    ) {
+        let sender = self.client.get_ref().peer_id();
+        self.push_channel_message(
+            channel,
+            HistoryEntry::Message {
+                id,
+                sender,
+                contents: contents.into(),
+                message_type,
+                in_reply_to,
+                edited: false,
+                mentioned: false,
+                sent_at: SystemTime::now(),
+            },
+        );
+    }
+
+    /// Replace the contents of a message in a channel's history, marking it
+    /// as edited.
+    fn edit_channel_message(
+        &self,
+        channel: &ChannelIdentifier,
+        target: &MessageId,
+        new_contents: String,
+    ) {
+        if let Some(buffer) = self.channel_by_ident(channel) {
+            for entry in buffer.borrow_mut().history.iter_mut() {
+                if let HistoryEntry::Message {
+                    id: Some(id),
+                    contents,
+                    edited,
+                    ..
+                } = entry
+                {
+                    if id == target {
+                        *contents = new_contents;
+                        *edited = true;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Highlight a message in a channel's history which mentions the user.
+    fn mark_mentioned(&self, channel: &ChannelIdentifier, target: &MessageId) {
+        if let Some(buffer) = self.channel_by_ident(channel) {
+            for entry in buffer.borrow_mut().history.iter_mut() {
+                if let HistoryEntry::Message {
+                    id: Some(id),
+                    mentioned,
+                    ..
+                } = entry
+                {
+                    if id == target {
+                        *mentioned = true;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace a message in a channel's history with a tombstone.
+    fn delete_channel_message(
+        &self,
+        channel: &ChannelIdentifier,
+        target: &MessageId,
+    ) {
+        if let Some(buffer) = self.channel_by_ident(channel) {
+            for entry in buffer.borrow_mut().history.iter_mut() {
+                match entry {
+                    HistoryEntry::Message {
+                        id: Some(id),
+                        sender,
+                        sent_at,
+                        ..
+                    } if id == target => {
+                        *entry = HistoryEntry::Deleted {
+                            sender: *sender,
+                            sent_at: *sent_at,
+                        };
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Remove a message from a channel's history entirely.
+    fn remove_channel_message(
+        &self,
+        channel: &ChannelIdentifier,
+        target: &MessageId,
+    ) {
+        if let Some(buffer) = self.channel_by_ident(channel) {
+            buffer.borrow_mut().history.retain(|entry| {
+                !matches!(entry, HistoryEntry::Message { id: Some(id), .. }
+                    if id == target)
+            });
+        }
+    }
+
+    fn push_channel_log(&self, contents: impl Into<String>) {
+        self.current_buffer
+            .borrow_mut()
+            .history
+            .push_back(HistoryEntry::log(contents));
+    }
+
+    fn push_log_in(
+        &self,
+        channel: &ChannelIdentifier,
+        contents: impl Into<String>,
+    ) {
+        if let Some(buffer) = self.channel_by_ident(channel) {
+            buffer
+                .borrow_mut()
+                .history
+                .push_back(HistoryEntry::log(contents));
+        }
+    }
+
+    /// Show a log record in the system buffer, unless too many were shown
+    /// recently.
+    fn push_log_record(&mut self, record: String) {
+        let feed = match &mut self.logs {
+            Some(feed) => feed,
+            None => return,
+        };
+
+        for message in feed.admit(record, Instant::now()) {
+            self.push_system(message);
+        }
+    }
+
+    /// Show how many log records were left out once the window they were left
+    /// out within has ended, even if no further records arrive.
+    fn flush_log_records(&mut self) {
+        let notice = match &mut self.logs {
+            Some(feed) => feed.roll_window(Instant::now()),
+            None => None,
+        };
+        if let Some(notice) = notice {
+            self.push_system(notice);
+        }
+    }
+
+    fn push_system(&self, message: impl Into<String>) {
+        self.system_buffer
+            .borrow_mut()
+            .history
+            .push_back(HistoryEntry::log(message));
+    }
+}
+
+/// The commands which may be run from the input line, by name.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "accept",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/accept",
+        help: "Join the channel of the last invitation received.",
+        run: |app, _| {
+            match app.invite.take() {
+                Some(token) => {
+                    match app.client.get_mut().join_channel_with_invite(token) {
+                        Ok(channel) => {
+                            app.open_channel(&channel);
+                            app.push_system(format!(
+                                "Joined channel {channel}"
+                            ));
+                        }
+                        Err(err) => app.push_system(format!("{err:?}")),
+                    }
+                }
+                None => app.push_system("You have no pending invitation."),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "advertise",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/advertise",
+        help: "List the current channel in the public directory.",
+        run: |app, _| {
+            let buffer_type = app.current_buffer.borrow().buffer_type.clone();
+            if let BufferType::Channel(channel) = buffer_type {
+                match app.client.get_mut().advertise_channel(channel) {
+                    Ok(_) => app.push_channel_log(
+                        "Advertised channel in the directory.",
+                    ),
+                    Err(err) => app.push_system(format!("{err:?}")),
+                }
+            } else {
+                app.push_system("You are not in a channel.");
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "archive",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/archive",
+        help: "Make the current channel read-only, as its owner.",
+        run: |app, _| {
+            let buffer_type = app.current_buffer.borrow().buffer_type.clone();
+            if let BufferType::Channel(channel) = buffer_type {
+                match app.client.get_mut().archive_channel(channel) {
+                    Ok(_) => app.push_channel_log("Archived channel."),
+                    Err(Error::NotChannelOwner(_)) => app.push_channel_log(
+                        "Only the channel owner may archive it.",
+                    ),
+                    Err(err) => app.push_system(format!("{err:?}")),
+                }
+            } else {
+                app.push_system("You are not in a channel.");
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "attach",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/attach <path>",
+        help: "Send a file to the current channel.",
+        run: |app, args| {
+            app.attach(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "avatar",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/avatar <path>",
+        help: "Set the image shown for you to others.",
+        run: |app, args| {
+            match std::fs::read(args[0]) {
+                Ok(data) => match app.client.get_mut().set_avatar(data) {
+                    Ok(_) => app.push_system("Updated avatar"),
+                    Err(err) => app.push_system(format!("{err:?}")),
+                },
+                Err(err) => {
+                    app.push_system(format!("Could not read avatar: {err}"))
+                }
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "ban",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/ban <nick|peer-id>",
+        help: "Remove a peer from the current channel for good.",
+        run: |app, args| {
+            app.remove_peer(args[0], true);
+            Ok(())
+        },
+    },
+    Command {
+        name: "bans",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/bans",
+        help: "List the peers banned from the current channel.",
+        run: |app, _| {
+            app.show_bans();
+            Ok(())
+        },
+    },
+    Command {
+        name: "block",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/block <nick|peer-id>",
+        help: "Refuse all connections and messages from a peer.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().block_peer(peer);
+                app.push_system(format!("Blocked {peer}"));
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "channels",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/channels",
+        help: "List the channels advertised in the public directory.",
+        run: |app, _| {
+            app.list_public_channels();
+            Ok(())
+        },
+    },
+    Command {
+        name: "close",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/close",
+        help: "Close the current direct conversation.",
+        run: |app, _| {
+            let buffer_type = app.current_buffer.borrow().buffer_type.clone();
+            if let BufferType::Direct(peer) = buffer_type {
+                app.client.get_mut().close_direct(&peer);
+                app.buffers
+                    .retain(|b| b.borrow().buffer_type != buffer_type);
+                app.switch_to(app.system_buffer.clone());
+            } else {
+                app.push_system("You are not in a direct conversation.");
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "create",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/create <channel>",
+        help: "Create a channel which you own, and join it.",
+        run: |app, args| {
+            let channel = args[0];
+            match app.client.get_mut().create_channel(channel.to_owned()) {
+                Ok(_) => {
+                    app.run_command(&format!("join {channel}"))?;
+                    app.push_system(format!("Created channel {channel}"));
+                }
+                Err(Error::ChannelExists(_)) => app
+                    .push_system(format!("Channel {channel} already exists.")),
+                Err(err) => app.push_system(format!("{err:?}")),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "delete",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/delete",
+        help: "Retract the last message you sent to the current channel.",
+        run: |app, _| {
+            app.delete_last_message();
+            Ok(())
+        },
+    },
+    Command {
+        name: "deop",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/deop <nick|peer-id>",
+        help: "Make a moderator of the current channel a member again.",
+        run: |app, args| {
+            app.grant_role(args[0], Role::Member);
+            Ok(())
+        },
+    },
+    Command {
+        name: "disappear",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/disappear <seconds|off>",
+        help: "Make the messages you send to the current channel disappear \
+               after a while.",
+        run: |app, args| {
+            app.set_message_lifetime(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "disconnect",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/disconnect <nick|peer-id>",
+        help: "Close the connections to a peer for a while.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().disconnect(&peer);
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "edit",
+        min_args: 1,
+        max_args: None,
+        usage: "/edit <message>",
+        help: "Replace the last message you sent to the current channel.",
+        run: |app, args| {
+            app.edit_last_message(args.join(" "));
+            Ok(())
+        },
+    },
+    Command {
+        name: "encrypt",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/encrypt <on|off>",
+        help: "Set whether the messages of the current channel are encrypted, \
+               as its owner.",
+        run: |app, args| {
+            match args[0] {
+                setting @ ("on" | "off") => {
+                    app.set_channel_flag("encrypt", setting)
+                }
+                _ => app.show_usage("encrypt"),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "go",
+        min_args: 0,
+        max_args: Some(1),
+        usage: "/go [channel]",
+        help: "Switch to a channel, or to the system buffer if none is given.",
+        run: |app, args| {
+            match *args {
+                [channel] => {
+                    if let Some(buffer) = app.channel_by_ident(channel) {
+                        app.switch_to(buffer.clone());
+                    }
+                }
+                _ => app.switch_to(app.buffers.first().unwrap().clone()),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "help",
+        min_args: 0,
+        max_args: Some(1),
+        usage: "/help [command]",
+        help: "List the commands, or describe one of them.",
+        run: |app, args| {
+            app.show_help(args.first().copied());
+            Ok(())
+        },
+    },
+    Command {
+        name: "invite",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/invite <nick|peer-id>",
+        help: "Invite a peer to the current channel, as its owner.",
+        run: |app, args| {
+            app.invite(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "inviteonly",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/inviteonly <on|off>",
+        help: "Set whether the current channel may only be joined by \
+               invitation, as its owner.",
+        run: |app, args| {
+            match args[0] {
+                setting @ ("on" | "off") => {
+                    app.set_channel_flag("inviteonly", setting)
+                }
+                _ => app.show_usage("inviteonly"),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "join",
+        min_args: 1,
+        max_args: Some(2),
+        usage: "/join <channel> [passphrase]",
+        help: "Join a channel, which is protected by the passphrase if one is \
+               given.",
+        run: |app, args| {
+            match *args {
+                [channel, passphrase] => {
+                    app.client.get_mut().join_channel_with_password(
+                        channel.to_owned(),
+                        passphrase,
+                    )?;
+                    app.open_channel(&channel.to_owned());
+                    app.push_system(format!(
+                        "Joined protected channel {channel}"
+                    ));
+                }
+                _ => {
+                    let channel = args[0];
+                    app.client
+                        .get_mut()
+                        .subscribe_channel(channel.to_owned())?;
+                    app.open_channel(&channel.to_owned());
+                    app.push_system(format!("Joined channel {channel}"));
+                }
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "kick",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/kick <nick|peer-id>",
+        help: "Remove a peer from the current channel, which it may rejoin.",
+        run: |app, args| {
+            app.remove_peer(args[0], false);
+            Ok(())
+        },
+    },
+    Command {
+        name: "leave",
+        min_args: 1,
+        max_args: None,
+        usage: "/leave <channel> [reason]",
+        help: "Leave a channel, telling its members why if a reason is given.",
+        run: |app, args| {
+            let channel = args[0];
+            let reason = Some(args[1..].join(" ")).filter(|r| !r.is_empty());
+            if let Err(err) = app
+                .client
+                .get_mut()
+                .leave_channel(channel.to_owned(), reason)
+            {
+                app.push_system(format!("{err:?}"));
+                return Ok(());
+            }
+            app.buffers.retain(|b| !matches!(&b.borrow().buffer_type, BufferType::Channel(ident) if ident == channel) );
+            app.push_system(format!("Left channel {channel}"));
+            Ok(())
+        },
+    },
+    Command {
+        name: "list",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/list",
+        help: "List the open buffers.",
+        run: |app, _| {
+            app.push_system("Open buffers:");
+            for buffer in app.buffers.clone() {
+                let name = buffer.borrow().name(app.client.get_mut());
+                app.push_system(format!("- {name}"));
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "listeners",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/listeners",
+        help: "List the addresses this peer listens on and is reachable at.",
+        run: |app, _| {
+            app.push_system("Listening on:");
+            for address in app.client.get_ref().listeners() {
+                app.push_system(format!("- {address}"));
+            }
+            let external = app.client.get_ref().external_addresses();
+            if !external.is_empty() {
+                app.push_system("Reachable at:");
+                for address in external {
+                    app.push_system(format!("- {address}"));
+                }
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "me",
+        min_args: 1,
+        max_args: None,
+        usage: "/me <action>",
+        help: "Send an action, shown as a sentence about yourself.",
+        run: |app, args| {
+            app.send_message_as(args.join(" "), MessageType::Me);
+            Ok(())
+        },
+    },
+    Command {
+        name: "msg",
+        min_args: 1,
+        max_args: None,
+        usage: "/msg <nick|peer-id> [message]",
+        help:
+            "Open a direct conversation with a peer, sending a message to it \
+               if one is given.",
+        run: |app, args| {
+            match *args {
+                [target] => {
+                    app.open_direct(target);
+                }
+                [target, ref message @ ..] => {
+                    if let Some(peer) = app.open_direct(target) {
+                        let message = message.join(" ");
+                        let message_type = message_type_of(&message);
+                        app.send_direct(peer, message, message_type);
+                    }
+                }
+                [] => {}
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "mute",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/mute <nick|peer-id>",
+        help: "Hide the messages of a peer.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().mute_peer(peer);
+                app.push_system(format!("Muted {peer}"));
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "nick",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/nick <nick>",
+        help: "Change your nickname.",
+        run: |app, args| {
+            app.change_nick(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "op",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/op <nick|peer-id>",
+        help: "Make a member of the current channel a moderator.",
+        run: |app, args| {
+            app.grant_role(args[0], Role::Moderator);
+            Ok(())
+        },
+    },
+    Command {
+        name: "peers",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/peers",
+        help: "List the connected peers.",
+        run: |app, _| {
+            let peers = app.client.get_ref().connected_peers();
+            app.push_system(format!("Connected to {} peers:", peers.len()));
+            for peer in peers {
+                let nick = peer.nick.unwrap_or_else(|| "(unknown)".to_owned());
+                let badge = verified_badge(
+                    app.client.get_ref().is_verified(&peer.peer_id),
+                );
+                let latency = match peer.latency {
+                    Some(latency) => format!("{}ms", latency.as_millis()),
+                    None => "unknown".to_owned(),
+                };
+                let score = match peer.score {
+                    Some(score) => format!(", score {score:.1}"),
+                    None => String::new(),
+                };
+                let last_seen = app
+                    .client
+                    .get_ref()
+                    .last_seen(&peer.peer_id)
+                    .map(|time| format_age(time, SystemTime::now()));
+                let last_seen = match last_seen.as_deref() {
+                    Some("now") => ", last seen now".to_owned(),
+                    Some(age) => format!(", last seen {age} ago"),
+                    None => String::new(),
+                };
+                app.push_system(format!(
+                    "- {nick}{badge} ({}), {} connections, latency {latency}{score}{last_seen}",
+                    peer.peer_id, peer.connections
+                ));
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "policy",
+        min_args: 0,
+        max_args: Some(2),
+        usage: "/policy [<slowmode|maxlength> <n|off>|types <types|all>]",
+        help: "Show the policy of the current channel, or change it as its \
+               owner.",
+        run: |app, args| {
+            match *args {
+                [] => app.show_policy(),
+                [setting, value] => app.set_policy(setting, value),
+                _ => app.show_usage("policy"),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "poll",
+        min_args: 2,
+        max_args: None,
+        usage: "/poll <seconds> <question> | <option> | <option>...",
+        help: "Post a poll to the current channel, open for a while.",
+        run: |app, args| {
+            app.create_poll(args[0], &args[1..].join(" "));
+            Ok(())
+        },
+    },
+    Command {
+        name: "quit",
+        min_args: 0,
+        max_args: None,
+        usage: "/quit [reason]",
+        help: "Leave the network, telling peers why if a reason is given.",
+        run: |app, args| {
+            app.quit_reason = Some(args.join(" ")).filter(|r| !r.is_empty());
+            app.wants_to_exit = true;
+            Ok(())
+        },
+    },
+    Command {
+        name: "register",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/register",
+        help: "Claim your nickname, so that others are warned of peers using \
+               it after you.",
+        run: |app, _| {
+            let client = app.client.get_mut();
+            let nick = client.nick().clone();
+            match client.register_nick() {
+                Ok(()) => app.push_system(format!("Registered {nick}")),
+                Err(err) => {
+                    app.push_system(format!("Could not register {nick}: {err}"))
+                }
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "reply",
+        min_args: 2,
+        max_args: None,
+        usage: "/reply <nick|peer-id> <message>",
+        help: "Reply to the last message a peer sent to the current channel.",
+        run: |app, args| {
+            app.reply(args[0], args[1..].join(" "));
+            Ok(())
+        },
+    },
+    Command {
+        name: "roles",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/roles",
+        help: "List the owner and moderators of the current channel.",
+        run: |app, _| {
+            app.show_roles();
+            Ok(())
+        },
+    },
+    Command {
+        name: "save",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/save <path>",
+        help: "Save the last attachment received in the current channel.",
+        run: |app, args| {
+            app.save_attachment(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "schedule",
+        min_args: 2,
+        max_args: None,
+        usage: "/schedule <seconds> <message>",
+        help: "Send a message to the current channel after a while.",
+        run: |app, args| {
+            app.schedule_message(args[0], &args[1..].join(" "));
+            Ok(())
+        },
+    },
+    Command {
+        name: "sidebar",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/sidebar",
+        help: "Show or hide the members of the current channel.",
+        run: |app, _| {
+            app.show_sidebar = !app.show_sidebar;
+            Ok(())
+        },
+    },
+    Command {
+        name: "stats",
+        min_args: 0,
+        max_args: Some(0),
+        usage: "/stats",
+        help: "Show how much has been sent and received.",
+        run: |app, _| {
+            let metrics = app.client.get_ref().metrics();
+            app.push_system(format!(
+                "Received {} bytes, sent {} bytes, {} DHT queries",
+                metrics.bytes_received, metrics.bytes_sent, metrics.dht_queries
+            ));
+            for (channel, count) in metrics.channel_messages {
+                app.push_system(format!("- {channel}: {count} messages"));
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "timestamps",
+        min_args: 0,
+        max_args: Some(1),
+        usage: "/timestamps [on|off|short|full]",
+        help: "Show or hide the times of messages, or change how they are \
+               shown.",
+        run: |app, args| {
+            match *args {
+                [] => app.show_timestamps = !app.show_timestamps,
+                ["on"] => app.show_timestamps = true,
+                ["off"] => app.show_timestamps = false,
+                [format] => match format.parse() {
+                    Ok(format) => {
+                        app.timestamp_format = format;
+                        app.show_timestamps = true;
+                    }
+                    Err(_) => app.show_usage("timestamps"),
+                },
+                _ => {}
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "trust",
+        min_args: 2,
+        max_args: Some(2),
+        usage: "/trust <nick> <peer-id>",
+        help: "Trust a peer's key for a nickname, in place of the key first \
+               seen using it.",
+        run: |app, args| {
+            let (nick, peer) = (args[0], args[1]);
+            match peer.parse() {
+                Ok(peer) => match app.client.get_mut().trust_key(nick, peer) {
+                    Ok(()) => {
+                        app.push_system(format!("Trusted {peer} as {nick}"))
+                    }
+                    Err(err) => app
+                        .push_system(format!("Could not trust {peer}: {err}")),
+                },
+                Err(_) => app.push_system(format!("Invalid peer ID {peer}")),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "unblock",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/unblock <nick|peer-id>",
+        help: "Accept connections and messages from a blocked peer again.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().unblock_peer(&peer);
+                app.push_system(format!("Unblocked {peer}"));
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "unmute",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/unmute <nick|peer-id>",
+        help: "Show the messages of a muted peer again.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().unmute_peer(&peer);
+                app.push_system(format!("Unmuted {peer}"));
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "unschedule",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/unschedule <id>",
+        help: "Cancel a scheduled message.",
+        run: |app, args| {
+            match args[0].parse() {
+                Ok(id) => {
+                    match app.client.get_mut().cancel_scheduled_message(id) {
+                        Ok(Some(_)) => app.push_system(format!(
+                            "Cancelled scheduled message {id}"
+                        )),
+                        Ok(None) => {
+                            app.push_system("No such scheduled message")
+                        }
+                        Err(err) => app.push_system(format!(
+                            "Could not cancel scheduled message: {err}"
+                        )),
+                    }
+                }
+                Err(_) => app.push_system("Invalid scheduled message ID"),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "unverify",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/unverify <nick|peer-id>",
+        help: "Unmark a peer as verified.",
+        run: |app, args| {
+            app.for_each_peer(args[0], |app, peer| {
+                app.client.get_mut().unmark_verified(&peer);
+                app.push_system(format!("Unverified {peer}"));
+            });
+            Ok(())
+        },
+    },
+    Command {
+        name: "verify",
+        min_args: 1,
+        max_args: Some(2),
+        usage: "/verify <nick|peer-id> [confirm]",
+        help: "Show the safety numbers to compare with a peer out of band, or \
+               mark the peer as verified once they match.",
+        run: |app, args| {
+            match *args {
+                [target] => app.show_safety_numbers(target),
+                [target, "confirm"] => {
+                    let peers = app.resolve_peers(target);
+                    if peers.len() != 1 {
+                        app.push_system(format!(
+                            "Expected exactly one peer {target}, found {}",
+                            peers.len()
+                        ));
+                    }
+                    if let [peer] = *peers.as_slice() {
+                        app.client.get_mut().mark_verified(peer);
+                        app.push_system(format!("Verified {peer}"));
+                    }
+                }
+                _ => app.show_usage("verify"),
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "vote",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/vote <option>",
+        help: "Vote in the last poll posted to the current channel.",
+        run: |app, args| {
+            app.vote(args[0]);
+            Ok(())
+        },
+    },
+    Command {
+        name: "welcome",
+        min_args: 0,
+        max_args: None,
+        usage: "/welcome [message]",
+        help: "Set the message shown to those joining the current channel, \
+               or remove it, as its owner.",
+        run: |app, args| {
+            let welcome = Some(args.join(" ")).filter(|w| !w.is_empty());
+            let buffer_type = app.current_buffer.borrow().buffer_type.clone();
+            if let BufferType::Channel(channel) = buffer_type {
+                match app.client.get_mut().set_channel_welcome(channel, welcome)
+                {
+                    Ok(_) => app.push_channel_log("Updated welcome."),
+                    Err(Error::NotChannelOwner(_)) => app.push_channel_log(
+                        "Only the channel owner may set its welcome.",
+                    ),
+                    Err(err) => app.push_system(format!("{err:?}")),
+                }
+            } else {
+                app.push_system("You are not in a channel.");
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "whois",
+        min_args: 1,
+        max_args: Some(1),
+        usage: "/whois <nick|peer-id>",
+        help: "Show what is known about a peer.",
+        run: |app, args| {
+            app.whois(args[0]);
+            Ok(())
+        },
+    },
+];
+
+/// The style of a character of formatted text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TextStyle {
+    bold: bool,
+    italic: bool,
+    code: bool,
+    link: bool,
+    /// The color of the text, if not that of its line.
+    color: Option<style::Color>,
+}
+
+/// Get the plain text of formatted spans, and the style of each character.
+fn flatten_spans(spans: &[Span]) -> (String, Vec<TextStyle>) {
+    fn flatten(
+        spans: &[Span],
+        style: TextStyle,
+        runs: &mut Vec<(String, TextStyle)>,
+    ) {
+        for span in spans {
+            match span {
+                Span::Text(text) => runs.push((text.clone(), style)),
+                Span::Code(code) => runs.push((
+                    code.clone(),
+                    TextStyle {
+                        code: true,
+                        ..style
+                    },
+                )),
+                Span::Link { text, url } => {
+                    runs.push((
+                        text.clone(),
+                        TextStyle {
+                            link: true,
+                            ..style
+                        },
+                    ));
+                    runs.push((format!(" <{url}>"), style));
+                }
+                Span::Bold(inner) => flatten(
+                    inner,
+                    TextStyle {
+                        bold: true,
+                        ..style
+                    },
+                    runs,
+                ),
+                Span::Italic(inner) => flatten(
+                    inner,
+                    TextStyle {
+                        italic: true,
+                        ..style
+                    },
+                    runs,
+                ),
+            }
+        }
+    }
+
+    let mut runs = vec![];
+    flatten(spans, TextStyle::default(), &mut runs);
+
+    let text = runs.iter().map(|(text, _)| text.as_str()).collect();
+    let styles = runs
+        .iter()
+        .flat_map(|(text, style)| text.chars().map(move |_| *style))
+        .collect();
+    (text, styles)
+}
+
+/// Assign the styles of the characters of a message to the lines it was
+/// wrapped into by [`wrap`].
+///
+/// Returns no styles for any line if the message is unstyled.
+fn style_lines(
+    lead: &str,
+    lines: Vec<String>,
+    contents: &str,
+    styles: &[TextStyle],
+) -> Vec<(String, Vec<TextStyle>)> {
+    if styles.is_empty() {
+        return lines.into_iter().map(|line| (line, vec![])).collect();
+    }
+
+    let mut chars = contents.chars().zip(styles.iter().copied()).peekable();
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            // skip the lead or the indent, which is as wide as the lead
+            let skip = if idx == 0 {
+                lead.chars().count()
+            } else {
+                lead.width()
+            };
+
+            let line_styles = line
+                .chars()
+                .enumerate()
+                .map(|(col, c)| {
+                    if col < skip {
+                        return TextStyle::default();
+                    }
+
+                    // whitespace may have been dropped where lines were broken
+                    while let Some((next, _)) = chars.peek() {
+                        if *next == c || !next.is_whitespace() {
+                            break;
+                        }
+                        chars.next();
+                    }
+                    match chars.peek() {
+                        Some((next, style)) if *next == c => {
+                            let style = *style;
+                            chars.next();
+                            style
+                        }
+                        _ => TextStyle::default(),
+                    }
+                })
+                .collect();
+
+            (line, line_styles)
+        })
+        .collect()
+}
+
+/// Color a range of the characters of a line, styling the line if it was
+/// not already.
+fn color_chars(
+    line: &str,
+    styles: &mut Vec<TextStyle>,
+    range: Range<usize>,
+    color: style::Color,
+) {
+    if styles.is_empty() {
+        styles.resize(line.chars().count(), TextStyle::default());
+    }
+    for style in styles.iter_mut().take(range.end).skip(range.start) {
+        style.color = Some(color);
+    }
+}
+
+/// Print a line of text, styling each character.
+fn print_styled<W: Write>(
+    writer: &mut W,
+    line: &str,
+    styles: &[TextStyle],
+    color: style::Color,
+    code_color: style::Color,
+) -> anyhow::Result<()> {
+    let mut segment = String::new();
+    let mut chars = line.chars().zip(styles.iter().copied()).peekable();
+
+    while let Some((c, style)) = chars.next() {
+        segment.push(c);
+        if chars.peek().is_some_and(|(_, next)| *next == style) {
+            continue;
+        }
+
+        queue!(
+            writer,
+            style::SetAttribute(if style.bold {
+                style::Attribute::Bold
+            } else {
+                style::Attribute::NormalIntensity
+            }),
+            style::SetAttribute(if style.italic {
+                style::Attribute::Italic
+            } else {
+                style::Attribute::NoItalic
+            }),
+            style::SetAttribute(if style.link {
+                style::Attribute::Underlined
+            } else {
+                style::Attribute::NoUnderline
+            }),
+            style::SetForegroundColor(style.color.unwrap_or(if style.code {
+                code_color
+            } else {
+                color
+            })),
+            style::Print(std::mem::take(&mut segment)),
+        )?;
+    }
+
+    queue!(writer, style::SetAttribute(style::Attribute::Reset))?;
+
+    Ok(())
+}
+
+/// Receive the next log record to show, if log records are shown at all.
+async fn recv_log(logs: &mut Option<LogFeed>) -> Option<String> {
+    match logs {
+        Some(feed) => feed.receiver.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Check whether more input is waiting to be read, as when text is pasted
+/// rather than typed.
+///
+/// The terminal library does not support bracketed paste, so a line break
+/// followed immediately by more input is taken to be part of a paste.
+fn is_pasting() -> bool {
+    crossterm::event::poll(Duration::ZERO).unwrap_or(false)
+}
+
+/// Get the type of message to send some text as, which is formatted if it
+/// uses any formatting syntax.
+fn message_type_of(message: &str) -> MessageType {
+    match parse_formatted(message) {
+        Some(spans) if spans != [Span::Text(message.to_owned())] => {
+            MessageType::Formatted
+        }
+        _ => MessageType::Normal,
+    }
+}
+
+/// Get the sender of a message in a buffer's history and the start of its
+/// contents, for quoting in replies.
+fn quote(
+    history: &VecDeque<HistoryEntry>,
+    target: &MessageId,
+) -> Option<(PeerId, String)> {
+    const EXCERPT_LENGTH: usize = 40;
+
+    history.iter().find_map(|entry| match entry {
+        HistoryEntry::Message {
+            id: Some(id),
+            sender,
+            contents,
+            ..
+        } if id == target => {
+            let mut excerpt =
+                contents.chars().take(EXCERPT_LENGTH).collect::<String>();
+            if excerpt.len() < contents.len() {
+                excerpt.push_str("...");
+            }
+            Some((*sender, excerpt))
+        }
+        _ => None,
+    })
+}
+
+/// Get the name to display for a peer, which is its nickname if known.
+///
+/// Nicknames shared by several peers are suffixed with part of the peer ID, so
+/// that impersonators can be told apart.
+fn display_name(client: &mut Client, peer: &PeerId) -> String {
+    let id = peer.to_base58();
+
+    match client.fetch_nickname(peer).unwrap() {
+        Some(nick) => {
+            let mut nick = nick.to_owned();
+            if client.find_peers_by_nick(&nick).len() > 1 {
+                nick = format!("{nick}#{}", &id[id.len() - 6..]);
+            }
+            // someone else registered the nickname
+            if !client.holds_nick(peer) {
+                nick.push_str("(unverified)");
+            }
+            nick
+        }
+        None => id.chars().skip(16).take(16).collect(),
+    }
+}
+
+/// Get the initials of a nickname, for use in place of an avatar.
+fn initials(nick: &str) -> String {
+    nick.split(|c: char| !c.is_alphanumeric())
+        .filter_map(|word| word.chars().next())
+        .take(2)
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// Get the badge shown after the nicknames of verified peers.
+fn verified_badge(verified: bool) -> &'static str {
+    if verified {
+        " [verified]"
+    } else {
+        ""
+    }
+}
+
+/// Convert a timestamp of the protocol, in milliseconds since the Unix epoch,
+/// to a time.
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// Describe how long before `now` a time was, in the largest whole unit, such
+/// as `5m` for five minutes.
+fn format_age(time: SystemTime, now: SystemTime) -> String {
+    let secs = now.duration_since(time).unwrap_or_default().as_secs();
+    match secs {
+        0..=59 => "now".to_owned(),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Get how many lines of a buffer are scrolled by a page, which is all but
+/// one of those shown at once.
+fn page_height() -> usize {
+    let (_, rows) =
+        terminal::size().expect("could not determine terminal size");
+    usize::from(rows.saturating_sub(3)).max(1)
+}
+
+/// Cut a string down to the graphemes which fit in a number of columns.
+fn truncate_to_width(s: &str, columns: usize) -> String {
+    let mut width = 0;
+    s.graphemes(true)
+        .take_while(|grapheme| {
+            width += grapheme.width();
+            width <= columns
+        })
+        .collect()
+}
+
+/// Wrap a message to the width of the screen, after a lead such as `nick: `,
+/// indenting the lines after the first to line up with the message.
+fn wrap(lead: &str, message: &str, columns: u16) -> Vec<String> {
+    let indent = " ".repeat(lead.width());
     let options =
         textwrap::Options::new(columns.into()).subsequent_indent(&indent);
-    let wrapped = textwrap::fill(&format!("{prefix}: {message}"), options);
+    let wrapped = textwrap::fill(&format!("{lead}{message}"), options);
     wrapped.lines().map(|s| s.to_owned()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        for (i, command) in COMMANDS.iter().enumerate() {
+            assert!(
+                COMMANDS[..i].iter().all(|other| other.name != command.name),
+                "/{} is registered twice",
+                command.name
+            );
+            assert!(command.usage.starts_with(&format!("/{}", command.name)));
+            assert!(command.max_args.is_none_or(|max| command.min_args <= max));
+            assert!(!command.help.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_truncate_to_width() {
+        assert_eq!(truncate_to_width("hello", 3), "hel");
+        assert_eq!(truncate_to_width("hello", 10), "hello");
+        // wide characters are not split across the limit
+        assert_eq!(truncate_to_width("日本語", 4), "日本");
+        assert_eq!(truncate_to_width("日本語", 5), "日本");
+        // combining marks stay with the character they combine with
+        assert_eq!(truncate_to_width("e\u{301}e\u{301}", 1), "e\u{301}");
+        assert_eq!(truncate_to_width("hello", 0), "");
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("bob: ", "one two three four", 14),
+            vec!["bob: one two", "     three", "     four"]
+        );
+        assert_eq!(wrap("bob: ", "hi", 14), vec!["bob: hi"]);
+
+        // wide characters take two columns each
+        let lines = wrap("日本: ", "語 語 語 語", 11);
+        assert_eq!(lines, vec!["日本: 語 語", "      語 語"]);
+        let lines = wrap("日本: ", "語 語 語 語", 10);
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|line| line.width() <= 10));
+
+        // combining marks take no columns
+        let lines = wrap("a: ", "cafe\u{301} cafe\u{301} cafe\u{301}", 12);
+        assert_eq!(lines, vec!["a: cafe\u{301} cafe\u{301}", "   cafe\u{301}"]);
+    }
+
+    #[test]
+    fn test_log_feed() {
+        let (_sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let start = Instant::now();
+        let mut feed = LogFeed {
+            receiver,
+            window_start: start,
+            shown: 0,
+            dropped: 0,
+        };
+
+        for i in 0..LOG_LIMIT {
+            assert_eq!(feed.admit(format!("{i}"), start), vec![format!("{i}")]);
+        }
+        assert!(feed.admit("left out".into(), start).is_empty());
+        assert!(feed.admit("left out".into(), start).is_empty());
+
+        // the notice is given once the window ends, without another record
+        assert_eq!(feed.roll_window(start), None);
+        assert_eq!(
+            feed.roll_window(start + LOG_WINDOW).as_deref(),
+            Some("(2 more log records were left out, see current.log)")
+        );
+        assert_eq!(feed.roll_window(start + LOG_WINDOW * 2), None);
+        assert_eq!(
+            feed.admit("shown".into(), start + LOG_WINDOW * 2),
+            vec!["shown"]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use crate::app::App;
+
+/// A command which may be run from the input line, such as `/join`.
+pub struct Command {
+    /// The name the command is run by, without the leading slash.
+    pub name: &'static str,
+    /// The fewest arguments the command takes.
+    pub min_args: usize,
+    /// The most arguments the command takes, or none if it takes the rest of
+    /// the line.
+    pub max_args: Option<usize>,
+    /// How the command is written, such as `/join <channel> [passphrase]`.
+    pub usage: &'static str,
+    /// What the command does.
+    pub help: &'static str,
+    /// Run the command with the arguments given to it, of which there are as
+    /// many as it takes.
+    pub run: fn(&mut App, &[&str]) -> anyhow::Result<()>,
+}
+
+impl Command {
+    /// Check whether the command takes a number of arguments.
+    pub fn accepts(&self, args: usize) -> bool {
+        args >= self.min_args && self.max_args.is_none_or(|max| args <= max)
+    }
+}
+
+/// Find a command by its name in a table of commands.
+pub fn find<'a>(commands: &'a [Command], name: &str) -> Option<&'a Command> {
+    commands.iter().find(|command| command.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_COMMANDS: &[Command] = &[
+        Command {
+            name: "list",
+            min_args: 0,
+            max_args: Some(0),
+            usage: "/list",
+            help: "",
+            run: |_, _| Ok(()),
+        },
+        Command {
+            name: "join",
+            min_args: 1,
+            max_args: Some(2),
+            usage: "/join <channel> [passphrase]",
+            help: "",
+            run: |_, _| Ok(()),
+        },
+        Command {
+            name: "me",
+            min_args: 1,
+            max_args: None,
+            usage: "/me <action>",
+            help: "",
+            run: |_, _| Ok(()),
+        },
+    ];
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find(TEST_COMMANDS, "join").unwrap().name, "join");
+        assert_eq!(find(TEST_COMMANDS, "me").unwrap().name, "me");
+        assert!(find(TEST_COMMANDS, "/join").is_none());
+        assert!(find(TEST_COMMANDS, "jo").is_none());
+        assert!(find(TEST_COMMANDS, "").is_none());
+    }
+
+    #[test]
+    fn test_accepts() {
+        let list = find(TEST_COMMANDS, "list").unwrap();
+        assert!(list.accepts(0));
+        assert!(!list.accepts(1));
+
+        let join = find(TEST_COMMANDS, "join").unwrap();
+        assert!(!join.accepts(0));
+        assert!(join.accepts(1));
+        assert!(join.accepts(2));
+        assert!(!join.accepts(3));
+
+        // commands taking the rest of the line take any number of words
+        let me = find(TEST_COMMANDS, "me").unwrap();
+        assert!(!me.accepts(0));
+        assert!(me.accepts(1));
+        assert!(me.accepts(100));
+    }
+}
@@ -0,0 +1,132 @@
+use std::ops::Range;
+
+use crate::input::InputLine;
+
+/// What the word before the cursor is being completed as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    Command,
+    Channel,
+    Nick,
+}
+
+/// The candidates for completing a word of the input line, which repeated
+/// presses of tab cycle through.
+#[derive(Debug)]
+pub struct Completion {
+    /// The byte range of the word being completed, as last filled in.
+    range: Range<usize>,
+    candidates: Vec<String>,
+    index: usize,
+    /// The line as last completed, to tell whether it was edited since.
+    line: String,
+}
+
+impl Completion {
+    /// Begin completing the word before the cursor from the given
+    /// candidates, filling in the first of them.
+    ///
+    /// Returns nothing if no candidate matches the word.
+    pub fn begin(
+        input: &mut InputLine,
+        candidates: impl IntoIterator<Item = String>,
+    ) -> Option<Self> {
+        let (start, word) = input.word_before_cursor();
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|c| c.to_lowercase().starts_with(&word.to_lowercase()))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut completion = Completion {
+            range: start..start + word.len(),
+            candidates,
+            index: 0,
+            line: String::new(),
+        };
+        completion.fill(input);
+        Some(completion)
+    }
+
+    /// Fill in the next candidate, if the line was not edited since the last
+    /// one was, returning whether it was.
+    pub fn advance(&mut self, input: &mut InputLine) -> bool {
+        if input.as_str() != self.line {
+            return false;
+        }
+        self.index = (self.index + 1) % self.candidates.len();
+        self.fill(input);
+        true
+    }
+
+    fn fill(&mut self, input: &mut InputLine) {
+        let candidate = &self.candidates[self.index];
+        input.replace_range(self.range.clone(), candidate);
+        self.range.end = self.range.start + candidate.len();
+        self.line = input.as_str().to_owned();
+    }
+}
+
+/// Decide what the word before the cursor is to be completed as, by the text
+/// before it.
+pub fn completion_kind(before: &str, word: &str) -> CompletionKind {
+    if before.is_empty() && word.starts_with('/') {
+        return CompletionKind::Command;
+    }
+    match before.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["/join" | "/go"] => CompletionKind::Channel,
+        _ => CompletionKind::Nick,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut input = InputLine::default();
+        input.insert_str("hi al");
+        let names = candidates(&["bob", "alice", "Alan", "alice"]);
+        let mut completion = Completion::begin(&mut input, names).unwrap();
+        assert_eq!(input.as_str(), "hi Alan");
+
+        assert!(completion.advance(&mut input));
+        assert_eq!(input.as_str(), "hi alice");
+        assert!(completion.advance(&mut input));
+        assert_eq!(input.as_str(), "hi Alan");
+        assert_eq!(input.before_cursor(), "hi Alan");
+
+        // editing the line ends the completion
+        input.insert('!');
+        assert!(!completion.advance(&mut input));
+        assert_eq!(input.as_str(), "hi Alan!");
+    }
+
+    #[test]
+    fn test_no_match() {
+        let mut input = InputLine::default();
+        input.insert_str("/jo");
+        let names = candidates(&["/help", "/list"]);
+        assert!(Completion::begin(&mut input, names).is_none());
+        assert_eq!(input.as_str(), "/jo");
+    }
+
+    #[test]
+    fn test_completion_kind() {
+        assert_eq!(completion_kind("", "/jo"), CompletionKind::Command);
+        assert_eq!(completion_kind("", "al"), CompletionKind::Nick);
+        assert_eq!(completion_kind("/join ", "#ge"), CompletionKind::Channel);
+        assert_eq!(completion_kind("/go  ", ""), CompletionKind::Channel);
+        assert_eq!(completion_kind("/join #a ", "b"), CompletionKind::Nick);
+        assert_eq!(completion_kind("hi ", "/jo"), CompletionKind::Nick);
+    }
+}
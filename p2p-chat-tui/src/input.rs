@@ -0,0 +1,378 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// The number of submitted lines kept for recall.
+const MAX_HISTORY: usize = 500;
+
+/// The line of input being edited, with a cursor, a kill buffer, and a
+/// history of submitted lines, which behaves like a readline prompt.
+#[derive(Default, Debug)]
+pub struct InputLine {
+    text: String,
+    /// The byte offset of the cursor into the text.
+    cursor: usize,
+    /// The text last killed, which may be yanked back.
+    killed: String,
+    /// The lines submitted this session, oldest first.
+    history: Vec<String>,
+    /// The index of the line recalled from the history, if any.
+    recalled: Option<usize>,
+    /// The line which was being typed before the history was recalled.
+    draft: String,
+}
+
+impl InputLine {
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the text before the cursor.
+    pub fn before_cursor(&self) -> &str {
+        &self.text[..self.cursor]
+    }
+
+    /// Get the word before the cursor, which is empty if the cursor follows
+    /// whitespace, with the byte offset it starts at.
+    pub fn word_before_cursor(&self) -> (usize, &str) {
+        let before = self.before_cursor();
+        let start = before
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8());
+        (start, &before[start..])
+    }
+
+    /// Replace a byte range of the text, moving the cursor to the end of the
+    /// replacement.
+    pub fn replace_range(&mut self, range: std::ops::Range<usize>, s: &str) {
+        let start = range.start;
+        self.text.replace_range(range, s);
+        self.cursor = start + s.len();
+    }
+
+    /// Take the text of the line, leaving it empty, and add it to the history.
+    pub fn submit(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.recalled = None;
+        self.draft.clear();
+
+        if !text.is_empty() && self.history.last() != Some(&text) {
+            if self.history.len() == MAX_HISTORY {
+                self.history.remove(0);
+            }
+            self.history.push(text.clone());
+        }
+        text
+    }
+
+    /// Recall the line submitted before the one shown, keeping the line being
+    /// typed to return to.
+    pub fn history_prev(&mut self) {
+        let index = match self.recalled {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                self.history.len() - 1
+            }
+        };
+        self.recall(index);
+    }
+
+    /// Recall the line submitted after the one shown, or return to the line
+    /// being typed after the newest.
+    pub fn history_next(&mut self) {
+        match self.recalled {
+            Some(index) if index + 1 < self.history.len() => {
+                self.recall(index + 1)
+            }
+            Some(_) => {
+                self.recalled = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set(draft);
+            }
+            None => {}
+        }
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    /// Delete the grapheme before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(start) = self.prev_boundary() {
+            self.text.replace_range(start..self.cursor, "");
+            self.cursor = start;
+        }
+    }
+
+    /// Delete the grapheme under the cursor.
+    pub fn delete(&mut self) {
+        if let Some(end) = self.next_boundary() {
+            self.text.replace_range(self.cursor..end, "");
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(start) = self.prev_boundary() {
+            self.cursor = start;
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(end) = self.next_boundary() {
+            self.cursor = end;
+        }
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    /// Move the cursor to the start of the word before it.
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.word_start();
+    }
+
+    /// Move the cursor to the end of the word after it.
+    pub fn move_word_right(&mut self) {
+        let rest = &self.text[self.cursor..];
+        let skipped = rest.len() - rest.trim_start().len();
+        let word = rest[skipped..]
+            .find(char::is_whitespace)
+            .unwrap_or(rest.len() - skipped);
+        self.cursor += skipped + word;
+    }
+
+    /// Kill the word before the cursor.
+    pub fn kill_word(&mut self) {
+        let start = self.word_start();
+        self.kill(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Kill the text from the cursor to the end of the line.
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor..self.text.len());
+    }
+
+    /// Kill the text from the start of the line to the cursor.
+    pub fn kill_to_start(&mut self) {
+        self.kill(0..self.cursor);
+        self.cursor = 0;
+    }
+
+    /// Insert the text last killed at the cursor.
+    pub fn yank(&mut self) {
+        let killed = self.killed.clone();
+        self.insert_str(&killed);
+    }
+
+    /// Get the position of the cursor, in columns of the terminal.
+    ///
+    /// Line breaks count as one column each, as they are shown as symbols.
+    pub fn cursor_column(&self) -> usize {
+        let before = self.before_cursor();
+        before.width() + before.matches('\n').count()
+    }
+
+    fn recall(&mut self, index: usize) {
+        self.recalled = Some(index);
+        self.set(self.history[index].clone());
+    }
+
+    /// Replace the text of the line, moving the cursor to its end.
+    fn set(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+    }
+
+    fn kill(&mut self, range: std::ops::Range<usize>) {
+        // killing nothing keeps the text killed before
+        if !range.is_empty() {
+            self.killed = self.text.drain(range).collect();
+        }
+    }
+
+    /// Get the byte offset of the start of the word before the cursor,
+    /// skipping any whitespace between them.
+    fn word_start(&self) -> usize {
+        let before = self.before_cursor().trim_end();
+        before
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8())
+    }
+
+    /// Get the byte offset of the grapheme before the cursor, so that
+    /// characters combined with others are moved over and deleted together.
+    fn prev_boundary(&self) -> Option<usize> {
+        self.before_cursor()
+            .grapheme_indices(true)
+            .next_back()
+            .map(|(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> Option<usize> {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map(|g| self.cursor + g.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> InputLine {
+        let mut line = InputLine::default();
+        line.insert_str(text);
+        line
+    }
+
+    #[test]
+    fn test_move() {
+        let mut line = line("hello there world");
+        line.move_word_left();
+        assert_eq!(line.before_cursor(), "hello there ");
+        line.move_word_left();
+        assert_eq!(line.before_cursor(), "hello ");
+        line.move_left();
+        assert_eq!(line.before_cursor(), "hello");
+        line.move_word_right();
+        assert_eq!(line.before_cursor(), "hello there");
+        line.move_right();
+        assert_eq!(line.before_cursor(), "hello there ");
+
+        line.move_home();
+        assert_eq!(line.before_cursor(), "");
+        line.move_left();
+        assert_eq!(line.before_cursor(), "");
+        line.move_end();
+        assert_eq!(line.before_cursor(), "hello there world");
+        line.move_right();
+        assert_eq!(line.before_cursor(), "hello there world");
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut line = line("helo");
+        line.move_left();
+        line.insert('l');
+        assert_eq!(line.as_str(), "hello");
+        assert_eq!(line.before_cursor(), "hell");
+
+        line.delete();
+        assert_eq!(line.as_str(), "hell");
+        line.backspace();
+        assert_eq!(line.as_str(), "hel");
+        line.move_home();
+        line.backspace();
+        assert_eq!(line.as_str(), "hel");
+    }
+
+    #[test]
+    fn test_graphemes() {
+        let mut input = line("e\u{301}日本");
+        assert_eq!(input.cursor_column(), 5);
+        input.move_left();
+        assert_eq!(input.cursor_column(), 3);
+        input.move_left();
+        assert_eq!(input.cursor_column(), 1);
+
+        // a combining mark is moved over and deleted with its character
+        input.move_left();
+        assert_eq!(input.cursor_column(), 0);
+        input.move_right();
+        assert_eq!(input.before_cursor(), "e\u{301}");
+        input.backspace();
+        assert_eq!(input.as_str(), "日本");
+        input.delete();
+        assert_eq!(input.as_str(), "本");
+
+        // line breaks are shown as one column
+        assert_eq!(line("a\nb").cursor_column(), 3);
+    }
+
+    #[test]
+    fn test_kill_yank() {
+        let mut line = line("one two  three");
+        line.kill_word();
+        assert_eq!(line.as_str(), "one two  ");
+        line.kill_word();
+        assert_eq!(line.as_str(), "one ");
+
+        // killing nothing keeps what was killed before
+        line.kill_to_end();
+        line.yank();
+        assert_eq!(line.as_str(), "one two  ");
+
+        line.move_word_left();
+        line.kill_to_start();
+        assert_eq!(line.as_str(), "two  ");
+        line.move_end();
+        line.yank();
+        assert_eq!(line.as_str(), "two  one ");
+
+        line.move_home();
+        line.kill_to_end();
+        assert_eq!(line.as_str(), "");
+        line.yank();
+        assert_eq!(line.as_str(), "two  one ");
+    }
+
+    #[test]
+    fn test_history() {
+        let mut line = InputLine::default();
+        line.history_prev();
+        assert_eq!(line.as_str(), "");
+
+        for text in ["first", "second", "second", ""] {
+            line.insert_str(text);
+            assert_eq!(line.submit(), text);
+        }
+
+        // repeated and empty lines are only kept once
+        line.insert_str("draft");
+        line.history_prev();
+        assert_eq!(line.as_str(), "second");
+        assert_eq!(line.before_cursor(), "second");
+        line.history_prev();
+        assert_eq!(line.as_str(), "first");
+        line.history_prev();
+        assert_eq!(line.as_str(), "first");
+
+        line.history_next();
+        assert_eq!(line.as_str(), "second");
+        line.history_next();
+        assert_eq!(line.as_str(), "draft");
+        line.history_next();
+        assert_eq!(line.as_str(), "draft");
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut line = InputLine::default();
+        for i in 0..=MAX_HISTORY {
+            line.insert_str(&i.to_string());
+            line.submit();
+        }
+
+        for _ in 0..=MAX_HISTORY {
+            line.history_prev();
+        }
+        assert_eq!(line.as_str(), "1");
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Something the user may do by pressing a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    PageUp,
+    PageDown,
+    CursorLeft,
+    CursorRight,
+    LineStart,
+    LineEnd,
+    WordLeft,
+    WordRight,
+    DeleteForward,
+    /// Kill the word before the cursor.
+    KillWord,
+    /// Kill the text from the cursor to the end of the line.
+    KillToEnd,
+    /// Kill the text from the start of the line to the cursor.
+    KillToStart,
+    /// Insert the text last killed.
+    Yank,
+    HistoryPrev,
+    HistoryNext,
+    /// Complete the word before the cursor, or cycle through the candidates.
+    Complete,
+    NextBuffer,
+    PrevBuffer,
+    /// Switch to the buffer at a position in the buffer list, counting from
+    /// one.
+    GoToBuffer(u8),
+    /// Show or hide the members of the current channel.
+    ToggleSidebar,
+    /// Break the line of a message being typed, rather than sending it.
+    InsertNewline,
+}
+
+impl Action {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quit" => Some(Action::Quit),
+            "page-up" => Some(Action::PageUp),
+            "page-down" => Some(Action::PageDown),
+            "cursor-left" => Some(Action::CursorLeft),
+            "cursor-right" => Some(Action::CursorRight),
+            "line-start" => Some(Action::LineStart),
+            "line-end" => Some(Action::LineEnd),
+            "word-left" => Some(Action::WordLeft),
+            "word-right" => Some(Action::WordRight),
+            "delete-forward" => Some(Action::DeleteForward),
+            "kill-word" => Some(Action::KillWord),
+            "kill-to-end" => Some(Action::KillToEnd),
+            "kill-to-start" => Some(Action::KillToStart),
+            "yank" => Some(Action::Yank),
+            "history-prev" => Some(Action::HistoryPrev),
+            "history-next" => Some(Action::HistoryNext),
+            "complete" => Some(Action::Complete),
+            "next-buffer" => Some(Action::NextBuffer),
+            "prev-buffer" => Some(Action::PrevBuffer),
+            "toggle-sidebar" => Some(Action::ToggleSidebar),
+            "insert-newline" => Some(Action::InsertNewline),
+            _ => match name.strip_prefix("buffer-")?.parse() {
+                Ok(n @ 1..=9) => Some(Action::GoToBuffer(n)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The actions bound to keys.
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: HashMap<(KeyCode, KeyModifiers), Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = [
+            (KeyCode::Char('c'), KeyModifiers::CONTROL, Action::Quit),
+            (KeyCode::Char('d'), KeyModifiers::CONTROL, Action::Quit),
+            (KeyCode::PageUp, KeyModifiers::NONE, Action::PageUp),
+            (KeyCode::PageDown, KeyModifiers::NONE, Action::PageDown),
+            (KeyCode::Left, KeyModifiers::NONE, Action::CursorLeft),
+            (
+                KeyCode::Char('b'),
+                KeyModifiers::CONTROL,
+                Action::CursorLeft,
+            ),
+            (KeyCode::Right, KeyModifiers::NONE, Action::CursorRight),
+            (
+                KeyCode::Char('f'),
+                KeyModifiers::CONTROL,
+                Action::CursorRight,
+            ),
+            (KeyCode::Home, KeyModifiers::NONE, Action::LineStart),
+            (KeyCode::Char('a'), KeyModifiers::CONTROL, Action::LineStart),
+            (KeyCode::End, KeyModifiers::NONE, Action::LineEnd),
+            (KeyCode::Char('e'), KeyModifiers::CONTROL, Action::LineEnd),
+            (KeyCode::Char('b'), KeyModifiers::ALT, Action::WordLeft),
+            (KeyCode::Left, KeyModifiers::CONTROL, Action::WordLeft),
+            (KeyCode::Char('f'), KeyModifiers::ALT, Action::WordRight),
+            (KeyCode::Right, KeyModifiers::CONTROL, Action::WordRight),
+            (KeyCode::Delete, KeyModifiers::NONE, Action::DeleteForward),
+            (KeyCode::Char('w'), KeyModifiers::CONTROL, Action::KillWord),
+            (KeyCode::Char('k'), KeyModifiers::CONTROL, Action::KillToEnd),
+            (
+                KeyCode::Char('u'),
+                KeyModifiers::CONTROL,
+                Action::KillToStart,
+            ),
+            (KeyCode::Char('y'), KeyModifiers::CONTROL, Action::Yank),
+            (KeyCode::Up, KeyModifiers::NONE, Action::HistoryPrev),
+            (KeyCode::Down, KeyModifiers::NONE, Action::HistoryNext),
+            (KeyCode::Tab, KeyModifiers::NONE, Action::Complete),
+            (
+                KeyCode::Char('n'),
+                KeyModifiers::CONTROL,
+                Action::NextBuffer,
+            ),
+            (
+                KeyCode::Char('p'),
+                KeyModifiers::CONTROL,
+                Action::PrevBuffer,
+            ),
+            (KeyCode::F(2), KeyModifiers::NONE, Action::ToggleSidebar),
+            (KeyCode::Enter, KeyModifiers::ALT, Action::InsertNewline),
+        ];
+        // alt and a digit switches to the buffer at that position
+        let buffers = (1..=9).map(|n| {
+            let digit = char::from_digit(n.into(), 10).unwrap();
+            (
+                KeyCode::Char(digit),
+                KeyModifiers::ALT,
+                Action::GoToBuffer(n),
+            )
+        });
+        Keymap {
+            bindings: bindings
+                .into_iter()
+                .chain(buffers)
+                .map(|(code, modifiers, action)| ((code, modifiers), action))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Build a keymap from the default one, binding the actions named in a
+    /// configuration file to the given keys instead of their default ones,
+    /// such as `page-up = "ctrl-b"`.
+    pub fn from_config(keys: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut keymap = Keymap::default();
+        for (name, key) in keys {
+            let action = Action::from_name(name)
+                .ok_or_else(|| anyhow!("unknown action {name}"))?;
+            let key =
+                parse_key(key).ok_or_else(|| anyhow!("unknown key {key}"))?;
+            keymap.bindings.retain(|_, bound| *bound != action);
+            keymap.bindings.insert(key, action);
+        }
+        Ok(keymap)
+    }
+
+    /// Get the action bound to a key, if any.
+    pub fn action(&self, event: &KeyEvent) -> Option<Action> {
+        self.bindings
+            .get(&normalize(event.code, event.modifiers))
+            .copied()
+    }
+}
+
+/// Parse a key such as `ctrl-b`, `alt-enter` or `pageup`.
+fn parse_key(key: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = key;
+    loop {
+        if let Some(r) = rest.strip_prefix("ctrl-") {
+            modifiers |= KeyModifiers::CONTROL;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("alt-") {
+            modifiers |= KeyModifiers::ALT;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+
+    let code = match rest {
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "delete" => KeyCode::Delete,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        _ => match rest.strip_prefix('f').and_then(|n| n.parse().ok()) {
+            Some(n) => KeyCode::F(n),
+            None => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return None,
+                }
+            }
+        },
+    };
+    Some(normalize(code, modifiers))
+}
+
+/// Drop the shift modifier of characters, which is already reflected in the
+/// character itself.
+fn normalize(
+    code: KeyCode,
+    modifiers: KeyModifiers,
+) -> (KeyCode, KeyModifiers) {
+    match code {
+        KeyCode::Char(_) => (code, modifiers - KeyModifiers::SHIFT),
+        _ => (code, modifiers),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("ctrl-b"),
+            Some((KeyCode::Char('b'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            parse_key("ctrl-alt-enter"),
+            Some((KeyCode::Enter, KeyModifiers::CONTROL | KeyModifiers::ALT))
+        );
+        assert_eq!(
+            parse_key("pageup"),
+            Some((KeyCode::PageUp, KeyModifiers::NONE))
+        );
+        assert_eq!(parse_key("f5"), Some((KeyCode::F(5), KeyModifiers::NONE)));
+        assert_eq!(
+            parse_key("f"),
+            Some((KeyCode::Char('f'), KeyModifiers::NONE))
+        );
+        assert_eq!(parse_key("ctrl-"), None);
+        assert_eq!(parse_key("shift-a"), None);
+        assert_eq!(parse_key("pgup"), None);
+    }
+
+    #[test]
+    fn test_action_names() {
+        assert_eq!(Action::from_name("page-up"), Some(Action::PageUp));
+        assert_eq!(Action::from_name("buffer-1"), Some(Action::GoToBuffer(1)));
+        assert_eq!(Action::from_name("buffer-9"), Some(Action::GoToBuffer(9)));
+        assert_eq!(Action::from_name("buffer-0"), None);
+        assert_eq!(Action::from_name("buffer-10"), None);
+        assert_eq!(Action::from_name("page_up"), None);
+    }
+
+    #[test]
+    fn test_default_keymap() {
+        let keymap = Keymap::default();
+        assert_eq!(
+            keymap.action(&press(KeyCode::Char('3'), KeyModifiers::ALT)),
+            Some(Action::GoToBuffer(3))
+        );
+        assert_eq!(
+            keymap.action(&press(KeyCode::Char('n'), KeyModifiers::CONTROL)),
+            Some(Action::NextBuffer)
+        );
+        assert_eq!(
+            keymap.action(&press(KeyCode::Char('n'), KeyModifiers::NONE)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_from_config() {
+        let keys = HashMap::from([
+            ("page-up".to_owned(), "ctrl-b".to_owned()),
+            ("buffer-2".to_owned(), "f2".to_owned()),
+            ("quit".to_owned(), "alt-Q".to_owned()),
+        ]);
+        let keymap = Keymap::from_config(&keys).unwrap();
+
+        // the new keys replace both the default keys of the actions and the
+        // actions bound to them before
+        assert_eq!(
+            keymap.action(&press(KeyCode::Char('b'), KeyModifiers::CONTROL)),
+            Some(Action::PageUp)
+        );
+        assert_eq!(
+            keymap.action(&press(KeyCode::PageUp, KeyModifiers::NONE)),
+            None
+        );
+        assert_eq!(
+            keymap.action(&press(KeyCode::F(2), KeyModifiers::NONE)),
+            Some(Action::GoToBuffer(2))
+        );
+        assert_eq!(
+            keymap.action(&press(KeyCode::Char('2'), KeyModifiers::ALT)),
+            None
+        );
+
+        // the shift of a character is already in the character
+        assert_eq!(
+            keymap.action(&press(
+                KeyCode::Char('Q'),
+                KeyModifiers::ALT | KeyModifiers::SHIFT
+            )),
+            Some(Action::Quit)
+        );
+
+        let unknown_action = HashMap::from([("fly".into(), "f1".into())]);
+        assert!(Keymap::from_config(&unknown_action).is_err());
+        let unknown_key = HashMap::from([("quit".into(), "hyper-q".into())]);
+        assert!(Keymap::from_config(&unknown_key).is_err());
+    }
+}
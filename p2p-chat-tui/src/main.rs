@@ -1,30 +1,133 @@
 use std::env;
+use std::path::{Path, PathBuf};
 use std::{io, net::SocketAddr};
 
 use crossterm::{execute, style, terminal};
-use libp2p::{multiaddr::multiaddr, Multiaddr};
+use libp2p::{
+    identity::Keypair, mdns::MdnsConfig, multiaddr::multiaddr,
+    relay::v2::relay::Config as RelayConfig, Multiaddr,
+};
 use structopt::StructOpt;
+use tokio::sync::mpsc::{self, UnboundedSender};
 
-use p2p_chat::{gen_id_keys, Client};
+use p2p_chat::{
+    gen_id_keys, load_id_keys, load_swarm_key, save_id_keys, ClientBuilder,
+    ConfigFile,
+};
 
 pub mod app;
-use app::App;
+use app::{App, TimestampFormat};
+pub mod commands;
+pub mod completion;
+pub mod input;
+pub mod keys;
+use keys::Keymap;
+pub mod theme;
+use theme::Theme;
+
+/// The file in which pending scheduled messages are kept between sessions.
+const SCHEDULE_FILE: &str = "scheduled.msgpack";
+
+/// The file in which the peers connected to are kept between sessions.
+const ADDRESS_BOOK_FILE: &str = "peers.msgpack";
+
+/// The file in which the blocked and muted peers are kept between sessions.
+const BLOCK_LIST_FILE: &str = "blocked.msgpack";
+
+/// The file in which the key first seen using each nickname and the verified
+/// peers are kept between sessions.
+const TRUST_FILE: &str = "trusted.msgpack";
+
+/// The file in which the open channels and direct conversations are kept
+/// between sessions.
+const SESSION_FILE: &str = "session.msgpack";
+
+/// The directory in which the messages of channels are kept between sessions.
+const HISTORY_DIR: &str = "history.sled";
 
 #[derive(StructOpt)]
 #[structopt(name = "p2p-chat-tui")]
 struct Opt {
+    /// Configuration file to read settings from, which are overridden by the
+    /// options given here. Defaults to `p2p-chat/config.toml` within the
+    /// configuration directory of the user.
+    #[structopt(short, long)]
+    config: Option<PathBuf>,
     /// Nickname.
     #[structopt(short, long)]
     nick: Option<String>,
-    /// Port to listen on.
+    /// Port to listen on, over TCP on all IPv4 interfaces, if no addresses to
+    /// listen on are given.
     #[structopt(short, long)]
     port: Option<u16>,
-    /// Peers to dial, separated by commas.
+    /// Addresses to listen on, as multi-addresses such as
+    /// `/ip6/::/tcp/4001`. May be given more than once. Only TCP and
+    /// WebSocket addresses are supported.
+    #[structopt(short, long, parse(try_from_str = parse_listen_addr))]
+    listen: Vec<Multiaddr>,
+    /// Addresses at which this peer can be reached from the outside, such as
+    /// through a static NAT mapping, to advertise to other peers.
+    #[structopt(long)]
+    external_addr: Vec<Multiaddr>,
+    /// Port to also listen on for WebSocket connections.
+    #[structopt(long)]
+    ws_port: Option<u16>,
+    /// Peers to dial, separated by commas, as host:port pairs or
+    /// multi-addresses such as `/dns4/example.com/tcp/4001/ws`.
     #[structopt(short, long, parse(try_from_str = parse_multiaddrs))]
     dial: Vec<Multiaddr>,
+    /// File in which the identity keypair is kept, created if it does not
+    /// exist. A new identity is generated on each run if not given.
+    #[structopt(short, long)]
+    keyfile: Option<PathBuf>,
+    /// Swarm key file of a private network to join, so that only peers with
+    /// the same key may connect.
+    #[structopt(long)]
+    swarm_key: Option<String>,
     /// Turn on verbose logging.
     #[structopt(short, long)]
     verbose: bool,
+    /// Show logged warnings and errors, such as connection problems, in the
+    /// system buffer as well as the log file.
+    #[structopt(long)]
+    log_to_buffer: bool,
+    /// Sign sent messages, so that others can prove who wrote them.
+    #[structopt(long)]
+    sign: bool,
+    /// Do not discover peers on the local network over mDNS.
+    #[structopt(long)]
+    no_mdns: bool,
+    /// Discover peers on the local network over IPv6 rather than IPv4.
+    #[structopt(long)]
+    mdns_ipv6: bool,
+    /// Relays to listen through, for when this peer cannot be dialed
+    /// directly, as multi-addresses ending with the relay's peer ID.
+    #[structopt(long)]
+    relay: Vec<Multiaddr>,
+    /// Act as a relay for peers which cannot be dialed directly. Only useful
+    /// if this peer is publicly reachable.
+    #[structopt(long)]
+    relay_server: bool,
+    /// Most reservations accepted at once when acting as a relay.
+    #[structopt(long, default_value = "128")]
+    relay_max_reservations: usize,
+    /// Most circuits relayed at once when acting as a relay.
+    #[structopt(long, default_value = "16")]
+    relay_max_circuits: usize,
+    /// Most bytes relayed over a single circuit when acting as a relay.
+    #[structopt(long, default_value = "131072")]
+    relay_max_circuit_bytes: u64,
+    /// Rendezvous points to register at and find peers through, as
+    /// multi-addresses ending with the rendezvous point's peer ID.
+    #[structopt(long)]
+    rendezvous: Vec<Multiaddr>,
+    /// How many of the most recently seen peers of previous sessions to dial
+    /// on startup.
+    #[structopt(long, default_value = "8")]
+    redial: usize,
+    /// Channels to join on startup. May be given more than once.
+    #[structopt(short, long)]
+    join: Vec<String>,
 }
 
 #[tokio::main]
@@ -37,21 +140,109 @@ async fn main() -> anyhow::Result<()> {
     } else {
         log::LevelFilter::Info
     };
-    setup_logger(level_filter)?;
+    let (log_sender, log_receiver) = if opts.log_to_buffer {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    setup_logger(level_filter, log_sender)?;
+
+    // options given on the command line take precedence over the
+    // configuration file
+    let config = match &opts.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::load_default()?,
+    };
+    let theme = Theme::from_config(&config.theme)?;
+    let keymap = Keymap::from_config(&config.keybindings)?;
+    let timestamp_format = match &config.timestamp_format {
+        Some(format) => format.parse()?,
+        None => TimestampFormat::default(),
+    };
+    let timestamps =
+        Some(timestamp_format).filter(|_| config.timestamps != Some(false));
+    let keyfile = opts.keyfile.or(config.keyfile);
+    let listen = if opts.listen.is_empty() {
+        config.listen
+    } else {
+        opts.listen
+    };
+    let dial = if opts.dial.is_empty() {
+        config.bootstrap
+    } else {
+        opts.dial
+    };
+    let channels = if opts.join.is_empty() {
+        config.channels
+    } else {
+        opts.join
+    };
 
     // start client
     let nick = opts
         .nick
+        .or(config.nick)
         .or_else(|| env::var("USER").ok())
         .unwrap_or_else(|| "user".to_owned());
-    let id_keys = gen_id_keys();
-    let mut client = Client::new(&nick, id_keys).await?;
+    // files kept between sessions are encrypted with the passphrase of the
+    // key file, if there is one
+    let (id_keys, passphrase) = match &keyfile {
+        Some(path) => {
+            let (id_keys, passphrase) = load_or_create_id_keys(path)?;
+            (id_keys, Some(passphrase))
+        }
+        None => (gen_id_keys(), None),
+    };
+    let mut builder = ClientBuilder::new(&nick, id_keys)
+        .sign_messages(opts.sign)
+        .mdns(!opts.no_mdns)
+        .mdns_config(MdnsConfig {
+            enable_ipv6: opts.mdns_ipv6,
+            ..Default::default()
+        });
+    if let Some(path) = &opts.swarm_key {
+        builder = builder.pnet_key(load_swarm_key(path)?);
+    }
+    for relay in opts.relay {
+        builder = builder.relay(relay);
+    }
+    if opts.relay_server {
+        builder = builder.relay_server(RelayConfig {
+            max_reservations: opts.relay_max_reservations,
+            max_circuits: opts.relay_max_circuits,
+            max_circuit_bytes: opts.relay_max_circuit_bytes,
+            ..Default::default()
+        });
+    }
+    let mut client = builder.build().await?;
+    client.persist_scheduled_messages(SCHEDULE_FILE, passphrase.as_deref())?;
+    client.persist_address_book(ADDRESS_BOOK_FILE, passphrase.as_deref())?;
+    client.persist_block_list(BLOCK_LIST_FILE, passphrase.as_deref())?;
+    client.persist_trust_store(TRUST_FILE, passphrase.as_deref())?;
+    client.persist_history(HISTORY_DIR, passphrase.as_deref())?;
+    client.resume_session(SESSION_FILE, passphrase.as_deref())?;
 
-    let port = opts.port.unwrap_or_default();
-    client.listen_on(multiaddr!(Ip4([0, 0, 0, 0]), Tcp(port)))?;
-    for addr in opts.dial {
+    if listen.is_empty() {
+        let port = opts.port.unwrap_or_default();
+        client.listen_on(multiaddr!(Ip4([0, 0, 0, 0]), Tcp(port)))?;
+    }
+    for addr in listen {
+        client.listen_on(addr)?;
+    }
+    for addr in opts.external_addr {
+        client.add_external_address(addr);
+    }
+    if let Some(port) = opts.ws_port {
+        client.listen_on(multiaddr!(Ip4([0, 0, 0, 0]), Tcp(port), Ws("/")))?;
+    }
+    for addr in dial {
         client.dial(addr)?;
     }
+    client.redial_known_peers(opts.redial);
+    for addr in opts.rendezvous {
+        client.register_rendezvous(addr)?;
+    }
 
     // setup tui
 
@@ -60,7 +251,13 @@ async fn main() -> anyhow::Result<()> {
     execute!(stdout, terminal::EnterAlternateScreen)?;
     terminal::enable_raw_mode()?;
 
-    let mut app = App::new(client);
+    let mut app = App::new(client, theme, keymap, timestamps);
+    if let Some(receiver) = log_receiver {
+        app.show_logs(receiver);
+    }
+    for channel in &channels {
+        app.join_channel(channel)?;
+    }
     app.run(&mut stdout).await?;
 
     terminal::disable_raw_mode()?;
@@ -73,28 +270,167 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn setup_logger(level: log::LevelFilter) -> anyhow::Result<()> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}][{}] {}",
-                chrono::Local::now()
-                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(level)
-        // .chain(std::io::stdout())
-        .chain(fern::log_file("current.log")?)
-        .apply()?;
+/// Log to `current.log`, and send any warnings and errors to a sink as well,
+/// if one is given.
+fn setup_logger(
+    level: log::LevelFilter,
+    sink: Option<UnboundedSender<String>>,
+) -> anyhow::Result<()> {
+    let mut dispatch = fern::Dispatch::new().level(level).chain(
+        fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "[{}][{}][{}] {}",
+                    chrono::Local::now()
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    record.target(),
+                    record.level(),
+                    message
+                ))
+            })
+            // .chain(std::io::stdout())
+            .chain(fern::log_file("current.log")?),
+    );
+    if let Some(sink) = sink {
+        dispatch = dispatch.chain(
+            fern::Dispatch::new().level(log::LevelFilter::Warn).chain(
+                fern::Output::call(move |record| {
+                    // the app may have quit already
+                    let _ = sink.send(format!(
+                        "{}: {}",
+                        record.level(),
+                        record.args()
+                    ));
+                }),
+            ),
+        );
+    }
+    dispatch.apply()?;
+    Ok(())
+}
+
+/// Load the identity keypair stored in a file, or generate one and store it
+/// there if the file does not exist, returning it along with the passphrase
+/// the file is encrypted with.
+fn load_or_create_id_keys(
+    path: impl AsRef<Path>,
+) -> anyhow::Result<(Keypair, String)> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        let passphrase = rpassword::read_password_from_tty(Some(
+            "Passphrase for key file: ",
+        ))?;
+        let id_keys = load_id_keys(path, &passphrase)?;
+        return Ok((id_keys, passphrase));
+    }
+
+    let passphrase = rpassword::read_password_from_tty(Some(
+        "Passphrase for new key file: ",
+    ))?;
+    let id_keys = gen_id_keys();
+    save_id_keys(&id_keys, path, &passphrase)?;
+
+    Ok((id_keys, passphrase))
+}
+
+/// Parse a multi-address to listen on.
+fn parse_listen_addr(s: &str) -> anyhow::Result<Multiaddr> {
+    check_transport(s)?;
+    Ok(s.parse()?)
+}
+
+/// Fail with a clear error for multi-addresses of transports which are not
+/// supported, which would otherwise fail to parse as unknown protocols.
+///
+/// WebRTC needs a newer libp2p than the one this client is built with.
+fn check_transport(s: &str) -> anyhow::Result<()> {
+    if s.split('/').any(|protocol| protocol.starts_with("webrtc")) {
+        anyhow::bail!(
+            "cannot use {s}, WebRTC is not supported, use TCP or WebSocket"
+        );
+    }
     Ok(())
 }
 
 fn parse_multiaddrs(s: &str) -> anyhow::Result<Multiaddr> {
-    Ok(match s.parse::<SocketAddr>()? {
-        SocketAddr::V4(addr) => multiaddr!(Ip4(*addr.ip()), Tcp(addr.port())),
-        SocketAddr::V6(addr) => multiaddr!(Ip6(*addr.ip()), Tcp(addr.port())),
+    if s.starts_with('/') {
+        check_transport(s)?;
+        return Ok(s.parse()?);
+    }
+
+    Ok(match s.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) => {
+            multiaddr!(Ip4(*addr.ip()), Tcp(addr.port()))
+        }
+        Ok(SocketAddr::V6(addr)) => {
+            multiaddr!(Ip6(*addr.ip()), Tcp(addr.port()))
+        }
+        // anything else is taken to be a domain name
+        Err(_) => {
+            let (host, port) = s
+                .rsplit_once(':')
+                .ok_or_else(|| anyhow::anyhow!("missing port in {s}"))?;
+            let port = port.parse::<u16>()?;
+            if host.contains(':') {
+                anyhow::bail!(
+                    "IPv6 address in {s} must be in brackets, as in [::1]:4001"
+                );
+            }
+            if host.is_empty()
+                || !host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            {
+                anyhow::bail!("invalid host name in {s}");
+            }
+            multiaddr!(Dns(host), Tcp(port))
+        }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multiaddrs() {
+        assert_eq!(
+            parse_multiaddrs("127.0.0.1:4001").unwrap(),
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap()
+        );
+        assert_eq!(
+            parse_multiaddrs("[::1]:4001").unwrap(),
+            "/ip6/::1/tcp/4001".parse().unwrap()
+        );
+        assert_eq!(
+            parse_multiaddrs("example.com:4001").unwrap(),
+            "/dns/example.com/tcp/4001".parse().unwrap()
+        );
+        assert_eq!(
+            parse_multiaddrs("/ip4/10.0.0.1/tcp/80").unwrap(),
+            "/ip4/10.0.0.1/tcp/80".parse().unwrap()
+        );
+
+        // IPv6 addresses are never mistaken for domain names
+        assert!(parse_multiaddrs("::1:4001").is_err());
+        assert!(parse_multiaddrs("fe80::1:4001").is_err());
+        assert!(parse_multiaddrs("[::1]").is_err());
+
+        assert!(parse_multiaddrs("example.com").is_err());
+        assert!(parse_multiaddrs(":4001").is_err());
+        assert!(parse_multiaddrs("exa mple.com:4001").is_err());
+        assert!(parse_multiaddrs("example.com:99999").is_err());
+    }
+
+    #[test]
+    fn test_unsupported_transports() {
+        let webrtc = "/ip4/127.0.0.1/udp/4001/webrtc-direct";
+        for result in [parse_multiaddrs(webrtc), parse_listen_addr(webrtc)] {
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("WebRTC is not supported"), "{err}");
+        }
+
+        assert!(parse_listen_addr("/ip4/0.0.0.0/tcp/4001/ws").is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use crossterm::style::Color;
+use libp2p::PeerId;
+
+/// Colors which are hard to read on most terminals, and so are left out of
+/// the colors of nicknames.
+const LOW_CONTRAST: &[Color] = &[Color::Black, Color::DarkGrey, Color::Grey];
+
+/// The colors of the user interface.
+#[derive(Clone, Debug)]
+pub struct Theme {
+    /// The color of messages and logs.
+    pub text: Color,
+    /// The color of quotes and deleted messages.
+    pub muted: Color,
+    /// The color of messages which mention the user.
+    pub mention: Color,
+    /// The color of actions sent with `/me`.
+    pub action: Color,
+    /// The color of buffers with unread messages in the status line.
+    pub activity: Color,
+    /// The background color of the status line.
+    pub status_line: Color,
+    /// The color of the status line's marker when no peers are connected.
+    pub offline: Color,
+    /// The color of inline code in formatted messages.
+    pub code: Color,
+    /// The colors nicknames are shown in, one of which is picked for each
+    /// peer.
+    pub nicks: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            text: Color::White,
+            muted: Color::DarkGrey,
+            mention: Color::Yellow,
+            action: Color::Magenta,
+            activity: Color::Green,
+            status_line: Color::DarkGrey,
+            offline: Color::Red,
+            code: Color::Cyan,
+            nicks: vec![
+                Color::Red,
+                Color::Green,
+                Color::Blue,
+                Color::Magenta,
+                Color::Cyan,
+                Color::DarkRed,
+                Color::DarkGreen,
+                Color::DarkYellow,
+                Color::DarkBlue,
+                Color::DarkMagenta,
+                Color::DarkCyan,
+            ],
+        }
+    }
+}
+
+impl Theme {
+    /// Build a theme from the default one, replacing the colors named in a
+    /// configuration file, such as `mention = "dark_red"`, or the colors of
+    /// nicknames, such as `nicks = "red, green, blue"`.
+    pub fn from_config(
+        colors: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let mut theme = Theme::default();
+        for (name, color) in colors {
+            if name == "nicks" {
+                theme.nicks = color
+                    .split(',')
+                    .map(|color| parse_color(color.trim(), name))
+                    .filter(|color| {
+                        !color.as_ref().is_ok_and(|c| LOW_CONTRAST.contains(c))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                if theme.nicks.is_empty() {
+                    return Err(anyhow!("no readable colors for nicks"));
+                }
+                continue;
+            }
+
+            let color = parse_color(color, name)?;
+            match name.as_str() {
+                "text" => theme.text = color,
+                "muted" => theme.muted = color,
+                "mention" => theme.mention = color,
+                "action" => theme.action = color,
+                "activity" => theme.activity = color,
+                "status-line" => theme.status_line = color,
+                "offline" => theme.offline = color,
+                "code" => theme.code = color,
+                _ => return Err(anyhow!("unknown theme color {name}")),
+            }
+        }
+        Ok(theme)
+    }
+
+    /// Get the color a peer's nickname is shown in, which is the same for
+    /// the peer every time.
+    pub fn nick_color(&self, peer: &PeerId) -> Color {
+        // FNV-1a, which unlike the standard hasher is stable across releases
+        let hash = peer.to_bytes().iter().fold(0xcbf29ce484222325, |h, b| {
+            (h ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+        });
+        self.nicks[(hash % self.nicks.len() as u64) as usize]
+    }
+}
+
+fn parse_color(color: &str, name: &str) -> anyhow::Result<Color> {
+    // parsing would take unknown colors to be white
+    Color::try_from(color)
+        .map_err(|_| anyhow!("unknown color {color} for {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(colors: &[(&str, &str)]) -> HashMap<String, String> {
+        colors
+            .iter()
+            .map(|&(name, color)| (name.to_owned(), color.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let theme = Theme::from_config(&config(&[
+            ("mention", "dark_red"),
+            ("status-line", "blue"),
+            ("nicks", "red, black, green"),
+        ]))
+        .unwrap();
+        assert_eq!(theme.mention, Color::DarkRed);
+        assert_eq!(theme.status_line, Color::Blue);
+        assert_eq!(theme.text, Theme::default().text);
+        // colors which are hard to read are left out
+        assert_eq!(theme.nicks, vec![Color::Red, Color::Green]);
+    }
+
+    #[test]
+    fn test_from_config_invalid() {
+        for colors in [
+            [("mention", "mauve")],
+            [("sparkle", "red")],
+            [("nicks", "red, mauve")],
+            [("nicks", "black, grey")],
+        ] {
+            assert!(Theme::from_config(&config(&colors)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_nick_color() {
+        let theme = Theme::default();
+        let peers: Vec<_> = (0..32).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            assert_eq!(theme.nick_color(peer), theme.nick_color(peer));
+            assert!(theme.nicks.contains(&theme.nick_color(peer)));
+        }
+
+        // peers are spread across the palette
+        let mut colors: Vec<_> =
+            peers.iter().map(|peer| theme.nick_color(peer)).collect();
+        colors.dedup();
+        assert!(colors.len() > 1);
+
+        let single = Theme {
+            nicks: vec![Color::Red],
+            ..Theme::default()
+        };
+        assert_eq!(single.nick_color(&peers[0]), Color::Red);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{load_file, write_file, StorageKey};
+
+/// The most addresses remembered for each peer.
+const MAX_ADDRESSES: usize = 4;
+
+/// The most peers remembered, beyond which those seen least recently are
+/// forgotten.
+const MAX_PEERS: usize = 256;
+
+/// A peer which has been connected to before.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct KnownPeer {
+    pub peer_id: PeerId,
+    /// The addresses the peer was reached at, most recent first.
+    pub addresses: Vec<Multiaddr>,
+    /// The time the peer was last connected to, in milliseconds since the
+    /// Unix epoch.
+    pub last_seen: u64,
+}
+
+/// The peers which have been connected to before, optionally persisted to
+/// disk so that they can be dialed again in later sessions.
+#[derive(Default, Debug)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, KnownPeer>,
+    path: Option<PathBuf>,
+    /// The key the file is encrypted with, if any.
+    key: Option<StorageKey>,
+}
+
+impl AddressBook {
+    /// Load the peers stored at a given path, and persist any further
+    /// changes to it.
+    ///
+    /// Peers already known in memory are kept and written to the file. If a
+    /// passphrase is given, the file is encrypted with a key derived from it.
+    pub fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        let path = path.as_ref().to_owned();
+
+        let (stored, key): (Option<Vec<KnownPeer>>, _) =
+            load_file(&path, passphrase)?;
+        self.key = key;
+        for peer in stored.into_iter().flatten() {
+            self.peers.entry(peer.peer_id).or_insert(peer);
+        }
+
+        self.path = Some(path);
+        self.save()
+    }
+
+    /// Remember that a peer was connected to at an address at a given time.
+    pub fn record(
+        &mut self,
+        peer_id: PeerId,
+        address: Multiaddr,
+        now: u64,
+    ) -> crate::Result<()> {
+        let peer = self.peers.entry(peer_id).or_insert_with(|| KnownPeer {
+            peer_id,
+            addresses: Vec::new(),
+            last_seen: now,
+        });
+        peer.last_seen = peer.last_seen.max(now);
+        peer.addresses.retain(|a| a != &address);
+        peer.addresses.insert(0, address);
+        peer.addresses.truncate(MAX_ADDRESSES);
+
+        if self.peers.len() > MAX_PEERS {
+            let oldest = self
+                .peers
+                .values()
+                .min_by_key(|peer| peer.last_seen)
+                .map(|peer| peer.peer_id);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+
+        self.save()
+    }
+
+    /// Get a known peer.
+    pub fn get(&self, peer_id: &PeerId) -> Option<&KnownPeer> {
+        self.peers.get(peer_id)
+    }
+
+    /// Get all known peers, most recently seen first.
+    pub fn recent(&self) -> Vec<&KnownPeer> {
+        let mut peers = self.peers.values().collect::<Vec<_>>();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(path) = &self.path {
+            let peers = self.peers.values().collect::<Vec<_>>();
+            write_file(path, &peers, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::multiaddr::multiaddr;
+
+    use super::*;
+
+    #[test]
+    fn test_address_book() {
+        let mut book = AddressBook::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        for port in 0..6u16 {
+            let address = multiaddr!(Ip4([127, 0, 0, 1]), Tcp(4000 + port));
+            book.record(a, address, 10).unwrap();
+        }
+        book.record(b, multiaddr!(Ip4([127, 0, 0, 1]), Tcp(5000u16)), 20)
+            .unwrap();
+
+        let recent = book.recent();
+        assert_eq!(recent[0].peer_id, b);
+        assert_eq!(recent[1].peer_id, a);
+
+        // the most recent addresses are kept first
+        let addresses = &book.get(&a).unwrap().addresses;
+        assert_eq!(addresses.len(), MAX_ADDRESSES);
+        assert_eq!(addresses[0], multiaddr!(Ip4([127, 0, 0, 1]), Tcp(4005u16)));
+
+        // seeing an address again moves it to the front
+        book.record(a, multiaddr!(Ip4([127, 0, 0, 1]), Tcp(4003u16)), 30)
+            .unwrap();
+        let addresses = &book.get(&a).unwrap().addresses;
+        assert_eq!(addresses.len(), MAX_ADDRESSES);
+        assert_eq!(addresses[0], multiaddr!(Ip4([127, 0, 0, 1]), Tcp(4003u16)));
+        assert_eq!(book.recent()[0].peer_id, a);
+    }
+}
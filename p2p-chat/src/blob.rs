@@ -0,0 +1,94 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+
+use crate::protocol::{BlobHash, BLOB_EXCHANGE_PROTOCOL, MAX_ATTACHMENT_SIZE};
+
+/// The maximum size of an encoded blob hash, in bytes.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// The maximum size of an encoded blob, in bytes, allowing for the overhead
+/// of its encoding.
+const MAX_RESPONSE_SIZE: usize = MAX_ATTACHMENT_SIZE + 1024;
+
+/// The request-response protocol over which blobs are exchanged.
+#[derive(Clone, Debug)]
+pub(crate) struct BlobProtocol;
+
+impl ProtocolName for BlobProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        BLOB_EXCHANGE_PROTOCOL.as_bytes()
+    }
+}
+
+/// Encodes requests for blobs by their hash, answered with the blob if the
+/// peer has it.
+#[derive(Clone, Default)]
+pub(crate) struct BlobCodec;
+
+#[async_trait]
+impl RequestResponseCodec for BlobCodec {
+    type Protocol = BlobProtocol;
+    type Request = BlobHash;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &BlobProtocol,
+        io: &mut T,
+    ) -> io::Result<BlobHash>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, MAX_REQUEST_SIZE).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &BlobProtocol,
+        io: &mut T,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+        rmp_serde::from_read(data.as_slice()).map_err(invalid_data)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &BlobProtocol,
+        io: &mut T,
+        hash: BlobHash,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, hash).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &BlobProtocol,
+        io: &mut T,
+        blob: Option<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = rmp_serde::to_vec(&blob).map_err(invalid_data)?;
+        write_length_prefixed(io, data).await
+    }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{load_file, write_file, StorageKey};
+
+/// The peers which are blocked or muted, as kept on disk.
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct Lists {
+    blocked: HashSet<PeerId>,
+    muted: HashSet<PeerId>,
+}
+
+/// The peers which were blocked or muted, optionally persisted to disk so
+/// that the decisions outlive the session.
+#[derive(Default, Debug)]
+pub struct BlockList {
+    lists: Lists,
+    path: Option<PathBuf>,
+    /// The key the file is encrypted with, if any.
+    key: Option<StorageKey>,
+}
+
+impl BlockList {
+    /// Load the peers stored at a given path, and persist any further
+    /// changes to it.
+    ///
+    /// Peers already blocked or muted in memory are kept and written to the
+    /// file. If a passphrase is given, the file is encrypted with a key
+    /// derived from it.
+    pub fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        let path = path.as_ref().to_owned();
+
+        let (stored, key): (Option<Lists>, _) = load_file(&path, passphrase)?;
+        self.key = key;
+        if let Some(stored) = stored {
+            self.lists.blocked.extend(stored.blocked);
+            self.lists.muted.extend(stored.muted);
+        }
+
+        self.path = Some(path);
+        self.save()
+    }
+
+    /// Block a peer, returning whether it was not blocked already.
+    pub fn block(&mut self, peer: PeerId) -> crate::Result<bool> {
+        let added = self.lists.blocked.insert(peer);
+        self.save_if(added)
+    }
+
+    /// Unblock a peer, returning whether it was blocked.
+    pub fn unblock(&mut self, peer: &PeerId) -> crate::Result<bool> {
+        let removed = self.lists.blocked.remove(peer);
+        self.save_if(removed)
+    }
+
+    /// Mute a peer, returning whether it was not muted already.
+    pub fn mute(&mut self, peer: PeerId) -> crate::Result<bool> {
+        let added = self.lists.muted.insert(peer);
+        self.save_if(added)
+    }
+
+    /// Unmute a peer, returning whether it was muted.
+    pub fn unmute(&mut self, peer: &PeerId) -> crate::Result<bool> {
+        let removed = self.lists.muted.remove(peer);
+        self.save_if(removed)
+    }
+
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        self.lists.blocked.contains(peer)
+    }
+
+    pub fn is_muted(&self, peer: &PeerId) -> bool {
+        self.lists.muted.contains(peer)
+    }
+
+    pub fn blocked(&self) -> impl Iterator<Item = &PeerId> {
+        self.lists.blocked.iter()
+    }
+
+    pub fn muted(&self) -> impl Iterator<Item = &PeerId> {
+        self.lists.muted.iter()
+    }
+
+    /// Save the lists if they were changed, passing along whether they were.
+    fn save_if(&self, changed: bool) -> crate::Result<bool> {
+        if changed {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(path) = &self.path {
+            write_file(path, &self.lists, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_list() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-block-list-{}", std::process::id()));
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        let mut list = BlockList::default();
+        assert!(list.block(a).unwrap());
+        assert!(!list.block(a).unwrap());
+        list.persist_to(&path, None).unwrap();
+        assert!(list.mute(b).unwrap());
+        assert!(list.unblock(&a).unwrap());
+        assert!(list.block(b).unwrap());
+
+        let mut loaded = BlockList::default();
+        loaded.persist_to(&path, None).unwrap();
+        assert!(!loaded.is_blocked(&a));
+        assert!(loaded.is_blocked(&b));
+        assert!(loaded.is_muted(&b));
+        assert!(!loaded.is_muted(&a));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
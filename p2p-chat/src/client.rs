@@ -1,43 +1,163 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap, HashSet, VecDeque,
+    },
     hash::{Hash, Hasher},
+    path::Path,
     pin::Pin,
-    task::Poll,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use futures::Stream;
+#[cfg(feature = "history-store")]
+use std::ops::RangeBounds;
+
+use futures::{Future, Stream, StreamExt};
 use libp2p::{
-    core::{either::EitherError, upgrade},
+    bandwidth::{BandwidthLogging, BandwidthSinks},
+    core::{
+        either::EitherTransport,
+        transport::OrTransport,
+        upgrade::{self, SelectUpgrade},
+        ConnectedPoint,
+    },
+    dns::TokioDnsConfig,
     gossipsub::{
-        self, error::GossipsubHandlerError, Gossipsub, GossipsubEvent,
-        GossipsubMessage, MessageId,
+        self, error::PublishError, Gossipsub, GossipsubEvent, GossipsubMessage,
+        MessageId, PeerScoreParams, PeerScoreThresholds, TopicScoreParams,
     },
+    identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo},
     identity::Keypair,
     kad::{
-        record::Key, store::MemoryStore, Kademlia, KademliaEvent, QueryResult,
-        Quorum, Record,
+        record::Key,
+        store::{MemoryStore, RecordStore},
+        GetProvidersOk, Kademlia, KademliaConfig, KademliaEvent, QueryId,
+        QueryResult, Quorum, Record,
     },
     mdns::{self, Mdns, MdnsEvent},
     mplex,
+    multiaddr::Protocol,
     noise::{self, AuthenticKeypair, X25519Spec},
-    swarm::{SwarmBuilder, SwarmEvent},
+    ping,
+    pnet::{PnetConfig, PreSharedKey},
+    relay::v2::{
+        client::{self as relay, Client as RelayClient},
+        relay::{self as relay_server, Relay},
+    },
+    rendezvous::{self, Cookie, Namespace},
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse,
+        RequestResponseConfig, RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{
+        behaviour::toggle::Toggle, dial_opts::DialOpts, AddressScore,
+        SwarmBuilder, SwarmEvent,
+    },
     tcp::TokioTcpConfig,
+    websocket::WsConfig,
+    yamux::YamuxConfig,
     Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport,
 };
-use log::{info, warn};
+use log::{debug, info, warn};
+use tokio::{
+    sync::oneshot,
+    time::{self, Interval, MissedTickBehavior},
+};
 
+use crate::address_book::{AddressBook, KnownPeer};
+use crate::blob::{BlobCodec, BlobProtocol};
+use crate::block_list::BlockList;
+use crate::crypto::{
+    x25519_public_key, x25519_secret_key, ChannelKey, Keyring,
+};
+use crate::direct::{DirectCodec, DirectProtocol};
+use crate::history::{History, HistoryCodec, HistoryProtocol};
+#[cfg(feature = "history-store")]
+use crate::history_store::{HistoryStore, StoredMessage};
+use crate::metrics::Metrics;
+use crate::poll::Poll;
 use crate::protocol::{
-    topic_from_channel, ChannelIdentifier, Command, MemoryKey, MemoryValue,
-    MessageType, DEFAULT_GOSSIPSUB_TOPIC,
+    blob_hash, channel_from_topic, check_timestamp, fragment,
+    is_valid_channel_identifier, mentioned_names, topic_from_channel,
+    Attachment, BlobHash, CausalOrder, Channel, ChannelIdentifier,
+    ChannelPolicy, Command, HistoryEntry, HistoryRequest, Invite, MemoryKey,
+    MemoryValue, MessageSignature, MessageType, Moderation, NickClaim, Profile,
+    Reassembler, ReplayWindow, Role, TimestampVerdict, CAPABILITIES,
+    DEFAULT_GOSSIPSUB_TOPIC, IDENTIFY_PROTOCOL_VERSION,
+    MAX_ADVERTISED_CHANNELS, MAX_ATTACHMENT_SIZE, MAX_AVATAR_SIZE,
+    MAX_GOODBYE_REASON_LENGTH, MAX_HISTORY_LENGTH, MAX_LONG_MESSAGE_LENGTH,
+    MAX_MENTIONS, MAX_MESSAGE_LENGTH, MAX_MESSAGE_LIFETIME,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RENDEZVOUS_NAMESPACE,
 };
+use crate::ratchet::{DmSession, RatchetHeader, Session};
+use crate::rate_limit::{RateLimiter, RateVerdict};
+use crate::schedule::{ScheduleId, ScheduledMessage, Scheduler};
+use crate::session::{SessionEntry, SessionState};
+use crate::spam::{SpamConfig, SpamFilter, SpamKind};
+use crate::trust::{TrustStore, TrustVerdict};
+
+/// How often the client checks for scheduled messages and disconnection
+/// cool-downs which are due.
+const SCHEDULE_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for a goodbye to be sent before disconnecting on shutdown.
+const SHUTDOWN_FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+/// How long to wait for connections to close on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to keep retrying to publish a message while there are no peers to
+/// publish it to.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a joined channel to have peers to publish to.
+const CHANNEL_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many of the most recent ping round-trip times are averaged to estimate
+/// the latency to a peer.
+const LATENCY_SAMPLES: usize = 5;
+
+/// How many peers are asked for the history of a channel upon joining it.
+const HISTORY_SYNC_PEERS: usize = 3;
+
+/// How often the client's nickname is announced again, for the sake of peers
+/// which missed it.
+const NICK_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How many times storing one of the client's own records in the DHT is
+/// retried before giving up.
+const MAX_PUT_RETRIES: u32 = 3;
+
+/// How long to wait before first retrying to store a record in the DHT,
+/// doubling with each attempt.
+const PUT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often the client bootstraps again to refresh its DHT routing table.
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the client registers at its rendezvous points again, and asks
+/// them for peers which registered since.
+const RENDEZVOUS_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How much sooner than slow mode allows a peer's message may arrive, since
+/// messages are delayed by varying amounts on their way.
+const SLOW_MODE_TOLERANCE: Duration = Duration::from_secs(1);
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ComposedEvent")]
 struct ComposedBehaviour {
     gossipsub: Gossipsub,
     kademlia: Kademlia<MemoryStore>,
-    mdns: Mdns,
+    mdns: Toggle<Mdns>,
+    ping: ping::Behaviour,
+    identify: Identify,
+    direct: RequestResponse<DirectCodec>,
+    blob: RequestResponse<BlobCodec>,
+    history: RequestResponse<HistoryCodec>,
+    relay: RelayClient,
+    relay_server: Toggle<Relay>,
+    rendezvous: rendezvous::client::Behaviour,
 }
 
 #[derive(Debug)]
@@ -45,6 +165,14 @@ enum ComposedEvent {
     Gossipsub(GossipsubEvent),
     Kademlia(KademliaEvent),
     Mdns(MdnsEvent),
+    Ping(ping::Event),
+    Identify(IdentifyEvent),
+    Direct(RequestResponseEvent<Command, ()>),
+    Blob(RequestResponseEvent<BlobHash, Option<Vec<u8>>>),
+    History(RequestResponseEvent<HistoryRequest, Vec<HistoryEntry>>),
+    Relay(relay::Event),
+    RelayServer(relay_server::Event),
+    Rendezvous(rendezvous::client::Event),
 }
 
 impl From<GossipsubEvent> for ComposedEvent {
@@ -65,21 +193,209 @@ impl From<MdnsEvent> for ComposedEvent {
     }
 }
 
+impl From<relay::Event> for ComposedEvent {
+    fn from(val: relay::Event) -> Self {
+        ComposedEvent::Relay(val)
+    }
+}
+
+impl From<relay_server::Event> for ComposedEvent {
+    fn from(val: relay_server::Event) -> Self {
+        ComposedEvent::RelayServer(val)
+    }
+}
+
+impl From<rendezvous::client::Event> for ComposedEvent {
+    fn from(val: rendezvous::client::Event) -> Self {
+        ComposedEvent::Rendezvous(val)
+    }
+}
+
+impl From<ping::Event> for ComposedEvent {
+    fn from(val: ping::Event) -> Self {
+        ComposedEvent::Ping(val)
+    }
+}
+
+impl From<IdentifyEvent> for ComposedEvent {
+    fn from(val: IdentifyEvent) -> Self {
+        ComposedEvent::Identify(val)
+    }
+}
+
+impl From<RequestResponseEvent<Command, ()>> for ComposedEvent {
+    fn from(val: RequestResponseEvent<Command, ()>) -> Self {
+        ComposedEvent::Direct(val)
+    }
+}
+
+impl From<RequestResponseEvent<BlobHash, Option<Vec<u8>>>> for ComposedEvent {
+    fn from(val: RequestResponseEvent<BlobHash, Option<Vec<u8>>>) -> Self {
+        ComposedEvent::Blob(val)
+    }
+}
+
+impl From<RequestResponseEvent<HistoryRequest, Vec<HistoryEntry>>>
+    for ComposedEvent
+{
+    fn from(
+        val: RequestResponseEvent<HistoryRequest, Vec<HistoryEntry>>,
+    ) -> Self {
+        ComposedEvent::History(val)
+    }
+}
+
 /// An event emitted by a [`Client`].
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum ClientEvent {
     Message {
+        id: MessageId,
         contents: String,
         channel: ChannelIdentifier,
         timestamp: u64,
         message_type: MessageType,
+        /// The message ID of the message being replied to, if any.
+        in_reply_to: Option<MessageId>,
+        /// The peers the sender mentioned in the message.
+        mentions: Vec<PeerId>,
+        /// A file attached to the message, which is fetched automatically and
+        /// reported with [`ClientEvent::AttachmentReceived`].
+        attachment: Option<Attachment>,
+        /// Whether the timestamp is plausible by the local clock.
+        timestamp_verdict: TimestampVerdict,
+        /// Where the message belongs among the others of its channel, which
+        /// unlike its timestamp is consistent across peers.
+        order: CausalOrder,
+        /// The signature of the sender, if they chose to sign the message,
+        /// which was verified on receipt.
+        signature: Option<Box<MessageSignature>>,
+        /// The author of the message, by the source signed into it.
+        sender: PeerId,
+        /// The peer the message was received from, which is not the author
+        /// if it was relayed by others, and for historical messages is the
+        /// peer which kept the history.
+        propagation_source: PeerId,
+        /// Whether the message was sent before we joined the channel, and was
+        /// synced from the history kept by another peer.
+        historical: bool,
+        /// Whether the sender is muted, in which case the message should be
+        /// hidden.
+        muted: bool,
+    },
+    /// The attachment of a message was fetched, and may be retrieved with
+    /// [`Client::attachment`].
+    AttachmentReceived {
+        message: MessageId,
+        channel: ChannelIdentifier,
+        attachment: Attachment,
+    },
+    /// A message mentioning this client was received, following its
+    /// [`ClientEvent::Message`].
+    ///
+    /// Mentions by muted peers are not reported.
+    Mentioned {
+        id: MessageId,
+        channel: ChannelIdentifier,
+        sender: PeerId,
+    },
+    /// The author of a message replaced its contents.
+    MessageEdited {
+        id: MessageId,
+        channel: ChannelIdentifier,
+        new_contents: String,
+        sender: PeerId,
+    },
+    /// The author of a message retracted it.
+    MessageDeleted {
+        id: MessageId,
+        channel: ChannelIdentifier,
+        sender: PeerId,
+    },
+    /// A disappearing message reached the end of its lifetime, and should no
+    /// longer be shown.
+    MessageExpired {
+        id: MessageId,
+        channel: ChannelIdentifier,
+    },
+    /// A message was sent directly to us by a peer.
+    DirectMessage {
+        contents: String,
+        timestamp: u64,
+        message_type: MessageType,
         sender: PeerId,
+        /// Whether the message was encrypted in a [`DmSession`] with the peer.
+        encrypted: bool,
+        /// Whether the sender is muted, in which case the message should be
+        /// hidden.
+        muted: bool,
     },
+    /// A peer invited us to join a channel, which may be done by passing the
+    /// token to [`Client::join_channel_with_invite`].
+    ChannelInvited {
+        channel: ChannelIdentifier,
+        inviter: PeerId,
+        /// The time after which the invitation may no longer be used, in
+        /// milliseconds since the Unix epoch.
+        expires_at: u64,
+        token: Vec<u8>,
+    },
+    /// A message was sent to a passphrase-protected channel, which could not be
+    /// decrypted with the passphrase we joined it with, if any.
+    UndecryptableMessage {
+        id: MessageId,
+        channel: ChannelIdentifier,
+        sender: PeerId,
+    },
+    /// A direct message returned by [`Client::send_direct`] could not be
+    /// delivered.
+    DirectMessageFailed {
+        request: RequestId,
+        peer: PeerId,
+        error: OutboundFailure,
+    },
+    /// A peer announced a nickname other than the one we knew it by.
     UpdatedNickname {
         nick: String,
         sender: PeerId,
     },
+    /// A lookup of a peer's nickname finished, whether or not one was found.
+    NicknameResolved {
+        peer: PeerId,
+        nick: Option<String>,
+    },
+    /// A nickname is in use by more than one peer, which may indicate an
+    /// impersonation attempt.
+    NicknameCollision {
+        nick: String,
+        peers: Vec<PeerId>,
+    },
+    /// A nickname was used by a peer other than the one first seen using it,
+    /// which may indicate an impersonation attempt, or that its user changed
+    /// their key.
+    ///
+    /// The key of the first peer stays trusted until
+    /// [`Client::trust_key`] is called.
+    KeyChanged {
+        nick: String,
+        trusted: PeerId,
+        peer: PeerId,
+    },
+    /// The earliest known claim to a nickname was found, so that peers other
+    /// than its owner using the nickname may be impostors.
+    NickRegistered {
+        nick: String,
+        owner: PeerId,
+    },
+    /// A peer was found at a rendezvous point, and is being dialed.
+    PeerDiscovered {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    /// The client started listening on a new address.
+    ListeningOn(Multiaddr),
+    /// The client stopped listening on an address.
+    StoppedListeningOn(Multiaddr),
     PeerConnected(PeerId),
     PeerDisconnected(PeerId),
     Dialing(PeerId),
@@ -87,81 +403,770 @@ pub enum ClientEvent {
         peer_id: Option<PeerId>,
         error: libp2p::swarm::DialError,
     },
+    /// A peer speaks a version of the chat protocol too old to talk to, and was
+    /// disconnected.
+    PeerIncompatible {
+        peer: PeerId,
+        protocol_version: u32,
+    },
+    /// A peer identified itself, and may be looked up with [`Client::whois`].
+    PeerIdentified(PeerId),
+    /// A peer sent messages faster than allowed, and its messages are being
+    /// rejected until it slows down.
+    PeerThrottled(PeerId),
+    /// A message was flagged as spam, following its [`ClientEvent::Message`],
+    /// so that it may be collapsed or hidden.
+    SpamDetected {
+        id: MessageId,
+        channel: ChannelIdentifier,
+        sender: PeerId,
+        kind: SpamKind,
+    },
+    /// A connected peer did not respond to a ping, which may mean the
+    /// connection is stale.
+    PingFailure {
+        peer: PeerId,
+        error: ping::Failure,
+    },
+    /// A channel or direct conversation of a previous session was resumed by
+    /// [`Client::resume_session`].
+    Resumed(SessionEntry),
+    /// A peer subscribed to a channel.
+    PeerJoinedChannel {
+        peer: PeerId,
+        channel: ChannelIdentifier,
+    },
+    /// A peer said goodbye to a channel we have joined, before leaving it or
+    /// the network.
+    ///
+    /// No [`ClientEvent::PeerLeftChannel`] follows for the same departure.
+    PeerLeft {
+        peer: PeerId,
+        channel: ChannelIdentifier,
+        reason: Option<String>,
+    },
+    /// A peer unsubscribed from a channel, or disconnected.
+    PeerLeftChannel {
+        peer: PeerId,
+        channel: ChannelIdentifier,
+    },
+    /// A channel record was created or updated by its owner.
+    ChannelUpdated(Channel),
+    /// A public channel was found in the directory, and is listed by
+    /// [`Client::list_public_channels`].
+    ChannelDiscovered(Channel),
+    /// The owner of a channel kicked a peer from it, which may be ourselves.
+    PeerKicked {
+        peer: PeerId,
+        channel: ChannelIdentifier,
+    },
+    /// The owner of a channel banned a peer from it, which may be ourselves.
+    ///
+    /// Messages from the peer to the channel are dropped from then on.
+    PeerBanned {
+        peer: PeerId,
+        channel: ChannelIdentifier,
+    },
+    /// A channel that was joined has a welcome message from its owner.
+    ChannelWelcome {
+        channel: ChannelIdentifier,
+        message: String,
+    },
+    /// A poll was posted to a channel.
+    PollCreated {
+        poll: MessageId,
+        channel: ChannelIdentifier,
+        question: String,
+        options: Vec<String>,
+        closes_at: u64,
+        sender: PeerId,
+    },
+    /// A vote was cast in a poll, changing its results.
+    PollUpdated {
+        poll: MessageId,
+        channel: ChannelIdentifier,
+        tally: Vec<usize>,
+    },
+    /// A poll closed, and is no longer kept, with its final results.
+    PollClosed {
+        poll: MessageId,
+        channel: ChannelIdentifier,
+        options: Vec<String>,
+        tally: Vec<usize>,
+    },
+    /// A peer's avatar was fetched, and may be retrieved with
+    /// [`Client::fetch_avatar`].
+    AvatarFetched(PeerId),
+    /// A peer's profile was fetched, and may be retrieved with
+    /// [`Client::fetch_profile`].
+    ProfileFetched(PeerId),
+    /// A message returned by [`Client::send_message`] was published to peers.
+    MessagePublished(MessageId),
+    /// A message returned by [`Client::send_message`] could not be
+    /// published.
+    MessageRejected(MessageId, crate::Error),
+    /// An error occurred while handling network activity, such as a message or
+    /// DHT record which could not be decoded.
+    Error(crate::Error),
+    /// A scheduled message was published.
+    ScheduledMessageSent {
+        id: ScheduleId,
+        contents: String,
+        channel: ChannelIdentifier,
+        message_type: MessageType,
+    },
+    /// A scheduled message could not be published when it was due, and will
+    /// not be tried again.
+    ScheduledMessageFailed {
+        id: ScheduleId,
+        error: crate::Error,
+    },
+}
+
+/// Information about a connected peer.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// The remote addresses of the open connections to the peer.
+    pub addresses: Vec<Multiaddr>,
+    /// The number of open connections to the peer.
+    pub connections: usize,
+    /// The average round-trip time of recent pings to the peer, if any have
+    /// completed.
+    pub latency: Option<Duration>,
+    /// The peer's nickname, if it is known.
+    pub nick: Option<String>,
+    /// The peer's gossipsub score, if peers are scored.
+    pub score: Option<f64>,
+}
+
+/// Tuning parameters of the gossipsub mesh.
+///
+/// The defaults suit larger networks, so small meshes such as those on a LAN
+/// may want to lower the mesh sizes to avoid publishing failing with
+/// [`PublishError::InsufficientPeers`].
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The target number of peers in each topic mesh.
+    pub mesh_n: usize,
+    /// The number of mesh peers below which more are added.
+    pub mesh_n_low: usize,
+    /// The number of mesh peers above which some are removed.
+    pub mesh_n_high: usize,
+    /// Whether or not our own messages are published to all known peers of a
+    /// topic, rather than only mesh peers.
+    pub flood_publish: bool,
+    /// The interval between gossipsub heartbeats.
+    pub heartbeat_interval: Duration,
+    /// The number of heartbeats for which sent messages are remembered.
+    pub history_length: usize,
+    /// Whether or not peers are scored by their behaviour, so that those
+    /// which misbehave are pruned from the mesh, and eventually ignored.
+    pub peer_scoring: bool,
+    /// The weight of each topic in the scores of peers.
+    pub topic_weight: f64,
+    /// The weight of the invalid messages a peer has delivered in its score,
+    /// which must not be positive.
+    pub invalid_message_weight: f64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            flood_publish: true,
+            heartbeat_interval: Duration::from_secs(15),
+            history_length: 5,
+            peer_scoring: true,
+            topic_weight: 0.5,
+            invalid_message_weight: -10.0,
+        }
+    }
+}
+
+/// A builder for a [`Client`] with configurable network parameters.
+pub struct ClientBuilder {
+    nick: String,
+    id_keys: Keypair,
+    config: ClientConfig,
+    validation_mode: gossipsub::ValidationMode,
+    mdns: bool,
+    mdns_config: mdns::MdnsConfig,
+    tcp_nodelay: bool,
+    mplex_config: mplex::MplexConfig,
+    yamux_config: YamuxConfig,
+    disconnect_cooldown: Duration,
+    max_clock_skew: Duration,
+    record_ttl: Duration,
+    nick_ttl: Duration,
+    rate_limit: (f64, u32),
+    spam_config: SpamConfig,
+    sign_messages: bool,
+    channels: Vec<ChannelIdentifier>,
+    relays: Vec<Multiaddr>,
+    relay_server: Option<relay_server::Config>,
+    pnet_key: Option<PreSharedKey>,
+}
+
+impl ClientBuilder {
+    pub fn new(nick: &str, id_keys: Keypair) -> Self {
+        ClientBuilder {
+            nick: nick.to_owned(),
+            id_keys,
+            config: ClientConfig::default(),
+            validation_mode: gossipsub::ValidationMode::Strict,
+            mdns: true,
+            mdns_config: mdns::MdnsConfig::default(),
+            tcp_nodelay: false,
+            mplex_config: mplex::MplexConfig::new(),
+            yamux_config: YamuxConfig::default(),
+            disconnect_cooldown: Duration::from_secs(60),
+            max_clock_skew: Duration::from_secs(5 * 60),
+            record_ttl: Duration::from_secs(24 * 60 * 60),
+            nick_ttl: Duration::from_secs(60 * 60),
+            rate_limit: (10.0, 20),
+            spam_config: SpamConfig::default(),
+            sign_messages: false,
+            channels: Vec::new(),
+            relays: Vec::new(),
+            relay_server: None,
+            pnet_key: None,
+        }
+    }
+
+    /// Set the tuning parameters of the gossipsub mesh.
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the interval between gossipsub heartbeats.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = interval;
+        self
+    }
+
+    /// Set the gossipsub message validation mode.
+    pub fn validation_mode(mut self, mode: gossipsub::ValidationMode) -> Self {
+        self.validation_mode = mode;
+        self
+    }
+
+    /// Set whether or not peers are discovered over mDNS.
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        self.mdns = enabled;
+        self
+    }
+
+    /// Set the configuration of mDNS discovery, such as how often the
+    /// network is queried and whether IPv6 is used instead of IPv4.
+    pub fn mdns_config(mut self, config: mdns::MdnsConfig) -> Self {
+        self.mdns_config = config;
+        self
+    }
+
+    /// Set whether or not TCP connections are created with `TCP_NODELAY`.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Set the configuration of the mplex stream multiplexer.
+    pub fn mplex_config(mut self, config: mplex::MplexConfig) -> Self {
+        self.mplex_config = config;
+        self
+    }
+
+    /// Set the configuration of the yamux stream multiplexer.
+    pub fn yamux_config(mut self, config: YamuxConfig) -> Self {
+        self.yamux_config = config;
+        self
+    }
+
+    /// Set how long a peer is refused after being disconnected with
+    /// [`Client::disconnect`].
+    pub fn disconnect_cooldown(mut self, cooldown: Duration) -> Self {
+        self.disconnect_cooldown = cooldown;
+        self
+    }
+
+    /// Set how long the records stored in the DHT last before they expire.
+    ///
+    /// The client stores its own records again whenever half of this time
+    /// has passed, so that they do not expire while it is running.
+    pub fn record_ttl(mut self, ttl: Duration) -> Self {
+        self.record_ttl = ttl;
+        self
+    }
+
+    /// Set how long the nickname of a peer is remembered without hearing it
+    /// again, after which it is looked up anew.
+    pub fn nick_ttl(mut self, ttl: Duration) -> Self {
+        self.nick_ttl = ttl;
+        self
+    }
+
+    /// Set how many messages per second each peer may publish in the long
+    /// run, and how many it may publish at once after being quiet.
+    ///
+    /// Messages beyond the limit are rejected, and
+    /// [`ClientEvent::PeerThrottled`] is emitted for the peer.
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+        self.rate_limit = (per_second, burst);
+        self
+    }
+
+    /// Set when messages are flagged as spam.
+    ///
+    /// Flagged messages are still received, followed by a
+    /// [`ClientEvent::SpamDetected`], and lower the gossipsub score of their
+    /// sender if peer scoring is on.
+    pub fn spam_filter(mut self, config: SpamConfig) -> Self {
+        self.spam_config = config;
+        self
+    }
+
+    /// Set whether or not messages are signed, so that recipients can prove
+    /// who wrote them.
+    ///
+    /// Signed messages carry their signature in [`ClientEvent::Message`].
+    pub fn sign_messages(mut self, enabled: bool) -> Self {
+        self.sign_messages = enabled;
+        self
+    }
+
+    /// Set how far ahead of local time message timestamps may be before they
+    /// are flagged with [`TimestampVerdict::Ahead`].
+    pub fn max_clock_skew(mut self, skew: Duration) -> Self {
+        self.max_clock_skew = skew;
+        self
+    }
+
+    /// Add a channel to join once the client is built.
+    pub fn channel(mut self, ident: ChannelIdentifier) -> Self {
+        self.channels.push(ident);
+        self
+    }
+
+    /// Only connect to peers with the same pre-shared key, so as to form a
+    /// private network.
+    ///
+    /// See [`load_swarm_key`](crate::load_swarm_key).
+    pub fn pnet_key(mut self, key: PreSharedKey) -> Self {
+        self.pnet_key = Some(key);
+        self
+    }
+
+    /// Add a relay to listen through once the client is built, for when it
+    /// cannot accept inbound connections itself.
+    ///
+    /// See [`Client::listen_via_relay`].
+    pub fn relay(mut self, addr: Multiaddr) -> Self {
+        self.relays.push(addr);
+        self
+    }
+
+    /// Act as a relay for other peers, accepting reservations from and
+    /// relaying traffic for those which cannot be dialed directly, within
+    /// the limits of the configuration.
+    ///
+    /// This is only useful for peers which are publicly reachable.
+    pub fn relay_server(mut self, config: relay_server::Config) -> Self {
+        self.relay_server = Some(config);
+        self
+    }
+
+    /// Build the client, starting its swarm.
+    pub async fn build(self) -> crate::Result<Client> {
+        Client::from_builder(self).await
+    }
+}
+
+/// Who sent a gossiped message, and who it was received from.
+#[derive(Clone, Copy, Debug)]
+struct Origin {
+    /// The peer which signed the message as its source.
+    author: PeerId,
+    /// The peer the message was forwarded to us by, which may have relayed it
+    /// from the author.
+    propagation_source: PeerId,
+    /// Whether the message was reassembled from fragments, and so may be
+    /// longer than a single gossipsub message.
+    reassembled: bool,
+}
+
+impl Origin {
+    /// The longest message which may have arrived this way, in bytes.
+    fn max_message_length(&self) -> usize {
+        if self.reassembled {
+            MAX_LONG_MESSAGE_LENGTH
+        } else {
+            MAX_MESSAGE_LENGTH
+        }
+    }
+}
+
+/// A message waiting for peers to be published to.
+struct OutgoingMessage {
+    topic: gossipsub::IdentTopic,
+    /// The encoded message, or its fragments, which are left to publish.
+    payloads: VecDeque<Vec<u8>>,
+    /// The time after which the message is given up on.
+    expires_at: Instant,
 }
 
 /// The client interface to p2p-chat.
 pub struct Client {
     nick: String,
     nick_cache: HashMap<PeerId, Option<String>>,
+    /// When each cached nickname was last heard or looked up.
+    nick_cached_at: HashMap<PeerId, Instant>,
+    /// How long cached nicknames are kept without hearing them again.
+    nick_ttl: Duration,
+    /// Outstanding DHT lookups of nicknames.
+    nick_queries: HashMap<QueryId, PeerId>,
+    nick_waiters: HashMap<PeerId, Vec<oneshot::Sender<Option<String>>>>,
+    avatar_cache: HashMap<PeerId, Option<BlobHash>>,
+    profile_cache: HashMap<PeerId, Profile>,
+    blob_cache: HashMap<BlobHash, Vec<u8>>,
+    /// Outstanding requests for the attachments of messages.
+    attachment_requests:
+        HashMap<RequestId, (MessageId, ChannelIdentifier, Attachment)>,
+    channels: HashMap<ChannelIdentifier, Channel>,
+    /// The moderation records of channels, as stored in the DHT by their
+    /// owners.
+    moderation: HashMap<ChannelIdentifier, Moderation>,
+    /// The earliest claims seen to nicknames, with their signed DHT values.
+    nick_claims: HashMap<String, (NickClaim, Vec<u8>)>,
+    joined_channels: HashSet<ChannelIdentifier>,
+    /// The remote peers subscribed to each channel.
+    channel_members: HashMap<ChannelIdentifier, HashSet<PeerId>>,
+    /// Those waiting for joined channels to have peers, and when they give up.
+    channel_waiters:
+        HashMap<ChannelIdentifier, Vec<(oneshot::Sender<bool>, Instant)>>,
+    /// Channels which were joined, but whose welcome has not been shown yet.
+    awaiting_welcome: HashSet<ChannelIdentifier>,
+    last_seen: HashMap<PeerId, SystemTime>,
+    /// The remote address of each open connection, by peer.
+    connections: HashMap<PeerId, Vec<Multiaddr>>,
+    /// The most recent identification received from each peer.
+    identities: HashMap<PeerId, IdentifyInfo>,
+    /// The protocol version and capabilities each connected peer greeted us
+    /// with.
+    hellos: HashMap<PeerId, (u32, Vec<String>)>,
+    /// Outstanding greetings sent to peers, whose failures are not reported.
+    hello_requests: HashSet<RequestId>,
+    /// The most recent ping round-trip times to each connected peer.
+    latencies: HashMap<PeerId, VecDeque<Duration>>,
+    polls: HashMap<MessageId, Poll>,
+    /// The channel and author of each message seen, against which edits and
+    /// deletions are checked.
+    message_authors: HashMap<MessageId, (ChannelIdentifier, PeerId)>,
+    /// Messages which could not be published yet, for lack of peers.
+    outbox: HashMap<MessageId, OutgoingMessage>,
+    /// Fragmented messages which have not fully arrived yet.
+    reassembler: Reassembler,
+    /// The sequence number of the last message sent by this client.
+    sequence: u64,
+    /// The encrypted direct message sessions with each peer.
+    dm_sessions: HashMap<PeerId, Session>,
+    /// The keys of the encrypted channels we have joined.
+    channel_keys: HashMap<ChannelIdentifier, Keyring>,
+    /// Peers which presented an invitation to join each invite-only channel.
+    invited: HashMap<ChannelIdentifier, HashSet<PeerId>>,
+    /// The channels this client advertises in the directory.
+    advertised_channels: HashSet<ChannelIdentifier>,
+    /// The channels found in the directory.
+    discovered_channels: HashSet<ChannelIdentifier>,
+    /// The recent messages of each channel, shared with peers who join it.
+    history: History,
+    /// The peers asked for the history of each channel we have joined.
+    history_syncs: HashMap<ChannelIdentifier, HashSet<PeerId>>,
+    /// Outstanding requests for the history of channels.
+    history_requests: HashMap<RequestId, ChannelIdentifier>,
+    /// The Lamport clock of each channel.
+    clocks: HashMap<ChannelIdentifier, u64>,
+    /// The sequence numbers seen from each peer in each channel.
+    replay_windows: HashMap<(PeerId, ChannelIdentifier), ReplayWindow>,
+    /// The DHT keys this client is a provider of.
+    provided_keys: HashSet<Key>,
+    /// Peers whose messages and connections are refused, and peers whose
+    /// messages are flagged as muted.
+    block_list: BlockList,
+    disconnect_cooldown: Duration,
+    /// How far ahead of local time message timestamps may plausibly be.
+    max_clock_skew: Duration,
+    /// Whether or not messages are signed by this client.
+    sign_messages: bool,
+    /// Peers which were disconnected on purpose, and when they may connect
+    /// again.
+    cooldowns: HashMap<PeerId, Instant>,
+    /// How long messages sent to each channel last before disappearing, in
+    /// seconds.
+    message_lifetimes: HashMap<ChannelIdentifier, u32>,
+    /// The channel of each disappearing message, and when it expires.
+    expirations: HashMap<MessageId, (ChannelIdentifier, u64)>,
+    /// When each peer last posted to each channel, for the sake of slow mode.
+    last_posted: HashMap<(PeerId, ChannelIdentifier), Instant>,
+    metrics: Metrics,
+    /// The byte counters of the transport.
+    bandwidth: Arc<BandwidthSinks>,
     id_keys: Keypair,
     swarm: Swarm<ComposedBehaviour>,
+    scheduler: Scheduler,
+    /// The peers which have been connected to before.
+    address_book: AddressBook,
+    /// The channels and direct conversations to resume in later sessions.
+    session: SessionState,
+    /// The key first seen using each nickname.
+    trust_store: TrustStore,
+    /// Where the messages of every channel are kept between sessions.
+    #[cfg(feature = "history-store")]
+    history_store: Option<HistoryStore>,
+    /// How many messages each peer may publish.
+    rate_limiter: RateLimiter,
+    /// Flags messages which look like spam.
+    spam_filter: SpamFilter,
+    schedule_tick: Interval,
+    /// When this client's nickname was last announced.
+    nick_announced_at: Option<Instant>,
+    /// Whether the nickname should be announced again at the next tick, as
+    /// new peers have arrived.
+    nick_announce_due: bool,
+    /// When the client last bootstrapped successfully, if it has yet.
+    bootstrapped_at: Option<Instant>,
+    /// How long records stored in the DHT last before they expire.
+    record_ttl: Duration,
+    /// When the client last stored its own records in the DHT again.
+    republished_at: Instant,
+    /// Own records which could not be stored in the DHT, with how many times
+    /// they have been retried and when they are next.
+    put_retries: HashMap<Key, (u32, Instant)>,
+    /// How peers are scored in each topic, if they are.
+    topic_score_params: Option<TopicScoreParams>,
+    /// The rendezvous points to register at and discover peers through, and
+    /// the cookie of the last discovery at each, if any.
+    rendezvous_points: HashMap<PeerId, Option<Cookie>>,
+    /// When the client last registered at its rendezvous points.
+    rendezvous_refreshed_at: Instant,
+    pending_events: VecDeque<ClientEvent>,
 }
 
 impl Client {
-    pub async fn new(nick: &str, id_keys: Keypair) -> crate::Result<Self> {
+    /// Create a client with the given mesh parameters, and defaults for the
+    /// rest.
+    ///
+    /// Use [`ClientBuilder`] for more control.
+    pub async fn new(
+        nick: &str,
+        id_keys: Keypair,
+        config: ClientConfig,
+    ) -> crate::Result<Self> {
+        ClientBuilder::new(nick, id_keys)
+            .config(config)
+            .build()
+            .await
+    }
+
+    async fn from_builder(builder: ClientBuilder) -> crate::Result<Self> {
+        let ClientBuilder {
+            nick,
+            id_keys,
+            config,
+            validation_mode,
+            mdns: enable_mdns,
+            mdns_config,
+            tcp_nodelay,
+            mplex_config,
+            yamux_config,
+            disconnect_cooldown,
+            max_clock_skew,
+            record_ttl,
+            nick_ttl,
+            rate_limit,
+            spam_config,
+            sign_messages,
+            channels,
+            relays,
+            relay_server,
+            pnet_key,
+        } = builder;
+        let nick = nick.as_str();
+
         let peer_id = PeerId::from(id_keys.public());
         let noise_keys = gen_static_keypair(&id_keys)?;
 
-        let transport = TokioTcpConfig::new()
-            .nodelay(false)
+        // peers behind a relay are dialed over the relay's own connection,
+        // while everything else goes over TCP, or WebSocket over TCP, with
+        // domain names resolved by the system's resolver
+        let (relay_transport, relay_client) =
+            RelayClient::new_transport_and_behaviour(peer_id);
+        let tcp =
+            TokioDnsConfig::system(TokioTcpConfig::new().nodelay(tcp_nodelay))?;
+        let transport = OrTransport::new(
+            relay_transport,
+            OrTransport::new(WsConfig::new(tcp.clone()), tcp),
+        );
+        // in a private network, connections are encrypted with the network's
+        // key before anything else, so outsiders cannot even handshake
+        let transport =
+            match pnet_key {
+                Some(key) => EitherTransport::Left(transport.and_then(
+                    move |socket, _| PnetConfig::new(key).handshake(socket),
+                )),
+                None => EitherTransport::Right(transport),
+            };
+        let (transport, bandwidth) = BandwidthLogging::new(transport);
+        let transport = transport
             .upgrade(upgrade::Version::V1)
             .authenticate(
                 noise::NoiseConfig::xx(noise_keys).into_authenticated(),
             )
-            .multiplex(mplex::MplexConfig::new())
+            // yamux is preferred, but older peers only speak mplex
+            .multiplex(SelectUpgrade::new(yamux_config, mplex_config))
             .boxed();
 
+        let mut provided_keys = HashSet::new();
+
+        let topic_score_params =
+            config.peer_scoring.then(|| TopicScoreParams {
+                topic_weight: config.topic_weight,
+                // chat is too quiet to expect messages from mesh peers at any
+                // steady rate
+                mesh_message_deliveries_weight: 0.0,
+                mesh_failure_penalty_weight: 0.0,
+                invalid_message_deliveries_weight: config
+                    .invalid_message_weight,
+                ..Default::default()
+            });
+
         let swarm = {
             let gossipsub = {
+                // the remaining parameters must not exceed the mesh size and
+                // history length, which may be smaller than their defaults
                 let gossipsub_config =
                     gossipsub::GossipsubConfigBuilder::default()
-                        .heartbeat_interval(Duration::from_secs(15))
-                        .validation_mode(gossipsub::ValidationMode::Strict)
+                        .mesh_n(config.mesh_n)
+                        .mesh_n_low(config.mesh_n_low)
+                        .mesh_n_high(config.mesh_n_high)
+                        .mesh_outbound_min(
+                            usize::min(2, config.mesh_n_low)
+                                .min(config.mesh_n / 2),
+                        )
+                        .flood_publish(config.flood_publish)
+                        .heartbeat_interval(config.heartbeat_interval)
+                        .history_length(config.history_length)
+                        .history_gossip(usize::min(3, config.history_length))
+                        .validation_mode(validation_mode)
                         .validate_messages()
                         .message_id_fn(message_id_fn)
                         .build()
-                        .unwrap();
-                Gossipsub::new(
+                        .map_err(crate::Error::ConfigError)?;
+                let mut gossipsub = Gossipsub::new(
                     gossipsub::MessageAuthenticity::Signed(id_keys.clone()),
                     gossipsub_config,
                 )
-                .unwrap()
+                .unwrap();
+                if topic_score_params.is_some() {
+                    let params = PeerScoreParams {
+                        // peers on the same machine or LAN share an address,
+                        // which is normal here
+                        ip_colocation_factor_weight: 0.0,
+                        ..Default::default()
+                    };
+                    gossipsub
+                        .with_peer_score(params, PeerScoreThresholds::default())
+                        .map_err(|_| {
+                            crate::Error::ConfigError(
+                                "peer scoring parameters are not valid",
+                            )
+                        })?;
+                }
+                gossipsub
             };
 
             let kademlia = {
                 let memory_store = MemoryStore::new(peer_id);
-                let mut kademlia = Kademlia::new(peer_id, memory_store);
+                let mut kademlia_config = KademliaConfig::default();
+                kademlia_config
+                    .set_record_ttl(Some(record_ttl))
+                    .set_provider_record_ttl(Some(record_ttl));
+                let mut kademlia = Kademlia::with_config(
+                    peer_id,
+                    memory_store,
+                    kademlia_config,
+                );
 
-                // we are the provider of our own nick
+                // we are the provider of our own profile
 
-                let nick_key =
-                    Key::new(&MemoryKey::Nickname(peer_id).encode()?);
-                let nick_value = MemoryValue::Nickname {
-                    user: peer_id,
-                    nickname: nick.to_owned(),
-                }
-                .encode_signed(&id_keys)?;
+                let profile = Profile {
+                    nick: nick.to_owned(),
+                    ..Default::default()
+                };
+                let record = profile_record(peer_id, &profile, &id_keys)?;
+                let profile_key = record.key.clone();
 
-                kademlia.start_providing(nick_key.clone())?;
-                kademlia.put_record(
-                    Record::new(nick_key, nick_value),
-                    Quorum::One,
-                )?;
+                kademlia.start_providing(profile_key.clone())?;
+                kademlia.put_record(record, Quorum::One)?;
+                provided_keys.insert(profile_key);
 
                 kademlia
             };
 
-            let mdns = Mdns::new(mdns::MdnsConfig::default()).await?;
+            let mdns = if enable_mdns {
+                Some(Mdns::new(mdns_config).await?)
+            } else {
+                None
+            };
 
             let mut behaviour = ComposedBehaviour {
                 gossipsub,
                 kademlia,
-                mdns,
+                mdns: mdns.into(),
+                ping: ping::Behaviour::new(ping::Config::new()),
+                identify: Identify::new(
+                    IdentifyConfig::new(
+                        IDENTIFY_PROTOCOL_VERSION.to_owned(),
+                        id_keys.public(),
+                    )
+                    .with_agent_version(format!(
+                        "p2p-chat/{}",
+                        env!("CARGO_PKG_VERSION")
+                    )),
+                ),
+                direct: RequestResponse::new(
+                    DirectCodec,
+                    [(DirectProtocol, ProtocolSupport::Full)],
+                    RequestResponseConfig::default(),
+                ),
+                blob: RequestResponse::new(
+                    BlobCodec,
+                    [(BlobProtocol, ProtocolSupport::Full)],
+                    RequestResponseConfig::default(),
+                ),
+                history: RequestResponse::new(
+                    HistoryCodec,
+                    [(HistoryProtocol, ProtocolSupport::Full)],
+                    RequestResponseConfig::default(),
+                ),
+                relay: relay_client,
+                relay_server: relay_server
+                    .map(|config| Relay::new(peer_id, config))
+                    .into(),
+                rendezvous: rendezvous::client::Behaviour::new(id_keys.clone()),
             };
 
             // subscribe to the default topic for network updates
             let topic = gossipsub::IdentTopic::new(DEFAULT_GOSSIPSUB_TOPIC);
             behaviour.gossipsub.subscribe(&topic)?;
+            if let Some(params) = &topic_score_params {
+                behaviour
+                    .gossipsub
+                    .set_topic_params(topic, params.clone())
+                    .map_err(crate::Error::ConfigError)?;
+            }
 
             SwarmBuilder::new(transport, behaviour, peer_id)
                 .executor(Box::new(|fut| {
@@ -173,215 +1178,4245 @@ impl Client {
         let mut nick_cache = HashMap::new();
         nick_cache.insert(peer_id, Some(nick.to_owned()));
 
-        Ok(Client {
+        let mut profile_cache = HashMap::new();
+        profile_cache.insert(
+            peer_id,
+            Profile {
+                nick: nick.to_owned(),
+                ..Default::default()
+            },
+        );
+
+        let mut schedule_tick = time::interval(SCHEDULE_TICK_INTERVAL);
+        schedule_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut client = Client {
             nick: nick.to_owned(),
             nick_cache,
+            nick_cached_at: HashMap::new(),
+            nick_ttl,
+            nick_queries: HashMap::new(),
+            nick_waiters: HashMap::new(),
+            avatar_cache: HashMap::new(),
+            profile_cache,
+            blob_cache: HashMap::new(),
+            attachment_requests: HashMap::new(),
+            channels: HashMap::new(),
+            moderation: HashMap::new(),
+            nick_claims: HashMap::new(),
+            joined_channels: HashSet::new(),
+            channel_members: HashMap::new(),
+            channel_waiters: HashMap::new(),
+            awaiting_welcome: HashSet::new(),
+            last_seen: HashMap::new(),
+            connections: HashMap::new(),
+            identities: HashMap::new(),
+            hellos: HashMap::new(),
+            hello_requests: HashSet::new(),
+            latencies: HashMap::new(),
+            polls: HashMap::new(),
+            message_authors: HashMap::new(),
+            outbox: HashMap::new(),
+            reassembler: Reassembler::default(),
+            // numbering from the current time keeps sequence numbers
+            // increasing across restarts
+            sequence: timestamp_now(),
+            dm_sessions: HashMap::new(),
+            channel_keys: HashMap::new(),
+            invited: HashMap::new(),
+            advertised_channels: HashSet::new(),
+            discovered_channels: HashSet::new(),
+            history: History::default(),
+            history_syncs: HashMap::new(),
+            history_requests: HashMap::new(),
+            clocks: HashMap::new(),
+            replay_windows: HashMap::new(),
+            provided_keys,
+            block_list: BlockList::default(),
+            disconnect_cooldown,
+            max_clock_skew,
+            sign_messages,
+            cooldowns: HashMap::new(),
+            message_lifetimes: HashMap::new(),
+            expirations: HashMap::new(),
+            last_posted: HashMap::new(),
+            metrics: Metrics::default(),
+            bandwidth,
             id_keys,
             swarm,
-        })
+            scheduler: Scheduler::default(),
+            address_book: AddressBook::default(),
+            session: SessionState::default(),
+            trust_store: TrustStore::default(),
+            #[cfg(feature = "history-store")]
+            history_store: None,
+            rate_limiter: RateLimiter::new(rate_limit.0, rate_limit.1),
+            spam_filter: SpamFilter::new(spam_config),
+            schedule_tick,
+            nick_announced_at: None,
+            nick_announce_due: false,
+            bootstrapped_at: None,
+            record_ttl,
+            republished_at: Instant::now(),
+            put_retries: HashMap::new(),
+            topic_score_params,
+            rendezvous_points: HashMap::new(),
+            rendezvous_refreshed_at: Instant::now(),
+            pending_events: VecDeque::new(),
+        };
+
+        for ident in channels {
+            client.subscribe_channel(ident)?;
+        }
+        for relay in relays {
+            client.listen_via_relay(relay)?;
+        }
+
+        Ok(client)
     }
 
     /// Join a channel by subscribing to it.
+    ///
+    /// The channel's record is also fetched from the DHT, causing a
+    /// [`ClientEvent::ChannelUpdated`] to be emitted if one is found, and a
+    /// [`ClientEvent::ChannelWelcome`] if the channel has a welcome message.
+    /// The owner of the channel, if it has one, is asked to add this client to
+    /// its peers.
     pub fn subscribe_channel(
         &mut self,
         ident: ChannelIdentifier,
     ) -> crate::Result<()> {
-        self.swarm
-            .behaviour_mut()
-            .gossipsub
-            .subscribe(&topic_from_channel(&ident))?;
+        self.subscribe_channel_with_invite(ident, None)
+    }
 
-        Ok(())
+    /// Join an invite-only channel with an invitation token from its owner,
+    /// returning the identifier of the channel.
+    ///
+    /// The channel is joined as with [`Client::subscribe_channel`], with the
+    /// invitation passed along to the owner and members of the channel.
+    pub fn join_channel_with_invite(
+        &mut self,
+        token: Vec<u8>,
+    ) -> crate::Result<ChannelIdentifier> {
+        let (invite, _) = Invite::verify(&token)?;
+        if invite.invitee != self.peer_id() {
+            return Err(crate::Error::InvalidData(String::from(
+                "invitation is for another peer",
+            )));
+        }
+        if invite.expires_at < timestamp_now() {
+            return Err(crate::Error::InvalidData(String::from(
+                "invitation has expired",
+            )));
+        }
+
+        self.subscribe_channel_with_invite(
+            invite.channel.clone(),
+            Some(token),
+        )?;
+        Ok(invite.channel)
     }
 
-    /// Leave a channel by unsubscribing from it.
-    pub fn unsubscribe_channel(
+    /// Join a passphrase-protected channel, deriving its key from the
+    /// passphrase.
+    ///
+    /// Messages sent to the channel are encrypted with the key from then on,
+    /// and those which cannot be decrypted with it are reported with a
+    /// [`ClientEvent::UndecryptableMessage`].
+    pub fn join_channel_with_password(
         &mut self,
         ident: ChannelIdentifier,
+        passphrase: &str,
     ) -> crate::Result<()> {
-        self.swarm
-            .behaviour_mut()
-            .gossipsub
-            .unsubscribe(&topic_from_channel(&ident))?;
-
-        Ok(())
+        let key = ChannelKey::derive(ident.clone(), passphrase)?;
+        let mut keyring = Keyring::default();
+        keyring.insert(0, key);
+        self.channel_keys.insert(ident.clone(), keyring);
+        self.subscribe_channel(ident)
     }
 
-    /// Post a message to a given channel.
-    pub fn send_message(
+    fn subscribe_channel_with_invite(
         &mut self,
-        message: &str,
-        message_type: MessageType,
-        channel: ChannelIdentifier,
+        ident: ChannelIdentifier,
+        invite: Option<Vec<u8>>,
     ) -> crate::Result<()> {
-        // TODO validate locally
+        let key = Key::new(&MemoryKey::Channel(ident.clone()).encode()?);
 
-        // https://stackoverflow.com/questions/26593387
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_millis()
-            .try_into()
-            .expect("time overflowed u64");
+        let behaviour = self.swarm.behaviour_mut();
+        let topic = topic_from_channel(&ident);
+        behaviour.gossipsub.subscribe(&topic)?;
+        if let Some(params) = &self.topic_score_params {
+            behaviour
+                .gossipsub
+                .set_topic_params(topic, params.clone())
+                .map_err(crate::Error::ConfigError)?;
+        }
+        behaviour.kademlia.get_record(key, Quorum::One);
+        let key = Key::new(&MemoryKey::Moderation(ident.clone()).encode()?);
+        behaviour.kademlia.get_record(key, Quorum::One);
+        self.joined_channels.insert(ident.clone());
+        // the passphrases of protected channels are not kept, so they cannot
+        // be resumed
+        if !self.channel_keys.contains_key(&ident) {
+            self.open_session_entry(SessionEntry::Channel(ident.clone()));
+        }
+        self.announce(&Command::ChannelRequestJoin {
+            channel: ident.clone(),
+            invite,
+        })?;
 
-        let topic = topic_from_channel(&channel);
-        let command = Command::MessageSend {
-            contents: message.to_owned(),
-            channel,
-            timestamp,
-            message_type,
-        };
+        // let the channel's peers know who we are
+        self.nick_announce_due = true;
 
-        self.swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(topic, command.encode()?)?;
+        // catch up on what was said before we joined
+        self.history_syncs.insert(ident.clone(), HashSet::new());
+        let members = self
+            .channel_members
+            .get(&ident)
+            .map(|members| members.iter().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for peer in members {
+            self.sync_history(&ident, &peer);
+        }
+
+        match self.channels.get(&ident).and_then(Channel::welcome) {
+            Some(welcome) => {
+                self.pending_events.push_back(ClientEvent::ChannelWelcome {
+                    channel: ident,
+                    message: welcome.to_owned(),
+                });
+            }
+            None => {
+                self.awaiting_welcome.insert(ident);
+            }
+        }
 
         Ok(())
     }
 
-    /// Dial another client.
+    /// Join a channel, returning a future which resolves once there are peers
+    /// in the channel to publish messages to.
     ///
-    /// This is a non-blocking operation which may cause the following events to be emitted:
-    /// - [`ClientEvent::Dialing`]
-    /// - [`ClientEvent::PeerConnected`]
-    /// - [`ClientEvent::OutgoingConnectionError`]
-    pub fn dial(&mut self, addr: Multiaddr) -> crate::Result<()> {
-        info!("Dialing {}", addr);
-        self.swarm.dial(addr)?;
-        Ok(())
+    /// The future resolves to `false` if no peers are found within a timeout.
+    /// The client must continue to be polled for the future to resolve.
+    pub fn join_channel_ready(
+        &mut self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<impl Future<Output = bool>> {
+        self.subscribe_channel(ident.clone())?;
+
+        let (tx, rx) = oneshot::channel();
+        if self.is_channel_ready(&ident) {
+            let _ = tx.send(true);
+        } else {
+            self.channel_waiters
+                .entry(ident)
+                .or_default()
+                .push((tx, Instant::now() + CHANNEL_READY_TIMEOUT));
+        }
+
+        Ok(async move { rx.await.unwrap_or(false) })
     }
 
-    /// Listen on a given address.
-    pub fn listen_on(&mut self, addr: Multiaddr) -> crate::Result<()> {
-        self.swarm.listen_on(addr)?;
+    /// Get whether or not a message published to a channel would reach any
+    /// peers.
+    ///
+    /// This is the case if a peer has been grafted to the channel's mesh, or
+    /// if any peer is known to be subscribed to it, which flood publishing and
+    /// explicit peers from mDNS will reach.
+    fn is_channel_ready(&self, ident: &ChannelIdentifier) -> bool {
+        let topic = topic_from_channel(ident).hash();
+        self.swarm
+            .behaviour()
+            .gossipsub
+            .mesh_peers(&topic)
+            .next()
+            .is_some()
+            || self.channel_members.contains_key(ident)
+    }
+
+    /// Notify those waiting on channels which have become ready, or which they
+    /// have given up on.
+    fn notify_channel_waiters(&mut self) {
+        let now = Instant::now();
+        let channels = self.channel_waiters.keys().cloned().collect::<Vec<_>>();
+
+        for channel in channels {
+            let ready = self.is_channel_ready(&channel);
+            let waiters = self.channel_waiters.remove(&channel).unwrap();
+
+            let (done, waiting): (Vec<_>, Vec<_>) = waiters
+                .into_iter()
+                .partition(|(_, gives_up_at)| ready || *gives_up_at <= now);
+            for (waiter, _) in done {
+                let _ = waiter.send(ready);
+            }
+
+            if !waiting.is_empty() {
+                self.channel_waiters.insert(channel, waiting);
+            }
+        }
+    }
+
+    /// Leave a channel, saying goodbye with an optional reason.
+    ///
+    /// The channel is then unsubscribed from as with
+    /// [`Client::unsubscribe_channel`].
+    pub fn leave_channel(
+        &mut self,
+        ident: ChannelIdentifier,
+        reason: Option<String>,
+    ) -> crate::Result<()> {
+        self.say_goodbye(&ident, reason)?;
+
+        self.close_session_entry(&SessionEntry::Channel(ident.clone()));
+        self.part_channel(ident)
+    }
+
+    /// Leave a channel by unsubscribing from it.
+    ///
+    /// A [`Command::Goodbye`] without a reason is announced first.
+    /// The owner of the channel, if it has one, is asked to remove this client
+    /// from its peers.
+    pub fn unsubscribe_channel(
+        &mut self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.leave_channel(ident, None)
+    }
+
+    fn part_channel(&mut self, ident: ChannelIdentifier) -> crate::Result<()> {
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .unsubscribe(&topic_from_channel(&ident))?;
+        self.joined_channels.remove(&ident);
+        self.awaiting_welcome.remove(&ident);
+        self.channel_keys.remove(&ident);
+        self.history_syncs.remove(&ident);
+        self.history.forget(&ident);
+        self.announce(&Command::ChannelRequestLeave {
+            channel: ident.clone(),
+        })?;
+
         Ok(())
     }
 
-    /// Get whether or not this client is connected to a given peer.
-    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
-        self.swarm.is_connected(peer_id)
+    /// Post a message to a given channel, returning its identifier.
+    ///
+    /// If there are no peers to publish the message to yet, it is retried
+    /// until there are or a timeout passes. Either a
+    /// [`ClientEvent::MessagePublished`] or [`ClientEvent::MessageRejected`]
+    /// is emitted for the message once its fate is known.
+    pub fn send_message(
+        &mut self,
+        message: &str,
+        message_type: MessageType,
+        channel: ChannelIdentifier,
+    ) -> crate::Result<MessageId> {
+        self.send_reply(message, message_type, channel, None)
     }
 
-    /// Get this client's peer ID.
-    pub fn peer_id(&self) -> PeerId {
-        PeerId::from(self.id_keys.public())
+    /// Post a message to a given channel in reply to another message,
+    /// returning its identifier.
+    ///
+    /// Replies are published in the same way as [`Client::send_message`].
+    pub fn send_reply(
+        &mut self,
+        message: &str,
+        message_type: MessageType,
+        channel: ChannelIdentifier,
+        in_reply_to: Option<MessageId>,
+    ) -> crate::Result<MessageId> {
+        // TODO validate locally
+
+        let command = Command::MessageSend {
+            contents: message.to_owned(),
+            channel: channel.clone(),
+            timestamp: timestamp_now(),
+            message_type,
+            in_reply_to,
+            mentions: self.resolve_mentions(message),
+            attachment: None,
+            sequence: self.next_sequence(),
+            clock: self.tick_clock(&channel),
+            expires_after: self.message_lifetimes.get(&channel).copied(),
+        };
+        self.publish_message(channel, command)
     }
 
-    /// Get this client's nickname.
-    pub fn nick(&self) -> &String {
-        &self.nick
+    /// Post a file to a given channel, returning the identifier of its
+    /// message.
+    ///
+    /// The file is kept in memory to be sent to the peers which request it,
+    /// and must be no larger than [`MAX_ATTACHMENT_SIZE`].
+    pub fn send_attachment(
+        &mut self,
+        channel: ChannelIdentifier,
+        name: &str,
+        data: Vec<u8>,
+    ) -> crate::Result<MessageId> {
+        if data.is_empty() || data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(crate::Error::InvalidData(String::from(
+                "attachment is empty or too large",
+            )));
+        }
+
+        let attachment = Attachment {
+            name: name.to_owned(),
+            hash: blob_hash(&data),
+            size: data.len(),
+        };
+        self.blob_cache.insert(attachment.hash.clone(), data);
+
+        let command = Command::MessageSend {
+            contents: name.to_owned(),
+            channel: channel.clone(),
+            timestamp: timestamp_now(),
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: Some(attachment),
+            sequence: self.next_sequence(),
+            clock: self.tick_clock(&channel),
+            expires_after: self.message_lifetimes.get(&channel).copied(),
+        };
+        self.publish_message(channel, command)
     }
 
-    pub fn fetch_nickname(
+    /// Get the contents of an attachment which was sent or received.
+    pub fn attachment(&self, hash: &BlobHash) -> Option<&Vec<u8>> {
+        self.blob_cache.get(hash)
+    }
+
+    /// Make the messages later sent to a channel disappear some time after
+    /// they are sent, or stop doing so.
+    ///
+    /// Once a disappearing message expires, peers no longer share it in the
+    /// history of the channel, and a [`ClientEvent::MessageExpired`] is
+    /// emitted for it.
+    pub fn set_message_lifetime(
+        &mut self,
+        channel: ChannelIdentifier,
+        lifetime: Option<Duration>,
+    ) -> crate::Result<()> {
+        let seconds = match lifetime {
+            Some(lifetime) => lifetime.as_secs(),
+            None => {
+                self.message_lifetimes.remove(&channel);
+                return Ok(());
+            }
+        };
+        match u32::try_from(seconds) {
+            Ok(seconds) if seconds > 0 && seconds <= MAX_MESSAGE_LIFETIME => {
+                self.message_lifetimes.insert(channel, seconds);
+                Ok(())
+            }
+            _ => Err(crate::Error::InvalidData(String::from(
+                "message lifetime is out of range",
+            ))),
+        }
+    }
+
+    /// Get how long the messages sent to a channel last before disappearing,
+    /// if they do.
+    pub fn message_lifetime(
+        &self,
+        channel: &ChannelIdentifier,
+    ) -> Option<Duration> {
+        self.message_lifetimes
+            .get(channel)
+            .map(|seconds| Duration::from_secs((*seconds).into()))
+    }
+
+    /// Number a message sent by this client.
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    /// Advance the Lamport clock of a channel for a message sent by this
+    /// client.
+    fn tick_clock(&mut self, channel: &ChannelIdentifier) -> u64 {
+        let clock = self.clocks.entry(channel.clone()).or_default();
+        *clock = clock.saturating_add(1);
+        *clock
+    }
+
+    /// Get the Lamport clock of a channel, which is that of the latest message
+    /// sent or received in it.
+    pub fn channel_clock(&self, channel: &ChannelIdentifier) -> u64 {
+        self.clocks.get(channel).copied().unwrap_or_default()
+    }
+
+    /// Publish a message, or a command about the messages of a channel such
+    /// as an edit or a vote, to the channel, retrying later if there are no
+    /// peers to publish it to yet.
+    ///
+    /// Commands to encrypted channels are encrypted with the key of the
+    /// channel, as messages are.
+    fn publish_message(
+        &mut self,
+        channel: ChannelIdentifier,
+        command: Command,
+    ) -> crate::Result<MessageId> {
+        if self.is_channel_archived(&channel) {
+            return Err(crate::Error::ChannelArchived(channel));
+        }
+        let peer_id = self.peer_id();
+        // a message may be fixed or retracted regardless of slow mode
+        if !matches!(
+            command,
+            Command::MessageEdit { .. } | Command::MessageDelete { .. }
+        ) {
+            self.check_policy(&command, &channel, &peer_id, Duration::ZERO)?;
+        }
+        let expires_at = command.expires_at();
+        let stored = match &command {
+            Command::MessageSend {
+                contents,
+                timestamp,
+                message_type,
+                ..
+            } => Some((contents.clone(), *timestamp, *message_type)),
+            _ => None,
+        };
+
+        // only messages themselves are signed, kept and numbered
+        let is_message = stored.is_some();
+        let command = if self.sign_messages && is_message {
+            command.sign(&self.id_keys)?
+        } else {
+            command
+        };
+
+        let key = self.channel_keys.get(&channel).and_then(Keyring::current);
+        // only messages sent in the clear are kept in the channel's history
+        let plain = (key.is_none() && is_message).then(|| command.clone());
+        let command = match key {
+            Some((epoch, key)) => {
+                let (nonce, ciphertext) = key.encrypt(&command.encode()?)?;
+                Command::EncryptedMessage {
+                    channel: channel.clone(),
+                    nonce,
+                    ciphertext,
+                    epoch,
+                }
+            }
+            None if self.expects_encryption(&channel) => {
+                return Err(crate::Error::NoChannelKey(channel));
+            }
+            None => command,
+        };
+
+        let topic = topic_from_channel(&channel);
+        let data = command.encode()?;
+        let id = message_id(Some(self.peer_id()), &data);
+        let mut payloads = VecDeque::from(fragment(data)?);
+
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        match publish_payloads(gossipsub, &topic, &mut payloads) {
+            Ok(_) => {
+                self.pending_events
+                    .push_back(ClientEvent::MessagePublished(id.clone()));
+            }
+            Err(PublishError::InsufficientPeers) => {
+                info!("No peers to publish message {id} to yet");
+                self.outbox.insert(
+                    id.clone(),
+                    OutgoingMessage {
+                        topic,
+                        payloads,
+                        expires_at: Instant::now() + PUBLISH_TIMEOUT,
+                    },
+                );
+            }
+            Err(err) => return Err(err.into()),
+        }
+        if !is_message {
+            return Ok(id);
+        }
+
+        self.metrics.record_channel_message(&channel);
+        self.message_authors
+            .insert(id.clone(), (channel.clone(), self.peer_id()));
+        self.last_posted
+            .insert((self.peer_id(), channel.clone()), Instant::now());
+        if let Some(expires_at) = expires_at {
+            self.expirations
+                .insert(id.clone(), (channel.clone(), expires_at));
+        }
+        if let Some(command) = plain {
+            let entry = HistoryEntry {
+                id: id.clone(),
+                sender: self.peer_id(),
+                command,
+            };
+            self.history.record(channel.clone(), entry);
+        }
+        if let Some((contents, timestamp, message_type)) = stored {
+            self.store_message(
+                &id,
+                &channel,
+                peer_id,
+                timestamp,
+                &contents,
+                message_type,
+            );
+        }
+
+        Ok(id)
+    }
+
+    /// Replace the contents of a message previously sent by this client,
+    /// returning the identifier of the edit.
+    pub fn edit_message(
+        &mut self,
+        target: &MessageId,
+        new_contents: &str,
+    ) -> crate::Result<MessageId> {
+        let channel = self.own_message_channel(target)?;
+
+        let command = Command::MessageEdit {
+            target: target.clone(),
+            new_contents: new_contents.to_owned(),
+            channel: channel.clone(),
+        };
+        let id = self.publish_message(channel.clone(), command)?;
+        self.history.edit(&channel, target, new_contents);
+        self.store_edit(target, new_contents);
+
+        Ok(id)
+    }
+
+    /// Retract a message previously sent by this client, returning the
+    /// identifier of the deletion.
+    pub fn delete_message(
+        &mut self,
+        target: &MessageId,
+    ) -> crate::Result<MessageId> {
+        let channel = self.own_message_channel(target)?;
+
+        let command = Command::MessageDelete {
+            target: target.clone(),
+            channel: channel.clone(),
+        };
+        let id = self.publish_message(channel.clone(), command)?;
+        self.forget_message(&channel, target);
+
+        Ok(id)
+    }
+
+    /// Get the channel of a message sent by this client, if it may still be
+    /// changed.
+    fn own_message_channel(
+        &self,
+        target: &MessageId,
+    ) -> crate::Result<ChannelIdentifier> {
+        let channel = match self.message_authors.get(target) {
+            Some((channel, author)) if *author == self.peer_id() => {
+                channel.clone()
+            }
+            _ => return Err(crate::Error::NotMessageAuthor(target.clone())),
+        };
+
+        if self.is_channel_archived(&channel) {
+            return Err(crate::Error::ChannelArchived(channel));
+        }
+
+        Ok(channel)
+    }
+
+    /// Try again to publish the messages which had no peers to be published
+    /// to.
+    fn retry_outgoing_messages(&mut self) {
+        let now = Instant::now();
+        let ids = self.outbox.keys().cloned().collect::<Vec<_>>();
+
+        for id in ids {
+            let message = self.outbox.get_mut(&id).unwrap();
+            let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+            let event = match publish_payloads(
+                gossipsub,
+                &message.topic,
+                &mut message.payloads,
+            ) {
+                Ok(_) => ClientEvent::MessagePublished(id.clone()),
+                Err(PublishError::InsufficientPeers)
+                    if message.expires_at > now =>
+                {
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Could not publish message {id}: {err:?}");
+                    ClientEvent::MessageRejected(id.clone(), err.into())
+                }
+            };
+
+            self.outbox.remove(&id);
+            self.pending_events.push_back(event);
+        }
+    }
+
+    /// Greet a newly connected peer with our protocol version and
+    /// capabilities.
+    fn send_hello(&mut self, peer: &PeerId) {
+        let command = Command::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        let request = self
+            .swarm
+            .behaviour_mut()
+            .direct
+            .send_request(peer, command);
+        self.hello_requests.insert(request);
+    }
+
+    /// Get the protocol version a connected peer greeted us with, if it has.
+    pub fn protocol_version(&self, peer: &PeerId) -> Option<u32> {
+        self.hellos.get(peer).map(|(version, _)| *version)
+    }
+
+    /// Get the capabilities a connected peer greeted us with, if it has.
+    pub fn capabilities(&self, peer: &PeerId) -> Option<&[String]> {
+        self.hellos
+            .get(peer)
+            .map(|(_, capabilities)| capabilities.as_slice())
+    }
+
+    /// Get whether or not a connected peer advertised a capability.
+    ///
+    /// Peers which have not greeted us are assumed to support nothing.
+    pub fn supports(&self, peer: &PeerId, capability: &str) -> bool {
+        self.capabilities(peer).is_some_and(|capabilities| {
+            capabilities.iter().any(|c| c == capability)
+        })
+    }
+
+    /// Send a message directly to a connected peer, outside of any channel.
+    ///
+    /// The returned ID is reported in a [`ClientEvent::DirectMessageFailed`]
+    /// if the message could not be delivered.
+    pub fn send_direct(
         &mut self,
         peer: &PeerId,
-    ) -> crate::Result<&Option<String>> {
-        // this seems to be the only way to satisfy the borrow checker ._.
-        if self.nick_cache.contains_key(peer) {
-            return Ok(self.nick_cache.get(peer).unwrap());
+        message: &str,
+        message_type: MessageType,
+    ) -> crate::Result<RequestId> {
+        let command = Command::DirectMessage {
+            contents: message.to_owned(),
+            timestamp: timestamp_now(),
+            message_type,
+        };
+        command.check_valid()?;
+
+        // peers which have not greeted us may not support encryption
+        let command = if self.supports(peer, "encrypted-dm") {
+            let session = match self.dm_sessions.entry(*peer) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Session::initiate(&self.id_keys, peer)?)
+                }
+            };
+            let (header, nonce, ciphertext) =
+                session.encrypt(&command.encode()?)?;
+            Command::EncryptedDirectMessage {
+                header,
+                nonce,
+                ciphertext,
+            }
+        } else {
+            command
+        };
+
+        self.open_session_entry(SessionEntry::Direct(*peer));
+        Ok(self
+            .swarm
+            .behaviour_mut()
+            .direct
+            .send_request(peer, command))
+    }
+
+    /// Close the direct conversation with a peer, so that it is not resumed
+    /// in later sessions until another message is sent or received.
+    pub fn close_direct(&mut self, peer: &PeerId) {
+        self.close_session_entry(&SessionEntry::Direct(*peer));
+    }
+
+    /// Get the state of the encrypted direct message session with a peer, if
+    /// one has been begun.
+    pub fn dm_session(&self, peer: &PeerId) -> Option<DmSession> {
+        self.dm_sessions
+            .get(peer)
+            .map(|session| session.state().clone())
+    }
+
+    /// Decrypt a direct message from a peer, beginning a new session with them
+    /// if the message asks to.
+    ///
+    /// Should both peers begin a session at once, the one begun by the peer
+    /// with the lesser ID is kept.
+    fn decrypt_direct(
+        &mut self,
+        peer: &PeerId,
+        header: &RatchetHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> crate::Result<Command> {
+        let existing = self.dm_sessions.get(peer);
+        let mut session = match (&header.ephemeral, existing) {
+            (Some(ephemeral), Some(session))
+                if session.was_begun_with(ephemeral) =>
+            {
+                session.clone()
+            }
+            (Some(_), Some(session))
+                if session.state().initiator
+                    && !session.state().confirmed
+                    && peer > &self.peer_id() =>
+            {
+                return Err(crate::Error::InvalidData(String::from(
+                    "peer began a session while ours was pending",
+                )));
+            }
+            (Some(ephemeral), _) => {
+                Session::respond(&self.id_keys, peer, ephemeral)?
+            }
+            (None, Some(session)) => session.clone(),
+            (None, None) => {
+                return Err(crate::Error::InvalidData(String::from(
+                    "no session with peer",
+                )))
+            }
+        };
+
+        let data = session.decrypt(header, nonce, ciphertext)?;
+        self.dm_sessions.insert(*peer, session);
+
+        let command = Command::decode(&data)?;
+        command.check_valid()?;
+        Ok(command)
+    }
+
+    /// Post a poll to a given channel, returning its identifier.
+    ///
+    /// Votes may be cast until the poll closes, and are reported with
+    /// [`ClientEvent::PollUpdated`], and the final results with
+    /// [`ClientEvent::PollClosed`] once it does.
+    pub fn create_poll(
+        &mut self,
+        channel: ChannelIdentifier,
+        question: &str,
+        options: Vec<String>,
+        closes_at: SystemTime,
+    ) -> crate::Result<MessageId> {
+        let closes_at = to_millis(closes_at);
+        let command = Command::Poll {
+            channel: channel.clone(),
+            question: question.to_owned(),
+            options: options.clone(),
+            closes_at,
+        };
+        let id = self.publish_message(channel.clone(), command)?;
+
+        let poll = Poll::new(
+            channel,
+            self.peer_id(),
+            question.to_owned(),
+            options,
+            closes_at,
+        );
+        self.polls.insert(id.clone(), poll);
+
+        Ok(id)
+    }
+
+    /// Vote for an option in a poll.
+    pub fn vote(
+        &mut self,
+        poll_id: &MessageId,
+        option: usize,
+    ) -> crate::Result<()> {
+        let poll = self.polls.get(poll_id).ok_or_else(|| {
+            crate::Error::InvalidData(String::from("no such poll"))
+        })?;
+
+        if option >= poll.options.len() {
+            return Err(crate::Error::InvalidData(String::from(
+                "no such poll option",
+            )));
+        }
+        if poll.has_voted(&self.peer_id()) {
+            return Err(crate::Error::InvalidData(String::from(
+                "already voted in poll",
+            )));
+        }
+
+        let channel = poll.channel.clone();
+        let command = Command::Vote {
+            channel: channel.clone(),
+            poll: poll_id.clone(),
+            option,
+        };
+        self.publish_message(channel, command)?;
+
+        let peer_id = self.peer_id();
+        if let Some(poll) = self.polls.get_mut(poll_id) {
+            poll.add_vote(peer_id, option, timestamp_now());
+        }
+
+        Ok(())
+    }
+
+    /// Get a poll which has been posted to a subscribed channel, and has not
+    /// closed yet.
+    pub fn poll(&self, poll_id: &MessageId) -> Option<&Poll> {
+        self.polls.get(poll_id)
+    }
+
+    /// Count the votes for each option of a poll.
+    pub fn poll_tally(&self, poll_id: &MessageId) -> Option<Vec<usize>> {
+        self.poll(poll_id).map(Poll::tally)
+    }
+
+    fn publish_command(
+        &mut self,
+        topic: gossipsub::IdentTopic,
+        command: &Command,
+    ) -> crate::Result<MessageId> {
+        Ok(self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic, command.encode()?)?)
+    }
+
+    /// Publish a command to the default topic, for the whole network to see.
+    ///
+    /// Having no peers to publish to is not considered an error, since there is
+    /// no one to announce anything to.
+    fn announce(&mut self, command: &Command) -> crate::Result<()> {
+        let topic = gossipsub::IdentTopic::new(DEFAULT_GOSSIPSUB_TOPIC);
+        match self.publish_command(topic, command) {
+            Err(crate::Error::PublishError(
+                PublishError::InsufficientPeers,
+            )) => {
+                warn!("No peers to announce to");
+                Ok(())
+            }
+            res => res.map(|_| ()),
+        }
+    }
+
+    /// Get the latest known record of a channel, if any.
+    pub fn channel(&self, ident: &ChannelIdentifier) -> Option<&Channel> {
+        self.channels.get(ident)
+    }
+
+    /// Advertise a channel in the public directory, so that other peers may
+    /// find it with [`Client::list_public_channels`].
+    ///
+    /// Only channels whose record is known, and which are not invite-only,
+    /// may be advertised.
+    pub fn advertise_channel(
+        &mut self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        let channel = self
+            .channel(&ident)
+            .ok_or_else(|| crate::Error::UnknownChannel(ident.clone()))?;
+        if channel.is_invite_only() {
+            return Err(crate::Error::InvalidData(String::from(
+                "invite-only channels cannot be advertised",
+            )));
+        }
+        if !self.advertised_channels.contains(&ident)
+            && self.advertised_channels.len() >= MAX_ADVERTISED_CHANNELS
+        {
+            return Err(crate::Error::InvalidData(String::from(
+                "too many channels advertised",
+            )));
+        }
+
+        self.advertised_channels.insert(ident.clone());
+        self.publish_listing()?;
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        for key in [MemoryKey::Directory, MemoryKey::Channel(ident)] {
+            let key = Key::new(&key.encode()?);
+            kademlia.start_providing(key.clone())?;
+            self.provided_keys.insert(key);
+        }
+
+        Ok(())
+    }
+
+    /// List the public channels found in the directory so far.
+    ///
+    /// The directory is also searched again, and a
+    /// [`ClientEvent::ChannelDiscovered`] is emitted for each channel found.
+    pub fn list_public_channels(&mut self) -> crate::Result<Vec<&Channel>> {
+        let key = Key::new(&MemoryKey::Directory.encode()?);
+        self.swarm.behaviour_mut().kademlia.get_providers(key);
+
+        let mut channels = self
+            .discovered_channels
+            .iter()
+            .filter_map(|ident| self.channels.get(ident))
+            .filter(|channel| !channel.is_invite_only())
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|channel| channel.identifier());
+        Ok(channels)
+    }
+
+    /// Get the identifiers of the public channels found in the directory so
+    /// far, without searching it again.
+    pub fn discovered_channels(
+        &self,
+    ) -> impl Iterator<Item = &ChannelIdentifier> {
+        self.discovered_channels.iter()
+    }
+
+    /// Store the list of channels this client advertises in the DHT.
+    fn publish_listing(&mut self) -> crate::Result<()> {
+        let peer_id = self.peer_id();
+        let key = Key::new(&MemoryKey::Listing(peer_id).encode()?);
+        let mut channels =
+            self.advertised_channels.iter().cloned().collect::<Vec<_>>();
+        channels.sort();
+        let value = MemoryValue::Listing {
+            user: peer_id,
+            channels,
+        }
+        .encode_signed(&self.id_keys)?;
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        kademlia.start_providing(key.clone())?;
+        kademlia.put_record(Record::new(key.clone(), value), Quorum::One)?;
+        self.provided_keys.insert(key);
+
+        Ok(())
+    }
+
+    /// Get whether or not a channel has been archived by its owner.
+    pub fn is_channel_archived(&self, ident: &ChannelIdentifier) -> bool {
+        self.channel(ident).is_some_and(Channel::is_archived)
+    }
+
+    /// Create a channel owned by this client, storing its record in the DHT
+    /// and announcing it to the network.
+    ///
+    /// Fails if a record of the channel is already known.
+    pub fn create_channel(
+        &mut self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        if self.channels.contains_key(&ident) {
+            return Err(crate::Error::ChannelExists(ident));
+        }
+
+        let channel = Channel::new(ident, self.peer_id());
+        self.publish_channel(channel)
+    }
+
+    /// Set whether or not peers need an invitation to join a channel.
+    ///
+    /// Only the owner of a channel may change this, once its record has been
+    /// created with [`Client::create_channel`] or found in the DHT.
+    pub fn set_channel_invite_only(
+        &mut self,
+        ident: ChannelIdentifier,
+        invite_only: bool,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?.with_invite_only(invite_only);
+        self.publish_channel(channel)
+    }
+
+    /// Invite a peer to join a channel owned by this client, returning the
+    /// invitation token to be passed to them.
+    ///
+    /// The token may be passed along by any means, such as with
+    /// [`Client::send_invite`], and is used with
+    /// [`Client::join_channel_with_invite`].
+    pub fn create_invite(
+        &self,
+        ident: ChannelIdentifier,
+        invitee: PeerId,
+        valid_for: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        let channel = self.owned_channel(ident)?;
+        Invite {
+            channel: channel.identifier().clone(),
+            invitee,
+            expires_at: to_millis(SystemTime::now() + valid_for),
+        }
+        .sign(&self.id_keys)
+    }
+
+    /// Invite a connected peer to join a channel owned by this client, sending
+    /// them the invitation directly.
+    ///
+    /// The invitee is notified with a [`ClientEvent::ChannelInvited`]. The
+    /// returned ID is reported in a [`ClientEvent::DirectMessageFailed`] if the
+    /// invitation could not be delivered.
+    pub fn send_invite(
+        &mut self,
+        ident: ChannelIdentifier,
+        invitee: PeerId,
+        valid_for: Duration,
+    ) -> crate::Result<RequestId> {
+        let token = self.create_invite(ident, invitee, valid_for)?;
+        let command = Command::ChannelInvite { token };
+        command.check_valid()?;
+
+        Ok(self
+            .swarm
+            .behaviour_mut()
+            .direct
+            .send_request(&invitee, command))
+    }
+
+    /// Kick a peer from a channel, removing them from its list of peers.
+    ///
+    /// Moderators may kick members, and the owner may kick anyone. Kicked peers
+    /// may rejoin the channel, unless it is invite-only.
+    pub fn kick_peer(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+    ) -> crate::Result<()> {
+        self.remove_peer(ident, peer, false)
+    }
+
+    /// Ban a peer from a channel, removing them from its list of peers and
+    /// keeping them from rejoining or posting to it.
+    ///
+    /// Moderators may ban members, and the owner may ban anyone.
+    pub fn ban_peer(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+    ) -> crate::Result<()> {
+        self.remove_peer(ident, peer, true)
+    }
+
+    fn remove_peer(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+        ban: bool,
+    ) -> crate::Result<()> {
+        let command = removal_command(ident.clone(), peer, ban);
+        let permitted = self.channels.get(&ident).is_some_and(|channel| {
+            command.is_permitted(&self.peer_id(), channel)
+        });
+        if !permitted {
+            return Err(crate::Error::NotPermitted(ident));
+        }
+
+        self.apply_removal(&ident, peer, ban)?;
+        self.announce(&command)
+    }
+
+    /// Publish a new version of a channel owned by this client, with a peer
+    /// kicked or banned from it.
+    fn apply_removal(
+        &mut self,
+        ident: &ChannelIdentifier,
+        peer: PeerId,
+        ban: bool,
+    ) -> crate::Result<()> {
+        if ban {
+            if let Some(invited) = self.invited.get_mut(ident) {
+                invited.remove(&peer);
+            }
+        }
+
+        let channel = match self.channels.get(ident) {
+            Some(channel) if channel.owner() == &self.peer_id() => channel,
+            _ => return Ok(()),
+        };
+        let channel = if ban {
+            channel.with_ban(peer)
+        } else {
+            channel.without_peer(&peer)
+        };
+        self.publish_channel(channel)?;
+
+        if ban {
+            let moderation = match self.moderation.get(ident) {
+                Some(moderation) if moderation.owner() == &self.peer_id() => {
+                    moderation.clone()
+                }
+                _ => Moderation::new(ident.clone(), self.peer_id()),
+            };
+            self.publish_moderation(moderation.with_ban(peer))?;
+        }
+        Ok(())
+    }
+
+    /// Store the moderation record of a channel we own locally and in the
+    /// DHT.
+    fn publish_moderation(
+        &mut self,
+        moderation: Moderation,
+    ) -> crate::Result<()> {
+        let key = Key::new(
+            &MemoryKey::Moderation(moderation.channel().clone()).encode()?,
+        );
+        let value = MemoryValue::Moderation(moderation.clone())
+            .encode_signed(&self.id_keys)?;
+        self.moderation
+            .insert(moderation.channel().clone(), moderation);
+
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(Record::new(key, value), Quorum::One)?;
+        Ok(())
+    }
+
+    /// Get whether a peer is banned from a channel, either by the channel
+    /// itself or by the moderation record of its owner.
+    fn is_banned(&self, channel: &Channel, peer: &PeerId) -> bool {
+        channel.is_banned(peer)
+            || self.moderation.get(channel.identifier()).is_some_and(
+                |moderation| {
+                    moderation.owner() == channel.owner()
+                        && moderation.is_banned(peer)
+                },
+            )
+    }
+
+    /// Get the peers banned from a channel, if it is known.
+    pub fn banned_peers(&self, ident: &ChannelIdentifier) -> Vec<PeerId> {
+        let channel = match self.channels.get(ident) {
+            Some(channel) => channel,
+            None => return Vec::new(),
+        };
+        let mut banned = channel.banned().to_vec();
+        let moderation = self
+            .moderation
+            .get(ident)
+            .filter(|moderation| moderation.owner() == channel.owner());
+        for peer in moderation.map_or(&[][..], Moderation::banned) {
+            if !banned.contains(peer) {
+                banned.push(*peer);
+            }
+        }
+        banned
+    }
+
+    /// Handle a peer being kicked or banned from a channel, leaving the channel
+    /// if the peer is ourselves.
+    ///
+    /// The owner of the channel publishes the change on behalf of moderators.
+    fn handle_removal(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+        ban: bool,
+        sender: PeerId,
+        acceptance: &mut gossipsub::MessageAcceptance,
+    ) -> Option<ClientEvent> {
+        let command = removal_command(ident.clone(), peer, ban);
+        match self.channels.get(&ident) {
+            Some(channel) if command.is_permitted(&sender, channel) => {}
+            Some(_) => {
+                warn!("Rejecting unpermitted removal from {ident} by {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Reject;
+                return None;
+            }
+            None => {
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                return None;
+            }
+        }
+
+        if let Err(err) = self.apply_removal(&ident, peer, ban) {
+            warn!("Failed to publish removal from {ident}: {err:?}");
+        }
+
+        if peer == self.peer_id() && self.joined_channels.remove(&ident) {
+            self.close_session_entry(&SessionEntry::Channel(ident.clone()));
+            self.awaiting_welcome.remove(&ident);
+            if let Err(err) = self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .unsubscribe(&topic_from_channel(&ident))
+            {
+                warn!("Failed to leave {ident}: {err:?}");
+            }
+        }
+
+        Some(if ban {
+            ClientEvent::PeerBanned {
+                peer,
+                channel: ident,
+            }
+        } else {
+            ClientEvent::PeerKicked {
+                peer,
+                channel: ident,
+            }
+        })
+    }
+
+    /// Give a peer a role within a channel owned by this client.
+    ///
+    /// Making a peer the owner transfers the channel to them, with this client
+    /// becoming a moderator.
+    pub fn grant_role(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+        role: Role,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?;
+        if peer == self.peer_id() {
+            return Err(crate::Error::InvalidData(String::from(
+                "the owner of a channel may only give up their role by \
+                 transferring it",
+            )));
+        }
+        self.publish_channel(channel.with_role(peer, role))
+    }
+
+    /// Get the roles of the peers within a channel, if known.
+    pub fn channel_roles(
+        &self,
+        ident: &ChannelIdentifier,
+    ) -> Vec<(PeerId, Role)> {
+        self.channels
+            .get(ident)
+            .map(|channel| {
+                channel
+                    .peers()
+                    .iter()
+                    .map(|peer| (*peer, channel.role(peer)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get whether or not a peer may post to a channel.
+    ///
+    /// Peers banned from a channel may not post to it, and only members of
+    /// invite-only channels, or those who have presented an invitation to
+    /// join, may post to them.
+    fn is_admitted(&self, ident: &ChannelIdentifier, peer: &PeerId) -> bool {
+        match self.channels.get(ident) {
+            Some(channel) if self.is_banned(channel, peer) => false,
+            Some(channel) if channel.is_invite_only() => {
+                channel.peers().contains(peer)
+                    || self
+                        .invited
+                        .get(ident)
+                        .is_some_and(|invited| invited.contains(peer))
+            }
+            _ => true,
+        }
+    }
+
+    /// Turn end-to-end encryption of a channel on or off.
+    ///
+    /// Only the owner of a channel may change this. While it is on, the owner
+    /// generates a new key for the channel whenever it changes, and sends it to
+    /// each of its peers, wrapped to their identity key.
+    pub fn set_channel_encrypted(
+        &mut self,
+        ident: ChannelIdentifier,
+        encrypted: bool,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?.with_encrypted(encrypted);
+        if !encrypted {
+            self.channel_keys.remove(channel.identifier());
+        }
+        self.publish_channel(channel)
+    }
+
+    /// Get whether or not messages to a channel must be encrypted.
+    fn expects_encryption(&self, ident: &ChannelIdentifier) -> bool {
+        self.channel_keys.contains_key(ident)
+            || self.channel(ident).is_some_and(Channel::is_encrypted)
+    }
+
+    /// Generate a new key for an end-to-end encrypted channel owned by this
+    /// client, and send it to each of its peers.
+    fn rotate_channel_key(&mut self, channel: &Channel) {
+        let key = ChannelKey::generate(channel.identifier().clone());
+        self.channel_keys
+            .entry(channel.identifier().clone())
+            .or_default()
+            .insert(channel.version(), key);
+
+        for peer in channel.peers() {
+            if peer != &self.peer_id() {
+                self.send_channel_key(channel.identifier(), peer);
+            }
+        }
+    }
+
+    /// Send the current keys of the end-to-end encrypted channels owned by
+    /// this client to a peer of theirs.
+    fn send_channel_keys(&mut self, peer: &PeerId) {
+        let channels = self
+            .channels
+            .values()
+            .filter(|channel| {
+                channel.is_encrypted()
+                    && channel.owner() == &self.peer_id()
+                    && channel.peers().contains(peer)
+            })
+            .map(|channel| channel.identifier().clone())
+            .collect::<Vec<_>>();
+
+        for channel in channels {
+            self.send_channel_key(&channel, peer);
+        }
+    }
+
+    fn send_channel_key(&mut self, ident: &ChannelIdentifier, peer: &PeerId) {
+        let current = self.channel_keys.get(ident).and_then(Keyring::current);
+        let command =
+            match current.map(|(epoch, key)| (epoch, key.wrap(peer, epoch))) {
+                Some((epoch, Ok(key))) => Command::ChannelKey {
+                    channel: ident.clone(),
+                    epoch,
+                    key,
+                },
+                Some((_, Err(err))) => {
+                    warn!("Could not wrap key of {ident} to {peer}: {err:?}");
+                    return;
+                }
+                None => return,
+            };
+
+        self.swarm
+            .behaviour_mut()
+            .direct
+            .send_request(peer, command);
+    }
+
+    /// Archive a channel, freezing it so that no new messages may be posted.
+    ///
+    /// Only the owner of a channel may archive it, once its record has been
+    /// created with [`Client::create_channel`] or found in the DHT.
+    pub fn archive_channel(
+        &mut self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?.archive();
+        self.publish_channel(channel)
+    }
+
+    /// Set or clear the welcome message shown to peers joining a channel.
+    ///
+    /// Only the owner of a channel may set its welcome message, once its
+    /// record has been created with [`Client::create_channel`] or found in the
+    /// DHT.
+    pub fn set_channel_welcome(
+        &mut self,
+        ident: ChannelIdentifier,
+        welcome: Option<String>,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?.with_welcome(welcome);
+        if !channel.is_valid() {
+            return Err(crate::Error::InvalidData(String::from(
+                "welcome message is not valid",
+            )));
+        }
+
+        self.publish_channel(channel)
+    }
+
+    /// Set the limits on messages sent to a channel.
+    ///
+    /// Only the owner of a channel may change this, once its record has been
+    /// created with [`Client::create_channel`] or found in the DHT.
+    pub fn set_channel_policy(
+        &mut self,
+        ident: ChannelIdentifier,
+        policy: ChannelPolicy,
+    ) -> crate::Result<()> {
+        let channel = self.owned_channel(ident)?.with_policy(policy);
+        if !channel.is_valid() {
+            return Err(crate::Error::InvalidData(String::from(
+                "channel policy is not valid",
+            )));
+        }
+
+        self.publish_channel(channel)
+    }
+
+    /// Check a message against the policy of its channel, including whether
+    /// the sender is posting faster than slow mode allows.
+    fn check_policy(
+        &self,
+        command: &Command,
+        ident: &ChannelIdentifier,
+        sender: &PeerId,
+        tolerance: Duration,
+    ) -> crate::Result<()> {
+        let channel = match self.channels.get(ident) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        if !command.is_permitted(sender, channel) {
+            return Err(crate::Error::PolicyViolation(ident.clone()));
+        }
+
+        let slow_mode = match channel.policy().slow_mode {
+            Some(seconds) if channel.role(sender) < Role::Moderator => {
+                Duration::from_secs(seconds.into())
+            }
+            _ => return Ok(()),
+        };
+        let too_soon = self
+            .last_posted
+            .get(&(*sender, ident.clone()))
+            .is_some_and(|at| at.elapsed() + tolerance < slow_mode);
+        if too_soon {
+            return Err(crate::Error::SlowMode(ident.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Get the current record of a channel owned by this client.
+    ///
+    /// No record is made up for a channel which is not known, since it may
+    /// belong to another peer whose record is still being looked up.
+    fn owned_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<Channel> {
+        let peer_id = self.peer_id();
+        match self.channels.get(&ident) {
+            Some(channel) if channel.owner() != &peer_id => {
+                Err(crate::Error::NotChannelOwner(ident))
+            }
+            Some(channel) => Ok(channel.clone()),
+            None => Err(crate::Error::UnknownChannel(ident)),
+        }
+    }
+
+    /// Store a channel record we own locally and in the DHT, and announce it
+    /// to the network.
+    fn publish_channel(&mut self, channel: Channel) -> crate::Result<()> {
+        let key = Key::new(
+            &MemoryKey::Channel(channel.identifier().clone()).encode()?,
+        );
+        let value = MemoryValue::Channel(channel.clone())
+            .encode_signed(&self.id_keys)?;
+        let command = Command::ChannelUpdate {
+            channel: channel.clone(),
+        };
+
+        if channel.is_encrypted() {
+            self.rotate_channel_key(&channel);
+        }
+        self.channels.insert(channel.identifier().clone(), channel);
+
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(Record::new(key, value), Quorum::One)?;
+
+        // the record is in the DHT, so peers can still learn of it later
+        self.announce(&command)
+    }
+
+    /// Respond to a peer asking to join or leave a channel, if this client owns
+    /// it, by publishing a new version of the channel.
+    ///
+    /// Peers joining invite-only channels must present a valid invitation,
+    /// which members also take note of in case the owner is away.
+    fn handle_membership_request(
+        &mut self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+        joining: bool,
+        invite: Option<Vec<u8>>,
+    ) -> Option<ClientEvent> {
+        let channel = self.channels.get(&ident)?;
+
+        if joining && self.is_banned(channel, &peer) {
+            warn!("Ignoring request from banned {peer} to join {ident}");
+            return None;
+        }
+
+        if !joining {
+            if let Some(invited) = self.invited.get_mut(&ident) {
+                invited.remove(&peer);
+            }
+        } else if channel.is_invite_only() {
+            let now = timestamp_now();
+            let admitted = invite
+                .and_then(|token| Invite::verify(&token).ok())
+                .is_some_and(|(invite, inviter)| {
+                    invite.admits(&inviter, channel, &peer, now)
+                });
+            if !admitted {
+                warn!("Ignoring uninvited request from {peer} to join {ident}");
+                return None;
+            }
+
+            self.invited.entry(ident.clone()).or_default().insert(peer);
+        }
+
+        let channel = self.channels.get(&ident)?;
+        if channel.owner() != &self.peer_id() {
+            return None;
+        }
+
+        // the owner is always a peer of its own channel
+        let is_peer = channel.peers().contains(&peer);
+        let channel = match (joining, is_peer) {
+            _ if channel.is_archived() => return None,
+            (true, false) => channel.with_peer(peer),
+            (false, true) if channel.owner() != &peer => {
+                channel.without_peer(&peer)
+            }
+            _ => return None,
+        };
+
+        match self.publish_channel(channel.clone()) {
+            Ok(()) => Some(ClientEvent::ChannelUpdated(channel)),
+            Err(err) => Some(ClientEvent::Error(err)),
+        }
+    }
+
+    /// Apply a channel record received from the network, returning whether it
+    /// was accepted.
+    ///
+    /// Records are only accepted from the owner of the currently known version,
+    /// and must be newer than it. The record of a channel which is not known
+    /// yet is only accepted from the DHT, where it is signed by its owner.
+    fn update_channel(&mut self, channel: Channel, author: &PeerId) -> bool {
+        let accepted = match self.channels.get(channel.identifier()) {
+            Some(known) => {
+                known.owner() == author && channel.version() > known.version()
+            }
+            None => channel.owner() == author,
+        };
+
+        if accepted {
+            if let Some(welcome) = channel.welcome() {
+                if self.awaiting_welcome.remove(channel.identifier()) {
+                    self.pending_events.push_back(
+                        ClientEvent::ChannelWelcome {
+                            channel: channel.identifier().clone(),
+                            message: welcome.to_owned(),
+                        },
+                    );
+                }
+            }
+
+            let was_encrypted = self
+                .channel(channel.identifier())
+                .is_some_and(Channel::is_encrypted);
+            if was_encrypted && !channel.is_encrypted() {
+                self.channel_keys.remove(channel.identifier());
+            }
+
+            self.channels.insert(channel.identifier().clone(), channel);
+        }
+
+        accepted
+    }
+
+    /// Schedule a message to be posted to a given channel at a later time.
+    ///
+    /// Once the message is published, a [`ClientEvent::ScheduledMessageSent`]
+    /// is emitted, or a [`ClientEvent::ScheduledMessageFailed`] if it cannot
+    /// be.
+    pub fn schedule_message(
+        &mut self,
+        channel: ChannelIdentifier,
+        contents: &str,
+        message_type: MessageType,
+        send_at: SystemTime,
+    ) -> crate::Result<ScheduleId> {
+        if self.is_channel_archived(&channel) {
+            return Err(crate::Error::ChannelArchived(channel));
+        }
+
+        let send_at = to_millis(send_at);
+
+        let command = Command::MessageSend {
+            contents: contents.to_owned(),
+            channel: channel.clone(),
+            timestamp: send_at,
+            message_type,
+            in_reply_to: None,
+            mentions: self.resolve_mentions(contents),
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        };
+        if !command.is_valid() {
+            return Err(crate::Error::InvalidData(String::from(
+                "scheduled message is not valid",
+            )));
+        }
+
+        self.scheduler.schedule(
+            channel,
+            contents.to_owned(),
+            message_type,
+            send_at,
+        )
+    }
+
+    /// Cancel a scheduled message, returning it if it was still pending.
+    pub fn cancel_scheduled_message(
+        &mut self,
+        id: ScheduleId,
+    ) -> crate::Result<Option<ScheduledMessage>> {
+        self.scheduler.cancel(id)
+    }
+
+    /// Get the messages which are scheduled but not yet sent.
+    pub fn scheduled_messages(
+        &self,
+    ) -> impl Iterator<Item = &ScheduledMessage> {
+        self.scheduler.pending()
+    }
+
+    /// Persist pending scheduled messages to a file, loading any messages
+    /// which were stored there by a previous session.
+    ///
+    /// If a passphrase is given, the file is encrypted with a key derived
+    /// from it, and must be loaded with the same passphrase again.
+    pub fn persist_scheduled_messages(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        self.scheduler.persist_to(path, passphrase)
+    }
+
+    /// Keep the messages of every channel in a store at a given path, so that
+    /// they may be retrieved with [`Client::history`], including in later
+    /// sessions.
+    ///
+    /// If a passphrase is given, the store is encrypted with a key derived
+    /// from it, and must be opened with the same passphrase again.
+    #[cfg(feature = "history-store")]
+    pub fn persist_history(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        self.history_store = Some(HistoryStore::open(path, passphrase)?);
+        Ok(())
+    }
+
+    /// Get the kept messages of a channel sent within a range of timestamps,
+    /// in milliseconds since the Unix epoch, oldest first.
+    ///
+    /// No messages are kept unless [`Client::persist_history`] was called.
+    #[cfg(feature = "history-store")]
+    pub fn history(
+        &self,
+        channel: &ChannelIdentifier,
+        range: impl RangeBounds<u64>,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        match &self.history_store {
+            Some(store) => store.range(channel, range),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Get up to `limit` kept messages of a channel which were sent before a
+    /// given message, oldest first, so that older history may be loaded a
+    /// page at a time.
+    ///
+    /// Passing the oldest message of one page gets the page before it. No
+    /// messages are returned if the given message is not kept.
+    #[cfg(feature = "history-store")]
+    pub fn history_page(
+        &self,
+        channel: &ChannelIdentifier,
+        before: &MessageId,
+        limit: usize,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        match &self.history_store {
+            Some(store) => store.page(channel, before, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Keep a message in the history store, if there is one.
+    #[cfg_attr(not(feature = "history-store"), allow(unused_variables))]
+    fn store_message(
+        &self,
+        id: &MessageId,
+        channel: &ChannelIdentifier,
+        sender: PeerId,
+        timestamp: u64,
+        contents: &str,
+        message_type: MessageType,
+    ) {
+        #[cfg(feature = "history-store")]
+        if let Some(store) = &self.history_store {
+            let message = StoredMessage {
+                id: id.clone(),
+                channel: channel.clone(),
+                sender,
+                timestamp,
+                contents: contents.to_owned(),
+                message_type,
+            };
+            if let Err(err) = store.insert(&message) {
+                warn!("Could not store message {id}: {err}");
+            }
+        }
+    }
+
+    /// Replace the contents of a message in the history store, if there is
+    /// one.
+    #[cfg_attr(not(feature = "history-store"), allow(unused_variables))]
+    fn store_edit(&self, id: &MessageId, contents: &str) {
+        #[cfg(feature = "history-store")]
+        if let Some(store) = &self.history_store {
+            if let Err(err) = store.update(id, contents) {
+                warn!("Could not store edit of message {id}: {err}");
+            }
+        }
+    }
+
+    /// Forget a message which was deleted, so that it is neither handed out
+    /// to peers syncing the channel's history nor reported as expired.
+    fn forget_message(&mut self, channel: &ChannelIdentifier, id: &MessageId) {
+        self.history.remove(channel, id);
+        self.message_authors.remove(id);
+        self.expirations.remove(id);
+        self.unstore_message(id);
+    }
+
+    /// Forget a message in the history store, if there is one.
+    #[cfg_attr(not(feature = "history-store"), allow(unused_variables))]
+    fn unstore_message(&self, id: &MessageId) {
+        #[cfg(feature = "history-store")]
+        if let Some(store) = &self.history_store {
+            if let Err(err) = store.remove(id) {
+                warn!("Could not remove stored message {id}: {err}");
+            }
+        }
+    }
+
+    /// Persist the peers which have been connected to to a file, loading
+    /// any peers which were stored there by a previous session.
+    ///
+    /// If a passphrase is given, the file is encrypted with a key derived
+    /// from it, and must be loaded with the same passphrase again.
+    pub fn persist_address_book(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        self.address_book.persist_to(path, passphrase)
+    }
+
+    /// Persist the channels and direct conversations which are open to a
+    /// file, resuming those which were stored there by a previous session.
+    ///
+    /// The stored channels are joined again, and a [`ClientEvent::Resumed`]
+    /// is emitted for each stored entry, in the order they were first opened.
+    /// Channels are remembered until they are left, and direct conversations
+    /// until [`Client::close_direct`] is called; shutting down forgets
+    /// neither. Passphrase-protected channels are never remembered.
+    ///
+    /// If a passphrase is given, the file is encrypted with a key derived
+    /// from it, and must be loaded with the same passphrase again.
+    pub fn resume_session(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        let entries = self.session.persist_to(path, passphrase)?;
+        for entry in entries {
+            if let SessionEntry::Channel(channel) = &entry {
+                if !self.joined_channels.contains(channel) {
+                    self.subscribe_channel(channel.clone())?;
+                }
+            }
+            self.pending_events.push_back(ClientEvent::Resumed(entry));
+        }
+        Ok(())
+    }
+
+    /// Remember that a channel or direct conversation was opened, so that it
+    /// is resumed in later sessions.
+    fn open_session_entry(&mut self, entry: SessionEntry) {
+        if let Err(err) = self.session.open(entry) {
+            warn!("Could not save session: {err}");
+        }
+    }
+
+    /// Forget a channel or direct conversation which was closed.
+    fn close_session_entry(&mut self, entry: &SessionEntry) {
+        if let Err(err) = self.session.close(entry) {
+            warn!("Could not save session: {err}");
+        }
+    }
+
+    /// Get the peers which have been connected to before, most recently seen
+    /// first.
+    pub fn known_peers(&self) -> Vec<KnownPeer> {
+        self.address_book.recent().into_iter().cloned().collect()
+    }
+
+    /// Dial up to a given number of the peers which have been connected to
+    /// before, most recently seen first, at the addresses they were reached
+    /// at. Returns how many peers are being dialed.
+    ///
+    /// Peers which are already connected are skipped.
+    pub fn redial_known_peers(&mut self, limit: usize) -> usize {
+        let peers = self
+            .address_book
+            .recent()
+            .into_iter()
+            .filter(|peer| !self.connections.contains_key(&peer.peer_id))
+            .filter(|peer| peer.peer_id != *self.swarm.local_peer_id())
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut dialed = 0;
+        for peer in peers {
+            info!("Dialing known peer {}", peer.peer_id);
+            let opts = DialOpts::peer_id(peer.peer_id)
+                .addresses(peer.addresses)
+                .build();
+            match self.swarm.dial(opts) {
+                Ok(()) => dialed += 1,
+                Err(err) => warn!("Could not dial {}: {err}", peer.peer_id),
+            }
+        }
+        dialed
+    }
+
+    /// Dial another client.
+    ///
+    /// This is a non-blocking operation which may cause the following events to be emitted:
+    /// - [`ClientEvent::Dialing`]
+    /// - [`ClientEvent::PeerConnected`]
+    /// - [`ClientEvent::OutgoingConnectionError`]
+    pub fn dial(&mut self, addr: Multiaddr) -> crate::Result<()> {
+        info!("Dialing {}", addr);
+        self.swarm.dial(addr)?;
+        Ok(())
+    }
+
+    /// Listen on a given address.
+    ///
+    /// Only TCP and WebSocket addresses are supported, and QUIC addresses are
+    /// refused rather than left without a listener.
+    pub fn listen_on(&mut self, addr: Multiaddr) -> crate::Result<()> {
+        if addr
+            .iter()
+            .any(|protocol| matches!(protocol, Protocol::Quic))
+        {
+            return Err(crate::Error::InvalidData(format!(
+                "cannot listen on {addr}, QUIC is not supported"
+            )));
+        }
+        self.swarm.listen_on(addr)?;
+        Ok(())
+    }
+
+    /// Listen through a relay, so that peers may dial this client at a
+    /// `/p2p-circuit` address even if it cannot accept inbound connections.
+    ///
+    /// The address of the relay must end with its peer ID. Once the relay
+    /// accepts, a [`ClientEvent::ListeningOn`] is emitted for the address
+    /// through it.
+    pub fn listen_via_relay(&mut self, relay: Multiaddr) -> crate::Result<()> {
+        if !matches!(relay.iter().last(), Some(Protocol::P2p(_))) {
+            return Err(crate::Error::InvalidData(String::from(
+                "relay address does not end with its peer ID",
+            )));
+        }
+
+        info!("Listening via relay {relay}");
+        self.listen_on(relay.with(Protocol::P2pCircuit))
+    }
+
+    /// Register at a rendezvous point, and discover the other peers which
+    /// registered there, so that peers may find each other beyond their
+    /// local network.
+    ///
+    /// The address of the rendezvous point must end with its peer ID. A
+    /// [`ClientEvent::PeerDiscovered`] is emitted for each peer found there,
+    /// and the registration is renewed for as long as the client runs.
+    pub fn register_rendezvous(
+        &mut self,
+        addr: Multiaddr,
+    ) -> crate::Result<()> {
+        let peer = match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => {
+                PeerId::from_multihash(hash).map_err(|_| {
+                    crate::Error::InvalidData(String::from(
+                        "rendezvous point has an invalid peer ID",
+                    ))
+                })?
+            }
+            _ => {
+                return Err(crate::Error::InvalidData(String::from(
+                    "rendezvous address does not end with its peer ID",
+                )))
+            }
+        };
+
+        self.rendezvous_points.insert(peer, None);
+        if self.connections.contains_key(&peer) {
+            self.rendezvous_with(peer);
+        } else {
+            // registration happens once the connection is established
+            self.dial(addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Register at a connected rendezvous point, and ask it for the peers
+    /// which registered since we last asked.
+    fn rendezvous_with(&mut self, point: PeerId) {
+        let cookie = self.rendezvous_points.get(&point).cloned().flatten();
+        let namespace = Namespace::from_static(RENDEZVOUS_NAMESPACE);
+        let rendezvous = &mut self.swarm.behaviour_mut().rendezvous;
+        rendezvous.register(namespace.clone(), point, None);
+        rendezvous.discover(Some(namespace), cookie, None, point);
+    }
+
+    fn refresh_rendezvous_if_due(&mut self) {
+        if self.rendezvous_refreshed_at.elapsed() < RENDEZVOUS_REFRESH_INTERVAL
+        {
+            return;
+        }
+
+        let points = self
+            .rendezvous_points
+            .keys()
+            .filter(|point| self.connections.contains_key(point))
+            .copied()
+            .collect::<Vec<_>>();
+        for point in points {
+            self.rendezvous_with(point);
+        }
+        self.rendezvous_refreshed_at = Instant::now();
+    }
+
+    /// Leave the network gracefully.
+    ///
+    /// A [`Command::Goodbye`] is sent to each joined channel, which are then
+    /// unsubscribed from, and this client stops providing its DHT records.
+    /// All connections are then closed, and this resolves once they have
+    /// been, or after a timeout.
+    pub async fn shutdown(&mut self) -> crate::Result<()> {
+        self.shutdown_with_reason(None).await
+    }
+
+    /// Leave the network gracefully as with [`Client::shutdown`], giving a
+    /// reason in the goodbye.
+    pub async fn shutdown_with_reason(
+        &mut self,
+        reason: Option<String>,
+    ) -> crate::Result<()> {
+        let channels = self.joined_channels.iter().cloned().collect::<Vec<_>>();
+
+        // a reason too long to be sent is cut short, rather than keeping this
+        // client from leaving
+        let reason = reason.map(|mut reason| {
+            let mut end = reason.len().min(MAX_GOODBYE_REASON_LENGTH);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+            reason
+        });
+        for ident in channels {
+            if let Err(err) = self.say_goodbye(&ident, reason.clone()) {
+                warn!("Could not say goodbye to {ident}: {err}");
+            }
+            self.part_channel(ident)?;
+        }
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        for key in self.provided_keys.drain() {
+            kademlia.stop_providing(&key);
+        }
+
+        // give the goodbye a chance to be sent before disconnecting
+        let _ = time::timeout(SHUTDOWN_FLUSH_DELAY, async {
+            loop {
+                self.swarm.select_next_some().await;
+            }
+        })
+        .await;
+
+        let peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, async {
+            while self.swarm.network_info().num_peers() > 0 {
+                self.swarm.select_next_some().await;
+            }
+        })
+        .await;
+
+        Ok(())
+    }
+
+    /// Publish a [`Command::Goodbye`] to a channel, for its members to see.
+    ///
+    /// Nothing is sent to encrypted or invite-only channels, since a goodbye
+    /// would give away who their members are to anyone listening. Having no
+    /// peers to publish to is not considered an error.
+    fn say_goodbye(
+        &mut self,
+        ident: &ChannelIdentifier,
+        reason: Option<String>,
+    ) -> crate::Result<()> {
+        let goodbye = Command::Goodbye {
+            channels: vec![ident.clone()],
+            reason,
+        };
+        goodbye.check_valid()?;
+
+        if self.expects_encryption(ident)
+            || self.channel(ident).is_some_and(Channel::is_invite_only)
+        {
+            return Ok(());
+        }
+        match self.publish_command(topic_from_channel(ident), &goodbye) {
+            Ok(_)
+            | Err(crate::Error::PublishError(
+                PublishError::InsufficientPeers,
+            )) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the remote peers which are subscribed to a channel.
+    ///
+    /// Membership is only known for peers this client is connected to.
+    pub fn channel_members(&self, ident: &ChannelIdentifier) -> Vec<PeerId> {
+        self.channel_members
+            .get(ident)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Get the peers in a channel's gossipsub mesh, to which its messages are
+    /// published directly.
+    pub fn mesh_peers(&self, ident: &ChannelIdentifier) -> Vec<PeerId> {
+        let topic = topic_from_channel(ident).hash();
+        self.swarm
+            .behaviour()
+            .gossipsub
+            .mesh_peers(&topic)
+            .copied()
+            .collect()
+    }
+
+    /// Get the channels this client has joined.
+    pub fn joined_channels(&self) -> impl Iterator<Item = &ChannelIdentifier> {
+        self.joined_channels.iter()
+    }
+
+    /// Get the addresses this client is listening on.
+    pub fn listeners(&self) -> Vec<Multiaddr> {
+        self.swarm.listeners().cloned().collect()
+    }
+
+    /// Advertise an address at which this client can be reached from the
+    /// outside, such as one forwarded to it by a static NAT mapping.
+    ///
+    /// The address is shared with peers when identifying to them, and with
+    /// rendezvous points when registering, so that remote peers may dial it.
+    pub fn add_external_address(&mut self, addr: Multiaddr) {
+        info!("Advertising external address {addr}");
+        self.swarm
+            .add_external_address(addr, AddressScore::Infinite);
+    }
+
+    /// Get the addresses at which this client is thought to be reachable
+    /// from the outside.
+    pub fn external_addresses(&self) -> Vec<Multiaddr> {
+        self.swarm
+            .external_addresses()
+            .map(|record| record.addr.clone())
+            .collect()
+    }
+
+    /// Get whether or not this client is connected to a given peer.
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.swarm.is_connected(peer_id)
+    }
+
+    /// Get information about every peer this client is connected to.
+    pub fn connected_peers(&self) -> Vec<PeerInfo> {
+        self.connections
+            .keys()
+            .filter_map(|peer| self.peer_info(peer))
+            .collect()
+    }
+
+    /// Get information about a connected peer.
+    pub fn peer_info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        let connections = self.connections.get(peer)?;
+
+        let mut addresses = connections.clone();
+        addresses.sort();
+        addresses.dedup();
+
+        Some(PeerInfo {
+            peer_id: *peer,
+            addresses,
+            connections: connections.len(),
+            latency: self.latency(peer),
+            nick: self.nick_cache.get(peer).cloned().flatten(),
+            score: self.peer_score(peer),
+        })
+    }
+
+    /// Get the gossipsub score of a peer, if peers are scored.
+    ///
+    /// Peers whose scores fall below zero are pruned from the mesh, and those
+    /// with far lower scores are ignored. Relaying invalid messages, which
+    /// are rejected in validation, lowers a peer's score.
+    pub fn peer_score(&self, peer: &PeerId) -> Option<f64> {
+        self.swarm.behaviour().gossipsub.peer_score(peer)
+    }
+
+    /// Close all connections to a peer, refusing to connect to it again until
+    /// a cool-down has passed.
+    ///
+    /// A [`ClientEvent::PeerDisconnected`] is emitted once the connections
+    /// have closed.
+    pub fn disconnect(&mut self, peer: &PeerId) {
+        // blocked peers are already refused indefinitely
+        if self.is_blocked(peer) {
+            return;
+        }
+
+        info!("Disconnecting from {peer}");
+        self.swarm.ban_peer_id(*peer);
+        self.cooldowns
+            .insert(*peer, Instant::now() + self.disconnect_cooldown);
+    }
+
+    /// Forget the disappearing messages which have expired.
+    fn expire_messages(&mut self) {
+        let now = timestamp_now();
+        let expired = self
+            .expirations
+            .iter()
+            .filter(|(_, (_, at))| *at <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            if let Some((channel, _)) = self.expirations.remove(&id) {
+                self.history.remove(&channel, &id);
+                self.message_authors.remove(&id);
+                self.unstore_message(&id);
+                self.pending_events
+                    .push_back(ClientEvent::MessageExpired { id, channel });
+            }
+        }
+    }
+
+    /// Forget polls which have closed, reporting their final results.
+    fn expire_polls(&mut self) {
+        let now = timestamp_now();
+        let closed = self
+            .polls
+            .iter()
+            .filter(|(_, poll)| poll.is_closed(now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in closed {
+            if let Some(poll) = self.polls.remove(&id) {
+                self.pending_events.push_back(ClientEvent::PollClosed {
+                    tally: poll.tally(),
+                    poll: id,
+                    channel: poll.channel,
+                    options: poll.options,
+                });
+            }
+        }
+    }
+
+    /// Allow peers whose disconnection cool-down has passed to connect again.
+    fn expire_cooldowns(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .cooldowns
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+
+        for peer in expired {
+            self.cooldowns.remove(&peer);
+            self.swarm.unban_peer_id(peer);
+        }
+    }
+
+    /// Block a peer, ignoring its messages and refusing connections to it.
+    ///
+    /// Any existing connections to the peer are closed.
+    pub fn block_peer(&mut self, peer: PeerId) {
+        info!("Blocking {peer}");
+        self.refuse_peer(peer);
+        if let Err(err) = self.block_list.block(peer) {
+            warn!("Could not save block list: {err}");
+        }
+    }
+
+    /// Refuse the messages and connections of a blocked peer.
+    fn refuse_peer(&mut self, peer: PeerId) {
+        self.swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        self.swarm.ban_peer_id(peer);
+        self.cooldowns.remove(&peer);
+    }
+
+    /// Unblock a previously blocked peer.
+    pub fn unblock_peer(&mut self, peer: &PeerId) {
+        info!("Unblocking {peer}");
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .remove_blacklisted_peer(peer);
+        self.swarm.unban_peer_id(*peer);
+        self.cooldowns.remove(peer);
+        if let Err(err) = self.block_list.unblock(peer) {
+            warn!("Could not save block list: {err}");
+        }
+    }
+
+    /// Get whether or not a peer is blocked.
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        self.block_list.is_blocked(peer)
+    }
+
+    /// Get the peers which are blocked.
+    pub fn blocked_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.block_list.blocked()
+    }
+
+    /// Mute a peer, so that its messages are flagged as muted for them to be
+    /// hidden.
+    ///
+    /// Unlike blocking, messages from the peer are still received and
+    /// forwarded, and connections to it are kept.
+    pub fn mute_peer(&mut self, peer: PeerId) {
+        info!("Muting {peer}");
+        if let Err(err) = self.block_list.mute(peer) {
+            warn!("Could not save block list: {err}");
+        }
+    }
+
+    /// Unmute a previously muted peer.
+    pub fn unmute_peer(&mut self, peer: &PeerId) {
+        info!("Unmuting {peer}");
+        if let Err(err) = self.block_list.unmute(peer) {
+            warn!("Could not save block list: {err}");
+        }
+    }
+
+    /// Get whether or not a peer is muted.
+    pub fn is_muted(&self, peer: &PeerId) -> bool {
+        self.block_list.is_muted(peer)
+    }
+
+    /// Get the peers which are muted.
+    pub fn muted_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.block_list.muted()
+    }
+
+    /// Persist the peers which are blocked or muted to a file, loading any
+    /// peers which were stored there by a previous session.
+    ///
+    /// The loaded peers which are blocked are refused from then on. If a
+    /// passphrase is given, the file is encrypted with a key derived from
+    /// it, and must be loaded with the same passphrase again.
+    pub fn persist_block_list(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        self.block_list.persist_to(path, passphrase)?;
+        let blocked = self.block_list.blocked().copied().collect::<Vec<_>>();
+        for peer in blocked {
+            self.refuse_peer(peer);
+        }
+        Ok(())
+    }
+
+    /// Pass the score the spam filter gives a peer on to gossipsub, so that
+    /// peers which keep sending spam are eventually ignored.
+    fn update_spam_score(&mut self, peer: &PeerId) {
+        let score = self.spam_filter.score(peer);
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .set_application_score(peer, score);
+    }
+
+    /// Get a snapshot of the traffic handled by this client.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            bytes_received: self.bandwidth.total_inbound(),
+            bytes_sent: self.bandwidth.total_outbound(),
+            ..self.metrics.clone()
+        }
+    }
+
+    /// Get the most recent identification of a peer, including its agent
+    /// version, supported protocols, listen addresses, and the address it
+    /// observed us at.
+    pub fn whois(&self, peer: &PeerId) -> Option<&IdentifyInfo> {
+        self.identities.get(peer)
+    }
+
+    /// Get a short fingerprint of the public key of a peer, which two people
+    /// can compare out of band to check that they see the same key.
+    pub fn fingerprint(&self, peer: &PeerId) -> String {
+        crate::crypto::fingerprint(peer)
+    }
+
+    /// Trust a peer's key for a nickname, replacing the key first seen using
+    /// it, such as after comparing fingerprints.
+    pub fn trust_key(&mut self, nick: &str, peer: PeerId) -> crate::Result<()> {
+        self.trust_store.trust(nick, peer, timestamp_now())
+    }
+
+    /// Get the key trusted for a nickname, which is the first seen using it
+    /// unless another was trusted with [`Client::trust_key`].
+    pub fn trusted_key(&self, nick: &str) -> Option<PeerId> {
+        self.trust_store.get(nick).copied()
+    }
+
+    /// Get the safety number of this client and a peer, which the peer derives
+    /// alike, so that the two can be compared out of band to check that
+    /// direct messages between them are not intercepted.
+    ///
+    /// The number covers the keys the direct message session with the peer
+    /// was agreed with, or those it would be agreed with if there is none yet.
+    pub fn safety_number(&self, peer: &PeerId) -> crate::Result<String> {
+        let (own_key, peer_key) = match self.dm_sessions.get(peer) {
+            Some(session) => session.identity_keys(),
+            None => {
+                let own = x25519_secret_key(&self.id_keys)?;
+                (
+                    x25519_dalek::PublicKey::from(&own).to_bytes(),
+                    x25519_public_key(peer)?.to_bytes(),
+                )
+            }
+        };
+        Ok(crate::crypto::safety_number(
+            (&self.peer_id(), &own_key),
+            (peer, &peer_key),
+        ))
+    }
+
+    /// Mark a peer as verified, such as after comparing safety numbers.
+    pub fn mark_verified(&mut self, peer: PeerId) {
+        info!("Marking {peer} as verified");
+        if let Err(err) = self.trust_store.verify(peer) {
+            warn!("Could not save trust store: {err}");
+        }
+    }
+
+    /// Unmark a peer previously marked as verified.
+    pub fn unmark_verified(&mut self, peer: &PeerId) {
+        info!("Unmarking {peer} as verified");
+        if let Err(err) = self.trust_store.unverify(peer) {
+            warn!("Could not save trust store: {err}");
+        }
+    }
+
+    /// Get whether or not a peer was marked as verified.
+    pub fn is_verified(&self, peer: &PeerId) -> bool {
+        self.trust_store.is_verified(peer)
+    }
+
+    /// Persist the keys trusted for each nickname and the verified peers to a
+    /// file, loading any which were stored there by a previous session.
+    ///
+    /// If a passphrase is given, the file is encrypted with a key derived
+    /// from it, and must be loaded with the same passphrase again.
+    pub fn persist_trust_store(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        self.trust_store.persist_to(path, passphrase)
+    }
+
+    /// Get the average round-trip time of recent pings to a connected peer.
+    pub fn latency(&self, peer: &PeerId) -> Option<Duration> {
+        let samples = self.latencies.get(peer)?;
+        let total = samples.iter().sum::<Duration>();
+        Some(total / samples.len().try_into().ok()?)
+    }
+
+    /// Get this client's peer ID.
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from(self.id_keys.public())
+    }
+
+    /// Get this client's nickname.
+    pub fn nick(&self) -> &String {
+        &self.nick
+    }
+
+    /// Change this client's nickname.
+    ///
+    /// The new nickname is stored in the DHT and announced to the network.
+    pub fn set_nick(&mut self, nick: &str) -> crate::Result<()> {
+        let command = Command::NicknameUpdate {
+            nick: nick.to_owned(),
+        };
+        if !command.is_valid() {
+            return Err(crate::Error::InvalidData(String::from(
+                "nickname is not valid",
+            )));
+        }
+
+        let mut profile = self.own_profile().clone();
+        profile.nick = nick.to_owned();
+        self.publish_profile(profile)?;
+
+        self.nick = nick.to_owned();
+        self.nick_cache
+            .insert(self.peer_id(), Some(nick.to_owned()));
+        self.nick_announced_at = Some(Instant::now());
+        self.query_nick_claim(nick)?;
+
+        self.announce(&command)
+    }
+
+    /// Register this client's nickname, by claiming it in the DHT.
+    ///
+    /// Other clients prefer the earliest claim to a nickname, and may show
+    /// other peers using it as unverified. Claims are not withdrawn when the
+    /// nickname is changed.
+    pub fn register_nick(&mut self) -> crate::Result<()> {
+        let peer_id = self.peer_id();
+        let value = match self.nick_claims.get(&self.nick) {
+            Some((claim, _)) if claim.owner() != &peer_id => {
+                return Err(crate::Error::NickTaken(self.nick.clone()));
+            }
+            Some((_, value)) => value.clone(),
+            None => {
+                let claim =
+                    NickClaim::new(self.nick.clone(), peer_id, timestamp_now());
+                let value = MemoryValue::NickClaim(claim.clone())
+                    .encode_signed(&self.id_keys)?;
+                self.nick_claims
+                    .insert(self.nick.clone(), (claim, value.clone()));
+                value
+            }
+        };
+
+        let observed =
+            self.trust_store
+                .observe(&self.nick, peer_id, timestamp_now());
+        if let Err(err) = observed {
+            warn!("Could not save trust store: {err}");
+        }
+
+        info!("Registering nickname {}", self.nick);
+        let key = Key::new(&MemoryKey::NickClaim(self.nick.clone()).encode()?);
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(Record::new(key, value), Quorum::One)?;
+        Ok(())
+    }
+
+    /// Get the peer which holds the earliest known claim to a nickname, if
+    /// it is registered.
+    pub fn nick_registrant(&self, nick: &str) -> Option<PeerId> {
+        self.nick_claims.get(nick).map(|(claim, _)| *claim.owner())
+    }
+
+    /// Get whether a peer's nickname is either unregistered or registered to
+    /// the peer itself, rather than to another peer it may be impersonating.
+    pub fn holds_nick(&self, peer: &PeerId) -> bool {
+        match self.nick_cache.get(peer) {
+            Some(Some(nick)) => self
+                .nick_registrant(nick)
+                .is_none_or(|owner| owner == *peer),
+            _ => true,
+        }
+    }
+
+    /// Change the bio and pronouns in this client's profile.
+    ///
+    /// The profile is stored in the DHT along with the nickname and avatar.
+    pub fn set_profile(
+        &mut self,
+        bio: Option<String>,
+        pronouns: Option<String>,
+    ) -> crate::Result<()> {
+        let profile = Profile {
+            bio,
+            pronouns,
+            ..self.own_profile().clone()
+        };
+        if !profile.is_valid() {
+            return Err(crate::Error::InvalidData(String::from(
+                "profile is not valid",
+            )));
+        }
+
+        self.publish_profile(profile)
+    }
+
+    /// Fetch the profile of a peer.
+    ///
+    /// Profiles which have not been fetched yet are requested from the DHT,
+    /// and a [`ClientEvent::ProfileFetched`] is emitted once they arrive.
+    pub fn fetch_profile(
+        &mut self,
+        peer: &PeerId,
+    ) -> crate::Result<Option<&Profile>> {
+        if !self.profile_cache.contains_key(peer) {
+            self.query_profile(peer)?;
+        }
+
+        Ok(self.profile_cache.get(peer))
+    }
+
+    fn own_profile(&self) -> &Profile {
+        self.profile_cache
+            .get(&self.peer_id())
+            .expect("own profile is always cached")
+    }
+
+    /// Store this client's profile in the DHT.
+    fn publish_profile(&mut self, profile: Profile) -> crate::Result<()> {
+        let peer_id = self.peer_id();
+        let record = profile_record(peer_id, &profile, &self.id_keys)?;
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(record, Quorum::One)?;
+
+        self.profile_cache.insert(peer_id, profile);
+
+        Ok(())
+    }
+
+    /// Announce this client's nickname if new peers have arrived since it was
+    /// last announced, or if it has not been announced in a while.
+    fn announce_nick_if_due(&mut self) {
+        let stale = self
+            .nick_announced_at
+            .is_none_or(|at| at.elapsed() >= NICK_ANNOUNCE_INTERVAL);
+        if !self.nick_announce_due && !stale {
+            return;
+        }
+
+        let command = Command::NicknameUpdate {
+            nick: self.nick.clone(),
+        };
+        if let Err(err) = self.announce(&command) {
+            warn!("Could not announce nickname: {err}");
+        }
+
+        // failures are not retried until the next announcement is due, to
+        // avoid retrying every tick
+        self.nick_announce_due = false;
+        self.nick_announced_at = Some(Instant::now());
+    }
+
+    /// Bootstrap once connected to a peer, and again now and then, and store
+    /// this client's own records again before they expire.
+    fn maintain_dht(&mut self) {
+        let bootstrap_due = self
+            .bootstrapped_at
+            .is_none_or(|at| at.elapsed() >= BOOTSTRAP_INTERVAL);
+        if bootstrap_due && !self.connections.is_empty() {
+            match self.swarm.behaviour_mut().kademlia.bootstrap() {
+                Ok(_) => self.bootstrapped_at = Some(Instant::now()),
+                Err(err) => debug!("Could not bootstrap yet: {err:?}"),
+            }
+        }
+
+        self.retry_failed_puts();
+
+        if self.republished_at.elapsed() >= self.record_ttl / 2 {
+            if let Err(err) = self.republish_records() {
+                warn!("Could not republish records: {err}");
+            }
+            self.republished_at = Instant::now();
+        }
+    }
+
+    /// Store the records this client published, and provide the keys it
+    /// provides, again, with their expiry pushed back.
+    fn republish_records(&mut self) -> crate::Result<()> {
+        let peer_id = self.peer_id();
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+
+        let records = kademlia
+            .store_mut()
+            .records()
+            .filter(|record| record.publisher == Some(peer_id))
+            .map(|record| Record {
+                expires: None,
+                ..record.into_owned()
+            })
+            .collect::<Vec<_>>();
+        for record in records {
+            kademlia.put_record(record, Quorum::One)?;
+        }
+
+        for key in &self.provided_keys {
+            kademlia.start_providing(key.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a failure to store a record in the DHT, scheduling a retry if
+    /// it is one of this client's own records.
+    ///
+    /// Returns whether the client gave up on storing the record.
+    fn handle_put_failure(&mut self, key: Key) -> bool {
+        let peer_id = self.peer_id();
+        let own = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .store_mut()
+            .get(&key)
+            .is_some_and(|record| record.publisher == Some(peer_id));
+        let attempts = self.put_retries.get(&key).map_or(0, |(n, _)| *n);
+        if !own || attempts >= MAX_PUT_RETRIES {
+            self.put_retries.remove(&key);
+            return true;
+        }
+
+        let delay = PUT_RETRY_DELAY * 2u32.pow(attempts);
+        self.put_retries
+            .insert(key, (attempts + 1, Instant::now() + delay));
+        false
+    }
+
+    /// Store the own records whose retries are due again.
+    fn retry_failed_puts(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .put_retries
+            .iter()
+            .filter(|(_, (_, at))| *at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        for key in due {
+            let record = match kademlia.store_mut().get(&key) {
+                Some(record) => Record {
+                    expires: None,
+                    ..record.into_owned()
+                },
+                None => {
+                    self.put_retries.remove(&key);
+                    continue;
+                }
+            };
+
+            info!("Retrying to store DHT record");
+            // the retry is not due again until it fails again
+            if let Some((_, at)) = self.put_retries.get_mut(&key) {
+                *at = now + self.record_ttl;
+            }
+            if let Err(err) = kademlia.put_record(record, Quorum::One) {
+                warn!("Could not store DHT record: {err}");
+            }
+        }
+    }
+
+    /// Forget the nicknames which have not been heard in a while, so that
+    /// they are looked up again rather than shown stale.
+    fn expire_nicknames(&mut self) {
+        let peer_id = self.peer_id();
+        let expired = self
+            .nick_cached_at
+            .iter()
+            .filter(|(_, at)| at.elapsed() >= self.nick_ttl)
+            .map(|(peer, _)| *peer)
+            .filter(|peer| {
+                *peer != peer_id
+                    && !self.nick_queries.values().any(|p| p == peer)
+            })
+            .collect::<Vec<_>>();
+
+        for peer in expired {
+            self.nick_cached_at.remove(&peer);
+            self.nick_cache.remove(&peer);
+            self.profile_cache.remove(&peer);
+        }
+    }
+
+    /// Get the last time a peer was seen active, whether by sending a
+    /// message, updating its nickname, or connecting or disconnecting.
+    pub fn last_seen(&self, peer: &PeerId) -> Option<SystemTime> {
+        self.last_seen.get(peer).copied()
+    }
+
+    /// Find the known peers which are currently using a given nickname.
+    pub fn find_peers_by_nick(&self, nick: &str) -> Vec<PeerId> {
+        self.nick_cache
+            .iter()
+            .filter(|(_, cached)| cached.as_deref() == Some(nick))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Resolve the peers mentioned in a message by nickname or peer ID.
+    ///
+    /// Nicknames shared by several peers mention all of them.
+    fn resolve_mentions(&self, message: &str) -> Vec<PeerId> {
+        let mut mentions = vec![];
+        for name in mentioned_names(message) {
+            let peers = match name.parse() {
+                Ok(peer) => vec![peer],
+                Err(_) => self.find_peers_by_nick(name),
+            };
+            for peer in peers {
+                if !mentions.contains(&peer) {
+                    mentions.push(peer);
+                }
+            }
+        }
+
+        mentions.truncate(MAX_MENTIONS);
+        mentions
+    }
+
+    pub fn fetch_nickname(
+        &mut self,
+        peer: &PeerId,
+    ) -> crate::Result<&Option<String>> {
+        // this seems to be the only way to satisfy the borrow checker ._.
+        if self.nick_cache.contains_key(peer) {
+            return Ok(self.nick_cache.get(peer).unwrap());
+        }
+
+        self.query_profile(peer)?;
+        self.nick_cache.insert(*peer, None);
+        self.nick_cached_at.insert(*peer, Instant::now());
+
+        Ok(&None)
+    }
+
+    /// Resolve the nickname of a peer.
+    ///
+    /// Unlike [`Client::fetch_nickname`], the returned future waits for the
+    /// DHT lookup to complete if the nickname is not already known. The client
+    /// must continue to be polled for the future to resolve.
+    pub fn resolve_nickname(
+        &mut self,
+        peer: &PeerId,
+    ) -> crate::Result<impl Future<Output = Option<String>>> {
+        let (tx, rx) = oneshot::channel();
+
+        match self.nick_cache.get(peer) {
+            Some(Some(nick)) => {
+                let _ = tx.send(Some(nick.clone()));
+            }
+            _ => {
+                self.query_profile(peer)?;
+                self.nick_cache.entry(*peer).or_insert(None);
+                self.nick_cached_at.insert(*peer, Instant::now());
+                self.nick_waiters.entry(*peer).or_default().push(tx);
+            }
+        }
+
+        Ok(async move { rx.await.ok().flatten() })
+    }
+
+    /// Look up the claim to a nickname in the DHT, unless it is known.
+    fn query_nick_claim(&mut self, nick: &str) -> crate::Result<()> {
+        if self.nick_claims.contains_key(nick) {
+            return Ok(());
+        }
+
+        let key = Key::new(&MemoryKey::NickClaim(nick.to_owned()).encode()?);
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .get_record(key, Quorum::One);
+        Ok(())
+    }
+
+    /// Look up the record of a channel in the DHT.
+    fn query_channel(
+        &mut self,
+        ident: &ChannelIdentifier,
+    ) -> crate::Result<()> {
+        let key = Key::new(&MemoryKey::Channel(ident.clone()).encode()?);
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .get_record(key, Quorum::One);
+        Ok(())
+    }
+
+    /// Look up the profile of a peer, and so its nickname, in the DHT, unless
+    /// a lookup is already in progress.
+    fn query_profile(&mut self, peer: &PeerId) -> crate::Result<()> {
+        if self.nick_queries.values().any(|queried| queried == peer) {
+            return Ok(());
+        }
+
+        let key = Key::new(&MemoryKey::Profile(*peer).encode()?);
+        let query_id = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .get_record(key, Quorum::One);
+        self.nick_queries.insert(query_id, *peer);
+
+        Ok(())
+    }
+
+    /// Finish a nickname lookup, notifying anyone waiting on it.
+    fn complete_nickname_query(&mut self, peer: PeerId) {
+        let nick = self.nick_cache.get(&peer).cloned().flatten();
+
+        for waiter in self.nick_waiters.remove(&peer).unwrap_or_default() {
+            let _ = waiter.send(nick.clone());
+        }
+
+        self.pending_events
+            .push_back(ClientEvent::NicknameResolved { peer, nick });
+    }
+
+    /// Record a peer's nickname, emitting a [`ClientEvent::NicknameCollision`]
+    /// if another known peer already uses it.
+    fn cache_nickname(&mut self, peer: PeerId, nick: String) {
+        self.nick_cached_at.insert(peer, Instant::now());
+        if self.nick_cache.get(&peer) == Some(&Some(nick.clone())) {
+            return;
+        }
+
+        let mut peers = self.find_peers_by_nick(&nick);
+        peers.retain(|other| other != &peer);
+
+        if !peers.is_empty() {
+            warn!("Nickname {nick} of {peer} collides with {peers:?}");
+            peers.push(peer);
+            self.pending_events
+                .push_back(ClientEvent::NicknameCollision {
+                    nick: nick.clone(),
+                    peers,
+                });
+        }
+
+        match self.trust_store.observe(&nick, peer, timestamp_now()) {
+            Ok(TrustVerdict::Changed { trusted }) => {
+                warn!("Nickname {nick} of {trusted} is now used by {peer}");
+                self.pending_events.push_back(ClientEvent::KeyChanged {
+                    nick: nick.clone(),
+                    trusted,
+                    peer,
+                });
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Could not save trust store: {err}"),
+        }
+        if let Err(err) = self.query_nick_claim(&nick) {
+            warn!("Could not look up the claim to {nick}: {err}");
+        }
+
+        self.nick_cache.insert(peer, Some(nick));
+    }
+
+    /// Remove a peer from a channel's members, returning whether it was one.
+    fn remove_channel_member(
+        &mut self,
+        channel: &ChannelIdentifier,
+        peer: &PeerId,
+    ) -> bool {
+        let members = match self.channel_members.get_mut(channel) {
+            Some(members) => members,
+            None => return false,
+        };
+
+        let removed = members.remove(peer);
+        if members.is_empty() {
+            self.channel_members.remove(channel);
+        }
+
+        removed
+    }
+
+    fn mark_seen(&mut self, peer: PeerId) {
+        self.last_seen.insert(peer, SystemTime::now());
+    }
+
+    fn send_due_messages(&mut self) {
+        for message in self.scheduler.due(timestamp_now()) {
+            // having no peers yet is handled by queueing the message, so only
+            // slow mode will let it through on a later try
+            let result = self.send_message(
+                &message.contents,
+                message.message_type,
+                message.channel.clone(),
+            );
+            if let Err(crate::Error::SlowMode(_)) = result {
+                continue;
+            }
+
+            if let Err(err) = self.scheduler.cancel(message.id) {
+                warn!("Could not remove scheduled message: {err}");
+            }
+
+            let event = match result {
+                Ok(_) => ClientEvent::ScheduledMessageSent {
+                    id: message.id,
+                    contents: message.contents,
+                    channel: message.channel,
+                    message_type: message.message_type,
+                },
+                Err(error) => {
+                    warn!(
+                        "Could not send scheduled message {}: {error}",
+                        message.id
+                    );
+                    ClientEvent::ScheduledMessageFailed {
+                        id: message.id,
+                        error,
+                    }
+                }
+            };
+            self.pending_events.push_back(event);
+        }
+    }
+
+    /// Set this client's avatar, publishing the image to the DHT.
+    ///
+    /// Returns the content hash of the avatar.
+    pub fn set_avatar(&mut self, data: Vec<u8>) -> crate::Result<BlobHash> {
+        if data.is_empty() || data.len() > MAX_AVATAR_SIZE {
+            return Err(crate::Error::InvalidData(String::from(
+                "avatar is empty or too large",
+            )));
+        }
+
+        let peer_id = self.peer_id();
+        let hash = self.put_blob(data)?;
+
+        let key = Key::new(&MemoryKey::Avatar(peer_id).encode()?);
+        let value = MemoryValue::Avatar {
+            user: peer_id,
+            hash: hash.clone(),
+        }
+        .encode_signed(&self.id_keys)?;
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        kademlia.start_providing(key.clone())?;
+        kademlia.put_record(Record::new(key.clone(), value), Quorum::One)?;
+        self.provided_keys.insert(key);
+
+        self.avatar_cache.insert(peer_id, Some(hash.clone()));
+
+        let mut profile = self.own_profile().clone();
+        profile.avatar_hash = Some(hash.clone());
+        self.publish_profile(profile)?;
+
+        Ok(hash)
+    }
+
+    /// Fetch the avatar of a peer, if it has one.
+    ///
+    /// Avatars which have not been fetched yet are requested from the DHT, and
+    /// a [`ClientEvent::AvatarFetched`] is emitted once they arrive.
+    pub fn fetch_avatar(
+        &mut self,
+        peer: &PeerId,
+    ) -> crate::Result<Option<&[u8]>> {
+        if self.avatar_cache.contains_key(peer) {
+            let hash = self.avatar_cache.get(peer).unwrap();
+            return Ok(hash
+                .as_ref()
+                .and_then(|hash| self.blob_cache.get(hash))
+                .map(Vec::as_slice));
+        }
+
+        let key = Key::new(&MemoryKey::Avatar(*peer).encode()?);
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .get_record(key, Quorum::One);
+        self.avatar_cache.insert(*peer, None);
+
+        Ok(None)
+    }
+
+    /// Store a blob in the DHT, keyed by its content hash.
+    fn put_blob(&mut self, data: Vec<u8>) -> crate::Result<BlobHash> {
+        let hash = blob_hash(&data);
+        let key = Key::new(&MemoryKey::Blob(hash.clone()).encode()?);
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        kademlia.start_providing(key.clone())?;
+        kademlia
+            .put_record(Record::new(key.clone(), data.clone()), Quorum::One)?;
+        self.provided_keys.insert(key);
+
+        self.blob_cache.insert(hash.clone(), data);
+
+        Ok(hash)
+    }
+
+    /// Look up a blob in the DHT, unless it is already known.
+    fn query_blob(&mut self, hash: &BlobHash) -> crate::Result<()> {
+        if !self.blob_cache.contains_key(hash) {
+            let key = Key::new(&MemoryKey::Blob(hash.clone()).encode()?);
+            self.swarm
+                .behaviour_mut()
+                .kademlia
+                .get_record(key, Quorum::One);
+        }
+
+        Ok(())
+    }
+
+    fn handle_event<HandlerErr>(
+        &mut self,
+        event: SwarmEvent<ComposedEvent, HandlerErr>,
+    ) -> crate::Result<Option<ClientEvent>> {
+        match event {
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
+                GossipsubEvent::Message {
+                    propagation_source: source,
+                    message_id,
+                    message,
+                },
+            )) => return Ok(self.handle_message(message, message_id, source)),
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
+                GossipsubEvent::Subscribed { peer_id, topic },
+            )) => {
+                if let Some(channel) = channel_from_topic(&topic) {
+                    let members = self
+                        .channel_members
+                        .entry(channel.clone())
+                        .or_default();
+                    if members.insert(peer_id) {
+                        self.sync_history(&channel, &peer_id);
+                        self.notify_channel_waiters();
+                        return Ok(Some(ClientEvent::PeerJoinedChannel {
+                            peer: peer_id,
+                            channel,
+                        }));
+                    }
+                } else if topic.as_str() == DEFAULT_GOSSIPSUB_TOPIC {
+                    // a new peer can only hear announcements once it has
+                    // subscribed to them
+                    self.nick_announce_due = true;
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
+                GossipsubEvent::Unsubscribed { peer_id, topic },
+            )) => {
+                if let Some(channel) = channel_from_topic(&topic) {
+                    if self.remove_channel_member(&channel, &peer_id) {
+                        return Ok(Some(ClientEvent::PeerLeftChannel {
+                            peer: peer_id,
+                            channel,
+                        }));
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted { id, result, .. },
+            )) => {
+                self.metrics.record_dht_query();
+
+                match result {
+                    QueryResult::GetRecord(Ok(get_record_ok)) => {
+                        for peer_record in get_record_ok.records {
+                            match self.handle_record(peer_record.record) {
+                                Ok(Some(event)) => {
+                                    self.pending_events.push_back(event)
+                                }
+                                Ok(None) => {}
+                                Err(err) => {
+                                    warn!("Could not handle DHT record: {err}");
+                                    self.pending_events
+                                        .push_back(ClientEvent::Error(err));
+                                }
+                            }
+                        }
+                    }
+                    QueryResult::GetProviders(Ok(get_providers_ok)) => {
+                        if let Err(err) =
+                            self.handle_providers(get_providers_ok)
+                        {
+                            warn!("Could not handle DHT providers: {err}");
+                        }
+                    }
+                    QueryResult::PutRecord(Ok(ok)) => {
+                        self.put_retries.remove(&ok.key);
+                    }
+                    QueryResult::PutRecord(Err(err)) => {
+                        warn!("Could not store DHT record: {err}");
+                        if self.handle_put_failure(err.key().clone()) {
+                            self.pending_events
+                                .push_back(ClientEvent::Error(err.into()));
+                        }
+                    }
+                    _ => {} // TODO log others
+                }
+
+                if let Some(peer) = self.nick_queries.remove(&id) {
+                    self.complete_nickname_query(peer);
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Mdns(event)) => match event {
+                MdnsEvent::Discovered(list) => {
+                    for (peer, multiaddr) in list {
+                        let behaviour = self.swarm.behaviour_mut();
+                        behaviour.gossipsub.add_explicit_peer(&peer);
+                        behaviour.kademlia.add_address(&peer, multiaddr);
+                    }
+                }
+                MdnsEvent::Expired(list) => {
+                    for (peer, multiaddr) in list {
+                        let behaviour = self.swarm.behaviour_mut();
+                        let has_node = behaviour
+                            .mdns
+                            .as_ref()
+                            .is_some_and(|mdns| mdns.has_node(&peer));
+                        if !has_node {
+                            behaviour.gossipsub.remove_explicit_peer(&peer);
+                        }
+                        behaviour.kademlia.remove_address(&peer, &multiaddr);
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Relay(event)) => match event {
+                relay::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal: false,
+                    ..
+                } => {
+                    info!("Relay {relay_peer_id} accepted our reservation");
+                }
+                relay::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                } => {
+                    warn!("Relay {relay_peer_id} refused reservation: {error}");
+                }
+                event => debug!("{event:?}"),
+            },
+            SwarmEvent::Behaviour(ComposedEvent::RelayServer(event)) => {
+                match event {
+                    relay_server::Event::ReservationReqAccepted {
+                        src_peer_id,
+                        renewed: false,
+                    } => {
+                        info!("Accepted reservation from {src_peer_id}");
+                    }
+                    relay_server::Event::CircuitReqAccepted {
+                        src_peer_id,
+                        dst_peer_id,
+                    } => {
+                        info!("Relaying from {src_peer_id} to {dst_peer_id}");
+                    }
+                    relay_server::Event::ReservationReqDenied {
+                        src_peer_id,
+                    } => {
+                        warn!("Denied reservation from {src_peer_id}");
+                    }
+                    event => debug!("{event:?}"),
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Rendezvous(event)) => {
+                match event {
+                    rendezvous::client::Event::Discovered {
+                        rendezvous_node,
+                        registrations,
+                        cookie,
+                    } => {
+                        self.rendezvous_points
+                            .insert(rendezvous_node, Some(cookie));
+                        for registration in registrations {
+                            let peer = registration.record.peer_id();
+                            if peer == self.peer_id() {
+                                continue;
+                            }
+
+                            let addresses = registration.record.addresses();
+                            let kademlia =
+                                &mut self.swarm.behaviour_mut().kademlia;
+                            for address in addresses {
+                                kademlia.add_address(&peer, address.clone());
+                            }
+                            if !self.connections.contains_key(&peer) {
+                                self.swarm.dial(peer)?;
+                            }
+                            self.pending_events.push_back(
+                                ClientEvent::PeerDiscovered {
+                                    peer,
+                                    addresses: addresses.to_vec(),
+                                },
+                            );
+                        }
+                    }
+                    rendezvous::client::Event::Registered {
+                        rendezvous_node,
+                        ..
+                    } => {
+                        info!("Registered at rendezvous {rendezvous_node}");
+                    }
+                    rendezvous::client::Event::RegisterFailed(error) => {
+                        warn!("Could not register at rendezvous: {error}");
+                    }
+                    event => debug!("{event:?}"),
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event {
+                peer,
+                result,
+            })) => match result {
+                Ok(ping::Success::Ping { rtt }) => {
+                    let samples = self.latencies.entry(peer).or_default();
+                    samples.push_back(rtt);
+                    if samples.len() > LATENCY_SAMPLES {
+                        samples.pop_front();
+                    }
+                }
+                Ok(ping::Success::Pong) => {}
+                Err(error) => {
+                    warn!("Ping to {peer} failed: {error}");
+                    return Ok(Some(ClientEvent::PingFailure { peer, error }));
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Identify(event)) => {
+                match event {
+                    IdentifyEvent::Received { peer_id, info } => {
+                        // learn addresses of peers beyond those found over mDNS
+                        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                        for address in &info.listen_addrs {
+                            kademlia.add_address(&peer_id, address.clone());
+                        }
+
+                        self.identities.insert(peer_id, info);
+                        return Ok(Some(ClientEvent::PeerIdentified(peer_id)));
+                    }
+                    IdentifyEvent::Error { peer_id, error } => {
+                        warn!("Could not identify {peer_id}: {error}");
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Direct(event)) => {
+                return Ok(self.handle_direct(event));
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Blob(event)) => {
+                return Ok(self.handle_blob(event));
+            }
+            SwarmEvent::Behaviour(ComposedEvent::History(event)) => {
+                self.handle_history(event);
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                let address = endpoint.get_remote_address().clone();
+                self.mark_seen(peer_id);
+                // only dialed addresses can be dialed again, unlike the
+                // ephemeral ports of inbound connections
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    let result = self.address_book.record(
+                        peer_id,
+                        address.clone(),
+                        timestamp_now(),
+                    );
+                    if let Err(err) = result {
+                        warn!("Could not save address book: {err}");
+                    }
+                }
+                let first = !self.connections.contains_key(&peer_id);
+                if first {
+                    self.send_hello(&peer_id);
+                }
+                self.connections
+                    .entry(peer_id)
+                    .or_default()
+                    .push(address.clone());
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, address);
+                if first && self.rendezvous_points.contains_key(&peer_id) {
+                    self.rendezvous_with(peer_id);
+                }
+                return Ok(Some(ClientEvent::PeerConnected(peer_id)));
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id, endpoint, ..
+            } => {
+                self.mark_seen(peer_id);
+                if let Some(addresses) = self.connections.get_mut(&peer_id) {
+                    let address = endpoint.get_remote_address();
+                    if let Some(idx) =
+                        addresses.iter().position(|a| a == address)
+                    {
+                        addresses.remove(idx);
+                    }
+                    if addresses.is_empty() {
+                        self.connections.remove(&peer_id);
+                    }
+                }
+
+                // gossipsub forgets the subscriptions of disconnected peers
+                // without telling us
+                if !self.connections.contains_key(&peer_id) {
+                    self.latencies.remove(&peer_id);
+                    self.hellos.remove(&peer_id);
+
+                    let channels = self
+                        .channel_members
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for channel in channels {
+                        if self.remove_channel_member(&channel, &peer_id) {
+                            self.pending_events.push_back(
+                                ClientEvent::PeerLeftChannel {
+                                    peer: peer_id,
+                                    channel,
+                                },
+                            );
+                        }
+                    }
+                }
+                return Ok(Some(ClientEvent::PeerDisconnected(peer_id)));
+            }
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {address}");
+                return Ok(Some(ClientEvent::ListeningOn(address)));
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                info!("No longer listening on {address}");
+                return Ok(Some(ClientEvent::StoppedListeningOn(address)));
+            }
+            SwarmEvent::Dialing(peer_id) => {
+                return Ok(Some(ClientEvent::Dialing(peer_id)));
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                return Ok(Some(ClientEvent::OutgoingConnectionError {
+                    peer_id,
+                    error,
+                }));
+            }
+            _ => {}
+        };
+
+        Ok(None)
+    }
+
+    fn handle_providers(
+        &mut self,
+        result: GetProvidersOk,
+    ) -> crate::Result<()> {
+        if !matches!(
+            MemoryKey::decode(&result.key.to_vec())?,
+            MemoryKey::Directory
+        ) {
+            return Ok(());
+        }
+
+        // look up which channels each provider advertises
+        for peer in result.providers {
+            if peer == self.peer_id() {
+                continue;
+            }
+
+            let key = Key::new(&MemoryKey::Listing(peer).encode()?);
+            self.swarm
+                .behaviour_mut()
+                .kademlia
+                .get_record(key, Quorum::One);
+        }
+
+        Ok(())
+    }
+
+    fn handle_record(
+        &mut self,
+        record: Record,
+    ) -> crate::Result<Option<ClientEvent>> {
+        let key = MemoryKey::decode(&record.key.to_vec())?;
+
+        // blobs are content-addressed rather than signed
+        if let MemoryKey::Blob(hash) = key {
+            if blob_hash(&record.value) != hash {
+                warn!("Blob does not match its hash in DHT!");
+                return Ok(None);
+            }
+
+            let waiting = self
+                .avatar_cache
+                .iter()
+                .filter(|(_, avatar)| avatar.as_ref() == Some(&hash))
+                .map(|(peer, _)| *peer)
+                .collect::<Vec<_>>();
+            self.blob_cache.insert(hash, record.value);
+
+            for peer in waiting {
+                self.pending_events
+                    .push_back(ClientEvent::AvatarFetched(peer));
+            }
+
+            return Ok(None);
+        }
+
+        let value = MemoryValue::decode(&record.value)?;
+
+        match (key, value) {
+            (
+                MemoryKey::Nickname(key),
+                MemoryValue::Nickname { user, nickname },
+            ) => {
+                if user != key {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                self.cache_nickname(key, nickname);
+            }
+            (
+                MemoryKey::Profile(key),
+                MemoryValue::Profile {
+                    user,
+                    nick,
+                    bio,
+                    pronouns,
+                    avatar_hash,
+                },
+            ) => {
+                let profile = Profile {
+                    nick,
+                    bio,
+                    pronouns,
+                    avatar_hash,
+                };
+                if user != key || !profile.is_valid() {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                self.cache_nickname(key, profile.nick.clone());
+                if let Some(hash) = &profile.avatar_hash {
+                    self.query_blob(hash)?;
+                    self.avatar_cache.insert(key, Some(hash.clone()));
+                }
+                self.profile_cache.insert(key, profile);
+
+                return Ok(Some(ClientEvent::ProfileFetched(key)));
+            }
+            (MemoryKey::Avatar(key), MemoryValue::Avatar { user, hash }) => {
+                if user != key {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                self.query_blob(&hash)?;
+                self.avatar_cache.insert(key, Some(hash));
+            }
+            (
+                MemoryKey::Listing(key),
+                MemoryValue::Listing { user, channels },
+            ) => {
+                if user != key
+                    || channels.len() > MAX_ADVERTISED_CHANNELS
+                    || !channels.iter().all(is_valid_channel_identifier)
+                {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                for ident in channels {
+                    if !self.discovered_channels.insert(ident.clone()) {
+                        continue;
+                    }
+
+                    match self.channel(&ident) {
+                        Some(channel) => {
+                            self.pending_events.push_back(
+                                ClientEvent::ChannelDiscovered(channel.clone()),
+                            );
+                        }
+                        None => {
+                            let key =
+                                Key::new(&MemoryKey::Channel(ident).encode()?);
+                            self.swarm
+                                .behaviour_mut()
+                                .kademlia
+                                .get_record(key, Quorum::One);
+                        }
+                    }
+                }
+            }
+            (
+                MemoryKey::Moderation(key),
+                MemoryValue::Moderation(moderation),
+            ) => {
+                if &key != moderation.channel() || !moderation.is_valid() {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                // the signature has been checked to be the owner's, and a
+                // record of a previous owner is only replaced by one of the
+                // current owner
+                let newer = match self.moderation.get(&key) {
+                    Some(known) if known.owner() == moderation.owner() => {
+                        known.version() < moderation.version()
+                    }
+                    Some(_) => self.channels.get(&key).is_some_and(|channel| {
+                        channel.owner() == moderation.owner()
+                    }),
+                    None => true,
+                };
+                if !newer {
+                    return Ok(None);
+                }
+                let owner = *moderation.owner();
+                self.moderation.insert(key.clone(), moderation);
+
+                // members store the record on the owner's behalf, so that it
+                // stays in the DHT while the owner is away
+                if owner != self.peer_id()
+                    && self.joined_channels.contains(&key)
+                {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .put_record(record, Quorum::One)?;
+                }
+            }
+            (MemoryKey::NickClaim(key), MemoryValue::NickClaim(claim)) => {
+                let verdict = check_timestamp(
+                    claim.claimed_at(),
+                    timestamp_now(),
+                    self.max_clock_skew,
+                );
+                if key != claim.nick()
+                    || !claim.is_valid()
+                    || verdict != TimestampVerdict::Plausible
+                {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+                let owner = *claim.owner();
+                if !self.trust_store.admits_claim(
+                    &key,
+                    &owner,
+                    claim.claimed_at(),
+                ) {
+                    warn!("Claim of {owner} to {key} predates its first use");
+                    return Ok(None);
+                }
+
+                match self.nick_claims.get(&key) {
+                    Some((known, _)) if *known == claim => return Ok(None),
+                    Some((known, value)) if known.precedes(&claim) => {
+                        // a later claim replaced the earliest in the DHT, so
+                        // store the earliest again
+                        let record = Record::new(record.key, value.clone());
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .put_record(record, Quorum::One)?;
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+
+                if owner != self.peer_id() && key == self.nick {
+                    warn!("Our nickname {key} is registered to {owner}");
+                }
+                // the claim bounds later claims to the nickname if it is the
+                // first seen of it
+                if let Err(err) =
+                    self.trust_store.observe(&key, owner, timestamp_now())
+                {
+                    warn!("Could not save trust store: {err}");
+                }
+                self.nick_claims.insert(key.clone(), (claim, record.value));
+                return Ok(Some(ClientEvent::NickRegistered {
+                    nick: key,
+                    owner,
+                }));
+            }
+            (MemoryKey::Channel(key), MemoryValue::Channel(channel)) => {
+                if &key != channel.identifier() {
+                    warn!("Possible key/value mismatch in DHT!");
+                    return Ok(None);
+                }
+
+                let discovered = self.discovered_channels.contains(&key)
+                    && self.channel(&key).is_none();
+
+                // the signature has been checked to be the owner's
+                let owner = *channel.owner();
+                if self.update_channel(channel.clone(), &owner) {
+                    if discovered {
+                        self.pending_events.push_back(
+                            ClientEvent::ChannelDiscovered(channel.clone()),
+                        );
+                    }
+                    return Ok(Some(ClientEvent::ChannelUpdated(channel)));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(None)
+    }
+
+    fn handle_direct(
+        &mut self,
+        event: RequestResponseEvent<Command, ()>,
+    ) -> Option<ClientEvent> {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                // drop the request without a response, as if we were offline
+                if self.is_blocked(&peer) {
+                    return None;
+                }
+
+                self.mark_seen(peer);
+                // the acknowledgement may fail if the peer has disconnected,
+                // but we still have the message
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .direct
+                    .send_response(channel, ());
+
+                match request {
+                    Command::Hello {
+                        protocol_version,
+                        capabilities,
+                    } => {
+                        if protocol_version < MIN_PROTOCOL_VERSION {
+                            warn!(
+                                "Disconnecting from {peer}, which speaks \
+                                 protocol version {protocol_version}"
+                            );
+                            let _ = self.swarm.disconnect_peer_id(peer);
+                            return Some(ClientEvent::PeerIncompatible {
+                                peer,
+                                protocol_version,
+                            });
+                        }
+
+                        self.hellos
+                            .insert(peer, (protocol_version, capabilities));
+                        self.send_channel_keys(&peer);
+
+                        // the peer may have joined our channels before it
+                        // told us it keeps their history
+                        let channels = self
+                            .history_syncs
+                            .keys()
+                            .filter(|channel| {
+                                self.channel_members
+                                    .get(*channel)
+                                    .is_some_and(|m| m.contains(&peer))
+                            })
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        for channel in channels {
+                            self.sync_history(&channel, &peer);
+                        }
+                        None
+                    }
+                    Command::ChannelKey {
+                        channel,
+                        epoch,
+                        key,
+                    } => {
+                        if self
+                            .channels
+                            .get(&channel)
+                            .is_none_or(|c| c.owner() != &peer)
+                        {
+                            warn!("Ignoring key to {channel} from non-owner");
+                            return None;
+                        }
+
+                        match ChannelKey::unwrap(
+                            &key,
+                            &self.id_keys,
+                            channel.clone(),
+                            epoch,
+                        ) {
+                            Ok(key) => self
+                                .channel_keys
+                                .entry(channel)
+                                .or_default()
+                                .insert(epoch, key),
+                            Err(err) => {
+                                warn!("Could not unwrap key to {channel}");
+                                return Some(ClientEvent::Error(err));
+                            }
+                        }
+                        None
+                    }
+                    Command::DirectMessage {
+                        contents,
+                        timestamp,
+                        message_type,
+                    } => {
+                        self.open_session_entry(SessionEntry::Direct(peer));
+                        Some(ClientEvent::DirectMessage {
+                            contents,
+                            timestamp,
+                            message_type,
+                            sender: peer,
+                            encrypted: false,
+                            muted: self.is_muted(&peer),
+                        })
+                    }
+                    Command::EncryptedDirectMessage {
+                        header,
+                        nonce,
+                        ciphertext,
+                    } => match self.decrypt_direct(
+                        &peer,
+                        &header,
+                        &nonce,
+                        &ciphertext,
+                    ) {
+                        Ok(Command::DirectMessage {
+                            contents,
+                            timestamp,
+                            message_type,
+                        }) => {
+                            self.open_session_entry(SessionEntry::Direct(peer));
+                            Some(ClientEvent::DirectMessage {
+                                contents,
+                                timestamp,
+                                message_type,
+                                sender: peer,
+                                encrypted: true,
+                                muted: self.is_muted(&peer),
+                            })
+                        }
+                        Ok(_) => {
+                            warn!("Ignoring unexpected encrypted command");
+                            None
+                        }
+                        Err(err) => {
+                            warn!("Could not decrypt message from {peer}");
+                            Some(ClientEvent::Error(err))
+                        }
+                    },
+                    Command::ChannelInvite { token } => {
+                        match Invite::verify(&token) {
+                            Ok((invite, inviter))
+                                if invite.invitee == self.peer_id() =>
+                            {
+                                Some(ClientEvent::ChannelInvited {
+                                    channel: invite.channel,
+                                    inviter,
+                                    expires_at: invite.expires_at,
+                                    token,
+                                })
+                            }
+                            _ => {
+                                warn!("Ignoring bad invitation from {peer}");
+                                None
+                            }
+                        }
+                    }
+                    _ => {
+                        warn!("Ignoring unexpected direct command from {peer}");
+                        None
+                    }
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } if self.hello_requests.remove(&request_id) => {
+                // old peers may not support greetings
+                info!("Could not greet {peer}: {error}");
+                None
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!("Could not send direct message to {peer}: {error}");
+                Some(ClientEvent::DirectMessageFailed {
+                    request: request_id,
+                    peer,
+                    error,
+                })
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("Could not receive direct message from {peer}: {error}");
+                None
+            }
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. },
+                ..
+            } => {
+                self.hello_requests.remove(&request_id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Record the sequence number of a message from a peer, returning whether
+    /// the message was seen before.
+    ///
+    /// Messages which are not numbered are never considered replays.
+    fn is_replay(
+        &mut self,
+        sender: PeerId,
+        channel: &ChannelIdentifier,
+        sequence: u64,
+    ) -> bool {
+        sequence > 0
+            && !self
+                .replay_windows
+                .entry((sender, channel.clone()))
+                .or_default()
+                .check(sequence)
+    }
+
+    /// Request an attachment from the sender of its message.
+    fn fetch_attachment(
+        &mut self,
+        sender: PeerId,
+        message: MessageId,
+        channel: ChannelIdentifier,
+        attachment: Attachment,
+    ) {
+        if self.blob_cache.contains_key(&attachment.hash) {
+            self.pending_events
+                .push_back(ClientEvent::AttachmentReceived {
+                    message,
+                    channel,
+                    attachment,
+                });
+            return;
         }
 
-        let key = Key::new(&MemoryKey::Nickname(*peer).encode()?);
-        self.swarm
+        let request = self
+            .swarm
             .behaviour_mut()
-            .kademlia
-            .get_record(key, Quorum::One);
-        self.nick_cache.insert(*peer, None);
-
-        Ok(&None)
+            .blob
+            .send_request(&sender, attachment.hash.clone());
+        self.attachment_requests
+            .insert(request, (message, channel, attachment));
     }
 
-    fn handle_event<OtherErr>(
+    fn handle_blob(
         &mut self,
-        event: SwarmEvent<
-            ComposedEvent,
-            EitherError<
-                EitherError<GossipsubHandlerError, std::io::Error>,
-                OtherErr,
-            >,
-        >,
-    ) -> crate::Result<Option<ClientEvent>> {
+        event: RequestResponseEvent<BlobHash, Option<Vec<u8>>>,
+    ) -> Option<ClientEvent> {
         match event {
-            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
-                GossipsubEvent::Message {
-                    propagation_source: source,
-                    message_id,
-                    message,
-                },
-            )) => return Ok(self.handle_message(message, message_id, source)),
-            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
-                KademliaEvent::OutboundQueryCompleted { result, .. },
-            )) => {
-                match result {
-                    QueryResult::GetRecord(Ok(get_record_ok)) => {
-                        for peer_record in get_record_ok.records {
-                            let record = peer_record.record;
-                            let key = MemoryKey::decode(&record.key.to_vec())?;
-                            let value = MemoryValue::decode(&record.value)?;
-
-                            match (key, value) {
-                                (
-                                    MemoryKey::Nickname(key),
-                                    MemoryValue::Nickname { user, nickname },
-                                ) => {
-                                    if user != key {
-                                        warn!("Possible key/value mismatch in DHT!");
-                                        return Ok(None);
-                                    }
-
-                                    self.nick_cache.insert(key, Some(nickname));
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    _ => {} // TODO log others
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                if !self.is_blocked(&peer) {
+                    let blob = self.blob_cache.get(&request).cloned();
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .blob
+                        .send_response(channel, blob);
                 }
+                None
             }
-            SwarmEvent::Behaviour(ComposedEvent::Mdns(event)) => match event {
-                MdnsEvent::Discovered(list) => {
-                    for (peer, multiaddr) in list {
-                        let behaviour = self.swarm.behaviour_mut();
-                        behaviour.gossipsub.add_explicit_peer(&peer);
-                        behaviour.kademlia.add_address(&peer, multiaddr);
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let (message, channel, attachment) =
+                    self.attachment_requests.remove(&request_id)?;
+                let name = &attachment.name;
+
+                // blobs are content-addressed, so what we receive can be
+                // checked against what we asked for
+                let err = match response {
+                    Some(data) if blob_hash(&data) == attachment.hash => {
+                        self.blob_cache.insert(attachment.hash.clone(), data);
+                        return Some(ClientEvent::AttachmentReceived {
+                            message,
+                            channel,
+                            attachment,
+                        });
                     }
-                }
-                MdnsEvent::Expired(list) => {
-                    for (peer, multiaddr) in list {
-                        let behaviour = self.swarm.behaviour_mut();
-                        if !behaviour.mdns.has_node(&peer) {
-                            behaviour.gossipsub.remove_explicit_peer(&peer);
-                        }
-                        behaviour.kademlia.remove_address(&peer, &multiaddr);
+                    Some(_) => {
+                        format!("attachment {name} does not match its hash")
                     }
-                }
-            },
-            SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                    None => format!("{peer} does not have attachment {name}"),
+                };
+                warn!("Could not fetch attachment: {err}");
+                Some(ClientEvent::Error(crate::Error::InvalidData(err)))
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
             } => {
-                self.swarm.behaviour_mut().kademlia.add_address(
-                    &peer_id,
-                    endpoint.get_remote_address().clone(),
-                );
-                return Ok(Some(ClientEvent::PeerConnected(peer_id)));
+                self.attachment_requests.remove(&request_id);
+                warn!("Could not fetch attachment from {peer}: {error}");
+                None
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                return Ok(Some(ClientEvent::PeerDisconnected(peer_id)));
+            _ => None,
+        }
+    }
+
+    /// Ask a peer in a channel we have joined for its history, unless enough
+    /// peers have been asked already.
+    fn sync_history(&mut self, channel: &ChannelIdentifier, peer: &PeerId) {
+        if !self.supports(peer, "history") || self.is_blocked(peer) {
+            return;
+        }
+
+        let asked = match self.history_syncs.get_mut(channel) {
+            Some(asked) => asked,
+            None => return,
+        };
+        if asked.len() >= HISTORY_SYNC_PEERS || !asked.insert(*peer) {
+            return;
+        }
+
+        let request = HistoryRequest {
+            channel: channel.clone(),
+            limit: MAX_HISTORY_LENGTH as u32,
+        };
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .history
+            .send_request(peer, request);
+        self.history_requests.insert(request_id, channel.clone());
+    }
+
+    fn handle_history(
+        &mut self,
+        event: RequestResponseEvent<HistoryRequest, Vec<HistoryEntry>>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                if self.is_blocked(&peer) {
+                    return;
+                }
+
+                // only peers which could read the channel are told of it
+                let HistoryRequest {
+                    channel: ident,
+                    limit,
+                } = request;
+                let entries = if self.is_admitted(&ident, &peer)
+                    && !self.expects_encryption(&ident)
+                {
+                    let limit = usize::min(limit as usize, MAX_HISTORY_LENGTH);
+                    self.history.recent(&ident, limit)
+                } else {
+                    vec![]
+                };
+
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .history
+                    .send_response(channel, entries);
             }
-            SwarmEvent::Dialing(peer_id) => {
-                return Ok(Some(ClientEvent::Dialing(peer_id)));
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let channel = match self.history_requests.remove(&request_id) {
+                    Some(channel) => channel,
+                    None => return,
+                };
+
+                for entry in response.into_iter().take(MAX_HISTORY_LENGTH) {
+                    self.handle_history_entry(&channel, entry, &peer);
+                }
             }
-            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
-                return Ok(Some(ClientEvent::OutgoingConnectionError {
-                    peer_id,
-                    error,
-                }));
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.history_requests.remove(&request_id);
+                info!("Could not sync history from {peer}: {error}");
             }
             _ => {}
+        }
+    }
+
+    /// Emit a message synced from the history of a channel, unless it has been
+    /// seen already.
+    fn handle_history_entry(
+        &mut self,
+        ident: &ChannelIdentifier,
+        entry: HistoryEntry,
+        peer: &PeerId,
+    ) {
+        let HistoryEntry {
+            id,
+            sender,
+            command,
+        } = entry;
+        if self.message_authors.contains_key(&id)
+            || self.history.contains(ident, &id)
+            || self.is_blocked(&sender)
+        {
+            return;
+        }
+
+        // the author of a signed message can be checked, but otherwise we can
+        // only take the peer's word for it
+        let (message, signature) = match command.clone() {
+            Command::SignedMessage { envelope } => {
+                match MessageSignature::verify(envelope) {
+                    Ok((cmd, signature)) if signature.author() == sender => {
+                        (cmd, Some(signature))
+                    }
+                    _ => {
+                        warn!("Ignoring badly signed history from {peer}");
+                        return;
+                    }
+                }
+            }
+            command => (command, None),
         };
+        // histories are not limited to a single gossipsub message, and carry
+        // messages which were sent in fragments
+        if !message.is_valid_within(MAX_LONG_MESSAGE_LENGTH) {
+            warn!("Ignoring invalid history from {peer}");
+            return;
+        }
+        let expires_at = message.expires_at();
+        if expires_at.is_some_and(|at| at <= timestamp_now()) {
+            return;
+        }
 
-        Ok(None)
+        match message {
+            Command::MessageSend {
+                contents,
+                channel,
+                timestamp,
+                message_type,
+                in_reply_to,
+                mentions,
+                attachment,
+                sequence: _,
+                clock,
+                expires_after: _,
+            } if &channel == ident => {
+                let local_clock =
+                    self.clocks.entry(channel.clone()).or_default();
+                *local_clock = u64::max(*local_clock, clock);
+                self.message_authors
+                    .insert(id.clone(), (channel.clone(), sender));
+                if let Some(expires_at) = expires_at {
+                    self.expirations
+                        .insert(id.clone(), (channel.clone(), expires_at));
+                }
+                self.history.record(
+                    channel.clone(),
+                    HistoryEntry {
+                        id: id.clone(),
+                        sender,
+                        command,
+                    },
+                );
+                self.store_message(
+                    &id,
+                    &channel,
+                    sender,
+                    timestamp,
+                    &contents,
+                    message_type,
+                );
+
+                self.pending_events.push_back(ClientEvent::Message {
+                    id,
+                    contents,
+                    channel,
+                    timestamp,
+                    message_type,
+                    in_reply_to,
+                    mentions,
+                    attachment,
+                    timestamp_verdict: check_timestamp(
+                        timestamp,
+                        timestamp_now(),
+                        self.max_clock_skew,
+                    ),
+                    order: CausalOrder { clock, sender },
+                    signature: signature.map(Box::new),
+                    sender,
+                    propagation_source: *peer,
+                    historical: true,
+                    muted: self.is_muted(&sender),
+                });
+            }
+            _ => warn!("Ignoring invalid history from {peer}"),
+        }
     }
 
     fn handle_message(
@@ -390,7 +5425,50 @@ impl Client {
         message_id: MessageId,
         source: PeerId,
     ) -> Option<ClientEvent> {
-        let acceptance;
+        let mut acceptance;
+
+        self.metrics.record_received(source, message.data.len());
+
+        // the author is the signed source of the message, rather than the
+        // peer which forwarded it to us
+        let Some(sender) = message.source else {
+            warn!("Rejecting message without a source from {source}");
+            self.swarm
+                .behaviour_mut()
+                .gossipsub
+                .report_message_validation_result(
+                    &message_id,
+                    &source,
+                    gossipsub::MessageAcceptance::Reject,
+                )
+                .expect("could not report message validation");
+            return None;
+        };
+        let origin = Origin {
+            author: sender,
+            propagation_source: source,
+            reassembled: false,
+        };
+
+        let verdict = self.rate_limiter.check(sender, Instant::now());
+        if verdict != RateVerdict::Allowed {
+            // the peer which forwarded the message is not to blame for its
+            // author's flood, so it is not penalized for it
+            debug!("Ignoring message from throttled peer {sender}");
+            self.swarm
+                .behaviour_mut()
+                .gossipsub
+                .report_message_validation_result(
+                    &message_id,
+                    &source,
+                    gossipsub::MessageAcceptance::Ignore,
+                )
+                .expect("could not report message validation");
+
+            // only tell of the peer once until it slows down
+            return (verdict == RateVerdict::Throttled)
+                .then_some(ClientEvent::PeerThrottled(sender));
+        }
 
         let evt = match Command::decode(&message.data) {
             Ok(cmd) => {
@@ -401,32 +5479,45 @@ impl Client {
                     acceptance = gossipsub::MessageAcceptance::Reject;
                 }
 
-                let sender = message.source.unwrap();
+                let blocked = self.is_blocked(&sender);
+                if !blocked {
+                    self.mark_seen(sender);
+                }
 
                 match cmd {
-                    Command::MessageSend {
-                        contents,
-                        channel,
-                        timestamp,
-                        message_type,
-                    } => Some(ClientEvent::Message {
-                        contents,
-                        channel,
-                        timestamp,
-                        message_type,
-                        sender,
-                    }),
-                    Command::NicknameUpdate { nick } => {
-                        self.nick_cache.insert(sender, Some(nick.clone()));
-                        Some(ClientEvent::UpdatedNickname { nick, sender })
+                    // messages from blocked peers may still be forwarded to us
+                    // by others
+                    _ if blocked => {
+                        acceptance = gossipsub::MessageAcceptance::Ignore;
+                        None
                     }
-                    _ => None,
+                    Command::Fragment {
+                        id,
+                        index,
+                        total,
+                        data,
+                    } => self.handle_fragment(
+                        origin,
+                        id,
+                        index,
+                        total,
+                        data,
+                        &mut acceptance,
+                    ),
+                    cmd => self.handle_command(
+                        cmd,
+                        message_id.clone(),
+                        origin,
+                        None,
+                        None,
+                        &mut acceptance,
+                    ),
                 }
             }
             Err(err) => {
                 warn!("Could not decode message, rejecting: {:x?}", err);
                 acceptance = gossipsub::MessageAcceptance::Reject;
-                None
+                Some(ClientEvent::Error(err))
             }
         };
 
@@ -438,31 +5529,619 @@ impl Client {
 
         evt
     }
+
+    /// Store a fragment of a message, handling the message once all of its
+    /// fragments have arrived.
+    fn handle_fragment(
+        &mut self,
+        origin: Origin,
+        id: u64,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+        acceptance: &mut gossipsub::MessageAcceptance,
+    ) -> Option<ClientEvent> {
+        let sender = origin.author;
+        let data = match self.reassembler.insert(sender, id, index, total, data)
+        {
+            Ok(Some(data)) => data,
+            Ok(None) => return None,
+            Err(err) => {
+                warn!("Ignoring fragment from {sender}: {err}");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                return None;
+            }
+        };
+
+        // the fragments themselves have been propagated already, so the
+        // verdict on the reassembled message is not reported
+        let mut inner_acceptance = gossipsub::MessageAcceptance::Accept;
+        let origin = Origin {
+            reassembled: true,
+            ..origin
+        };
+        match Command::decode(&data) {
+            Ok(Command::Fragment { .. }) => {
+                warn!("Ignoring nested fragments from {sender}");
+                None
+            }
+            Ok(cmd) => self.handle_command(
+                cmd,
+                message_id(Some(sender), &data),
+                origin,
+                None,
+                None,
+                &mut inner_acceptance,
+            ),
+            Err(err) => {
+                warn!("Could not decode reassembled message from {sender}");
+                Some(ClientEvent::Error(err))
+            }
+        }
+    }
+
+    /// Handle a command published by a peer, lowering its acceptance if it
+    /// should not be propagated.
+    ///
+    /// Messages which were signed by their author are handled along with the
+    /// verified signature, and those which were decrypted with the key of a
+    /// passphrase-protected channel along with that channel.
+    fn handle_command(
+        &mut self,
+        cmd: Command,
+        message_id: MessageId,
+        origin: Origin,
+        signature: Option<MessageSignature>,
+        decrypted: Option<ChannelIdentifier>,
+        acceptance: &mut gossipsub::MessageAcceptance,
+    ) -> Option<ClientEvent> {
+        let sender = origin.author;
+        // messages wrapped in others are only checked once unwrapped, and
+        // only those reassembled from fragments may be long
+        if !cmd.is_valid_within(origin.max_message_length()) {
+            warn!("Rejecting invalid message from {sender}");
+            *acceptance = gossipsub::MessageAcceptance::Reject;
+            return None;
+        }
+        let expires_at = cmd.expires_at();
+        match cmd {
+            Command::EncryptedMessage { .. } if decrypted.is_some() => {
+                warn!("Rejecting doubly encrypted message from {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Reject;
+                None
+            }
+            Command::EncryptedMessage {
+                channel,
+                nonce,
+                ciphertext,
+                epoch,
+            } => {
+                let key = self
+                    .channel_keys
+                    .get(&channel)
+                    .and_then(|keyring| keyring.get(epoch));
+                let inner = match key {
+                    Some(key) => key
+                        .decrypt(&nonce, &ciphertext)
+                        .and_then(|data| Command::decode(&data)),
+                    None => Err(crate::Error::EncryptionError),
+                };
+
+                match inner {
+                    Ok(cmd)
+                        if cmd.message_channel().is_some()
+                            || matches!(cmd, Command::SignedMessage { .. }) =>
+                    {
+                        self.handle_command(
+                            cmd,
+                            message_id,
+                            origin,
+                            signature,
+                            Some(channel),
+                            acceptance,
+                        )
+                    }
+                    Ok(_) => {
+                        warn!("Rejecting invalid encrypted command");
+                        *acceptance = gossipsub::MessageAcceptance::Reject;
+                        None
+                    }
+                    Err(_) => {
+                        // peers without the key still forward these
+                        Some(ClientEvent::UndecryptableMessage {
+                            id: message_id,
+                            channel,
+                            sender,
+                        })
+                    }
+                }
+            }
+            Command::SignedMessage { envelope } => {
+                match MessageSignature::verify(envelope) {
+                    Ok((_, signature)) if signature.author() != sender => {
+                        warn!(
+                            "Rejecting message from {sender} signed by another"
+                        );
+                        *acceptance = gossipsub::MessageAcceptance::Reject;
+                        None
+                    }
+                    Ok((cmd, signature)) => self.handle_command(
+                        cmd,
+                        message_id,
+                        origin,
+                        Some(signature),
+                        decrypted,
+                        acceptance,
+                    ),
+                    Err(err) => {
+                        warn!("Rejecting badly signed message from {sender}");
+                        *acceptance = gossipsub::MessageAcceptance::Reject;
+                        Some(ClientEvent::Error(err))
+                    }
+                }
+            }
+            _ if cmd
+                .message_channel()
+                .is_some_and(|channel| self.is_channel_archived(channel)) =>
+            {
+                warn!("Ignoring message from {sender} to archived channel");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            // whether the key of the channel was given out with a passphrase
+            // or by its owner, nothing may be sent to it in the clear
+            _ if cmd.message_channel().is_some_and(|channel| {
+                decrypted
+                    .as_ref()
+                    .map_or(self.expects_encryption(channel), |decrypted| {
+                        decrypted != channel
+                    })
+            }) =>
+            {
+                warn!("Ignoring unencrypted message from {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            _ if cmd
+                .message_channel()
+                .is_some_and(|channel| !self.is_admitted(channel, &sender)) =>
+            {
+                warn!("Ignoring message from {sender} outside of its channel");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            Command::MessageSend { ref channel, .. }
+            | Command::Poll { ref channel, .. }
+            | Command::Vote { ref channel, .. }
+                if self
+                    .check_policy(&cmd, channel, &sender, SLOW_MODE_TOLERANCE)
+                    .is_err() =>
+            {
+                warn!(
+                    "Ignoring message from {sender} against {channel} policy"
+                );
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            Command::MessageSend {
+                ref channel,
+                sequence,
+                ..
+            } if self.is_replay(sender, channel, sequence) => {
+                warn!("Ignoring replayed message {sequence} from {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            Command::MessageSend { .. }
+                if expires_at.is_some_and(|at| at <= timestamp_now()) =>
+            {
+                info!("Ignoring expired message from {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            Command::MessageSend {
+                contents,
+                channel,
+                timestamp,
+                message_type,
+                in_reply_to,
+                mentions,
+                attachment,
+                sequence,
+                clock,
+                expires_after,
+            } => {
+                // messages to encrypted channels are not kept, so as not to
+                // hand them out in the clear
+                if decrypted.is_none() {
+                    let command = match &signature {
+                        Some(signature) => Command::SignedMessage {
+                            envelope: signature.envelope().to_vec(),
+                        },
+                        None => Command::MessageSend {
+                            contents: contents.clone(),
+                            channel: channel.clone(),
+                            timestamp,
+                            message_type,
+                            in_reply_to: in_reply_to.clone(),
+                            mentions: mentions.clone(),
+                            attachment: attachment.clone(),
+                            sequence,
+                            clock,
+                            expires_after,
+                        },
+                    };
+                    let entry = HistoryEntry {
+                        id: message_id.clone(),
+                        sender,
+                        command,
+                    };
+                    self.history.record(channel.clone(), entry);
+                }
+
+                self.metrics.record_channel_message(&channel);
+                self.store_message(
+                    &message_id,
+                    &channel,
+                    sender,
+                    timestamp,
+                    &contents,
+                    message_type,
+                );
+                self.last_posted
+                    .insert((sender, channel.clone()), Instant::now());
+                let local_clock =
+                    self.clocks.entry(channel.clone()).or_default();
+                *local_clock = u64::max(*local_clock, clock);
+                self.message_authors
+                    .insert(message_id.clone(), (channel.clone(), sender));
+                if let Some(expires_at) = expires_at {
+                    self.expirations.insert(
+                        message_id.clone(),
+                        (channel.clone(), expires_at),
+                    );
+                }
+                if let Some(attachment) = &attachment {
+                    self.fetch_attachment(
+                        sender,
+                        message_id.clone(),
+                        channel.clone(),
+                        attachment.clone(),
+                    );
+                }
+
+                // the message itself must be emitted first, so it is
+                // queued rather than returned
+                let spam = self.spam_filter.check(
+                    sender,
+                    &contents,
+                    mentions.len(),
+                    Instant::now(),
+                );
+                if spam.is_some() {
+                    self.update_spam_score(&sender);
+                }
+
+                let muted = self.is_muted(&sender);
+                let mentioned = !muted
+                    && spam.is_none()
+                    && mentions.contains(&self.peer_id());
+                self.pending_events.push_back(ClientEvent::Message {
+                    id: message_id.clone(),
+                    contents,
+                    channel: channel.clone(),
+                    timestamp,
+                    message_type,
+                    in_reply_to,
+                    mentions,
+                    attachment,
+                    timestamp_verdict: check_timestamp(
+                        timestamp,
+                        timestamp_now(),
+                        self.max_clock_skew,
+                    ),
+                    order: CausalOrder { clock, sender },
+                    signature: signature.map(Box::new),
+                    sender,
+                    propagation_source: origin.propagation_source,
+                    historical: false,
+                    muted,
+                });
+                if let Some(kind) = spam {
+                    info!("Flagged message from {sender} as spam: {kind:?}");
+                    self.pending_events.push_back(ClientEvent::SpamDetected {
+                        id: message_id.clone(),
+                        channel: channel.clone(),
+                        sender,
+                        kind,
+                    });
+                }
+                mentioned.then(|| ClientEvent::Mentioned {
+                    id: message_id.clone(),
+                    channel,
+                    sender,
+                })
+            }
+            Command::MessageEdit {
+                target,
+                new_contents,
+                channel,
+            } => {
+                // we cannot tell whether edits of messages we have not
+                // seen are genuine, so they are not propagated either
+                let origin = (channel.clone(), sender);
+                if self.message_authors.get(&target) == Some(&origin) {
+                    self.history.edit(&channel, &target, &new_contents);
+                    self.store_edit(&target, &new_contents);
+                    Some(ClientEvent::MessageEdited {
+                        id: target,
+                        channel,
+                        new_contents,
+                        sender,
+                    })
+                } else {
+                    warn!("Ignoring edit of {target} by {sender}");
+                    *acceptance = gossipsub::MessageAcceptance::Ignore;
+                    None
+                }
+            }
+            Command::MessageDelete { target, channel } => {
+                let origin = (channel.clone(), sender);
+                if self.message_authors.get(&target) == Some(&origin) {
+                    self.forget_message(&channel, &target);
+                    Some(ClientEvent::MessageDeleted {
+                        id: target,
+                        channel,
+                        sender,
+                    })
+                } else {
+                    warn!("Ignoring deletion of {target} by {sender}");
+                    *acceptance = gossipsub::MessageAcceptance::Ignore;
+                    None
+                }
+            }
+            Command::NicknameUpdate { nick } => {
+                // nicknames are announced again periodically, so only
+                // changes are reported
+                let known = self.nick_cache.get(&sender).cloned().flatten();
+                self.cache_nickname(sender, nick.clone());
+                (known.as_ref() != Some(&nick))
+                    .then_some(ClientEvent::UpdatedNickname { nick, sender })
+            }
+            // a poll is only posted once, and would otherwise lose its votes
+            Command::Poll { closes_at, .. }
+                if self.polls.contains_key(&message_id)
+                    || closes_at < timestamp_now() =>
+            {
+                warn!("Ignoring replayed or closed poll from {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Ignore;
+                None
+            }
+            Command::Poll {
+                channel,
+                question,
+                options,
+                closes_at,
+            } => {
+                let poll = Poll::new(
+                    channel.clone(),
+                    sender,
+                    question.clone(),
+                    options.clone(),
+                    closes_at,
+                );
+                self.polls.insert(message_id.clone(), poll);
+
+                Some(ClientEvent::PollCreated {
+                    poll: message_id.clone(),
+                    channel,
+                    question,
+                    options,
+                    closes_at,
+                    sender,
+                })
+            }
+            Command::Vote {
+                channel,
+                poll: poll_id,
+                option,
+            } => match self.polls.get_mut(&poll_id) {
+                Some(poll) if poll.channel == channel => {
+                    if poll.add_vote(sender, option, timestamp_now()) {
+                        Some(ClientEvent::PollUpdated {
+                            poll: poll_id,
+                            channel,
+                            tally: poll.tally(),
+                        })
+                    } else {
+                        warn!("Ignoring repeated vote by {sender}");
+                        *acceptance = gossipsub::MessageAcceptance::Ignore;
+                        None
+                    }
+                }
+                _ => {
+                    warn!("Ignoring vote for unknown poll {poll_id}");
+                    None
+                }
+            },
+            Command::ChannelRequestJoin { channel, invite } => {
+                self.handle_membership_request(channel, sender, true, invite)
+            }
+            Command::ChannelRequestLeave { channel } => {
+                self.handle_membership_request(channel, sender, false, None)
+            }
+            Command::Goodbye { channels, reason } => {
+                for channel in channels {
+                    // forgetting the peer now keeps its unsubscription from
+                    // being reported again
+                    let was_member =
+                        self.remove_channel_member(&channel, &sender);
+                    if was_member && self.joined_channels.contains(&channel) {
+                        self.pending_events.push_back(ClientEvent::PeerLeft {
+                            peer: sender,
+                            channel,
+                            reason: reason.clone(),
+                        });
+                    }
+                }
+                None
+            }
+            Command::ChannelUpdate { channel } => {
+                let ident = channel.identifier().clone();
+                if self.channel(&ident).is_none() {
+                    // the owner is only learned from the record in the DHT,
+                    // rather than from whichever peer announces one first
+                    if let Err(err) = self.query_channel(&ident) {
+                        warn!("Could not look up channel {ident}: {err}");
+                    }
+                    None
+                } else if self.update_channel(channel.clone(), &sender) {
+                    Some(ClientEvent::ChannelUpdated(channel))
+                } else {
+                    warn!("Ignoring channel update from {sender}");
+                    None
+                }
+            }
+            Command::ChannelKick { channel, peer } => {
+                self.handle_removal(channel, peer, false, sender, acceptance)
+            }
+            Command::ChannelBan { channel, peer } => {
+                self.handle_removal(channel, peer, true, sender, acceptance)
+            }
+            Command::DirectMessage { .. }
+            | Command::Hello { .. }
+            | Command::ChannelInvite { .. }
+            | Command::ChannelKey { .. }
+            | Command::EncryptedDirectMessage { .. } => {
+                warn!("Rejecting direct command published by {sender}");
+                *acceptance = gossipsub::MessageAcceptance::Reject;
+                None
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Stream for Client {
-    type Item = Option<ClientEvent>;
+    type Item = ClientEvent;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        // TODO handle error...
-        Pin::new(&mut self.swarm)
-            .poll_next(cx)
-            .map(|e| e.map(|e| self.handle_event(e).unwrap_or(None)))
+    ) -> std::task::Poll<Option<Self::Item>> {
+        while self.schedule_tick.poll_tick(cx).is_ready() {
+            self.send_due_messages();
+            self.retry_outgoing_messages();
+            self.notify_channel_waiters();
+            self.expire_cooldowns();
+            self.expire_messages();
+            self.expire_polls();
+            self.reassembler.expire();
+            self.rate_limiter.expire(Instant::now());
+            for peer in self.spam_filter.expire(Instant::now()) {
+                self.update_spam_score(&peer);
+            }
+            self.announce_nick_if_due();
+            self.maintain_dht();
+            self.expire_nicknames();
+            self.refresh_rendezvous_if_due();
+        }
+
+        // many swarm events are handled internally without producing a client
+        // event, so keep polling until one is produced
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return std::task::Poll::Ready(Some(event));
+            }
+
+            match Pin::new(&mut self.swarm).poll_next(cx) {
+                std::task::Poll::Ready(Some(event)) => {
+                    match self.handle_event(event) {
+                        Ok(Some(event)) => self.pending_events.push_back(event),
+                        Ok(None) => {}
+                        Err(err) => self
+                            .pending_events
+                            .push_back(ClientEvent::Error(err)),
+                    }
+                }
+                std::task::Poll::Ready(None) => {
+                    return std::task::Poll::Ready(None)
+                }
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Publish the payloads of a message in order, removing each once it is
+/// published.
+fn publish_payloads(
+    gossipsub: &mut Gossipsub,
+    topic: &gossipsub::IdentTopic,
+    payloads: &mut VecDeque<Vec<u8>>,
+) -> Result<(), PublishError> {
+    while let Some(payload) = payloads.front() {
+        gossipsub.publish(topic.clone(), payload.clone())?;
+        payloads.pop_front();
     }
+
+    Ok(())
 }
 
 fn message_id_fn(
     message: &gossipsub::GossipsubMessage,
 ) -> gossipsub::MessageId {
+    message_id(message.source, &message.data)
+}
+
+/// Compute the identifier of a message from its author and contents.
+fn message_id(source: Option<PeerId>, data: &[u8]) -> gossipsub::MessageId {
     let mut hasher = DefaultHasher::new();
-    message.source.hash(&mut hasher);
-    message.data.hash(&mut hasher);
+    source.hash(&mut hasher);
+    data.hash(&mut hasher);
     gossipsub::MessageId::from(hasher.finish().to_string())
 }
 
+/// Create a signed DHT record of a peer's profile.
+fn profile_record(
+    peer_id: PeerId,
+    profile: &Profile,
+    id_keys: &Keypair,
+) -> crate::Result<Record> {
+    let key = Key::new(&MemoryKey::Profile(peer_id).encode()?);
+    let value = profile.to_value(peer_id).encode_signed(id_keys)?;
+
+    Ok(Record::new(key, value))
+}
+
+/// Build the command which kicks or bans a peer from a channel.
+fn removal_command(
+    channel: ChannelIdentifier,
+    peer: PeerId,
+    ban: bool,
+) -> Command {
+    if ban {
+        Command::ChannelBan { channel, peer }
+    } else {
+        Command::ChannelKick { channel, peer }
+    }
+}
+
+/// Get the current time, in milliseconds since the Unix epoch.
+fn timestamp_now() -> u64 {
+    to_millis(SystemTime::now())
+}
+
+/// Convert a time to milliseconds since the Unix epoch.
+fn to_millis(time: SystemTime) -> u64 {
+    // https://stackoverflow.com/questions/26593387
+    time.duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis()
+        .try_into()
+        .expect("time overflowed u64")
+}
+
 /// Generate a public/private Ed25519 keypair.
 pub fn gen_id_keys() -> Keypair {
     Keypair::generate_ed25519()
@@ -0,0 +1,158 @@
+use serde::{de::DeserializeOwned, Serialize};
+use unsigned_varint::{decode, encode};
+
+/// A serialization format for the data exchanged with peers.
+///
+/// Encodings are tagged with the code of their format within
+/// [multicodec](https://github.com/multiformats/multicodec), so that peers can
+/// tell which format to decode them with.
+pub trait Codec {
+    /// The multicodec code of the format.
+    const CODE: u64;
+
+    fn to_vec<T: Serialize>(value: &T) -> crate::Result<Vec<u8>>;
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> crate::Result<T>;
+
+    /// The multicodec code of the format, as an unsigned varint.
+    fn tag() -> Vec<u8> {
+        encode::u64(Self::CODE, &mut encode::u64_buffer()).to_vec()
+    }
+}
+
+/// The format data is encoded with, unless another is asked for.
+///
+/// This is msgpack, unless the `encode-cbor` or `encode-json` feature is
+/// enabled, which should only be done on networks where all peers can decode
+/// the format.
+#[cfg(not(any(feature = "encode-cbor", feature = "encode-json")))]
+pub type DefaultCodec = MessagePack;
+
+/// The format data is encoded with, unless another is asked for.
+#[cfg(feature = "encode-cbor")]
+pub type DefaultCodec = Cbor;
+
+/// The format data is encoded with, unless another is asked for.
+#[cfg(all(feature = "encode-json", not(feature = "encode-cbor")))]
+pub type DefaultCodec = Json;
+
+/// [MessagePack](https://msgpack.org), with indexed fixmaps to denote
+/// variants.
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    const CODE: u64 = 0x0201;
+
+    fn to_vec<T: Serialize>(value: &T) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> crate::Result<T> {
+        Ok(rmp_serde::from_read(data)?)
+    }
+}
+
+/// [CBOR](https://cbor.io).
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const CODE: u64 = 0x51;
+
+    fn to_vec<T: Serialize>(value: &T) -> crate::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(value, &mut data)?;
+        Ok(data)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> crate::Result<T> {
+        Ok(ciborium::de::from_reader(data)?)
+    }
+}
+
+/// JSON, which is larger than the binary formats but readable when debugging.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const CODE: u64 = 0x0200;
+
+    fn to_vec<T: Serialize>(value: &T) -> crate::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> crate::Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Encode a value, prefixed with the multicodec tag of its format.
+pub fn encode_tagged<C: Codec, T: Serialize>(
+    value: &T,
+) -> crate::Result<Vec<u8>> {
+    let mut encoded = C::tag();
+    encoded.extend(C::to_vec(value)?);
+    Ok(encoded)
+}
+
+/// Decode a value prefixed with the multicodec tag of any of the enabled
+/// formats.
+pub fn decode_tagged<T: DeserializeOwned>(encoded: &[u8]) -> crate::Result<T> {
+    let (code, data) = decode::u64(encoded).map_err(|_| {
+        crate::Error::InvalidData(String::from("missing multicodec tag"))
+    })?;
+
+    match code {
+        MessagePack::CODE => MessagePack::from_slice(data),
+        #[cfg(feature = "cbor")]
+        Cbor::CODE => Cbor::from_slice(data),
+        #[cfg(feature = "json")]
+        Json::CODE => Json::from_slice(data),
+        code => Err(crate::Error::UnsupportedCodec(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tagged_roundtrip() {
+        let value = (String::from("hello"), 64u64, vec![true, false]);
+
+        let encoded = encode_tagged::<MessagePack, _>(&value).unwrap();
+        assert_eq!(&encoded[..2], &[0x81, 0x04]);
+        assert_eq!(
+            decode_tagged::<(String, u64, Vec<bool>)>(&encoded).unwrap(),
+            value
+        );
+
+        #[cfg(feature = "cbor")]
+        {
+            let encoded = encode_tagged::<Cbor, _>(&value).unwrap();
+            assert_eq!(encoded[0], 0x51);
+            assert_eq!(
+                decode_tagged::<(String, u64, Vec<bool>)>(&encoded).unwrap(),
+                value
+            );
+        }
+
+        #[cfg(feature = "json")]
+        {
+            let encoded = encode_tagged::<Json, _>(&value).unwrap();
+            assert_eq!(&encoded[..2], &[0x80, 0x04]);
+            assert_eq!(
+                decode_tagged::<(String, u64, Vec<bool>)>(&encoded).unwrap(),
+                value
+            );
+        }
+
+        // bad: unknown format
+        assert!(decode_tagged::<u64>(&[0x55, 0x00]).is_err());
+
+        // bad: missing tag
+        assert!(decode_tagged::<u64>(&[]).is_err());
+    }
+}
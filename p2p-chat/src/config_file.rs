@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use libp2p::Multiaddr;
+use serde::Deserialize;
+
+use crate::protocol::ChannelIdentifier;
+
+/// Settings read from a TOML configuration file, any of which may be left
+/// out.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    pub nick: Option<String>,
+    /// The file in which the identity keypair is kept.
+    pub keyfile: Option<PathBuf>,
+    /// Peers to dial on startup.
+    pub bootstrap: Vec<Multiaddr>,
+    /// Addresses to listen on.
+    pub listen: Vec<Multiaddr>,
+    /// Channels to join on startup.
+    pub channels: Vec<ChannelIdentifier>,
+    /// Colors of the user interface, by the name of what they are used for.
+    pub theme: HashMap<String, String>,
+    /// Keys of the user interface, by the name of the action they perform.
+    pub keybindings: HashMap<String, String>,
+    /// Whether the times of messages are shown before them.
+    pub timestamps: Option<bool>,
+    /// How the times of messages are shown, either `short` for the time of
+    /// day or `full` for the date as well.
+    pub timestamp_format: Option<String>,
+}
+
+impl ConfigFile {
+    /// Get the path at which the configuration file is kept by default,
+    /// `p2p-chat/config.toml` within the configuration directory of the user.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("p2p-chat").join("config.toml"))
+    }
+
+    /// Load the configuration from a file.
+    pub fn load(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Load the configuration from the file at the default path, or the
+    /// default configuration if there is no such file.
+    pub fn load_default() -> crate::Result<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(path),
+            _ => Ok(ConfigFile::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            nick = "alice"
+            bootstrap = ["/ip4/127.0.0.1/tcp/4001"]
+            channels = ["general", "random"]
+            timestamp-format = "full"
+
+            [theme]
+            mention = "yellow"
+
+            [keybindings]
+            page-up = "ctrl-b"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.nick.as_deref(), Some("alice"));
+        assert_eq!(config.bootstrap.len(), 1);
+        assert!(config.listen.is_empty());
+        assert_eq!(config.channels, ["general", "random"]);
+        assert_eq!(config.timestamps, None);
+        assert_eq!(config.timestamp_format.as_deref(), Some("full"));
+        assert_eq!(config.theme["mention"], "yellow");
+        assert_eq!(config.keybindings["page-up"], "ctrl-b");
+
+        assert!(toml::from_str::<ConfigFile>("nickname = \"bob\"").is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::VecDeque;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::StaticSecret;
+
+use crate::protocol::ChannelIdentifier;
+
+/// The length of the keys used to encrypt data, in bytes.
+pub const KEY_LENGTH: usize = 32;
+
+/// The length of the nonces used to encrypt data, in bytes.
+pub const NONCE_LENGTH: usize = 24;
+
+/// The number of past keys of a channel which are kept, so that messages sent
+/// just before a key was rotated can still be read.
+pub const MAX_KEY_EPOCHS: usize = 4;
+
+/// The domain channel keys are derived in, which salts them along with the
+/// channel identifier so that channels sharing a passphrase get distinct keys.
+const CHANNEL_KEY_DOMAIN: &str = "p2p-chat-channel/";
+
+/// The domain keys are wrapped to peers in.
+const WRAPPED_KEY_DOMAIN: &str = "p2p-chat-wrapped-key/";
+
+/// The domain the fingerprints of peers are hashed in.
+const FINGERPRINT_DOMAIN: &str = "p2p-chat-fingerprint/";
+
+/// The domain the safety numbers of pairs of peers are hashed in.
+const SAFETY_NUMBER_DOMAIN: &str = "p2p-chat-safety-number/";
+
+/// The number of groups of five digits in a safety number.
+const SAFETY_NUMBER_GROUPS: usize = 6;
+
+/// The number of bytes of the hash of a peer's key shown in its fingerprint.
+const FINGERPRINT_LENGTH: usize = 10;
+
+/// The multihash code of identity hashes, which peer IDs of Ed25519 keys use.
+const MULTIHASH_IDENTITY: u64 = 0x00;
+
+/// Derive a key from a passphrase with Argon2id.
+pub(crate) fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+) -> crate::Result<[u8; KEY_LENGTH]> {
+    let mut key = [0; KEY_LENGTH];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| crate::Error::EncryptionError)?;
+    Ok(key)
+}
+
+/// Generate a random nonce.
+pub(crate) fn random_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Generate a new random X25519 secret key.
+pub(crate) fn random_secret() -> StaticSecret {
+    let mut secret = [0; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    StaticSecret::from(secret)
+}
+
+/// Encrypt data with XChaCha20-Poly1305.
+pub(crate) fn encrypt(
+    key: &[u8; KEY_LENGTH],
+    nonce: &[u8],
+    data: &[u8],
+    aad: &[u8],
+) -> crate::Result<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        return Err(crate::Error::InvalidData(String::from(
+            "nonce has the wrong length",
+        )));
+    }
+
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+        .map_err(|_| crate::Error::EncryptionError)
+}
+
+/// Decrypt data encrypted with [`encrypt`], failing if the key or associated
+/// data differ.
+pub(crate) fn decrypt(
+    key: &[u8; KEY_LENGTH],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> crate::Result<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        return Err(crate::Error::InvalidData(String::from(
+            "nonce has the wrong length",
+        )));
+    }
+
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| crate::Error::EncryptionError)
+}
+
+/// A symmetric key which the messages of a channel are encrypted with.
+#[derive(Clone)]
+pub(crate) struct ChannelKey {
+    channel: ChannelIdentifier,
+    key: [u8; KEY_LENGTH],
+}
+
+impl ChannelKey {
+    /// Derive the key of a channel from its passphrase.
+    pub(crate) fn derive(
+        channel: ChannelIdentifier,
+        passphrase: &str,
+    ) -> crate::Result<Self> {
+        let salt = format!("{CHANNEL_KEY_DOMAIN}{channel}");
+        let key = derive_key(passphrase, salt.as_bytes())?;
+        Ok(ChannelKey { channel, key })
+    }
+
+    /// Generate a new random key for a channel.
+    pub(crate) fn generate(channel: ChannelIdentifier) -> Self {
+        let mut key = [0; KEY_LENGTH];
+        rand::thread_rng().fill_bytes(&mut key);
+        ChannelKey { channel, key }
+    }
+
+    /// Encrypt data for the channel, returning the nonce and ciphertext.
+    pub(crate) fn encrypt(
+        &self,
+        data: &[u8],
+    ) -> crate::Result<(Vec<u8>, Vec<u8>)> {
+        let nonce = random_nonce();
+        let ciphertext =
+            encrypt(&self.key, &nonce, data, self.channel.as_bytes())?;
+        Ok((nonce, ciphertext))
+    }
+
+    /// Decrypt data sent to the channel, failing if it was encrypted with
+    /// another key or for another channel.
+    pub(crate) fn decrypt(
+        &self,
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> crate::Result<Vec<u8>> {
+        decrypt(&self.key, nonce, ciphertext, self.channel.as_bytes())
+    }
+
+    /// Encrypt this key to a peer, who alone may unwrap it with their identity
+    /// key.
+    ///
+    /// The key is encrypted with a secret agreed between a new ephemeral X25519
+    /// key and the X25519 form of the peer's Ed25519 identity key.
+    pub(crate) fn wrap(
+        &self,
+        recipient: &PeerId,
+        epoch: u64,
+    ) -> crate::Result<WrappedKey> {
+        let recipient = x25519_public_key(recipient)?;
+
+        let ephemeral = random_secret();
+        let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral);
+
+        let shared = ephemeral.diffie_hellman(&recipient);
+        let wrapping_key = wrapping_key(
+            shared.as_bytes(),
+            ephemeral_public.as_bytes(),
+            recipient.as_bytes(),
+        );
+
+        let nonce = random_nonce();
+        let aad = wrapped_key_aad(&self.channel, epoch);
+        let ciphertext = encrypt(&wrapping_key, &nonce, &self.key, &aad)?;
+
+        Ok(WrappedKey {
+            ephemeral: ephemeral_public.as_bytes().to_vec(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt a key wrapped to us with [`ChannelKey::wrap`].
+    pub(crate) fn unwrap(
+        wrapped: &WrappedKey,
+        id_keys: &Keypair,
+        channel: ChannelIdentifier,
+        epoch: u64,
+    ) -> crate::Result<Self> {
+        let secret = x25519_secret_key(id_keys)?;
+        let public = x25519_dalek::PublicKey::from(&secret);
+        let ephemeral =
+            x25519_dalek::PublicKey::from(to_key(&wrapped.ephemeral)?);
+
+        let shared = secret.diffie_hellman(&ephemeral);
+        let wrapping_key = wrapping_key(
+            shared.as_bytes(),
+            ephemeral.as_bytes(),
+            public.as_bytes(),
+        );
+
+        let aad = wrapped_key_aad(&channel, epoch);
+        let key = to_key(&decrypt(
+            &wrapping_key,
+            &wrapped.nonce,
+            &wrapped.ciphertext,
+            &aad,
+        )?)?;
+
+        Ok(ChannelKey { channel, key })
+    }
+}
+
+/// The key of a channel, encrypted to a single peer.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WrappedKey {
+    /// The public half of the ephemeral X25519 key the key was wrapped with.
+    #[serde(with = "serde_bytes")]
+    pub ephemeral: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl WrappedKey {
+    pub fn is_valid(&self) -> bool {
+        self.ephemeral.len() == KEY_LENGTH
+            && self.nonce.len() == NONCE_LENGTH
+            && !self.ciphertext.is_empty()
+            && self.ciphertext.len() <= 2 * KEY_LENGTH
+    }
+}
+
+/// The keys a channel has been encrypted with, identified by their epoch.
+///
+/// Only the newest [`MAX_KEY_EPOCHS`] keys are kept.
+#[derive(Clone, Default)]
+pub(crate) struct Keyring {
+    keys: VecDeque<(u64, ChannelKey)>,
+}
+
+impl Keyring {
+    /// Add a key to the ring, unless it is older than every key kept.
+    pub(crate) fn insert(&mut self, epoch: u64, key: ChannelKey) {
+        self.keys.retain(|(e, _)| *e != epoch);
+
+        let position = self.keys.partition_point(|(e, _)| *e < epoch);
+        if position == 0 && self.keys.len() >= MAX_KEY_EPOCHS {
+            return;
+        }
+        self.keys.insert(position, (epoch, key));
+
+        while self.keys.len() > MAX_KEY_EPOCHS {
+            self.keys.pop_front();
+        }
+    }
+
+    /// Get the newest key, along with its epoch.
+    pub(crate) fn current(&self) -> Option<(u64, &ChannelKey)> {
+        self.keys.back().map(|(epoch, key)| (*epoch, key))
+    }
+
+    /// Get the key of an epoch, if it is kept.
+    pub(crate) fn get(&self, epoch: u64) -> Option<&ChannelKey> {
+        self.keys
+            .iter()
+            .find(|(e, _)| *e == epoch)
+            .map(|(_, key)| key)
+    }
+}
+
+/// Get a short fingerprint of the public key of a peer, as groups of four hex
+/// digits, which two people can read out to one another to check that they
+/// see the same key.
+pub fn fingerprint(peer: &PeerId) -> String {
+    let hash = Sha256::new()
+        .chain(FINGERPRINT_DOMAIN)
+        .chain(peer.to_bytes())
+        .finalize();
+    hash[..FINGERPRINT_LENGTH]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Get the safety number of two peers, which both derive alike from the
+/// keys their direct message sessions are rooted in, as groups of five
+/// decimal digits.
+///
+/// Each peer is given along with the public key its side of the session was
+/// agreed with. The number does not depend on the order of the peers, so two
+/// people can read theirs out to one another to check that neither session
+/// was intercepted.
+pub fn safety_number(a: (&PeerId, &[u8]), b: (&PeerId, &[u8])) -> String {
+    let (a, b) = ((a.0.to_bytes(), a.1), (b.0.to_bytes(), b.1));
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let hash = [first, second]
+        .iter()
+        .fold(
+            Sha256::new().chain(SAFETY_NUMBER_DOMAIN),
+            |hash, (peer, key)| {
+                hash.chain((peer.len() as u64).to_be_bytes())
+                    .chain(peer)
+                    .chain((key.len() as u64).to_be_bytes())
+                    .chain(key)
+            },
+        )
+        .finalize();
+
+    // as in Signal, each group is taken from five bytes of the hash
+    hash.chunks(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Get the X25519 form of the Ed25519 public key of a peer.
+pub(crate) fn x25519_public_key(
+    peer: &PeerId,
+) -> crate::Result<x25519_dalek::PublicKey> {
+    let unsupported =
+        || crate::Error::InvalidData(String::from("peer has no Ed25519 key"));
+
+    let multihash = peer.as_ref();
+    if multihash.code() != MULTIHASH_IDENTITY {
+        return Err(unsupported());
+    }
+
+    match PublicKey::from_protobuf_encoding(multihash.digest()) {
+        Ok(PublicKey::Ed25519(key)) => {
+            let point = CompressedEdwardsY(key.encode())
+                .decompress()
+                .ok_or_else(unsupported)?;
+            Ok(x25519_dalek::PublicKey::from(
+                point.to_montgomery().to_bytes(),
+            ))
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+/// Get the X25519 form of an Ed25519 identity key.
+pub(crate) fn x25519_secret_key(
+    id_keys: &Keypair,
+) -> crate::Result<StaticSecret> {
+    match id_keys {
+        Keypair::Ed25519(keypair) => {
+            let hash = Sha512::digest(keypair.secret().as_ref());
+            Ok(StaticSecret::from(to_key(&hash[..KEY_LENGTH])?))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(crate::Error::InvalidData(String::from(
+            "identity key is not an Ed25519 key",
+        ))),
+    }
+}
+
+/// Derive the key a channel key is wrapped with from an X25519 shared secret.
+fn wrapping_key(
+    shared: &[u8],
+    ephemeral: &[u8],
+    recipient: &[u8],
+) -> [u8; KEY_LENGTH] {
+    Sha256::new()
+        .chain(WRAPPED_KEY_DOMAIN)
+        .chain(shared)
+        .chain(ephemeral)
+        .chain(recipient)
+        .finalize()
+        .into()
+}
+
+fn wrapped_key_aad(channel: &ChannelIdentifier, epoch: u64) -> Vec<u8> {
+    let mut aad = channel.as_bytes().to_vec();
+    aad.extend(epoch.to_be_bytes());
+    aad
+}
+
+pub(crate) fn to_key(data: &[u8]) -> crate::Result<[u8; KEY_LENGTH]> {
+    data.try_into().map_err(|_| {
+        crate::Error::InvalidData(String::from("key has the wrong length"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_key() {
+        let key = ChannelKey::derive("hello".to_owned(), "hunter2").unwrap();
+        let (nonce, ciphertext) = key.encrypt(b"secret").unwrap();
+
+        let same = ChannelKey::derive("hello".to_owned(), "hunter2").unwrap();
+        assert_eq!(same.decrypt(&nonce, &ciphertext).unwrap(), b"secret");
+
+        // bad: wrong passphrase
+        let wrong = ChannelKey::derive("hello".to_owned(), "hunter3").unwrap();
+        assert!(wrong.decrypt(&nonce, &ciphertext).is_err());
+
+        // bad: same passphrase, another channel
+        let other = ChannelKey::derive("world".to_owned(), "hunter2").unwrap();
+        assert!(other.decrypt(&nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_wrapped_key() {
+        let id_keys = Keypair::generate_ed25519();
+        let peer = id_keys.public().to_peer_id();
+
+        let key = ChannelKey::generate("hello".to_owned());
+        let (nonce, ciphertext) = key.encrypt(b"secret").unwrap();
+
+        let wrapped = key.wrap(&peer, 3).unwrap();
+        assert!(wrapped.is_valid());
+
+        let unwrapped =
+            ChannelKey::unwrap(&wrapped, &id_keys, "hello".to_owned(), 3)
+                .unwrap();
+        assert_eq!(unwrapped.decrypt(&nonce, &ciphertext).unwrap(), b"secret");
+
+        // bad: another peer
+        let other = Keypair::generate_ed25519();
+        assert!(ChannelKey::unwrap(&wrapped, &other, "hello".to_owned(), 3)
+            .is_err());
+
+        // bad: another epoch
+        assert!(
+            ChannelKey::unwrap(&wrapped, &id_keys, "hello".to_owned(), 4)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_keyring() {
+        let key = ChannelKey::generate("hello".to_owned());
+        let mut keyring = Keyring::default();
+        assert!(keyring.current().is_none());
+
+        for epoch in [2, 1, 4, 3, 5] {
+            keyring.insert(epoch, key.clone());
+        }
+        assert_eq!(keyring.current().map(|(epoch, _)| epoch), Some(5));
+        assert!(keyring.get(2).is_some());
+
+        // oldest key is dropped
+        assert!(keyring.get(1).is_none());
+
+        // keys older than those kept are not added
+        keyring.insert(0, key);
+        assert!(keyring.get(0).is_none());
+    }
+
+    #[test]
+    fn test_safety_number() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (a_key, b_key) = ([1; KEY_LENGTH], [2; KEY_LENGTH]);
+
+        let number = safety_number((&a, &a_key), (&b, &b_key));
+        assert_eq!(number, safety_number((&b, &b_key), (&a, &a_key)));
+        assert_ne!(number, safety_number((&a, &a_key), (&c, &b_key)));
+        assert_eq!(number.len(), 6 * 5 + 5);
+
+        // a session agreed with another key gives another number
+        let other_key = [3; KEY_LENGTH];
+        assert_ne!(number, safety_number((&a, &a_key), (&b, &other_key)));
+        assert_ne!(number, safety_number((&a, &b_key), (&b, &a_key)));
+    }
+}
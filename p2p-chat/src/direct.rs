@@ -0,0 +1,91 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+
+use crate::protocol::{Command, DIRECT_MESSAGE_PROTOCOL};
+
+/// The maximum size of an encoded direct command, in bytes.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// The request-response protocol over which direct commands are sent.
+#[derive(Clone, Debug)]
+pub(crate) struct DirectProtocol;
+
+impl ProtocolName for DirectProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        DIRECT_MESSAGE_PROTOCOL.as_bytes()
+    }
+}
+
+/// Encodes direct commands as length-prefixed msgpack, acknowledged with an
+/// empty response.
+#[derive(Clone, Default)]
+pub(crate) struct DirectCodec;
+
+#[async_trait]
+impl RequestResponseCodec for DirectCodec {
+    type Protocol = DirectProtocol;
+    type Request = Command;
+    type Response = ();
+
+    async fn read_request<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+    ) -> io::Result<Command>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+        Command::decode(&data).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, 0).await?;
+        Ok(())
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        command: Command,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = command.encode().map_err(invalid_data)?;
+        write_length_prefixed(io, data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        _: (),
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, []).await
+    }
+}
+
+fn invalid_data(err: crate::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
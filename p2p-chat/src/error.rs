@@ -1,8 +1,10 @@
 use std::io;
 
-use libp2p::noise;
+use libp2p::{gossipsub::MessageId, noise};
 use thiserror::Error;
 
+use crate::protocol::ChannelIdentifier;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Error, Debug)]
@@ -17,6 +19,10 @@ pub enum Error {
     SubscriptionError(#[from] libp2p::gossipsub::error::SubscriptionError),
     #[error("kademlia store error")]
     KademeliaStoreError(#[from] libp2p::kad::record::store::Error),
+    #[error("key decoding error")]
+    KeyDecodingError(#[from] libp2p::identity::error::DecodingError),
+    #[error("kademlia put record error")]
+    KademliaPutRecordError(#[from] libp2p::kad::PutRecordError),
     #[error("signing error")]
     SigningError(#[from] libp2p::identity::error::SigningError),
     #[error("signed envelope decoding error")]
@@ -33,10 +39,54 @@ pub enum Error {
     EncodeError(#[from] rmp_serde::encode::Error),
     #[error("decode error")]
     DecodeError(#[from] rmp_serde::decode::Error),
+    #[cfg(feature = "cbor")]
+    #[error("CBOR encode error")]
+    CborEncodeError(#[from] ciborium::ser::Error<io::Error>),
+    #[cfg(feature = "cbor")]
+    #[error("CBOR decode error")]
+    CborDecodeError(#[from] ciborium::de::Error<io::Error>),
+    #[cfg(feature = "json")]
+    #[error("JSON error")]
+    JsonError(#[from] serde_json::Error),
+    #[cfg(feature = "history-store")]
+    #[error("history store error")]
+    StoreError(#[from] sled::Error),
+    #[error("unsupported multicodec {0:#x}")]
+    UnsupportedCodec(u64),
     #[error("I/O error")]
     IoError(#[from] io::Error),
     #[error("invalid data received from peer")]
     InvalidData(String),
     #[error("signature mismatch")]
     SignatureMismatch,
+    #[error("channel {0} is archived")]
+    ChannelArchived(ChannelIdentifier),
+    #[error("channel {0} is not known")]
+    UnknownChannel(ChannelIdentifier),
+    #[error("channel {0} already exists")]
+    ChannelExists(ChannelIdentifier),
+    #[error("not the owner of channel {0}")]
+    NotChannelOwner(ChannelIdentifier),
+    #[error("not permitted to do this in channel {0}")]
+    NotPermitted(ChannelIdentifier),
+    #[error("message is not permitted by the policy of channel {0}")]
+    PolicyViolation(ChannelIdentifier),
+    #[error("channel {0} is in slow mode, wait before sending again")]
+    SlowMode(ChannelIdentifier),
+    #[error("no key to encrypt messages to channel {0} with yet")]
+    NoChannelKey(ChannelIdentifier),
+    #[error("not the author of message {0}")]
+    NotMessageAuthor(MessageId),
+    #[error("encryption error, the passphrase may be incorrect")]
+    EncryptionError,
+    #[error("invalid configuration: {0}")]
+    ConfigError(&'static str),
+    #[error("invalid configuration file: {0}")]
+    ConfigFileError(#[from] toml::de::Error),
+    #[error("invalid swarm key: {0}")]
+    SwarmKeyError(#[from] libp2p::pnet::KeyParseError),
+    #[error("nickname {0} is registered to another peer")]
+    NickTaken(String),
+    #[error("client task has shut down")]
+    ClientClosed,
 }
@@ -0,0 +1,711 @@
+#[cfg(feature = "history-store")]
+use std::ops::RangeBounds;
+use std::time::{Duration, SystemTime};
+
+use futures::StreamExt;
+use libp2p::{
+    gossipsub::MessageId, identify::IdentifyInfo, request_response::RequestId,
+    Multiaddr, PeerId,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::protocol::{
+    Channel, ChannelIdentifier, ChannelPolicy, MessageType, Profile, Role,
+};
+#[cfg(feature = "history-store")]
+use crate::StoredMessage;
+use crate::{
+    Client, ClientEvent, DmSession, KnownPeer, Metrics, PeerInfo, ScheduleId,
+};
+
+type Request = Box<dyn FnOnce(&mut Client) + Send>;
+
+impl Client {
+    /// Run this client on its own task, returning a handle with which to drive
+    /// it and a receiver for the events it emits.
+    ///
+    /// The task runs until every handle has been dropped.
+    pub fn spawn(
+        mut self,
+    ) -> (ClientHandle, mpsc::UnboundedReceiver<ClientEvent>) {
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel::<Request>();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    request = request_rx.recv() => match request {
+                        Some(request) => request(&mut self),
+                        None => break,
+                    },
+                    event = self.next() => match event {
+                        Some(event) => {
+                            // the receiver may have been dropped by a
+                            // frontend which only sends commands
+                            let _ = event_tx.send(event);
+                        }
+                        None => break,
+                    },
+                }
+            }
+        });
+
+        let handle = ClientHandle {
+            requests: request_tx,
+        };
+
+        (handle, event_rx)
+    }
+}
+
+/// A cheap, cloneable handle to a [`Client`] running on its own task.
+///
+/// See [`Client::spawn`].
+#[derive(Clone)]
+pub struct ClientHandle {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl ClientHandle {
+    /// Run a function with exclusive access to the client, returning its
+    /// result.
+    pub async fn call<T, F>(&self, f: F) -> crate::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Client) -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.requests
+            .send(Box::new(move |client| {
+                // the caller may have stopped waiting for the result
+                let _ = tx.send(f(client));
+            }))
+            .map_err(|_| crate::Error::ClientClosed)?;
+
+        rx.await.map_err(|_| crate::Error::ClientClosed)
+    }
+
+    /// See [`Client::subscribe_channel`].
+    pub async fn subscribe_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.subscribe_channel(ident))
+            .await?
+    }
+
+    /// See [`Client::join_channel_ready`].
+    pub async fn join_channel_ready(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<bool> {
+        let ready = self
+            .call(move |client| client.join_channel_ready(ident))
+            .await??;
+        Ok(ready.await)
+    }
+
+    /// See [`Client::unsubscribe_channel`].
+    pub async fn unsubscribe_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.unsubscribe_channel(ident))
+            .await?
+    }
+
+    /// See [`Client::leave_channel`].
+    pub async fn leave_channel(
+        &self,
+        ident: ChannelIdentifier,
+        reason: Option<String>,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.leave_channel(ident, reason))
+            .await?
+    }
+
+    /// See [`Client::send_message`].
+    pub async fn send_message(
+        &self,
+        message: String,
+        message_type: MessageType,
+        channel: ChannelIdentifier,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| {
+            client.send_message(&message, message_type, channel)
+        })
+        .await?
+    }
+
+    /// See [`Client::send_reply`].
+    pub async fn send_reply(
+        &self,
+        message: String,
+        message_type: MessageType,
+        channel: ChannelIdentifier,
+        in_reply_to: MessageId,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| {
+            client.send_reply(
+                &message,
+                message_type,
+                channel,
+                Some(in_reply_to),
+            )
+        })
+        .await?
+    }
+
+    /// See [`Client::send_attachment`].
+    pub async fn send_attachment(
+        &self,
+        channel: ChannelIdentifier,
+        name: String,
+        data: Vec<u8>,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| client.send_attachment(channel, &name, data))
+            .await?
+    }
+
+    /// See [`Client::set_message_lifetime`].
+    pub async fn set_message_lifetime(
+        &self,
+        channel: ChannelIdentifier,
+        lifetime: Option<Duration>,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.set_message_lifetime(channel, lifetime))
+            .await?
+    }
+
+    /// See [`Client::edit_message`].
+    pub async fn edit_message(
+        &self,
+        target: MessageId,
+        new_contents: String,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| client.edit_message(&target, &new_contents))
+            .await?
+    }
+
+    /// See [`Client::delete_message`].
+    pub async fn delete_message(
+        &self,
+        target: MessageId,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| client.delete_message(&target))
+            .await?
+    }
+
+    /// See [`Client::send_direct`].
+    pub async fn send_direct(
+        &self,
+        peer: PeerId,
+        message: String,
+        message_type: MessageType,
+    ) -> crate::Result<RequestId> {
+        self.call(move |client| {
+            client.send_direct(&peer, &message, message_type)
+        })
+        .await?
+    }
+
+    /// See [`Client::close_direct`].
+    pub async fn close_direct(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.close_direct(&peer)).await
+    }
+
+    /// See [`Client::schedule_message`].
+    pub async fn schedule_message(
+        &self,
+        channel: ChannelIdentifier,
+        contents: String,
+        message_type: MessageType,
+        send_at: SystemTime,
+    ) -> crate::Result<ScheduleId> {
+        self.call(move |client| {
+            client.schedule_message(channel, &contents, message_type, send_at)
+        })
+        .await?
+    }
+
+    /// See [`Client::cancel_scheduled_message`].
+    pub async fn cancel_scheduled_message(
+        &self,
+        id: ScheduleId,
+    ) -> crate::Result<bool> {
+        self.call(move |client| {
+            client.cancel_scheduled_message(id).map(|m| m.is_some())
+        })
+        .await?
+    }
+
+    /// See [`Client::create_channel`].
+    pub async fn create_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.create_channel(ident))
+            .await?
+    }
+
+    /// See [`Client::join_channel_with_invite`].
+    pub async fn join_channel_with_invite(
+        &self,
+        token: Vec<u8>,
+    ) -> crate::Result<ChannelIdentifier> {
+        self.call(move |client| client.join_channel_with_invite(token))
+            .await?
+    }
+
+    /// See [`Client::set_channel_invite_only`].
+    pub async fn set_channel_invite_only(
+        &self,
+        ident: ChannelIdentifier,
+        invite_only: bool,
+    ) -> crate::Result<()> {
+        self.call(move |client| {
+            client.set_channel_invite_only(ident, invite_only)
+        })
+        .await?
+    }
+
+    /// See [`Client::create_invite`].
+    pub async fn create_invite(
+        &self,
+        ident: ChannelIdentifier,
+        invitee: PeerId,
+        valid_for: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        self.call(move |client| client.create_invite(ident, invitee, valid_for))
+            .await?
+    }
+
+    /// See [`Client::send_invite`].
+    pub async fn send_invite(
+        &self,
+        ident: ChannelIdentifier,
+        invitee: PeerId,
+        valid_for: Duration,
+    ) -> crate::Result<RequestId> {
+        self.call(move |client| client.send_invite(ident, invitee, valid_for))
+            .await?
+    }
+
+    /// See [`Client::kick_peer`].
+    pub async fn kick_peer(
+        &self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.kick_peer(ident, peer))
+            .await?
+    }
+
+    /// See [`Client::ban_peer`].
+    pub async fn ban_peer(
+        &self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.ban_peer(ident, peer))
+            .await?
+    }
+
+    /// See [`Client::grant_role`].
+    pub async fn grant_role(
+        &self,
+        ident: ChannelIdentifier,
+        peer: PeerId,
+        role: Role,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.grant_role(ident, peer, role))
+            .await?
+    }
+
+    /// See [`Client::fingerprint`].
+    pub async fn fingerprint(&self, peer: PeerId) -> crate::Result<String> {
+        self.call(move |client| client.fingerprint(&peer)).await
+    }
+
+    /// See [`Client::trust_key`].
+    pub async fn trust_key(
+        &self,
+        nick: String,
+        peer: PeerId,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.trust_key(&nick, peer))
+            .await?
+    }
+
+    /// See [`Client::trusted_key`].
+    pub async fn trusted_key(
+        &self,
+        nick: String,
+    ) -> crate::Result<Option<PeerId>> {
+        self.call(move |client| client.trusted_key(&nick)).await
+    }
+
+    /// See [`Client::safety_number`].
+    pub async fn safety_number(&self, peer: PeerId) -> crate::Result<String> {
+        self.call(move |client| client.safety_number(&peer)).await?
+    }
+
+    /// See [`Client::mark_verified`].
+    pub async fn mark_verified(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.mark_verified(peer)).await
+    }
+
+    /// See [`Client::unmark_verified`].
+    pub async fn unmark_verified(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.unmark_verified(&peer)).await
+    }
+
+    /// See [`Client::is_verified`].
+    pub async fn is_verified(&self, peer: PeerId) -> crate::Result<bool> {
+        self.call(move |client| client.is_verified(&peer)).await
+    }
+
+    /// See [`Client::register_nick`].
+    pub async fn register_nick(&self) -> crate::Result<()> {
+        self.call(|client| client.register_nick()).await?
+    }
+
+    /// See [`Client::nick_registrant`].
+    pub async fn nick_registrant(
+        &self,
+        nick: String,
+    ) -> crate::Result<Option<PeerId>> {
+        self.call(move |client| client.nick_registrant(&nick)).await
+    }
+
+    /// See [`Client::channel_roles`].
+    pub async fn channel_roles(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<Vec<(PeerId, Role)>> {
+        self.call(move |client| client.channel_roles(&ident)).await
+    }
+
+    /// See [`Client::banned_peers`].
+    pub async fn banned_peers(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<Vec<PeerId>> {
+        self.call(move |client| client.banned_peers(&ident)).await
+    }
+
+    /// See [`Client::set_channel_encrypted`].
+    pub async fn set_channel_encrypted(
+        &self,
+        ident: ChannelIdentifier,
+        encrypted: bool,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.set_channel_encrypted(ident, encrypted))
+            .await?
+    }
+
+    /// See [`Client::archive_channel`].
+    pub async fn archive_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.archive_channel(ident))
+            .await?
+    }
+
+    /// See [`Client::set_channel_welcome`].
+    pub async fn set_channel_welcome(
+        &self,
+        ident: ChannelIdentifier,
+        welcome: Option<String>,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.set_channel_welcome(ident, welcome))
+            .await?
+    }
+
+    /// See [`Client::set_channel_policy`].
+    pub async fn set_channel_policy(
+        &self,
+        ident: ChannelIdentifier,
+        policy: ChannelPolicy,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.set_channel_policy(ident, policy))
+            .await?
+    }
+
+    /// See [`Client::create_poll`].
+    pub async fn create_poll(
+        &self,
+        channel: ChannelIdentifier,
+        question: String,
+        options: Vec<String>,
+        closes_at: SystemTime,
+    ) -> crate::Result<MessageId> {
+        self.call(move |client| {
+            client.create_poll(channel, &question, options, closes_at)
+        })
+        .await?
+    }
+
+    /// See [`Client::vote`].
+    pub async fn vote(
+        &self,
+        poll_id: MessageId,
+        option: usize,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.vote(&poll_id, option))
+            .await?
+    }
+
+    /// See [`Client::dial`].
+    pub async fn dial(&self, addr: Multiaddr) -> crate::Result<()> {
+        self.call(move |client| client.dial(addr)).await?
+    }
+
+    /// See [`Client::listen_on`].
+    pub async fn listen_on(&self, addr: Multiaddr) -> crate::Result<()> {
+        self.call(move |client| client.listen_on(addr)).await?
+    }
+
+    /// See [`Client::history`].
+    #[cfg(feature = "history-store")]
+    pub async fn history(
+        &self,
+        channel: ChannelIdentifier,
+        range: impl RangeBounds<u64> + Send + 'static,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        self.call(move |client| client.history(&channel, range))
+            .await?
+    }
+
+    /// See [`Client::history_page`].
+    #[cfg(feature = "history-store")]
+    pub async fn history_page(
+        &self,
+        channel: ChannelIdentifier,
+        before: MessageId,
+        limit: usize,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        self.call(move |client| client.history_page(&channel, &before, limit))
+            .await?
+    }
+
+    /// See [`Client::known_peers`].
+    pub async fn known_peers(&self) -> crate::Result<Vec<KnownPeer>> {
+        self.call(|client| client.known_peers()).await
+    }
+
+    /// See [`Client::redial_known_peers`].
+    pub async fn redial_known_peers(
+        &self,
+        limit: usize,
+    ) -> crate::Result<usize> {
+        self.call(move |client| client.redial_known_peers(limit))
+            .await
+    }
+
+    /// See [`Client::register_rendezvous`].
+    pub async fn register_rendezvous(
+        &self,
+        addr: Multiaddr,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.register_rendezvous(addr))
+            .await?
+    }
+
+    /// See [`Client::add_external_address`].
+    pub async fn add_external_address(
+        &self,
+        addr: Multiaddr,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.add_external_address(addr))
+            .await
+    }
+
+    /// See [`Client::fetch_nickname`].
+    pub async fn fetch_nickname(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<String>> {
+        self.call(move |client| client.fetch_nickname(&peer).cloned())
+            .await?
+    }
+
+    /// See [`Client::set_profile`].
+    pub async fn set_profile(
+        &self,
+        bio: Option<String>,
+        pronouns: Option<String>,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.set_profile(bio, pronouns))
+            .await?
+    }
+
+    /// See [`Client::fetch_profile`].
+    pub async fn fetch_profile(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<Profile>> {
+        self.call(move |client| {
+            client.fetch_profile(&peer).map(Option::<&_>::cloned)
+        })
+        .await?
+    }
+
+    /// See [`Client::resolve_nickname`].
+    pub async fn resolve_nickname(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<String>> {
+        let nick = self
+            .call(move |client| client.resolve_nickname(&peer))
+            .await??;
+        Ok(nick.await)
+    }
+
+    /// See [`Client::last_seen`].
+    pub async fn last_seen(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<SystemTime>> {
+        self.call(move |client| client.last_seen(&peer)).await
+    }
+
+    /// See [`Client::listeners`].
+    pub async fn listeners(&self) -> crate::Result<Vec<Multiaddr>> {
+        self.call(|client| client.listeners()).await
+    }
+
+    /// See [`Client::connected_peers`].
+    pub async fn connected_peers(&self) -> crate::Result<Vec<PeerInfo>> {
+        self.call(|client| client.connected_peers()).await
+    }
+
+    /// See [`Client::metrics`].
+    pub async fn metrics(&self) -> crate::Result<Metrics> {
+        self.call(|client| client.metrics()).await
+    }
+
+    /// See [`Client::whois`].
+    pub async fn whois(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<IdentifyInfo>> {
+        self.call(move |client| client.whois(&peer).cloned()).await
+    }
+
+    /// See [`Client::capabilities`].
+    pub async fn capabilities(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<Vec<String>>> {
+        self.call(move |client| client.capabilities(&peer).map(<[_]>::to_vec))
+            .await
+    }
+
+    /// See [`Client::advertise_channel`].
+    pub async fn advertise_channel(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<()> {
+        self.call(move |client| client.advertise_channel(ident))
+            .await?
+    }
+
+    /// See [`Client::list_public_channels`].
+    pub async fn list_public_channels(&self) -> crate::Result<Vec<Channel>> {
+        self.call(|client| {
+            client
+                .list_public_channels()
+                .map(|channels| channels.into_iter().cloned().collect())
+        })
+        .await?
+    }
+
+    /// See [`Client::dm_session`].
+    pub async fn dm_session(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<DmSession>> {
+        self.call(move |client| client.dm_session(&peer)).await
+    }
+
+    /// See [`Client::peer_score`].
+    pub async fn peer_score(&self, peer: PeerId) -> crate::Result<Option<f64>> {
+        self.call(move |client| client.peer_score(&peer)).await
+    }
+
+    /// See [`Client::latency`].
+    pub async fn latency(
+        &self,
+        peer: PeerId,
+    ) -> crate::Result<Option<Duration>> {
+        self.call(move |client| client.latency(&peer)).await
+    }
+
+    /// See [`Client::channel_members`].
+    pub async fn channel_members(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<Vec<PeerId>> {
+        self.call(move |client| client.channel_members(&ident))
+            .await
+    }
+
+    /// See [`Client::mesh_peers`].
+    pub async fn mesh_peers(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<Vec<PeerId>> {
+        self.call(move |client| client.mesh_peers(&ident)).await
+    }
+
+    /// See [`Client::channel_clock`].
+    pub async fn channel_clock(
+        &self,
+        ident: ChannelIdentifier,
+    ) -> crate::Result<u64> {
+        self.call(move |client| client.channel_clock(&ident)).await
+    }
+
+    /// See [`Client::disconnect`].
+    pub async fn disconnect(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.disconnect(&peer)).await
+    }
+
+    /// See [`Client::block_peer`].
+    pub async fn block_peer(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.block_peer(peer)).await
+    }
+
+    /// See [`Client::unblock_peer`].
+    pub async fn unblock_peer(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.unblock_peer(&peer)).await
+    }
+
+    /// See [`Client::mute_peer`].
+    pub async fn mute_peer(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.mute_peer(peer)).await
+    }
+
+    /// See [`Client::unmute_peer`].
+    pub async fn unmute_peer(&self, peer: PeerId) -> crate::Result<()> {
+        self.call(move |client| client.unmute_peer(&peer)).await
+    }
+
+    /// See [`Client::peer_id`].
+    pub async fn peer_id(&self) -> crate::Result<PeerId> {
+        self.call(|client| client.peer_id()).await
+    }
+
+    /// See [`Client::set_nick`].
+    pub async fn set_nick(&self, nick: String) -> crate::Result<()> {
+        self.call(move |client| client.set_nick(&nick)).await?
+    }
+
+    /// See [`Client::nick`].
+    pub async fn nick(&self) -> crate::Result<String> {
+        self.call(|client| client.nick().clone()).await
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
+    gossipsub::MessageId,
+    request_response::RequestResponseCodec,
+};
+
+use crate::protocol::{
+    ChannelIdentifier, Command, HistoryEntry, HistoryRequest,
+    HISTORY_SYNC_PROTOCOL, MAX_HISTORY_LENGTH, MAX_LONG_MESSAGE_LENGTH,
+};
+
+/// The maximum size of an encoded history request, in bytes.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// The maximum size of an encoded history, in bytes, allowing for the overhead
+/// of encoding each message.
+const MAX_RESPONSE_SIZE: usize =
+    MAX_HISTORY_LENGTH * (MAX_LONG_MESSAGE_LENGTH + 1024);
+
+/// The request-response protocol over which channel histories are synced.
+#[derive(Clone, Debug)]
+pub(crate) struct HistoryProtocol;
+
+impl ProtocolName for HistoryProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        HISTORY_SYNC_PROTOCOL.as_bytes()
+    }
+}
+
+/// Encodes requests for the recent messages of a channel, answered with as
+/// many of them as the peer has kept.
+#[derive(Clone, Default)]
+pub(crate) struct HistoryCodec;
+
+#[async_trait]
+impl RequestResponseCodec for HistoryCodec {
+    type Protocol = HistoryProtocol;
+    type Request = HistoryRequest;
+    type Response = Vec<HistoryEntry>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &HistoryProtocol,
+        io: &mut T,
+    ) -> io::Result<HistoryRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+        rmp_serde::from_read(data.as_slice()).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &HistoryProtocol,
+        io: &mut T,
+    ) -> io::Result<Vec<HistoryEntry>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+        rmp_serde::from_read(data.as_slice()).map_err(invalid_data)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &HistoryProtocol,
+        io: &mut T,
+        request: HistoryRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = rmp_serde::to_vec(&request).map_err(invalid_data)?;
+        write_length_prefixed(io, data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &HistoryProtocol,
+        io: &mut T,
+        entries: Vec<HistoryEntry>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = rmp_serde::to_vec(&entries).map_err(invalid_data)?;
+        write_length_prefixed(io, data).await
+    }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// The most recent messages of each channel, which are shared with peers
+/// who join it later.
+#[derive(Default)]
+pub(crate) struct History {
+    channels: HashMap<ChannelIdentifier, VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    /// Keep a message, forgetting the oldest of its channel if there are too
+    /// many.
+    pub(crate) fn record(
+        &mut self,
+        channel: ChannelIdentifier,
+        entry: HistoryEntry,
+    ) {
+        let entries = self.channels.entry(channel).or_default();
+        if entries.iter().any(|kept| kept.id == entry.id) {
+            return;
+        }
+
+        entries.push_back(entry);
+        if entries.len() > MAX_HISTORY_LENGTH {
+            entries.pop_front();
+        }
+    }
+
+    /// Get whether or not a message of a channel has been kept.
+    pub(crate) fn contains(
+        &self,
+        channel: &ChannelIdentifier,
+        id: &MessageId,
+    ) -> bool {
+        self.channels
+            .get(channel)
+            .is_some_and(|entries| entries.iter().any(|kept| &kept.id == id))
+    }
+
+    /// Get up to a number of the most recent messages of a channel, oldest
+    /// first.
+    pub(crate) fn recent(
+        &self,
+        channel: &ChannelIdentifier,
+        limit: usize,
+    ) -> Vec<HistoryEntry> {
+        let entries = match self.channels.get(channel) {
+            Some(entries) => entries,
+            None => return vec![],
+        };
+
+        let skip = entries.len().saturating_sub(limit);
+        entries.iter().skip(skip).cloned().collect()
+    }
+
+    /// Forget a message of a channel.
+    pub(crate) fn remove(
+        &mut self,
+        channel: &ChannelIdentifier,
+        id: &MessageId,
+    ) {
+        if let Some(entries) = self.channels.get_mut(channel) {
+            entries.retain(|kept| &kept.id != id);
+        }
+    }
+
+    /// Replace the contents of a kept message of a channel with those of an
+    /// edit.
+    ///
+    /// Signed messages cannot be changed without breaking their signature,
+    /// so they are forgotten instead, rather than handed out as they were.
+    pub(crate) fn edit(
+        &mut self,
+        channel: &ChannelIdentifier,
+        id: &MessageId,
+        new_contents: &str,
+    ) {
+        let Some(entries) = self.channels.get_mut(channel) else {
+            return;
+        };
+        entries.retain_mut(|kept| match &mut kept.command {
+            _ if &kept.id != id => true,
+            Command::MessageSend { contents, .. } => {
+                *contents = new_contents.to_owned();
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Forget the messages of a channel.
+    pub(crate) fn forget(&mut self, channel: &ChannelIdentifier) {
+        self.channels.remove(channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::protocol::MessageType;
+
+    fn entry(id: usize) -> HistoryEntry {
+        HistoryEntry {
+            id: MessageId::from(id.to_string()),
+            sender: PeerId::random(),
+            command: Command::NicknameUpdate {
+                nick: "hello".to_owned(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let channel = "hello".to_owned();
+        let mut history = History::default();
+
+        for id in 0..MAX_HISTORY_LENGTH + 10 {
+            history.record(channel.clone(), entry(id));
+        }
+        // duplicates are not kept twice
+        history.record(channel.clone(), entry(MAX_HISTORY_LENGTH + 9));
+
+        let recent = history.recent(&channel, MAX_HISTORY_LENGTH * 2);
+        assert_eq!(recent.len(), MAX_HISTORY_LENGTH);
+        assert_eq!(recent[0].id, MessageId::from("10"));
+
+        let recent = history.recent(&channel, 2);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[1].id, entry(MAX_HISTORY_LENGTH + 9).id);
+
+        assert!(!history.contains(&channel, &MessageId::from("0")));
+        assert!(history.contains(&channel, &MessageId::from("10")));
+
+        history.remove(&channel, &MessageId::from("10"));
+        assert!(!history.contains(&channel, &MessageId::from("10")));
+
+        history.forget(&channel);
+        assert!(history.recent(&channel, 10).is_empty());
+    }
+
+    #[test]
+    fn test_history_edit() {
+        let channel = "hello".to_owned();
+        let mut history = History::default();
+
+        let message = HistoryEntry {
+            id: MessageId::from("message"),
+            sender: PeerId::random(),
+            command: Command::MessageSend {
+                contents: "helo".to_owned(),
+                channel: channel.clone(),
+                timestamp: 0,
+                message_type: MessageType::Normal,
+                in_reply_to: None,
+                mentions: vec![],
+                attachment: None,
+                sequence: 0,
+                clock: 0,
+                expires_after: None,
+            },
+        };
+        history.record(channel.clone(), message.clone());
+        history.record(channel.clone(), entry(1));
+
+        history.edit(&channel, &message.id, "hello");
+        match &history.recent(&channel, 10)[0].command {
+            Command::MessageSend { contents, .. } => {
+                assert_eq!(contents, "hello")
+            }
+            command => panic!("unexpected command {command:?}"),
+        }
+
+        // messages which cannot be changed are forgotten
+        history.edit(&channel, &entry(1).id, "hello");
+        assert!(!history.contains(&channel, &entry(1).id));
+        assert!(history.contains(&channel, &message.id));
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+
+use libp2p::{gossipsub::MessageId, PeerId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::protocol::{ChannelIdentifier, MessageType};
+use crate::storage::StorageKey;
+
+/// The prefix of the names of the trees in which the messages of each channel
+/// are kept.
+const CHANNEL_TREE_PREFIX: &str = "channel/";
+
+/// The name of the tree mapping message IDs to where the messages are kept.
+const INDEX_TREE: &str = "index";
+
+/// The name of the tree holding what is needed to decrypt the store.
+const META_TREE: &str = "meta";
+
+/// The key under which the salt of the key of an encrypted store is kept.
+const SALT_KEY: &str = "salt";
+
+/// The key under which a value encrypted with the key of an encrypted store is
+/// kept, to tell whether a passphrase is the right one.
+const CHECK_KEY: &str = "check";
+
+/// A message kept in a [`HistoryStore`].
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StoredMessage {
+    pub id: MessageId,
+    pub channel: ChannelIdentifier,
+    pub sender: PeerId,
+    /// The time the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub contents: String,
+    pub message_type: MessageType,
+}
+
+/// The messages of every channel which were sent or received, kept on disk so
+/// that they outlive the session.
+///
+/// The messages of each channel are ordered by timestamp. If the store is
+/// encrypted, only their timestamps and IDs are kept in the clear.
+pub struct HistoryStore {
+    db: sled::Db,
+    index: sled::Tree,
+    key: Option<StorageKey>,
+}
+
+impl HistoryStore {
+    /// Open the store at a given path, creating it if it does not exist.
+    ///
+    /// If a passphrase is given, the store is encrypted with a key derived
+    /// from it. A store must always be opened with the passphrase it was
+    /// created with, or with none if it was created without one.
+    pub fn open(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<Self> {
+        let db = sled::open(path)?;
+        let index = db.open_tree(INDEX_TREE)?;
+        let meta = db.open_tree(META_TREE)?;
+
+        let key = match (meta.get(SALT_KEY)?, passphrase) {
+            (Some(salt), Some(passphrase)) => {
+                let key = StorageKey::derive(passphrase, salt.to_vec())?;
+                let check = meta
+                    .get(CHECK_KEY)?
+                    .ok_or(crate::Error::EncryptionError)?;
+                key.decrypt(&check)?;
+                Some(key)
+            }
+            (Some(_), None) => {
+                return Err(crate::Error::ConfigError(
+                    "history store is encrypted, but no passphrase was given",
+                ));
+            }
+            (None, Some(_)) if !index.is_empty() => {
+                return Err(crate::Error::ConfigError(
+                    "history store was created without encryption",
+                ));
+            }
+            (None, Some(passphrase)) => {
+                let key = StorageKey::generate(passphrase)?;
+                meta.insert(CHECK_KEY, key.encrypt(&[])?)?;
+                meta.insert(SALT_KEY, key.salt())?;
+                Some(key)
+            }
+            (None, None) => None,
+        };
+
+        Ok(HistoryStore { db, index, key })
+    }
+
+    /// Keep a message, replacing any message with the same ID.
+    pub fn insert(&self, message: &StoredMessage) -> crate::Result<()> {
+        let key = message_key(message.timestamp, &message.id);
+        self.channel_tree(&message.channel)?
+            .insert(&key, self.encode(message)?)?;
+        self.index
+            .insert(&message.id.0, self.encode(&(&message.channel, key))?)?;
+        Ok(())
+    }
+
+    /// Replace the contents of a kept message, such as when it is edited.
+    ///
+    /// Returns whether the message was kept.
+    pub fn update(
+        &self,
+        id: &MessageId,
+        contents: &str,
+    ) -> crate::Result<bool> {
+        let (tree, key) = match self.locate(id)? {
+            Some(location) => location,
+            None => return Ok(false),
+        };
+        let mut message: StoredMessage = match tree.get(&key)? {
+            Some(value) => self.decode(&value)?,
+            None => return Ok(false),
+        };
+        message.contents = contents.to_owned();
+        tree.insert(key, self.encode(&message)?)?;
+        Ok(true)
+    }
+
+    /// Forget a kept message, such as when it is deleted or has expired.
+    pub fn remove(&self, id: &MessageId) -> crate::Result<()> {
+        if let Some((tree, key)) = self.locate(id)? {
+            tree.remove(key)?;
+        }
+        self.index.remove(&id.0)?;
+        Ok(())
+    }
+
+    /// Get the kept messages of a channel sent within a range of timestamps,
+    /// in milliseconds since the Unix epoch, oldest first.
+    pub fn range(
+        &self,
+        channel: &ChannelIdentifier,
+        range: impl RangeBounds<u64>,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        // keys begin with the timestamp, so messages sent at the end of the
+        // range are included by ending before the next millisecond
+        let start = match range.start_bound() {
+            Bound::Included(start) => Some(*start),
+            Bound::Excluded(start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.checked_add(1),
+            Bound::Excluded(end) => Some(*end),
+            Bound::Unbounded => None,
+        };
+        let start = match start {
+            Some(start) => start.to_be_bytes(),
+            None => return Ok(Vec::new()),
+        };
+
+        let tree = self.channel_tree(channel)?;
+        let entries = match end {
+            Some(end) if end.to_be_bytes() <= start => return Ok(Vec::new()),
+            Some(end) => tree.range(start..end.to_be_bytes()),
+            None => tree.range(start..),
+        };
+        entries.values().map(|value| self.decode(&value?)).collect()
+    }
+
+    /// Get up to `limit` kept messages of a channel which come before a given
+    /// message, oldest first.
+    ///
+    /// Passing the oldest message of one page gets the page before it. No
+    /// messages are returned if the given message is not kept in the channel.
+    pub fn page(
+        &self,
+        channel: &ChannelIdentifier,
+        before: &MessageId,
+        limit: usize,
+    ) -> crate::Result<Vec<StoredMessage>> {
+        let key = match self.index.get(&before.0)? {
+            Some(value) => {
+                let (kept_in, key): (ChannelIdentifier, Vec<u8>) =
+                    self.decode(&value)?;
+                if &kept_in != channel {
+                    return Ok(Vec::new());
+                }
+                key
+            }
+            None => return Ok(Vec::new()),
+        };
+
+        let mut messages = self
+            .channel_tree(channel)?
+            .range(..key)
+            .values()
+            .rev()
+            .take(limit)
+            .map(|value| self.decode(&value?))
+            .collect::<crate::Result<Vec<StoredMessage>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Forget all kept messages of a channel.
+    pub fn forget(&self, channel: &ChannelIdentifier) -> crate::Result<()> {
+        let tree = self.channel_tree(channel)?;
+        for key in tree.iter().keys() {
+            let key = key?;
+            self.index.remove(&key[8..])?;
+        }
+        tree.clear()?;
+        Ok(())
+    }
+
+    fn channel_tree(
+        &self,
+        channel: &ChannelIdentifier,
+    ) -> crate::Result<sled::Tree> {
+        // the names of channels are not kept in the clear either
+        let name = match &self.key {
+            Some(key) => key.hash_name(channel),
+            None => channel.clone(),
+        };
+        Ok(self.db.open_tree(format!("{CHANNEL_TREE_PREFIX}{name}"))?)
+    }
+
+    /// Encode a value to be kept, encrypting it if the store is encrypted.
+    fn encode(&self, value: &impl Serialize) -> crate::Result<Vec<u8>> {
+        let data = rmp_serde::to_vec(value)?;
+        match &self.key {
+            Some(key) => key.encrypt(&data),
+            None => Ok(data),
+        }
+    }
+
+    /// Decode a kept value, decrypting it if the store is encrypted.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> crate::Result<T> {
+        match &self.key {
+            Some(key) => Ok(rmp_serde::from_slice(&key.decrypt(data)?)?),
+            None => Ok(rmp_serde::from_slice(data)?),
+        }
+    }
+
+    /// Find the tree and key of a kept message.
+    fn locate(
+        &self,
+        id: &MessageId,
+    ) -> crate::Result<Option<(sled::Tree, Vec<u8>)>> {
+        match self.index.get(&id.0)? {
+            Some(value) => {
+                let (channel, key): (ChannelIdentifier, Vec<u8>) =
+                    self.decode(&value)?;
+                Ok(Some((self.channel_tree(&channel)?, key)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The key of a message within the tree of its channel, which orders messages
+/// by timestamp.
+fn message_key(timestamp: u64, id: &MessageId) -> Vec<u8> {
+    let mut key = timestamp.to_be_bytes().to_vec();
+    key.extend_from_slice(&id.0);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Close a store, waiting for its writes to reach the disk so that it may
+    /// be opened again right away.
+    fn close(store: HistoryStore) {
+        store.db.flush().unwrap();
+        drop(store);
+    }
+
+    #[test]
+    fn test_history_store() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-history-{}", std::process::id()));
+        let store = HistoryStore::open(&path, None).unwrap();
+        let channel = ChannelIdentifier::from("general");
+        let sender = PeerId::random();
+
+        for timestamp in [30, 10, 20] {
+            let message = StoredMessage {
+                id: MessageId::from(timestamp.to_string()),
+                channel: channel.clone(),
+                sender,
+                timestamp,
+                contents: format!("message {timestamp}"),
+                message_type: MessageType::Normal,
+            };
+            store.insert(&message).unwrap();
+        }
+
+        let all = store.range(&channel, ..).unwrap();
+        let timestamps = all.iter().map(|m| m.timestamp).collect::<Vec<_>>();
+        assert_eq!(timestamps, [10, 20, 30]);
+        assert_eq!(store.range(&channel, 15..=30).unwrap().len(), 2);
+        assert_eq!(store.range(&channel, 15..30).unwrap().len(), 1);
+        assert!(store.range(&String::from("other"), ..).unwrap().is_empty());
+
+        let page = store.page(&channel, &MessageId::from("30"), 1).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].timestamp, 20);
+        let page = store.page(&channel, &page[0].id, 5).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].timestamp, 10);
+        assert!(store.page(&channel, &page[0].id, 5).unwrap().is_empty());
+
+        let id = MessageId::from("20");
+        assert!(store.update(&id, "edited").unwrap());
+        assert_eq!(
+            store.range(&channel, 20..=20).unwrap()[0].contents,
+            "edited"
+        );
+
+        store.remove(&id).unwrap();
+        assert!(!store.update(&id, "edited").unwrap());
+        assert_eq!(store.range(&channel, ..).unwrap().len(), 2);
+
+        store.forget(&channel).unwrap();
+        assert!(store.range(&channel, ..).unwrap().is_empty());
+
+        drop(store);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_history_store() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-history-sealed-{}", std::process::id()));
+        let store = HistoryStore::open(&path, Some("hunter2")).unwrap();
+        let message = StoredMessage {
+            id: MessageId::from("1"),
+            channel: ChannelIdentifier::from("general"),
+            sender: PeerId::random(),
+            timestamp: 1,
+            contents: String::from("secret"),
+            message_type: MessageType::Normal,
+        };
+        store.insert(&message).unwrap();
+        close(store);
+
+        // neither the wrong passphrase nor none at all open the store
+        assert!(matches!(
+            HistoryStore::open(&path, Some("hunter3")),
+            Err(crate::Error::EncryptionError)
+        ));
+        assert!(matches!(
+            HistoryStore::open(&path, None),
+            Err(crate::Error::ConfigError(_))
+        ));
+
+        let store = HistoryStore::open(&path, Some("hunter2")).unwrap();
+        let messages = store.range(&message.channel, ..).unwrap();
+        assert_eq!(messages[0].contents, "secret");
+
+        drop(store);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
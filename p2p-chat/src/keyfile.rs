@@ -0,0 +1,50 @@
+use std::{fs, path::Path};
+
+use libp2p::{identity::Keypair, pnet::PreSharedKey};
+
+use crate::storage::Sealed;
+
+/// Save an identity keypair to a file, encrypted with a passphrase.
+pub fn save_id_keys(
+    id_keys: &Keypair,
+    path: impl AsRef<Path>,
+    passphrase: &str,
+) -> crate::Result<()> {
+    let sealed = Sealed::seal(&id_keys.to_protobuf_encoding()?, passphrase)?;
+    fs::write(path, rmp_serde::to_vec(&sealed)?)?;
+    Ok(())
+}
+
+/// Load an identity keypair from a file created by [`save_id_keys`].
+pub fn load_id_keys(
+    path: impl AsRef<Path>,
+    passphrase: &str,
+) -> crate::Result<Keypair> {
+    let sealed: Sealed = rmp_serde::from_read(fs::File::open(path)?)?;
+    Ok(Keypair::from_protobuf_encoding(&sealed.open(passphrase)?)?)
+}
+
+/// Load the pre-shared key of a private network from a swarm key file, in
+/// the format used by IPFS.
+pub fn load_swarm_key(path: impl AsRef<Path>) -> crate::Result<PreSharedKey> {
+    Ok(fs::read_to_string(path)?.parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_swarm_key() {
+        let path = std::env::temp_dir().join("p2p-chat-test-swarm.key");
+        let key =
+            format!("/key/swarm/psk/1.0.0/\n/base16/\n{}", "ab".repeat(32));
+        fs::write(&path, key).unwrap();
+        assert!(load_swarm_key(&path).is_ok());
+
+        fs::write(&path, "/key/swarm/psk/1.0.0/\n/base16/\nabcd").unwrap();
+        assert!(load_swarm_key(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
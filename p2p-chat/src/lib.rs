@@ -1,7 +1,65 @@
 mod client;
 pub use client::*;
 
+mod handle;
+pub use handle::*;
+
 pub mod protocol;
 
+mod codec;
+pub use codec::*;
+
+mod crypto;
+pub use crypto::*;
+
+mod ratchet;
+pub use ratchet::*;
+
+mod address_book;
+pub use address_book::*;
+
+mod blob;
+
+mod block_list;
+pub use block_list::*;
+
+mod direct;
+
+mod history;
+
+#[cfg(feature = "history-store")]
+mod history_store;
+#[cfg(feature = "history-store")]
+pub use history_store::*;
+
+mod rate_limit;
+
+mod spam;
+pub use spam::*;
+
+mod session;
+pub use session::*;
+
+mod trust;
+pub use trust::*;
+
+mod config_file;
+pub use config_file::*;
+
+mod keyfile;
+pub use keyfile::*;
+
+mod storage;
+pub use storage::*;
+
+mod metrics;
+pub use metrics::*;
+
+mod poll;
+pub use poll::*;
+
+mod schedule;
+pub use schedule::*;
+
 mod error;
 pub use error::*;
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+
+use crate::protocol::ChannelIdentifier;
+
+/// A snapshot of the traffic handled by a [`Client`](crate::Client).
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// The total number of bytes received over all connections.
+    pub bytes_received: u64,
+    /// The total number of bytes sent over all connections.
+    pub bytes_sent: u64,
+    /// Traffic received from each peer.
+    pub peers: HashMap<PeerId, PeerMetrics>,
+    /// The number of chat messages sent and received in each channel.
+    pub channel_messages: HashMap<ChannelIdentifier, u64>,
+    /// The number of DHT queries which have completed.
+    pub dht_queries: u64,
+}
+
+/// The traffic received from a single peer.
+///
+/// Only gossipsub messages are counted, by the peer which forwarded them to
+/// us rather than their author.
+#[derive(Clone, Debug, Default)]
+pub struct PeerMetrics {
+    /// The number of messages received from the peer.
+    pub messages_received: u64,
+    /// The total size of the messages received from the peer, in bytes.
+    pub bytes_received: u64,
+}
+
+impl Metrics {
+    /// Record a gossipsub message of a given size received from a peer.
+    pub(crate) fn record_received(&mut self, peer: PeerId, bytes: usize) {
+        let peer = self.peers.entry(peer).or_default();
+        peer.messages_received += 1;
+        peer.bytes_received += bytes as u64;
+    }
+
+    /// Record a chat message sent or received in a channel.
+    pub(crate) fn record_channel_message(
+        &mut self,
+        channel: &ChannelIdentifier,
+    ) {
+        *self.channel_messages.entry(channel.clone()).or_default() += 1;
+    }
+
+    /// Record the completion of a DHT query.
+    pub(crate) fn record_dht_query(&mut self) {
+        self.dht_queries += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_received() {
+        let mut metrics = Metrics::default();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        metrics.record_received(alice, 10);
+        metrics.record_received(alice, 5);
+        metrics.record_received(bob, 1);
+
+        let alice_metrics = &metrics.peers[&alice];
+        assert_eq!(alice_metrics.messages_received, 2);
+        assert_eq!(alice_metrics.bytes_received, 15);
+        assert_eq!(metrics.peers[&bob].bytes_received, 1);
+    }
+
+    #[test]
+    fn test_record_channel_message() {
+        let mut metrics = Metrics::default();
+
+        metrics.record_channel_message(&"hello".to_owned());
+        metrics.record_channel_message(&"hello".to_owned());
+        metrics.record_channel_message(&"world".to_owned());
+
+        assert_eq!(metrics.channel_messages["hello"], 2);
+        assert_eq!(metrics.channel_messages["world"], 1);
+    }
+}
@@ -0,0 +1,132 @@
+use libp2p::PeerId;
+
+use crate::protocol::ChannelIdentifier;
+
+/// A poll posted to a channel, along with the votes received for it.
+#[derive(Clone, Debug)]
+pub struct Poll {
+    pub channel: ChannelIdentifier,
+    pub creator: PeerId,
+    pub question: String,
+    pub options: Vec<String>,
+    /// The time at which voting closes, in milliseconds since the Unix epoch.
+    pub closes_at: u64,
+    /// The votes counted, in order of arrival, as the voter, the chosen
+    /// option, and the time of arrival.
+    votes: Vec<(PeerId, usize, u64)>,
+}
+
+impl Poll {
+    pub fn new(
+        channel: ChannelIdentifier,
+        creator: PeerId,
+        question: String,
+        options: Vec<String>,
+        closes_at: u64,
+    ) -> Self {
+        Poll {
+            channel,
+            creator,
+            question,
+            options,
+            closes_at,
+            votes: Vec::new(),
+        }
+    }
+
+    /// Record a vote received at a given time, returning whether it was
+    /// counted.
+    ///
+    /// Only the first vote of each peer is kept, so long as it is for an
+    /// option of the poll and arrived before the poll closed, so that a peer
+    /// voting over and over cannot grow the poll.
+    pub fn add_vote(&mut self, voter: PeerId, option: usize, at: u64) -> bool {
+        if option >= self.options.len()
+            || self.is_closed(at)
+            || self.has_voted(&voter)
+        {
+            return false;
+        }
+
+        self.votes.push((voter, option, at));
+        true
+    }
+
+    /// Get whether or not a peer has voted in the poll.
+    pub fn has_voted(&self, voter: &PeerId) -> bool {
+        self.votes.iter().any(|(v, _, _)| v == voter)
+    }
+
+    /// Get whether or not voting has closed at a given time.
+    pub fn is_closed(&self, now: u64) -> bool {
+        now > self.closes_at
+    }
+
+    /// Count the votes for each option.
+    pub fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for (_, option, _) in &self.votes {
+            counts[*option] += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_poll() -> Poll {
+        Poll::new(
+            "hello".to_owned(),
+            PeerId::random(),
+            "lunch?".to_owned(),
+            vec!["pizza".to_owned(), "tacos".to_owned()],
+            100,
+        )
+    }
+
+    #[test]
+    fn test_tally() {
+        let mut poll = test_poll();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        poll.add_vote(alice, 0, 10);
+        poll.add_vote(bob, 1, 20);
+
+        assert_eq!(poll.tally(), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_tally_one_vote_per_peer() {
+        let mut poll = test_poll();
+        let alice = PeerId::random();
+
+        assert!(poll.add_vote(alice, 0, 10));
+        assert!(!poll.add_vote(alice, 1, 20));
+        assert!(!poll.add_vote(alice, 1, 30));
+
+        assert_eq!(poll.tally(), vec![1, 0]);
+        assert!(poll.has_voted(&alice));
+        assert_eq!(poll.votes.len(), 1);
+    }
+
+    #[test]
+    fn test_tally_invalid_votes() {
+        let mut poll = test_poll();
+
+        // bad: no such option
+        assert!(!poll.add_vote(PeerId::random(), 2, 10));
+
+        // bad: poll already closed
+        assert!(!poll.add_vote(PeerId::random(), 0, 200));
+
+        assert_eq!(poll.tally(), vec![0, 0]);
+
+        // an invalid vote does not keep the peer from voting again
+        let alice = PeerId::random();
+        assert!(!poll.add_vote(alice, 2, 10));
+        assert!(poll.add_vote(alice, 1, 20));
+        assert_eq!(poll.tally(), vec![0, 1]);
+    }
+}
@@ -1,251 +1,2652 @@
-use libp2p::{core::SignedEnvelope, gossipsub, identity::Keypair, PeerId};
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use libp2p::{
+    core::{signed_envelope::ReadPayloadError, SignedEnvelope},
+    gossipsub::{self, MessageId},
+    identity::Keypair,
+    multihash::{Code, MultihashDigest},
+    PeerId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::codec::{
+    decode_tagged, encode_tagged, Codec, DefaultCodec, MessagePack,
+};
+use crate::crypto::{WrappedKey, NONCE_LENGTH};
+use crate::ratchet::RatchetHeader;
 
 // NOTE u128 not supported in msgpack
 
 /// The gossipsub topic for top-level network communication.
 pub const DEFAULT_GOSSIPSUB_TOPIC: &str = "/p2p-chat";
 
+/// The protocol version advertised to peers over identify.
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/p2p-chat/0.1.0";
+
+/// The version of the chat protocol spoken by this implementation.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest version of the chat protocol this implementation can talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// The optional features supported by this implementation, advertised to
+/// peers so that they may avoid features we do not understand.
+pub const CAPABILITIES: &[&str] = &[
+    "direct",
+    "edit",
+    "delete",
+    "reply",
+    "mentions",
+    "formatted",
+    "attachments",
+    "encrypted-dm",
+    "history",
+];
+
+/// The maximum number of capabilities a peer may advertise.
+pub const MAX_CAPABILITIES: usize = 64;
+
+/// The maximum length of a capability name, in characters.
+pub const MAX_CAPABILITY_LENGTH: usize = 32;
+
+/// The request-response protocol name used for direct messages.
+pub const DIRECT_MESSAGE_PROTOCOL: &str = "/p2p-chat/dm/0.1.0";
+
+/// The request-response protocol name used to exchange blobs between peers.
+pub const BLOB_EXCHANGE_PROTOCOL: &str = "/p2p-chat/blob/0.1.0";
+
+/// The request-response protocol name used to sync the history of channels.
+pub const HISTORY_SYNC_PROTOCOL: &str = "/p2p-chat/history/0.1.0";
+
 /// The signed envelope domain for use in Kademlia memory store.
 pub const SIGNED_ENVELOPE_DOMAIN: &str = "p2p-chat-data";
 
+/// The domain separation string of signed messages.
+pub const SIGNED_MESSAGE_DOMAIN: &str = "p2p-chat-message";
+
+/// The domain separation string of signed channel invitations.
+pub const SIGNED_INVITE_DOMAIN: &str = "p2p-chat-invite";
+
+/// The payload type of envelopes signed before payloads were tagged with
+/// their format, whose payloads are always msgpack.
+const LEGACY_PAYLOAD_TYPE: &[u8] = &[0x02, 0x01];
+
+/// The namespace under which peers register at rendezvous points.
+pub const RENDEZVOUS_NAMESPACE: &str = "p2p-chat";
+
+/// The maximum size of an encoded invitation, in bytes.
+pub const MAX_INVITE_SIZE: usize = 1024;
+
+/// The maximum number of recent messages kept and shared for each channel.
+pub const MAX_HISTORY_LENGTH: usize = 100;
+
+/// The maximum number of channels a peer may advertise in the directory.
+pub const MAX_ADVERTISED_CHANNELS: usize = 32;
+
+/// The maximum number of peers in the moderation record of a channel.
+pub const MAX_MODERATED_PEERS: usize = 1024;
+
 /// The maximum length of a message, in characters.
 pub const MAX_MESSAGE_LENGTH: usize = 512;
 
 /// The maximum length of a channel identifier, in characters.
 pub const MAX_CHANNEL_IDENTIFIER_LENGTH: usize = 20;
 
+/// The maximum length of a channel's welcome message, in characters.
+pub const MAX_WELCOME_LENGTH: usize = 1024;
+
+/// The maximum length of a reason given when leaving, in characters.
+pub const MAX_GOODBYE_REASON_LENGTH: usize = 128;
+
 /// The maximum length of a nickname, in characters.
 pub const MAX_NICK_LENGTH: usize = 20;
 
-/// The MessagePack codec code within [multicodec](https://github.com/multiformats/multicodec).
-pub const MULTICODEC_MSGPACK: &[u8] = &[0x02, 0x01];
+/// The maximum length of a peer's bio, in characters.
+pub const MAX_BIO_LENGTH: usize = 256;
+
+/// The maximum length of a peer's pronouns, in characters.
+pub const MAX_PRONOUNS_LENGTH: usize = 32;
+
+/// The maximum length of a poll question, in characters.
+pub const MAX_POLL_QUESTION_LENGTH: usize = 256;
+
+/// The maximum length of a poll option, in characters.
+pub const MAX_POLL_OPTION_LENGTH: usize = 64;
+
+/// The maximum number of options in a poll.
+pub const MAX_POLL_OPTIONS: usize = 10;
+
+/// The maximum number of peers mentioned in a message.
+pub const MAX_MENTIONS: usize = 16;
+
+/// The longest time after which a message may disappear, in seconds.
+pub const MAX_MESSAGE_LIFETIME: u32 = 7 * 24 * 60 * 60;
+
+/// The maximum size of an avatar image, in bytes.
+pub const MAX_AVATAR_SIZE: usize = 32 * 1024;
+
+/// The maximum size of a message attachment, in bytes.
+pub const MAX_ATTACHMENT_SIZE: usize = 256 * 1024;
+
+/// The maximum length of the file name of an attachment, in characters.
+pub const MAX_ATTACHMENT_NAME_LENGTH: usize = 64;
+
+/// The maximum length of a message sent in fragments, in bytes.
+pub const MAX_LONG_MESSAGE_LENGTH: usize = 16 * 1024;
+
+/// The maximum size of the data carried by a single fragment, in bytes.
+pub const MAX_FRAGMENT_SIZE: usize = 1024;
+
+/// The maximum number of fragments a command may be split into.
+pub const MAX_FRAGMENTS: usize = 64;
+
+/// The maximum number of commands being reassembled from a peer at once.
+pub const MAX_PARTIAL_COMMANDS: usize = 4;
+
+/// How long to wait for the rest of a fragmented command to arrive.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The earliest plausible message timestamp, in milliseconds since the Unix
+/// epoch (the start of 2020).
+pub const MIN_TIMESTAMP: u64 = 1_577_836_800_000;
+
+/// How many sequence numbers below the highest one seen from a sender are
+/// still accepted, if they were not seen before.
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Encoded commands at least this many bytes long are compressed.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// The maximum size of a command once decompressed, in bytes.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024;
+
+/// Flag byte preceding an uncompressed command.
+const FLAG_UNCOMPRESSED: u8 = 0x00;
+
+/// Flag byte preceding a deflate-compressed command.
+const FLAG_DEFLATE: u8 = 0x01;
 
 pub type ChannelIdentifier = String;
 
-#[derive(Deserialize, Serialize, Debug)]
+/// The content address of a blob, as an encoded SHA2-256 multihash.
+pub type BlobHash = Vec<u8>;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Channel {
     identifier: ChannelIdentifier,
     owner: PeerId,
     peers: Vec<PeerId>,
     version: u64,
+    /// Whether the channel has been frozen by its owner. Archived channels
+    /// remain readable, but no longer accept new messages.
+    #[serde(default)]
+    archived: bool,
+    /// A message shown to peers upon joining the channel.
+    #[serde(default)]
+    welcome: Option<String>,
+    /// Whether peers may only join the channel with an [`Invite`] from its
+    /// owner.
+    #[serde(default)]
+    invite_only: bool,
+    /// Peers which the owner has banned from the channel.
+    #[serde(default)]
+    banned: Vec<PeerId>,
+    /// Peers which the owner has made moderators of the channel.
+    #[serde(default)]
+    moderators: Vec<PeerId>,
+    /// Whether messages to the channel are encrypted with a key which the
+    /// owner distributes to its peers.
+    #[serde(default)]
+    encrypted: bool,
+    /// The limits placed on messages sent to the channel.
+    #[serde(default)]
+    policy: ChannelPolicy,
 }
 
-#[derive(Deserialize, Serialize, Copy, Clone, Debug)]
-pub enum MessageType {
-    Normal,
-    Me,
-}
+impl Channel {
+    /// Create a new channel at version zero, with the owner as its only peer.
+    pub fn new(identifier: ChannelIdentifier, owner: PeerId) -> Self {
+        Channel {
+            identifier,
+            owner,
+            peers: vec![owner],
+            version: 0,
+            archived: false,
+            welcome: None,
+            invite_only: false,
+            banned: Vec::new(),
+            moderators: Vec::new(),
+            encrypted: false,
+            policy: ChannelPolicy::default(),
+        }
+    }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum Command {
-    ChannelUpdate {
-        channel: Channel,
-    },
-    ChannelRequestJoin {
-        channel: ChannelIdentifier,
-    },
-    ChannelRequestLeave {
-        channel: ChannelIdentifier,
-    },
-    MessageSend {
-        contents: String,
-        channel: ChannelIdentifier,
-        timestamp: u64,
-        message_type: MessageType,
-    },
-    NicknameUpdate {
-        nick: String,
-    },
-}
+    pub fn identifier(&self) -> &ChannelIdentifier {
+        &self.identifier
+    }
 
-// TODO map err
+    pub fn owner(&self) -> &PeerId {
+        &self.owner
+    }
 
-impl Command {
-    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
-        // TODO buff?
-        let dec: Command = rmp_serde::from_read(encoded)?;
+    pub fn peers(&self) -> &[PeerId] {
+        &self.peers
+    }
 
-        dec.check_valid()?;
+    pub fn version(&self) -> u64 {
+        self.version
+    }
 
-        Ok(dec)
+    pub fn is_archived(&self) -> bool {
+        self.archived
     }
 
-    pub fn encode(&self) -> crate::Result<Vec<u8>> {
-        self.check_valid()?;
-        Ok(rmp_serde::to_vec(self)?)
+    pub fn welcome(&self) -> Option<&str> {
+        self.welcome.as_deref()
     }
 
-    pub fn is_valid(&self) -> bool {
-        match self {
-            Command::MessageSend {
-                contents,
-                channel,
-                timestamp: _,
-                message_type: _,
-            } => {
-                // TODO validate timestamp?
-                !contents.is_empty()
-                    && contents.len() <= MAX_MESSAGE_LENGTH
-                    && !channel.is_empty()
-                    && channel.len() <= MAX_CHANNEL_IDENTIFIER_LENGTH
-            }
-            Command::NicknameUpdate { nick } => {
-                !nick.is_empty() && nick.len() <= MAX_NICK_LENGTH
-            }
-            _ => true,
-        }
+    pub fn is_invite_only(&self) -> bool {
+        self.invite_only
     }
 
-    fn check_valid(&self) -> crate::Result<()> {
-        if !self.is_valid() {
-            Err(crate::Error::InvalidData(String::from(
-                "command is not valid",
-            )))
-        } else {
-            Ok(())
-        }
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
     }
-}
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum MemoryKey {
-    Nickname(PeerId),
-    Channel(String),
-}
+    pub fn banned(&self) -> &[PeerId] {
+        &self.banned
+    }
 
-impl MemoryKey {
-    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
-        Ok(rmp_serde::from_read(encoded)?)
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
     }
 
-    pub fn encode(&self) -> crate::Result<Vec<u8>> {
-        Ok(rmp_serde::to_vec(self)?)
+    pub fn policy(&self) -> &ChannelPolicy {
+        &self.policy
     }
-}
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum MemoryValue {
-    Nickname { user: PeerId, nickname: String },
-    Channel(Channel),
-}
+    /// Get the next version of this channel, with a new message policy.
+    pub fn with_policy(&self, policy: ChannelPolicy) -> Self {
+        Channel {
+            version: self.version + 1,
+            policy,
+            ..self.clone()
+        }
+    }
 
-impl MemoryValue {
-    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
-        let envelope = SignedEnvelope::from_protobuf_encoding(encoded)?;
-        let (payload, signing_key) = envelope.payload_and_signing_key(
-            SIGNED_ENVELOPE_DOMAIN.to_owned(),
-            MULTICODEC_MSGPACK,
-        )?;
-        let value = rmp_serde::from_read(payload)?;
+    /// Get the next version of this channel, with or without end-to-end
+    /// encryption.
+    pub fn with_encrypted(&self, encrypted: bool) -> Self {
+        Channel {
+            version: self.version + 1,
+            encrypted,
+            ..self.clone()
+        }
+    }
 
-        let expected_signer = match &value {
-            MemoryValue::Nickname { user, .. } => user,
-            MemoryValue::Channel(channel) => &channel.owner,
+    /// Get the role of a peer within the channel.
+    pub fn role(&self, peer: &PeerId) -> Role {
+        if peer == &self.owner {
+            Role::Owner
+        } else if self.moderators.contains(peer) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+
+    /// Get the next version of this channel, with a peer given a new role.
+    ///
+    /// Making a peer the owner transfers the channel to them, with the previous
+    /// owner becoming a moderator.
+    pub fn with_role(&self, peer: PeerId, role: Role) -> Self {
+        let mut channel = Channel {
+            version: self.version + 1,
+            ..self.clone()
         };
+        channel.moderators.retain(|p| p != &peer);
 
-        if expected_signer != &signing_key.to_peer_id() {
-            return Err(crate::Error::SignatureMismatch);
+        match role {
+            Role::Owner if peer != self.owner => {
+                channel.moderators.push(self.owner);
+                channel.owner = peer;
+                if !channel.peers.contains(&peer) {
+                    channel.peers.push(peer);
+                }
+            }
+            Role::Moderator => channel.moderators.push(peer),
+            _ => {}
         }
 
-        Ok(value)
+        channel
     }
 
-    pub fn encode_signed(&self, key: &Keypair) -> crate::Result<Vec<u8>> {
-        let payload = rmp_serde::to_vec(self)?;
-        let envelope = SignedEnvelope::new(
-            key,
-            SIGNED_ENVELOPE_DOMAIN.to_owned(),
-            MULTICODEC_MSGPACK.to_owned(),
-            payload,
-        )?;
-        Ok(envelope.into_protobuf_encoding())
+    /// Get the next version of this channel, which may or may not be joined
+    /// without an invitation.
+    pub fn with_invite_only(&self, invite_only: bool) -> Self {
+        Channel {
+            version: self.version + 1,
+            invite_only,
+            ..self.clone()
+        }
     }
-}
-
-pub fn topic_from_channel(ident: &ChannelIdentifier) -> gossipsub::IdentTopic {
-    gossipsub::IdentTopic::new(format!("/p2p-chat/channel/{ident}"))
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_command_message_send() {
-        // good
-        assert!(Command::MessageSend {
-            contents: "hello world!".to_owned(),
-            channel: "hello".to_owned(),
-            timestamp: 64,
-            message_type: MessageType::Normal
+    /// Get the next version of this channel, marked as archived.
+    pub fn archive(&self) -> Self {
+        Channel {
+            version: self.version + 1,
+            archived: true,
+            ..self.clone()
         }
-        .is_valid());
+    }
 
-        // bad: too long of channel name
-        assert!(!Command::MessageSend {
-            contents: "hello world!".to_owned(),
-            channel: "thisisanabsurdlylongchannelnameohnoiwonderifthisisokay"
-                .to_owned(),
-            timestamp: 64,
-            message_type: MessageType::Normal
+    /// Get the next version of this channel, with a peer added to it.
+    pub fn with_peer(&self, peer: PeerId) -> Self {
+        let mut peers = self.peers.clone();
+        peers.push(peer);
+        Channel {
+            version: self.version + 1,
+            peers,
+            ..self.clone()
         }
-        .is_valid());
+    }
 
-        // bad: empty channel name
-        assert!(!Command::MessageSend {
-            contents: "hello world!".to_owned(),
-            channel: String::new(),
-            timestamp: 64,
-            message_type: MessageType::Normal
+    /// Get the next version of this channel, with a peer removed from it along
+    /// with any role they had.
+    pub fn without_peer(&self, peer: &PeerId) -> Self {
+        Channel {
+            version: self.version + 1,
+            peers: self.peers.iter().filter(|p| *p != peer).copied().collect(),
+            moderators: self
+                .moderators
+                .iter()
+                .filter(|p| *p != peer)
+                .copied()
+                .collect(),
+            ..self.clone()
         }
-        .is_valid());
+    }
 
-        // bad: very long message
-        assert!(!Command::MessageSend {
-            contents: String::new(),
-            channel: "hello".repeat(200),
-            timestamp: 0,
-            message_type: MessageType::Normal
+    /// Get the next version of this channel, with a peer removed from it and
+    /// banned from rejoining.
+    pub fn with_ban(&self, peer: PeerId) -> Self {
+        let mut banned = self.banned.clone();
+        if !banned.contains(&peer) {
+            banned.push(peer);
         }
-        .is_valid());
+        Channel {
+            banned,
+            ..self.without_peer(&peer)
+        }
+    }
 
-        // bad: empty message
-        assert!(!Command::MessageSend {
-            contents: String::new(),
-            channel: "hello".to_owned(),
-            timestamp: 0,
-            message_type: MessageType::Me
+    /// Get the next version of this channel, with a new welcome message.
+    pub fn with_welcome(&self, welcome: Option<String>) -> Self {
+        Channel {
+            version: self.version + 1,
+            welcome,
+            ..self.clone()
         }
-        .is_valid());
     }
 
-    #[test]
-    fn test_command_nickname_update() {
-        // good
-        assert!(Command::NicknameUpdate {
-            nick: "alice".to_owned()
+    pub fn is_valid(&self) -> bool {
+        is_valid_channel_identifier(&self.identifier)
+            && !self.peers.is_empty()
+            && !self.banned.contains(&self.owner)
+            && !self.moderators.contains(&self.owner)
+            && self.welcome.as_ref().is_none_or(|welcome| {
+                !welcome.is_empty() && welcome.len() <= MAX_WELCOME_LENGTH
+            })
+            && self.policy.is_valid()
+    }
+}
+
+/// The moderation decisions of the owner of a channel, stored in the DHT
+/// apart from the channel itself so that they outlast the owner's session.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Moderation {
+    channel: ChannelIdentifier,
+    owner: PeerId,
+    version: u64,
+    /// Peers which the owner has banned from the channel.
+    banned: Vec<PeerId>,
+}
+
+impl Moderation {
+    /// Create an empty moderation record at version zero.
+    pub fn new(channel: ChannelIdentifier, owner: PeerId) -> Self {
+        Moderation {
+            channel,
+            owner,
+            version: 0,
+            banned: Vec::new(),
         }
-        .is_valid());
+    }
 
-        // bad: name empty
-        assert!(!Command::NicknameUpdate {
-            nick: String::new(),
+    pub fn channel(&self) -> &ChannelIdentifier {
+        &self.channel
+    }
+
+    pub fn owner(&self) -> &PeerId {
+        &self.owner
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn banned(&self) -> &[PeerId] {
+        &self.banned
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
+    }
+
+    /// Get the next version of this record, with a peer banned.
+    pub fn with_ban(&self, peer: PeerId) -> Self {
+        let mut banned = self.banned.clone();
+        if !banned.contains(&peer) {
+            banned.push(peer);
         }
-        .is_valid());
+        Moderation {
+            version: self.version + 1,
+            banned,
+            ..self.clone()
+        }
+    }
 
-        // bad: name too long
-        assert!(!Command::NicknameUpdate {
-            nick: "verylongnameverylongname".to_owned()
+    pub fn is_valid(&self) -> bool {
+        is_valid_channel_identifier(&self.channel)
+            && self.banned.len() <= MAX_MODERATED_PEERS
+            && !self.banned.contains(&self.owner)
+    }
+}
+
+/// A peer's claim to a nickname, stored in the DHT under the nickname itself.
+///
+/// When several peers claim the same nickname, the earliest claim wins.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct NickClaim {
+    nick: String,
+    owner: PeerId,
+    /// When the nickname was claimed, in milliseconds since the Unix epoch.
+    claimed_at: u64,
+}
+
+impl NickClaim {
+    pub fn new(nick: String, owner: PeerId, claimed_at: u64) -> Self {
+        NickClaim {
+            nick,
+            owner,
+            claimed_at,
         }
-        .is_valid());
     }
 
-    #[test]
-    fn test_channel_topics() {
-        assert_eq!(
-            "/p2p-chat/channel/hello world",
-            &format!("{}", topic_from_channel(&"hello world".to_owned()))
-        )
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    pub fn owner(&self) -> &PeerId {
+        &self.owner
+    }
+
+    pub fn claimed_at(&self) -> u64 {
+        self.claimed_at
+    }
+
+    /// Get whether this claim takes precedence over another for the same
+    /// nickname, by being made earlier.
+    ///
+    /// Claims made at the same time are ordered by their owners, so that
+    /// every peer settles on the same one.
+    pub fn precedes(&self, other: &NickClaim) -> bool {
+        (self.claimed_at, self.owner.to_bytes())
+            < (other.claimed_at, other.owner.to_bytes())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.nick.is_empty() && self.nick.len() <= MAX_NICK_LENGTH
+    }
+}
+
+/// The limits the owner of a channel places on the messages sent to it.
+///
+/// Unset limits leave messages as free as the protocol allows.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelPolicy {
+    /// The maximum length of a message, in bytes.
+    pub max_message_length: Option<u32>,
+    /// The minimum time between messages from each peer, in seconds.
+    /// Moderators and the owner are exempt.
+    pub slow_mode: Option<u32>,
+    /// The types of message which may be sent.
+    pub allowed_types: Option<Vec<MessageType>>,
+}
+
+impl ChannelPolicy {
+    /// Check whether the limits of this policy are sensible.
+    pub fn is_valid(&self) -> bool {
+        self.max_message_length != Some(0)
+            && self.slow_mode != Some(0)
+            && self
+                .allowed_types
+                .as_ref()
+                .is_none_or(|types| !types.is_empty() && types.len() <= 8)
+    }
+
+    /// Check whether a message is within the limits of this policy, other
+    /// than slow mode.
+    pub fn permits(&self, contents: &str, message_type: MessageType) -> bool {
+        self.max_message_length
+            .is_none_or(|max| contents.len() <= max as usize)
+            && self
+                .allowed_types
+                .as_ref()
+                .is_none_or(|types| types.contains(&message_type))
+    }
+}
+
+/// The role of a peer within a channel, from the least to the most privileged.
+#[derive(
+    Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl Role {
+    /// Check whether peers with this role may do something within a channel.
+    pub fn can(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::Kick | Permission::Ban => *self >= Role::Moderator,
+            Permission::SetWelcome | Permission::GrantRole => {
+                *self == Role::Owner
+            }
+        }
+    }
+}
+
+/// Something which only peers with some roles may do within a channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Set the welcome message shown to peers joining the channel.
+    SetWelcome,
+    /// Remove a peer with a lesser role from the channel.
+    Kick,
+    /// Remove a peer with a lesser role from the channel for good.
+    Ban,
+    /// Change the role of a peer.
+    GrantRole,
+}
+
+/// A request for the most recent messages of a channel.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HistoryRequest {
+    pub channel: ChannelIdentifier,
+    /// The number of messages wanted, which peers cap at
+    /// [`MAX_HISTORY_LENGTH`].
+    pub limit: u32,
+}
+
+/// A message from the history of a channel, as kept by a peer which saw it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub id: MessageId,
+    pub sender: PeerId,
+    /// The [`Command::MessageSend`] or [`Command::SignedMessage`] which was
+    /// published.
+    pub command: Command,
+}
+
+/// An invitation from the owner of a channel for a peer to join it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Invite {
+    pub channel: ChannelIdentifier,
+    pub invitee: PeerId,
+    /// The time after which the invitation may no longer be used, in
+    /// milliseconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl Invite {
+    /// Sign the invitation, returning the token to be passed to the invitee.
+    pub fn sign(&self, key: &Keypair) -> crate::Result<Vec<u8>> {
+        sign_value::<DefaultCodec, _>(self, key, SIGNED_INVITE_DOMAIN)
+    }
+
+    /// Verify an invitation token, returning the invitation along with the
+    /// peer which issued it.
+    pub fn verify(token: &[u8]) -> crate::Result<(Self, PeerId)> {
+        open_signed(token, SIGNED_INVITE_DOMAIN)
+    }
+
+    /// Check whether the invitation, issued by `inviter`, lets a peer join a
+    /// channel at the given time, in milliseconds since the Unix epoch.
+    pub fn admits(
+        &self,
+        inviter: &PeerId,
+        channel: &Channel,
+        peer: &PeerId,
+        now: u64,
+    ) -> bool {
+        inviter == channel.owner()
+            && &self.channel == channel.identifier()
+            && &self.invitee == peer
+            && now <= self.expires_at
+    }
+}
+
+/// A file attached to a message, which is fetched from its sender.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    /// The file name of the attachment.
+    pub name: String,
+    /// The content address of the attachment.
+    pub hash: BlobHash,
+    /// The size of the attachment, in bytes.
+    pub size: usize,
+}
+
+impl Attachment {
+    pub fn is_valid(&self) -> bool {
+        !self.name.is_empty()
+            && self.name.len() <= MAX_ATTACHMENT_NAME_LENGTH
+            && !self.hash.is_empty()
+            && self.size <= MAX_ATTACHMENT_SIZE
+    }
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Normal,
+    Me,
+    /// A message written in the markdown subset parsed by [`parse_formatted`].
+    Formatted,
+}
+
+impl MessageType {
+    /// Check whether message contents are valid for this type of message,
+    /// and no longer than `max_length` bytes.
+    pub fn is_valid_contents(&self, contents: &str, max_length: usize) -> bool {
+        !contents.is_empty()
+            && contents.len() <= max_length
+            && (*self != MessageType::Formatted
+                || parse_formatted(contents).is_some())
+    }
+}
+
+/// How plausible the timestamp of a received message is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimestampVerdict {
+    Plausible,
+    /// The timestamp is further ahead of local time than clocks are allowed to
+    /// drift apart.
+    Ahead,
+    /// The timestamp is before [`MIN_TIMESTAMP`].
+    Ancient,
+}
+
+/// Judge a message timestamp against the local time, allowing clocks to be
+/// up to `max_skew` apart.
+///
+/// Both times are in milliseconds since the Unix epoch.
+pub fn check_timestamp(
+    timestamp: u64,
+    now: u64,
+    max_skew: Duration,
+) -> TimestampVerdict {
+    if timestamp < MIN_TIMESTAMP {
+        TimestampVerdict::Ancient
+    } else if timestamp > now.saturating_add(max_skew.as_millis() as u64) {
+        TimestampVerdict::Ahead
+    } else {
+        TimestampVerdict::Plausible
+    }
+}
+
+/// A span of formatted message text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Span {
+    Text(String),
+    Bold(Vec<Span>),
+    Italic(Vec<Span>),
+    Code(String),
+    Link { text: String, url: String },
+}
+
+/// Parse a formatted message into spans.
+///
+/// The supported syntax is `**bold**`, `*italic*`, `` `code` `` and
+/// `[text](url)`, where bold and italic text may contain one another but not
+/// themselves. Any character may be escaped with a backslash. Returns `None`
+/// if a delimiter is left unclosed or is nested within itself.
+pub fn parse_formatted(contents: &str) -> Option<Vec<Span>> {
+    let mut rest = contents;
+    parse_spans(&mut rest, &mut vec![])
+}
+
+/// Parse spans until the innermost open delimiter is closed, or until the end
+/// of the input if none are open.
+fn parse_spans(
+    rest: &mut &str,
+    open: &mut Vec<&'static str>,
+) -> Option<Vec<Span>> {
+    let mut spans = vec![];
+    let mut text = String::new();
+
+    loop {
+        let delimiter = if rest.starts_with("**") {
+            // `***` may close italic text within bold text
+            if open.last() == Some(&"*") && open.contains(&"**") {
+                Some("*")
+            } else {
+                Some("**")
+            }
+        } else if rest.starts_with('*') {
+            Some("*")
+        } else {
+            None
+        };
+
+        if let Some(delimiter) = delimiter {
+            *rest = &rest[delimiter.len()..];
+            if open.last() == Some(&delimiter) {
+                break;
+            } else if open.contains(&delimiter) {
+                return None;
+            }
+
+            open.push(delimiter);
+            let inner = parse_spans(rest, open)?;
+            open.pop();
+
+            push_text(&mut spans, &mut text);
+            spans.push(match delimiter {
+                "**" => Span::Bold(inner),
+                _ => Span::Italic(inner),
+            });
+            continue;
+        }
+
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some('\\') => {
+                text.extend(chars.next());
+                *rest = chars.as_str();
+            }
+            Some('`') => {
+                let (code, after) = chars.as_str().split_once('`')?;
+                push_text(&mut spans, &mut text);
+                spans.push(Span::Code(code.to_owned()));
+                *rest = after;
+            }
+            Some('[') => match parse_link(chars.as_str()) {
+                Some((link, after)) => {
+                    push_text(&mut spans, &mut text);
+                    spans.push(link);
+                    *rest = after;
+                }
+                None => {
+                    text.push('[');
+                    *rest = chars.as_str();
+                }
+            },
+            Some(c) => {
+                text.push(c);
+                *rest = chars.as_str();
+            }
+            // the end was reached with delimiters left open
+            None if !open.is_empty() => return None,
+            None => break,
+        }
+    }
+
+    push_text(&mut spans, &mut text);
+    Some(spans)
+}
+
+/// Parse the remainder of a link after its opening bracket.
+fn parse_link(rest: &str) -> Option<(Span, &str)> {
+    let (text, rest) = rest.split_once("](")?;
+    let (url, rest) = rest.split_once(')')?;
+
+    if text.is_empty() || url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let link = Span::Link {
+        text: text.to_owned(),
+        url: url.to_owned(),
+    };
+    Some((link, rest))
+}
+
+fn push_text(spans: &mut Vec<Span>, text: &mut String) {
+    if !text.is_empty() {
+        spans.push(Span::Text(std::mem::take(text)));
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum Command {
+    ChannelUpdate {
+        channel: Channel,
+    },
+    ChannelRequestJoin {
+        channel: ChannelIdentifier,
+        /// The invitation token of the joining peer, required to join
+        /// invite-only channels.
+        #[serde(default, with = "serde_bytes")]
+        invite: Option<Vec<u8>>,
+    },
+    ChannelRequestLeave {
+        channel: ChannelIdentifier,
+    },
+    MessageSend {
+        contents: String,
+        channel: ChannelIdentifier,
+        timestamp: u64,
+        message_type: MessageType,
+        /// The message ID of the message being replied to, if any.
+        #[serde(default)]
+        in_reply_to: Option<MessageId>,
+        /// The peers mentioned in the message, as resolved by its sender.
+        #[serde(default)]
+        mentions: Vec<PeerId>,
+        #[serde(default)]
+        attachment: Option<Attachment>,
+        /// Increases with each message of the sender, with zero meaning the
+        /// message is not numbered.
+        #[serde(default)]
+        sequence: u64,
+        /// The Lamport clock of the sender in the channel.
+        #[serde(default)]
+        clock: u64,
+        /// How many seconds after it was sent the message disappears, if
+        /// ever.
+        #[serde(default)]
+        expires_after: Option<u32>,
+    },
+    /// Replaces the contents of a message previously sent by the same author.
+    MessageEdit {
+        /// The message ID of the message being edited.
+        target: MessageId,
+        new_contents: String,
+        channel: ChannelIdentifier,
+    },
+    /// Retracts a message previously sent by the same author.
+    MessageDelete {
+        /// The message ID of the message being deleted.
+        target: MessageId,
+        channel: ChannelIdentifier,
+    },
+    NicknameUpdate {
+        nick: String,
+    },
+    /// Sent directly to a peer upon connecting, describing what we support.
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    /// A message sent directly to a single peer, outside of any channel.
+    DirectMessage {
+        contents: String,
+        timestamp: u64,
+        message_type: MessageType,
+    },
+    /// Announces that a peer is leaving the given channels.
+    Goodbye {
+        channels: Vec<ChannelIdentifier>,
+        reason: Option<String>,
+    },
+    Poll {
+        channel: ChannelIdentifier,
+        question: String,
+        options: Vec<String>,
+        /// The time at which voting closes, in milliseconds since the Unix
+        /// epoch.
+        closes_at: u64,
+    },
+    Vote {
+        channel: ChannelIdentifier,
+        /// The message ID of the poll being voted on.
+        poll: MessageId,
+        option: usize,
+    },
+    /// One part of an encoded command too large to be sent at once.
+    Fragment {
+        /// Identifies the fragmented command among those of the same sender.
+        id: u64,
+        index: u32,
+        total: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// An invitation token for the recipient to join a channel.
+    ChannelInvite {
+        #[serde(with = "serde_bytes")]
+        token: Vec<u8>,
+    },
+    /// A [`Command::MessageSend`] signed by its author, as an encoded signed
+    /// envelope.
+    SignedMessage {
+        #[serde(with = "serde_bytes")]
+        envelope: Vec<u8>,
+    },
+    /// The owner of a channel removing a peer from it.
+    ChannelKick {
+        channel: ChannelIdentifier,
+        peer: PeerId,
+    },
+    /// The owner of a channel removing a peer from it for good.
+    ChannelBan {
+        channel: ChannelIdentifier,
+        peer: PeerId,
+    },
+    /// A [`Command::MessageSend`] or [`Command::SignedMessage`], or an edit,
+    /// deletion, poll or vote, sent to an encrypted channel, encrypted with
+    /// the key of the channel.
+    EncryptedMessage {
+        channel: ChannelIdentifier,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+        /// The epoch of the key the message was encrypted with, which is zero
+        /// for keys derived from a passphrase.
+        #[serde(default)]
+        epoch: u64,
+    },
+    /// The key of an end-to-end encrypted channel, wrapped to the recipient
+    /// by the owner of the channel.
+    ChannelKey {
+        channel: ChannelIdentifier,
+        /// The version of the channel the key was generated for.
+        epoch: u64,
+        key: WrappedKey,
+    },
+    /// A [`Command::DirectMessage`] encrypted in a double ratchet session
+    /// between the sender and the recipient.
+    EncryptedDirectMessage {
+        header: RatchetHeader,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+    },
+}
+
+// TODO map err
+
+impl Command {
+    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
+        let (flag, payload) = encoded.split_first().ok_or_else(|| {
+            crate::Error::InvalidData("empty command".to_owned())
+        })?;
+
+        // TODO buff?
+        let dec: Command = match *flag {
+            FLAG_UNCOMPRESSED => decode_tagged(payload)?,
+            FLAG_DEFLATE => decode_tagged(&inflate(payload)?)?,
+            flag => {
+                return Err(crate::Error::InvalidData(format!(
+                    "unknown compression flag {flag:#04x}"
+                )))
+            }
+        };
+
+        dec.check_valid()?;
+
+        Ok(dec)
+    }
+
+    /// Encode the command with the default format, compressing it if it is
+    /// large enough for that to pay off.
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        self.encode_with::<DefaultCodec>()
+    }
+
+    /// Encode the command with the given format, compressing it if it is
+    /// large enough for that to pay off.
+    pub fn encode_with<C: Codec>(&self) -> crate::Result<Vec<u8>> {
+        self.check_valid()?;
+        let data = encode_tagged::<C, _>(self)?;
+
+        if data.len() >= COMPRESSION_THRESHOLD {
+            let compressed = deflate(&data)?;
+            if compressed.len() <= data.len() {
+                return Ok(compressed);
+            }
+        }
+
+        let mut encoded = Vec::with_capacity(data.len() + 1);
+        encoded.push(FLAG_UNCOMPRESSED);
+        encoded.extend(data);
+        Ok(encoded)
+    }
+
+    /// Check whether the command is valid to be sent or received whole, in a
+    /// single gossipsub message or request.
+    pub fn is_valid(&self) -> bool {
+        self.is_valid_within(MAX_MESSAGE_LENGTH)
+    }
+
+    /// Check whether the command is valid, allowing messages of up to
+    /// `max_message_length` bytes, such as [`MAX_LONG_MESSAGE_LENGTH`] for
+    /// those reassembled from fragments.
+    pub fn is_valid_within(&self, max_message_length: usize) -> bool {
+        match self {
+            Command::MessageSend {
+                contents,
+                channel,
+                timestamp: _,
+                message_type,
+                in_reply_to: _,
+                mentions,
+                attachment,
+                sequence: _,
+                clock: _,
+                expires_after,
+            } => {
+                // timestamps are judged against the receiver's clock with
+                // check_timestamp instead
+                message_type.is_valid_contents(contents, max_message_length)
+                    && is_valid_channel_identifier(channel)
+                    && mentions.len() <= MAX_MENTIONS
+                    && attachment.as_ref().is_none_or(Attachment::is_valid)
+                    && expires_after.is_none_or(|lifetime| {
+                        lifetime > 0 && lifetime <= MAX_MESSAGE_LIFETIME
+                    })
+            }
+            Command::MessageEdit {
+                target: _,
+                new_contents,
+                channel,
+            } => {
+                !new_contents.is_empty()
+                    && new_contents.len() <= MAX_MESSAGE_LENGTH
+                    && is_valid_channel_identifier(channel)
+            }
+            Command::MessageDelete { target: _, channel } => {
+                is_valid_channel_identifier(channel)
+            }
+            Command::DirectMessage {
+                contents,
+                timestamp: _,
+                message_type,
+            } => message_type.is_valid_contents(contents, MAX_MESSAGE_LENGTH),
+            Command::Hello {
+                protocol_version: _,
+                capabilities,
+            } => {
+                capabilities.len() <= MAX_CAPABILITIES
+                    && capabilities.iter().all(|capability| {
+                        !capability.is_empty()
+                            && capability.len() <= MAX_CAPABILITY_LENGTH
+                    })
+            }
+            Command::NicknameUpdate { nick } => {
+                !nick.is_empty() && nick.len() <= MAX_NICK_LENGTH
+            }
+            Command::ChannelUpdate { channel } => channel.is_valid(),
+            Command::Goodbye { channels, reason } => {
+                channels.iter().all(is_valid_channel_identifier)
+                    && reason.as_ref().is_none_or(|reason| {
+                        reason.len() <= MAX_GOODBYE_REASON_LENGTH
+                    })
+            }
+            Command::Poll {
+                channel,
+                question,
+                options,
+                closes_at: _,
+            } => {
+                is_valid_channel_identifier(channel)
+                    && !question.is_empty()
+                    && question.len() <= MAX_POLL_QUESTION_LENGTH
+                    && options.len() >= 2
+                    && options.len() <= MAX_POLL_OPTIONS
+                    && options.iter().all(|option| {
+                        !option.is_empty()
+                            && option.len() <= MAX_POLL_OPTION_LENGTH
+                    })
+            }
+            Command::Vote {
+                channel,
+                poll: _,
+                option,
+            } => {
+                is_valid_channel_identifier(channel)
+                    && *option < MAX_POLL_OPTIONS
+            }
+            Command::Fragment {
+                id: _,
+                index,
+                total,
+                data,
+            } => {
+                *total >= 2
+                    && *total as usize <= MAX_FRAGMENTS
+                    && index < total
+                    && !data.is_empty()
+                    && data.len() <= MAX_FRAGMENT_SIZE
+            }
+            Command::SignedMessage { envelope } => !envelope.is_empty(),
+            Command::ChannelRequestJoin { channel, invite } => {
+                is_valid_channel_identifier(channel)
+                    && invite.as_ref().is_none_or(|invite| {
+                        !invite.is_empty() && invite.len() <= MAX_INVITE_SIZE
+                    })
+            }
+            Command::ChannelInvite { token } => {
+                !token.is_empty() && token.len() <= MAX_INVITE_SIZE
+            }
+            Command::ChannelKick { channel, peer: _ }
+            | Command::ChannelBan { channel, peer: _ } => {
+                is_valid_channel_identifier(channel)
+            }
+            Command::EncryptedMessage {
+                channel,
+                nonce,
+                ciphertext,
+                epoch: _,
+            } => {
+                is_valid_channel_identifier(channel)
+                    && nonce.len() == NONCE_LENGTH
+                    && !ciphertext.is_empty()
+                    && ciphertext.len() <= MAX_DECOMPRESSED_SIZE
+            }
+            Command::ChannelKey {
+                channel,
+                epoch: _,
+                key,
+            } => is_valid_channel_identifier(channel) && key.is_valid(),
+            Command::EncryptedDirectMessage {
+                header,
+                nonce,
+                ciphertext,
+            } => {
+                header.is_valid()
+                    && nonce.len() == NONCE_LENGTH
+                    && !ciphertext.is_empty()
+                    && ciphertext.len() <= MAX_DECOMPRESSED_SIZE
+            }
+            _ => true,
+        }
+    }
+
+    /// Check whether a peer is permitted to send this command, given the record
+    /// of the channel it concerns.
+    ///
+    /// Peers may only kick or ban those with a lesser role than their own, and
+    /// messages must be within the limits of the channel's policy.
+    pub fn is_permitted(&self, sender: &PeerId, channel: &Channel) -> bool {
+        let (permission, peer) = match self {
+            Command::ChannelKick { peer, .. } => (Permission::Kick, peer),
+            Command::ChannelBan { peer, .. } => (Permission::Ban, peer),
+            Command::MessageSend {
+                contents,
+                message_type,
+                ..
+            } => return channel.policy().permits(contents, *message_type),
+            _ => return true,
+        };
+
+        let role = channel.role(sender);
+        role.can(permission) && channel.role(peer) < role
+    }
+
+    /// Sign a message send command as its author, wrapping it in a
+    /// [`Command::SignedMessage`].
+    pub fn sign(&self, key: &Keypair) -> crate::Result<Command> {
+        if !matches!(self, Command::MessageSend { .. }) {
+            return Err(crate::Error::InvalidData(String::from(
+                "only messages may be signed",
+            )));
+        }
+        self.check_valid()?;
+
+        Ok(Command::SignedMessage {
+            envelope: sign_value::<DefaultCodec, _>(
+                self,
+                key,
+                SIGNED_MESSAGE_DOMAIN,
+            )?,
+        })
+    }
+
+    /// Get the time at which a message disappears, in milliseconds since the
+    /// Unix epoch, if it ever does.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::MessageSend {
+                timestamp,
+                expires_after: Some(lifetime),
+                ..
+            } => Some(timestamp.saturating_add(u64::from(*lifetime) * 1000)),
+            _ => None,
+        }
+    }
+
+    /// Get the channel of a message, or of a command about the messages of a
+    /// channel such as an edit or a vote, which must be encrypted if the
+    /// channel is.
+    pub fn message_channel(&self) -> Option<&ChannelIdentifier> {
+        match self {
+            Command::MessageSend { channel, .. }
+            | Command::MessageEdit { channel, .. }
+            | Command::MessageDelete { channel, .. }
+            | Command::Poll { channel, .. }
+            | Command::Vote { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Fail if the command is not valid, allowing messages as long as those
+    /// sent in fragments, since encoding does not know how it is sent.
+    ///
+    /// Whether a received message may be that long is judged by how it
+    /// arrived, with [`Command::is_valid`] or [`Command::is_valid_within`].
+    pub(crate) fn check_valid(&self) -> crate::Result<()> {
+        if !self.is_valid_within(MAX_LONG_MESSAGE_LENGTH) {
+            Err(crate::Error::InvalidData(String::from(
+                "command is not valid",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum MemoryKey {
+    Nickname(PeerId),
+    Channel(String),
+    Avatar(PeerId),
+    /// A blob of data, keyed by its content hash. Blob values are stored
+    /// as-is, rather than as a signed [`MemoryValue`].
+    Blob(BlobHash),
+    Profile(PeerId),
+    /// The key provided by every peer which advertises public channels.
+    Directory,
+    /// The channels advertised by a peer.
+    Listing(PeerId),
+    /// The moderation record of a channel.
+    Moderation(ChannelIdentifier),
+    /// The claim to a nickname.
+    NickClaim(String),
+}
+
+impl MemoryKey {
+    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::from_read(encoded)?)
+    }
+
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(self)?)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum MemoryValue {
+    Nickname {
+        user: PeerId,
+        nickname: String,
+    },
+    Channel(Channel),
+    Avatar {
+        user: PeerId,
+        hash: BlobHash,
+    },
+    /// The profile of a peer, which supersedes [`MemoryValue::Nickname`].
+    Profile {
+        user: PeerId,
+        nick: String,
+        bio: Option<String>,
+        pronouns: Option<String>,
+        avatar_hash: Option<BlobHash>,
+    },
+    Listing {
+        user: PeerId,
+        channels: Vec<ChannelIdentifier>,
+    },
+    Moderation(Moderation),
+    NickClaim(NickClaim),
+}
+
+impl MemoryValue {
+    pub fn decode(encoded: &[u8]) -> crate::Result<Self> {
+        let (value, signer) = open_signed(encoded, SIGNED_ENVELOPE_DOMAIN)?;
+
+        let expected_signer = match &value {
+            MemoryValue::Nickname { user, .. } => user,
+            MemoryValue::Avatar { user, .. } => user,
+            MemoryValue::Profile { user, .. } => user,
+            MemoryValue::Listing { user, .. } => user,
+            MemoryValue::Channel(channel) => &channel.owner,
+            MemoryValue::Moderation(moderation) => &moderation.owner,
+            MemoryValue::NickClaim(claim) => &claim.owner,
+        };
+
+        if expected_signer != &signer {
+            return Err(crate::Error::SignatureMismatch);
+        }
+
+        Ok(value)
+    }
+
+    pub fn encode_signed(&self, key: &Keypair) -> crate::Result<Vec<u8>> {
+        self.encode_signed_with::<DefaultCodec>(key)
+    }
+
+    /// Sign and encode the value with the given format.
+    pub fn encode_signed_with<C: Codec>(
+        &self,
+        key: &Keypair,
+    ) -> crate::Result<Vec<u8>> {
+        sign_value::<C, _>(self, key, SIGNED_ENVELOPE_DOMAIN)
+    }
+}
+
+/// The public details a peer shares about itself.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub nick: String,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    /// The content hash of the peer's avatar, as set by
+    /// [`Client::set_avatar`](crate::Client::set_avatar).
+    pub avatar_hash: Option<BlobHash>,
+}
+
+impl Profile {
+    pub fn is_valid(&self) -> bool {
+        !self.nick.is_empty()
+            && self.nick.len() <= MAX_NICK_LENGTH
+            && self
+                .bio
+                .as_ref()
+                .is_none_or(|bio| bio.len() <= MAX_BIO_LENGTH)
+            && self
+                .pronouns
+                .as_ref()
+                .is_none_or(|pronouns| pronouns.len() <= MAX_PRONOUNS_LENGTH)
+    }
+
+    /// Build the signed DHT value of this profile.
+    pub fn to_value(&self, user: PeerId) -> MemoryValue {
+        MemoryValue::Profile {
+            user,
+            nick: self.nick.clone(),
+            bio: self.bio.clone(),
+            pronouns: self.pronouns.clone(),
+            avatar_hash: self.avatar_hash.clone(),
+        }
+    }
+}
+
+/// The signature of the author of a message, kept whole so that the
+/// authorship of the message can be proven to others later.
+#[derive(Clone, Debug)]
+pub struct MessageSignature {
+    author: PeerId,
+    envelope: Vec<u8>,
+}
+
+impl MessageSignature {
+    /// Verify the envelope of a [`Command::SignedMessage`], returning the
+    /// message along with its signature.
+    pub fn verify(envelope: Vec<u8>) -> crate::Result<(Command, Self)> {
+        let (command, author): (Command, _) =
+            open_signed(&envelope, SIGNED_MESSAGE_DOMAIN)?;
+        if !matches!(command, Command::MessageSend { .. }) {
+            return Err(crate::Error::InvalidData(String::from(
+                "signed command is not a message",
+            )));
+        }
+        command.check_valid()?;
+
+        Ok((command, MessageSignature { author, envelope }))
+    }
+
+    /// The peer which signed the message.
+    pub fn author(&self) -> PeerId {
+        self.author
+    }
+
+    /// The signed envelope of the message, in its protobuf encoding.
+    pub fn envelope(&self) -> &[u8] {
+        &self.envelope
+    }
+}
+
+/// Sign a value, encoding it in a signed envelope with the given format.
+fn sign_value<C: Codec, T: Serialize>(
+    value: &T,
+    key: &Keypair,
+    domain: &str,
+) -> crate::Result<Vec<u8>> {
+    let envelope = SignedEnvelope::new(
+        key,
+        domain.to_owned(),
+        C::tag(),
+        C::to_vec(value)?,
+    )?;
+    Ok(envelope.into_protobuf_encoding())
+}
+
+/// Verify and decode a value from a signed envelope, returning it along with
+/// its signer.
+fn open_signed<T: DeserializeOwned>(
+    encoded: &[u8],
+    domain: &str,
+) -> crate::Result<(T, PeerId)> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(encoded)?;
+
+    // the payload type is the multicodec tag of the payload's format
+    let mut payload_type = DefaultCodec::tag();
+    let (payload, signing_key) = match envelope
+        .payload_and_signing_key(domain.to_owned(), &payload_type)
+    {
+        Err(ReadPayloadError::UnexpectedPayloadType { got, .. }) => {
+            payload_type = got;
+            envelope
+                .payload_and_signing_key(domain.to_owned(), &payload_type)?
+        }
+        result => result?,
+    };
+    let value = if payload_type == LEGACY_PAYLOAD_TYPE {
+        MessagePack::from_slice(payload)?
+    } else {
+        decode_tagged(&[payload_type.as_slice(), payload].concat())?
+    };
+
+    Ok((value, signing_key.to_peer_id()))
+}
+
+pub fn is_valid_channel_identifier(ident: &ChannelIdentifier) -> bool {
+    !ident.is_empty() && ident.len() <= MAX_CHANNEL_IDENTIFIER_LENGTH
+}
+
+/// Compute the content hash of a blob.
+pub fn blob_hash(data: &[u8]) -> BlobHash {
+    Code::Sha2_256.digest(data).to_bytes()
+}
+
+/// The prefix of the gossipsub topics of channels.
+const CHANNEL_TOPIC_PREFIX: &str = "/p2p-chat/channel/";
+
+pub fn topic_from_channel(ident: &ChannelIdentifier) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("{CHANNEL_TOPIC_PREFIX}{ident}"))
+}
+
+/// Get the identifier of the channel a topic belongs to, if any.
+pub fn channel_from_topic(
+    topic: &gossipsub::TopicHash,
+) -> Option<ChannelIdentifier> {
+    topic
+        .as_str()
+        .strip_prefix(CHANNEL_TOPIC_PREFIX)
+        .map(|ident| ident.to_owned())
+}
+
+/// Get the names mentioned in a message, written as `@name`.
+///
+/// Names may be nicknames or peer IDs, and are left to the sender to resolve.
+pub fn mentioned_names(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| {
+            name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_')
+        })
+        .filter(|name| !name.is_empty())
+}
+
+/// Split an encoded command into encoded fragments, if it is too large to be
+/// sent at once.
+pub fn fragment(encoded: Vec<u8>) -> crate::Result<Vec<Vec<u8>>> {
+    if encoded.len() <= MAX_FRAGMENT_SIZE {
+        return Ok(vec![encoded]);
+    }
+
+    let total = encoded.len().div_ceil(MAX_FRAGMENT_SIZE);
+    if total > MAX_FRAGMENTS {
+        return Err(crate::Error::InvalidData(String::from(
+            "command is too large to be fragmented",
+        )));
+    }
+
+    let id = rand::random();
+    encoded
+        .chunks(MAX_FRAGMENT_SIZE)
+        .enumerate()
+        .map(|(index, data)| {
+            Command::Fragment {
+                id,
+                index: index as u32,
+                total: total as u32,
+                data: data.to_vec(),
+            }
+            .encode()
+        })
+        .collect()
+}
+
+/// A position in the causal order of the messages of a channel.
+///
+/// Messages are ordered by their Lamport clocks, with ties broken by sender,
+/// so that every peer puts them in the same order regardless of when they
+/// arrived.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CausalOrder {
+    pub clock: u64,
+    pub sender: PeerId,
+}
+
+/// Tracks the sequence numbers seen from a sender, to tell replayed messages
+/// apart.
+///
+/// Only the [`REPLAY_WINDOW_SIZE`] sequence numbers below the highest one seen
+/// are tracked, and older ones are assumed to be replays. Messages may
+/// otherwise arrive in any order.
+#[derive(Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    /// Whether each of the sequence numbers below the highest one was seen,
+    /// from the nearest in the lowest bit.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Record a sequence number, returning whether it is new.
+    pub fn check(&mut self, sequence: u64) -> bool {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift < REPLAY_WINDOW_SIZE {
+                self.seen << shift
+            } else {
+                0
+            };
+            if self.highest > 0 && shift <= REPLAY_WINDOW_SIZE {
+                self.seen |= 1 << (shift - 1);
+            }
+            self.highest = sequence;
+            return true;
+        }
+
+        let offset = self.highest - sequence;
+        if offset == 0 || offset > REPLAY_WINDOW_SIZE {
+            return false;
+        }
+
+        let bit = 1 << (offset - 1);
+        let new = self.seen & bit == 0;
+        self.seen |= bit;
+        new
+    }
+}
+
+/// Reassembles fragmented commands as their fragments arrive.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(PeerId, u64), PartialCommand>,
+}
+
+struct PartialCommand {
+    fragments: Vec<Option<Vec<u8>>>,
+    /// The number of fragments which have not arrived yet.
+    remaining: usize,
+    /// The time after which the command is given up on.
+    expires_at: Instant,
+}
+
+impl Reassembler {
+    /// Add a fragment sent by a peer, returning the encoded command once all
+    /// of its fragments have arrived.
+    pub fn insert(
+        &mut self,
+        sender: PeerId,
+        id: u64,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        let key = (sender, id);
+        if !self.partial.contains_key(&key)
+            && self
+                .partial
+                .keys()
+                .filter(|(peer, _)| *peer == sender)
+                .count()
+                >= MAX_PARTIAL_COMMANDS
+        {
+            return Err(crate::Error::InvalidData(String::from(
+                "too many fragmented commands in flight",
+            )));
+        }
+
+        let partial =
+            self.partial.entry(key).or_insert_with(|| PartialCommand {
+                fragments: vec![None; total as usize],
+                remaining: total as usize,
+                expires_at: Instant::now() + FRAGMENT_TIMEOUT,
+            });
+        if partial.fragments.len() != total as usize {
+            return Err(crate::Error::InvalidData(String::from(
+                "fragment count does not match",
+            )));
+        }
+
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            *slot = Some(data);
+            partial.remaining -= 1;
+        }
+
+        if partial.remaining > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&key).unwrap();
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Forget the commands whose fragments stopped arriving.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.partial.retain(|_, partial| partial.expires_at > now);
+    }
+}
+
+/// Compress an encoded command, prefixed with its flag byte.
+fn deflate(data: &[u8]) -> crate::Result<Vec<u8>> {
+    let mut encoder =
+        DeflateEncoder::new(vec![FLAG_DEFLATE], Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress an encoded command, refusing to inflate it past
+/// [`MAX_DECOMPRESSED_SIZE`].
+fn inflate(data: &[u8]) -> crate::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(&mut inflated)?;
+
+    if inflated.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(crate::Error::InvalidData(
+            "decompressed command too large".to_owned(),
+        ));
+    }
+
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_message_send() {
+        // good
+        assert!(Command::MessageSend {
+            contents: "hello world!".to_owned(),
+            channel: "hello".to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        // bad: too long of channel name
+        assert!(!Command::MessageSend {
+            contents: "hello world!".to_owned(),
+            channel: "thisisanabsurdlylongchannelnameohnoiwonderifthisisokay"
+                .to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        // bad: empty channel name
+        assert!(!Command::MessageSend {
+            contents: "hello world!".to_owned(),
+            channel: String::new(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        // bad: very long message
+        assert!(!Command::MessageSend {
+            contents: String::new(),
+            channel: "hello".repeat(200),
+            timestamp: 0,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        // long messages are only valid when sent in fragments
+        let long = Command::MessageSend {
+            contents: "a".repeat(MAX_MESSAGE_LENGTH + 1),
+            channel: "hello".to_owned(),
+            timestamp: 0,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        };
+        assert!(!long.is_valid());
+        assert!(long.is_valid_within(MAX_LONG_MESSAGE_LENGTH));
+        assert!(Command::decode(&long.encode().unwrap()).is_ok());
+
+        // bad: empty message
+        assert!(!Command::MessageSend {
+            contents: String::new(),
+            channel: "hello".to_owned(),
+            timestamp: 0,
+            message_type: MessageType::Me,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        // bad: too many mentions
+        assert!(!Command::MessageSend {
+            contents: "hello everyone!".to_owned(),
+            channel: "hello".to_owned(),
+            timestamp: 0,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: (0..=MAX_MENTIONS).map(|_| PeerId::random()).collect(),
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        }
+        .is_valid());
+
+        let disappearing = |expires_after| Command::MessageSend {
+            contents: "hello world!".to_owned(),
+            channel: "hello".to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after,
+        };
+
+        // good: disappearing message
+        assert!(disappearing(Some(60)).is_valid());
+        assert_eq!(disappearing(Some(60)).expires_at(), Some(60_064));
+        assert_eq!(disappearing(None).expires_at(), None);
+
+        // bad: no lifetime
+        assert!(!disappearing(Some(0)).is_valid());
+
+        // bad: very long lifetime
+        assert!(!disappearing(Some(MAX_MESSAGE_LIFETIME + 1)).is_valid());
+    }
+
+    #[test]
+    fn test_attachment() {
+        let attachment = Attachment {
+            name: "cat.png".to_owned(),
+            hash: blob_hash(b"meow"),
+            size: 4,
+        };
+        assert!(attachment.is_valid());
+
+        // bad: empty name
+        assert!(!Attachment {
+            name: String::new(),
+            ..attachment.clone()
+        }
+        .is_valid());
+
+        // bad: too large
+        assert!(!Attachment {
+            size: MAX_ATTACHMENT_SIZE + 1,
+            ..attachment
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_parse_formatted() {
+        let text = |text: &str| Span::Text(text.to_owned());
+
+        assert_eq!(
+            parse_formatted("a **b *c*** `*d*` [e](f) \\*g"),
+            Some(vec![
+                text("a "),
+                Span::Bold(vec![text("b "), Span::Italic(vec![text("c")])]),
+                text(" "),
+                Span::Code("*d*".to_owned()),
+                text(" "),
+                Span::Link {
+                    text: "e".to_owned(),
+                    url: "f".to_owned()
+                },
+                text(" *g"),
+            ])
+        );
+
+        // a bracket without a link is plain text
+        assert_eq!(parse_formatted("[a] b"), Some(vec![text("[a] b")]));
+
+        // bad: unclosed delimiters
+        assert_eq!(parse_formatted("**a"), None);
+        assert_eq!(parse_formatted("`a"), None);
+
+        // bad: nested or interleaved delimiters
+        assert_eq!(parse_formatted("**a *b** c*"), None);
+    }
+
+    #[test]
+    fn test_mentioned_names() {
+        let names = mentioned_names("@alice, @bob_1: hi @ @@ bob@carol")
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["alice", "bob_1"]);
+    }
+
+    #[test]
+    fn test_command_message_edit() {
+        let target = MessageId::new(b"message");
+
+        // good
+        assert!(Command::MessageEdit {
+            target: target.clone(),
+            new_contents: "hello world!".to_owned(),
+            channel: "hello".to_owned(),
+        }
+        .is_valid());
+
+        // bad: empty message
+        assert!(!Command::MessageEdit {
+            target: target.clone(),
+            new_contents: String::new(),
+            channel: "hello".to_owned(),
+        }
+        .is_valid());
+
+        // bad: empty channel name
+        assert!(!Command::MessageEdit {
+            target,
+            new_contents: "hello world!".to_owned(),
+            channel: String::new(),
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_message_delete() {
+        // good
+        assert!(Command::MessageDelete {
+            target: MessageId::new(b"message"),
+            channel: "hello".to_owned(),
+        }
+        .is_valid());
+
+        // bad: empty channel name
+        assert!(!Command::MessageDelete {
+            target: MessageId::new(b"message"),
+            channel: String::new(),
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_hello() {
+        // good
+        assert!(Command::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+        .is_valid());
+
+        // bad: empty capability
+        assert!(!Command::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![String::new()],
+        }
+        .is_valid());
+
+        // bad: too many capabilities
+        assert!(!Command::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec!["direct".to_owned(); MAX_CAPABILITIES + 1],
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_direct_message() {
+        // good
+        assert!(Command::DirectMessage {
+            contents: "hello world!".to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal
+        }
+        .is_valid());
+
+        // bad: empty message
+        assert!(!Command::DirectMessage {
+            contents: String::new(),
+            timestamp: 64,
+            message_type: MessageType::Normal
+        }
+        .is_valid());
+
+        // bad: very long message
+        assert!(!Command::DirectMessage {
+            contents: "hello".repeat(200),
+            timestamp: 64,
+            message_type: MessageType::Me
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_nickname_update() {
+        // good
+        assert!(Command::NicknameUpdate {
+            nick: "alice".to_owned()
+        }
+        .is_valid());
+
+        // bad: name empty
+        assert!(!Command::NicknameUpdate {
+            nick: String::new(),
+        }
+        .is_valid());
+
+        // bad: name too long
+        assert!(!Command::NicknameUpdate {
+            nick: "verylongnameverylongname".to_owned()
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_goodbye() {
+        // good
+        assert!(Command::Goodbye {
+            channels: vec!["hello".to_owned(), "world".to_owned()],
+            reason: None,
+        }
+        .is_valid());
+
+        // good: no channels
+        assert!(Command::Goodbye {
+            channels: vec![],
+            reason: Some("quit".to_owned()),
+        }
+        .is_valid());
+
+        // bad: empty channel name
+        assert!(!Command::Goodbye {
+            channels: vec![String::new()],
+            reason: None,
+        }
+        .is_valid());
+
+        // bad: very long reason
+        assert!(!Command::Goodbye {
+            channels: vec![],
+            reason: Some("goodbye".repeat(100)),
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_command_poll() {
+        let poll = |question: &str, options: &[&str]| Command::Poll {
+            channel: "hello".to_owned(),
+            question: question.to_owned(),
+            options: options.iter().map(|o| o.to_string()).collect(),
+            closes_at: 64,
+        };
+
+        // good
+        assert!(poll("lunch?", &["pizza", "tacos"]).is_valid());
+
+        // bad: empty question
+        assert!(!poll("", &["pizza", "tacos"]).is_valid());
+
+        // bad: only one option
+        assert!(!poll("lunch?", &["pizza"]).is_valid());
+
+        // bad: empty option
+        assert!(!poll("lunch?", &["pizza", ""]).is_valid());
+
+        // bad: too many options
+        assert!(!poll("lunch?", &["pizza"; MAX_POLL_OPTIONS + 1]).is_valid());
+    }
+
+    #[test]
+    fn test_command_vote() {
+        // good
+        assert!(Command::Vote {
+            channel: "hello".to_owned(),
+            poll: MessageId::from("poll"),
+            option: 1,
+        }
+        .is_valid());
+
+        // bad: option out of range
+        assert!(!Command::Vote {
+            channel: "hello".to_owned(),
+            poll: MessageId::from("poll"),
+            option: MAX_POLL_OPTIONS,
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_message_channel() {
+        let channel = "hello".to_owned();
+        let commands = [
+            Command::MessageDelete {
+                target: MessageId::new(b"message"),
+                channel: channel.clone(),
+            },
+            Command::Vote {
+                channel: channel.clone(),
+                poll: MessageId::from("poll"),
+                option: 1,
+            },
+        ];
+        for command in &commands {
+            assert_eq!(command.message_channel(), Some(&channel));
+        }
+
+        // commands about the channel itself are not its messages
+        let leave = Command::ChannelRequestLeave { channel };
+        assert_eq!(leave.message_channel(), None);
+        let nick = Command::NicknameUpdate {
+            nick: "alice".to_owned(),
+        };
+        assert_eq!(nick.message_channel(), None);
+    }
+
+    #[test]
+    fn test_command_channel_update() {
+        let owner = PeerId::random();
+
+        // good
+        assert!(Command::ChannelUpdate {
+            channel: Channel::new("hello".to_owned(), owner)
+        }
+        .is_valid());
+
+        // good: archived
+        assert!(Command::ChannelUpdate {
+            channel: Channel::new("hello".to_owned(), owner).archive()
+        }
+        .is_valid());
+
+        // bad: empty channel name
+        assert!(!Command::ChannelUpdate {
+            channel: Channel::new(String::new(), owner)
+        }
+        .is_valid());
+
+        // bad: no peers
+        assert!(!Command::ChannelUpdate {
+            channel: Channel {
+                peers: vec![],
+                ..Channel::new("hello".to_owned(), owner)
+            }
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_channel_welcome() {
+        let channel = Channel::new("hello".to_owned(), PeerId::random());
+
+        // good
+        let welcomed = channel.with_welcome(Some("read the rules".to_owned()));
+        assert!(welcomed.is_valid());
+        assert_eq!(welcomed.welcome(), Some("read the rules"));
+        assert_eq!(welcomed.version(), channel.version() + 1);
+
+        // good: cleared
+        assert!(welcomed.with_welcome(None).is_valid());
+
+        // bad: empty welcome
+        assert!(!channel.with_welcome(Some(String::new())).is_valid());
+
+        // bad: very long welcome
+        assert!(!channel.with_welcome(Some("welcome".repeat(200))).is_valid());
+    }
+
+    #[test]
+    fn test_channel_older_record() {
+        // records written before channels had any of their later fields
+        let owner = PeerId::random();
+        let record =
+            rmp_serde::to_vec(&("hello", owner, vec![owner], 3u64)).unwrap();
+        let channel: Channel = rmp_serde::from_slice(&record).unwrap();
+        assert_eq!(channel.version(), 3);
+        assert!(!channel.is_archived());
+        assert_eq!(channel.welcome(), None);
+        assert!(channel.is_valid());
+    }
+
+    #[test]
+    fn test_channel_policy() {
+        let channel = Channel::new("hello".to_owned(), PeerId::random());
+        let policy = ChannelPolicy {
+            max_message_length: Some(5),
+            slow_mode: Some(30),
+            allowed_types: Some(vec![MessageType::Normal]),
+        };
+        assert!(policy.permits("hello", MessageType::Normal));
+        assert!(!policy.permits("hello!", MessageType::Normal));
+        assert!(!policy.permits("waves", MessageType::Me));
+
+        let limited = channel.with_policy(policy);
+        assert!(limited.is_valid());
+        assert_eq!(limited.version(), channel.version() + 1);
+
+        // bad: zero limits
+        assert!(!channel
+            .with_policy(ChannelPolicy {
+                slow_mode: Some(0),
+                ..Default::default()
+            })
+            .is_valid());
+
+        // bad: no allowed types
+        assert!(!channel
+            .with_policy(ChannelPolicy {
+                allowed_types: Some(vec![]),
+                ..Default::default()
+            })
+            .is_valid());
+    }
+
+    #[test]
+    fn test_channel_archive() {
+        let channel = Channel::new("hello".to_owned(), PeerId::random());
+        assert!(!channel.is_archived());
+
+        let archived = channel.archive();
+        assert!(archived.is_archived());
+        assert_eq!(archived.version(), channel.version() + 1);
+    }
+
+    #[test]
+    fn test_command_compression() {
+        let message = |contents: String| Command::MessageSend {
+            contents,
+            channel: "hello".to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        };
+
+        // small commands are left alone
+        let small = message("hello world!".to_owned());
+        let encoded = small.encode().unwrap();
+        assert_eq!(encoded[0], FLAG_UNCOMPRESSED);
+        assert_eq!(
+            Command::decode(&encoded).unwrap().encode().unwrap(),
+            encoded
+        );
+
+        // large commands are compressed
+        let large = message("hello ".repeat(80));
+        let encoded = large.encode().unwrap();
+        assert_eq!(encoded[0], FLAG_DEFLATE);
+        assert!(
+            encoded.len()
+                < encode_tagged::<DefaultCodec, _>(&large).unwrap().len()
+        );
+        assert_eq!(
+            Command::decode(&encoded).unwrap().encode().unwrap(),
+            encoded
+        );
+
+        // bad: unknown flag
+        assert!(Command::decode(&[0xff]).is_err());
+
+        // bad: empty
+        assert!(Command::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let peer = PeerId::random();
+        let command = Command::NicknameUpdate {
+            nick: "hello".to_owned(),
+        };
+
+        // small commands are sent whole
+        let encoded = command.encode().unwrap();
+        assert_eq!(fragment(encoded.clone()).unwrap(), vec![encoded]);
+
+        // large commands are split, and reassembled in any order
+        let encoded = (0..=255).cycle().take(3000).collect::<Vec<u8>>();
+        let fragments = fragment(encoded.clone()).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let mut reassembler = Reassembler::default();
+        let mut reassembled = None;
+        for data in fragments.iter().rev() {
+            let Command::Fragment {
+                id,
+                index,
+                total,
+                data,
+            } = Command::decode(data).unwrap()
+            else {
+                panic!("expected a fragment");
+            };
+            assert!(reassembled.is_none());
+            reassembled =
+                reassembler.insert(peer, id, index, total, data).unwrap();
+        }
+        assert_eq!(reassembled, Some(encoded));
+
+        // bad: too large to fragment
+        assert!(
+            fragment(vec![0; MAX_FRAGMENTS * MAX_FRAGMENT_SIZE + 1]).is_err()
+        );
+
+        // bad: inconsistent fragment count
+        assert!(reassembler
+            .insert(peer, 1, 0, 2, vec![0])
+            .unwrap()
+            .is_none());
+        assert!(reassembler.insert(peer, 1, 1, 3, vec![0]).is_err());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check(10));
+        assert!(!window.check(10));
+
+        // out of order, but within the window
+        assert!(window.check(12));
+        assert!(window.check(11));
+        assert!(!window.check(11));
+        assert!(window.check(5));
+
+        // too old to tell
+        assert!(window.check(100));
+        assert!(!window.check(12));
+        assert!(window.check(100 - REPLAY_WINDOW_SIZE));
+        assert!(!window.check(99 - REPLAY_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = MIN_TIMESTAMP + 3_600_000;
+        let skew = Duration::from_secs(60);
+
+        assert_eq!(
+            check_timestamp(now, now, skew),
+            TimestampVerdict::Plausible
+        );
+        assert_eq!(
+            check_timestamp(now + 60_000, now, skew),
+            TimestampVerdict::Plausible
+        );
+        assert_eq!(
+            check_timestamp(MIN_TIMESTAMP, now, skew),
+            TimestampVerdict::Plausible
+        );
+
+        // bad: too far ahead
+        assert_eq!(
+            check_timestamp(now + 60_001, now, skew),
+            TimestampVerdict::Ahead
+        );
+
+        // bad: impossibly old
+        assert_eq!(check_timestamp(64, now, skew), TimestampVerdict::Ancient);
+    }
+
+    #[test]
+    fn test_causal_order() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (first, second) = if a < b { (a, b) } else { (b, a) };
+
+        let order = |clock, sender| CausalOrder { clock, sender };
+        assert!(order(1, second) < order(2, first));
+        assert!(order(2, first) < order(2, second));
+        assert_eq!(order(2, first), order(2, first));
+    }
+
+    #[test]
+    fn test_signed_message() {
+        let key = Keypair::generate_ed25519();
+        let command = Command::MessageSend {
+            contents: "hello world!".to_owned(),
+            channel: "hello".to_owned(),
+            timestamp: 64,
+            message_type: MessageType::Normal,
+            in_reply_to: None,
+            mentions: vec![],
+            attachment: None,
+            sequence: 0,
+            clock: 0,
+            expires_after: None,
+        };
+
+        let Command::SignedMessage { envelope } = command.sign(&key).unwrap()
+        else {
+            panic!("expected a signed message");
+        };
+        let (verified, signature) =
+            MessageSignature::verify(envelope.clone()).unwrap();
+        assert_eq!(verified.encode().unwrap(), command.encode().unwrap());
+        assert_eq!(signature.author(), key.public().to_peer_id());
+        assert_eq!(signature.envelope(), envelope.as_slice());
+
+        // bad: only messages may be signed
+        let nick = Command::NicknameUpdate {
+            nick: "hello".to_owned(),
+        };
+        assert!(nick.sign(&key).is_err());
+
+        // bad: signed for another purpose
+        let value = MemoryValue::Nickname {
+            user: key.public().to_peer_id(),
+            nickname: "hello".to_owned(),
+        };
+        let envelope = value.encode_signed(&key).unwrap();
+        assert!(MessageSignature::verify(envelope).is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let key = Keypair::generate_ed25519();
+        let user = key.public().to_peer_id();
+        let profile = Profile {
+            nick: "hello".to_owned(),
+            bio: Some("world".to_owned()),
+            pronouns: Some("they/them".to_owned()),
+            avatar_hash: None,
+        };
+        assert!(profile.is_valid());
+
+        let envelope = profile.to_value(user).encode_signed(&key).unwrap();
+        assert!(matches!(
+            MemoryValue::decode(&envelope).unwrap(),
+            MemoryValue::Profile { user: decoded, .. } if decoded == user
+        ));
+
+        // good: signed before payloads were tagged with their format
+        let envelope = SignedEnvelope::new(
+            &key,
+            SIGNED_ENVELOPE_DOMAIN.to_owned(),
+            LEGACY_PAYLOAD_TYPE.to_vec(),
+            rmp_serde::to_vec(&profile.to_value(user)).unwrap(),
+        )
+        .unwrap()
+        .into_protobuf_encoding();
+        assert!(matches!(
+            MemoryValue::decode(&envelope).unwrap(),
+            MemoryValue::Profile { user: decoded, .. } if decoded == user
+        ));
+
+        // bad: signed by someone other than the user
+        let envelope = profile
+            .to_value(PeerId::random())
+            .encode_signed(&key)
+            .unwrap();
+        assert!(MemoryValue::decode(&envelope).is_err());
+
+        // bad: bio too long
+        let long = Profile {
+            bio: Some("a".repeat(MAX_BIO_LENGTH + 1)),
+            ..profile
+        };
+        assert!(!long.is_valid());
+    }
+
+    #[test]
+    fn test_channel_membership() {
+        let owner = PeerId::random();
+        let peer = PeerId::random();
+        let channel = Channel::new("hello".to_owned(), owner);
+
+        let joined = channel.with_peer(peer);
+        assert_eq!(joined.peers(), &[owner, peer]);
+        assert_eq!(joined.version(), channel.version() + 1);
+
+        let left = joined.without_peer(&peer);
+        assert_eq!(left.peers(), &[owner]);
+        assert_eq!(left.version(), joined.version() + 1);
+        assert!(left.is_valid());
+
+        let banned = joined.with_ban(peer);
+        assert_eq!(banned.peers(), &[owner]);
+        assert!(banned.is_banned(&peer));
+        assert!(!banned.is_banned(&owner));
+        assert!(banned.is_valid());
+
+        // bad: owner banned from their own channel
+        assert!(!channel.with_ban(owner).is_valid());
+    }
+
+    #[test]
+    fn test_moderation() {
+        let key = Keypair::generate_ed25519();
+        let owner = key.public().to_peer_id();
+        let peer = PeerId::random();
+        let moderation = Moderation::new("hello".to_owned(), owner);
+
+        let banned = moderation.with_ban(peer).with_ban(peer);
+        assert_eq!(banned.banned(), &[peer]);
+        assert_eq!(banned.version(), 2);
+        assert!(banned.is_valid());
+
+        let envelope = MemoryValue::Moderation(banned.clone())
+            .encode_signed(&key)
+            .unwrap();
+        assert!(matches!(
+            MemoryValue::decode(&envelope).unwrap(),
+            MemoryValue::Moderation(decoded) if decoded.is_banned(&peer)
+        ));
+
+        // bad: signed by someone other than the owner
+        let other = Keypair::generate_ed25519();
+        let envelope = MemoryValue::Moderation(banned)
+            .encode_signed(&other)
+            .unwrap();
+        assert!(MemoryValue::decode(&envelope).is_err());
+
+        // bad: owner banned from their own channel
+        assert!(!moderation.with_ban(owner).is_valid());
+    }
+
+    #[test]
+    fn test_nick_claim() {
+        let key = Keypair::generate_ed25519();
+        let owner = key.public().to_peer_id();
+        let claim = NickClaim::new("alice".to_owned(), owner, MIN_TIMESTAMP);
+        assert!(claim.is_valid());
+
+        let envelope = MemoryValue::NickClaim(claim.clone())
+            .encode_signed(&key)
+            .unwrap();
+        assert!(matches!(
+            MemoryValue::decode(&envelope).unwrap(),
+            MemoryValue::NickClaim(decoded) if decoded == claim
+        ));
+
+        // the earliest claim wins, whoever made it
+        let later = NickClaim::new(
+            "alice".to_owned(),
+            PeerId::random(),
+            MIN_TIMESTAMP + 1,
+        );
+        assert!(claim.precedes(&later));
+        assert!(!later.precedes(&claim));
+        assert!(!claim.precedes(&claim));
+
+        // bad: signed by someone other than the owner
+        let other = Keypair::generate_ed25519();
+        let envelope =
+            MemoryValue::NickClaim(later).encode_signed(&other).unwrap();
+        assert!(MemoryValue::decode(&envelope).is_err());
+    }
+
+    #[test]
+    fn test_channel_roles() {
+        let owner = PeerId::random();
+        let moderator = PeerId::random();
+        let member = PeerId::random();
+        let channel = Channel::new("hello".to_owned(), owner)
+            .with_peer(moderator)
+            .with_peer(member)
+            .with_role(moderator, Role::Moderator);
+        assert_eq!(channel.role(&owner), Role::Owner);
+        assert_eq!(channel.role(&moderator), Role::Moderator);
+        assert_eq!(channel.role(&member), Role::Member);
+        assert!(channel.is_valid());
+
+        let kick = |peer| Command::ChannelKick {
+            channel: "hello".to_owned(),
+            peer,
+        };
+        assert!(kick(member).is_permitted(&owner, &channel));
+        assert!(kick(moderator).is_permitted(&owner, &channel));
+        assert!(kick(member).is_permitted(&moderator, &channel));
+
+        // bad: kicking a peer of equal or greater role
+        assert!(!kick(owner).is_permitted(&moderator, &channel));
+        assert!(!kick(moderator).is_permitted(&moderator, &channel));
+
+        // bad: members may not kick anyone
+        assert!(!kick(member).is_permitted(&member, &channel));
+
+        // removing a moderator takes away their role
+        let kicked = channel.without_peer(&moderator);
+        assert_eq!(kicked.role(&moderator), Role::Member);
+
+        // transferring ownership
+        let transferred = channel.with_role(member, Role::Owner);
+        assert_eq!(transferred.owner(), &member);
+        assert_eq!(transferred.role(&owner), Role::Moderator);
+        assert!(transferred.is_valid());
+
+        let demoted = channel.with_role(moderator, Role::Member);
+        assert_eq!(demoted.role(&moderator), Role::Member);
+        assert_eq!(demoted.version(), channel.version() + 1);
+    }
+
+    #[test]
+    fn test_invite() {
+        let key = Keypair::generate_ed25519();
+        let owner = key.public().to_peer_id();
+        let peer = PeerId::random();
+        let channel =
+            Channel::new("hello".to_owned(), owner).with_invite_only(true);
+        assert!(channel.is_invite_only());
+
+        let invite = Invite {
+            channel: "hello".to_owned(),
+            invitee: peer,
+            expires_at: 1000,
+        };
+        let token = invite.sign(&key).unwrap();
+        let (invite, inviter) = Invite::verify(&token).unwrap();
+        assert_eq!(inviter, owner);
+
+        // good
+        assert!(invite.admits(&inviter, &channel, &peer, 1000));
+
+        // bad: expired
+        assert!(!invite.admits(&inviter, &channel, &peer, 1001));
+
+        // bad: for another peer
+        assert!(!invite.admits(&inviter, &channel, &owner, 1000));
+
+        // bad: for another channel
+        let other = Channel::new("world".to_owned(), owner);
+        assert!(!invite.admits(&inviter, &other, &peer, 1000));
+
+        // bad: not issued by the owner
+        let (invite, inviter) =
+            Invite::verify(&invite.sign(&Keypair::generate_ed25519()).unwrap())
+                .unwrap();
+        assert!(!invite.admits(&inviter, &channel, &peer, 1000));
+    }
+
+    #[test]
+    fn test_blob_hash() {
+        assert_eq!(blob_hash(b"hello"), blob_hash(b"hello"));
+        assert_ne!(blob_hash(b"hello"), blob_hash(b"world"));
+    }
+
+    #[test]
+    fn test_channel_topics() {
+        assert_eq!(
+            "/p2p-chat/channel/hello world",
+            &format!("{}", topic_from_channel(&"hello world".to_owned()))
+        );
+
+        let topic = topic_from_channel(&"hello".to_owned()).hash();
+        assert_eq!(channel_from_topic(&topic), Some("hello".to_owned()));
+
+        let topic = gossipsub::IdentTopic::new(DEFAULT_GOSSIPSUB_TOPIC).hash();
+        assert_eq!(channel_from_topic(&topic), None);
     }
 }
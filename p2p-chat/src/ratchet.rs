@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use libp2p::{identity::Keypair, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::crypto::{
+    decrypt, encrypt, random_nonce, random_secret, to_key, x25519_public_key,
+    x25519_secret_key, KEY_LENGTH, NONCE_LENGTH,
+};
+
+/// The maximum number of message keys kept for messages which have not
+/// arrived yet, in case they arrive out of order.
+pub const MAX_SKIPPED_KEYS: usize = 256;
+
+/// The HKDF info used to derive the shared secret a session begins with.
+const SESSION_KEY_INFO: &[u8] = b"p2p-chat-session";
+
+/// The HKDF info used to derive new root and chain keys in a ratchet step.
+const ROOT_KEY_INFO: &[u8] = b"p2p-chat-ratchet";
+
+/// The header of a direct message encrypted in a [`DmSession`], which is sent
+/// in the clear and authenticated along with the message.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RatchetHeader {
+    /// The current ratchet public key of the sender.
+    #[serde(with = "serde_bytes")]
+    pub ratchet_key: Vec<u8>,
+    /// The number of messages in the sender's previous sending chain.
+    pub previous_length: u32,
+    /// The number of this message in the sender's current sending chain.
+    pub number: u32,
+    /// The public key of the ephemeral key the session was begun with, which
+    /// the peer who began it sends until they hear back.
+    #[serde(default, with = "serde_bytes")]
+    pub ephemeral: Option<Vec<u8>>,
+}
+
+impl RatchetHeader {
+    pub fn is_valid(&self) -> bool {
+        self.ratchet_key.len() == KEY_LENGTH
+            && self
+                .ephemeral
+                .as_ref()
+                .is_none_or(|ephemeral| ephemeral.len() == KEY_LENGTH)
+    }
+
+    /// The data the message is authenticated along with.
+    fn associated_data(&self) -> Vec<u8> {
+        let mut data = self.ratchet_key.clone();
+        data.extend(self.previous_length.to_be_bytes());
+        data.extend(self.number.to_be_bytes());
+        if let Some(ephemeral) = &self.ephemeral {
+            data.extend(ephemeral);
+        }
+        data
+    }
+}
+
+/// The state of an encrypted direct message session with a peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DmSession {
+    /// Whether we began the session.
+    pub initiator: bool,
+    /// Whether the peer has shown that they share the session, by sending us
+    /// a message in it which we could decrypt.
+    ///
+    /// Since sessions are agreed with the identity keys of both peers, a
+    /// confirmed session is known to be with the peer it claims to be.
+    pub confirmed: bool,
+    /// The number of ratchet steps taken, each of which replaces the keys of
+    /// the session with new ones.
+    pub ratchet_steps: u64,
+    /// The number of messages sent in the session.
+    pub sent: u64,
+    /// The number of messages received in the session.
+    pub received: u64,
+}
+
+/// A double ratchet session with a peer, begun with a key agreement between
+/// the identity keys of both peers and an ephemeral key.
+///
+/// Each message is encrypted with its own key, which is deleted once used, and
+/// the keys are replaced whenever the peers take turns sending messages, so
+/// that leaking the current state does not reveal earlier messages.
+#[derive(Clone)]
+pub(crate) struct Session {
+    root_key: [u8; KEY_LENGTH],
+    ratchet: StaticSecret,
+    remote_ratchet: Option<PublicKey>,
+    sending_chain: Option<[u8; KEY_LENGTH]>,
+    receiving_chain: Option<[u8; KEY_LENGTH]>,
+    sent_in_chain: u32,
+    received_in_chain: u32,
+    previous_length: u32,
+    skipped: HashMap<([u8; KEY_LENGTH], u32), [u8; KEY_LENGTH]>,
+    /// The public key of the ephemeral key the session was begun with.
+    ephemeral: [u8; KEY_LENGTH],
+    /// The public forms of our identity key and the peer's which the session
+    /// was agreed with.
+    identity_keys: ([u8; KEY_LENGTH], [u8; KEY_LENGTH]),
+    state: DmSession,
+}
+
+impl Session {
+    /// Begin a new session with a peer.
+    pub(crate) fn initiate(
+        id_keys: &Keypair,
+        peer: &PeerId,
+    ) -> crate::Result<Self> {
+        let identity = x25519_secret_key(id_keys)?;
+        let remote_identity = x25519_public_key(peer)?;
+        let ephemeral = random_secret();
+
+        let session_key = session_key(
+            &identity.diffie_hellman(&remote_identity).to_bytes(),
+            &ephemeral.diffie_hellman(&remote_identity).to_bytes(),
+        );
+
+        // the identity key of the peer serves as their first ratchet key
+        let ratchet = random_secret();
+        let (root_key, sending_chain) = kdf_root(
+            &session_key,
+            &ratchet.diffie_hellman(&remote_identity).to_bytes(),
+        );
+
+        Ok(Session {
+            root_key,
+            ratchet,
+            remote_ratchet: Some(remote_identity),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent_in_chain: 0,
+            received_in_chain: 0,
+            previous_length: 0,
+            skipped: HashMap::new(),
+            ephemeral: PublicKey::from(&ephemeral).to_bytes(),
+            identity_keys: (
+                PublicKey::from(&identity).to_bytes(),
+                remote_identity.to_bytes(),
+            ),
+            state: DmSession {
+                initiator: true,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Accept a session begun by a peer with an ephemeral key.
+    ///
+    /// The session may only send messages once it has received the first
+    /// message from the peer.
+    pub(crate) fn respond(
+        id_keys: &Keypair,
+        peer: &PeerId,
+        ephemeral: &[u8],
+    ) -> crate::Result<Self> {
+        let identity = x25519_secret_key(id_keys)?;
+        let remote_identity = x25519_public_key(peer)?;
+        let ephemeral = to_key(ephemeral)?;
+
+        let session_key = session_key(
+            &identity.diffie_hellman(&remote_identity).to_bytes(),
+            &identity
+                .diffie_hellman(&PublicKey::from(ephemeral))
+                .to_bytes(),
+        );
+
+        let identity_keys = (
+            PublicKey::from(&identity).to_bytes(),
+            remote_identity.to_bytes(),
+        );
+
+        Ok(Session {
+            root_key: session_key,
+            ratchet: identity,
+            remote_ratchet: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent_in_chain: 0,
+            received_in_chain: 0,
+            previous_length: 0,
+            skipped: HashMap::new(),
+            ephemeral,
+            identity_keys,
+            state: DmSession::default(),
+        })
+    }
+
+    /// Get the state of the session.
+    pub(crate) fn state(&self) -> &DmSession {
+        &self.state
+    }
+
+    /// Get the public keys of this peer and the remote peer which the session
+    /// was agreed with, in that order.
+    pub(crate) fn identity_keys(&self) -> ([u8; KEY_LENGTH], [u8; KEY_LENGTH]) {
+        self.identity_keys
+    }
+
+    /// Get whether or not the session was begun with an ephemeral key.
+    pub(crate) fn was_begun_with(&self, ephemeral: &[u8]) -> bool {
+        self.ephemeral == ephemeral
+    }
+
+    /// Encrypt a message to the peer, returning its header, nonce and
+    /// ciphertext.
+    pub(crate) fn encrypt(
+        &mut self,
+        data: &[u8],
+    ) -> crate::Result<(RatchetHeader, Vec<u8>, Vec<u8>)> {
+        let chain = self.sending_chain.ok_or_else(|| {
+            crate::Error::InvalidData(String::from(
+                "session cannot send messages yet",
+            ))
+        })?;
+        let (chain, message_key) = kdf_chain(&chain);
+
+        let header = RatchetHeader {
+            ratchet_key: PublicKey::from(&self.ratchet).as_bytes().to_vec(),
+            previous_length: self.previous_length,
+            number: self.sent_in_chain,
+            ephemeral: (self.state.initiator && !self.state.confirmed)
+                .then(|| self.ephemeral.to_vec()),
+        };
+
+        let nonce = random_nonce();
+        let ciphertext =
+            encrypt(&message_key, &nonce, data, &header.associated_data())?;
+
+        self.sending_chain = Some(chain);
+        self.sent_in_chain += 1;
+        self.state.sent += 1;
+
+        Ok((header, nonce, ciphertext))
+    }
+
+    /// Decrypt a message from the peer.
+    ///
+    /// The session is left untouched if the message cannot be decrypted.
+    pub(crate) fn decrypt(
+        &mut self,
+        header: &RatchetHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> crate::Result<Vec<u8>> {
+        if !header.is_valid() || nonce.len() != NONCE_LENGTH {
+            return Err(crate::Error::InvalidData(String::from(
+                "invalid ratchet header",
+            )));
+        }
+
+        let mut session = self.clone();
+        let data = session.ratchet_decrypt(header, nonce, ciphertext)?;
+
+        session.state.confirmed = true;
+        session.state.received += 1;
+        *self = session;
+
+        Ok(data)
+    }
+
+    fn ratchet_decrypt(
+        &mut self,
+        header: &RatchetHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> crate::Result<Vec<u8>> {
+        let associated_data = header.associated_data();
+        let remote_ratchet = to_key(&header.ratchet_key)?;
+
+        if let Some(message_key) =
+            self.skipped.remove(&(remote_ratchet, header.number))
+        {
+            return decrypt(&message_key, nonce, ciphertext, &associated_data);
+        }
+
+        let is_new_ratchet = self
+            .remote_ratchet
+            .is_none_or(|ratchet| ratchet.to_bytes() != remote_ratchet);
+        if is_new_ratchet {
+            self.skip_message_keys(header.previous_length)?;
+            self.ratchet_step(PublicKey::from(remote_ratchet));
+        }
+
+        self.skip_message_keys(header.number)?;
+        let chain =
+            self.receiving_chain.ok_or(crate::Error::EncryptionError)?;
+        let (chain, message_key) = kdf_chain(&chain);
+        self.receiving_chain = Some(chain);
+        self.received_in_chain += 1;
+
+        decrypt(&message_key, nonce, ciphertext, &associated_data)
+    }
+
+    /// Keep the keys of the messages of the current receiving chain up to a
+    /// number, which have not arrived yet.
+    fn skip_message_keys(&mut self, until: u32) -> crate::Result<()> {
+        let (mut chain, remote_ratchet) =
+            match (self.receiving_chain, self.remote_ratchet) {
+                (Some(chain), Some(ratchet)) => (chain, ratchet.to_bytes()),
+                _ => return Ok(()),
+            };
+
+        let skipping = until.saturating_sub(self.received_in_chain) as usize;
+        if self.skipped.len() + skipping > MAX_SKIPPED_KEYS {
+            return Err(crate::Error::InvalidData(String::from(
+                "too many skipped messages",
+            )));
+        }
+
+        while self.received_in_chain < until {
+            let (next, message_key) = kdf_chain(&chain);
+            self.skipped
+                .insert((remote_ratchet, self.received_in_chain), message_key);
+            chain = next;
+            self.received_in_chain += 1;
+        }
+        self.receiving_chain = Some(chain);
+
+        Ok(())
+    }
+
+    /// Replace the keys of the session upon the peer sending a new ratchet
+    /// key.
+    fn ratchet_step(&mut self, remote_ratchet: PublicKey) {
+        self.previous_length = self.sent_in_chain;
+        self.sent_in_chain = 0;
+        self.received_in_chain = 0;
+        self.remote_ratchet = Some(remote_ratchet);
+
+        let (root_key, receiving_chain) = kdf_root(
+            &self.root_key,
+            &self.ratchet.diffie_hellman(&remote_ratchet).to_bytes(),
+        );
+        self.ratchet = random_secret();
+        let (root_key, sending_chain) = kdf_root(
+            &root_key,
+            &self.ratchet.diffie_hellman(&remote_ratchet).to_bytes(),
+        );
+
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+        self.state.ratchet_steps += 1;
+    }
+}
+
+/// Derive the secret a session begins with from its two key agreements.
+fn session_key(identities: &[u8], ephemeral: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut ikm = identities.to_vec();
+    ikm.extend(ephemeral);
+
+    let mut key = [0; KEY_LENGTH];
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand(SESSION_KEY_INFO, &mut key)
+        .expect("key length is valid");
+    key
+}
+
+/// Derive a new root key and chain key from a root key and a key agreement.
+fn kdf_root(
+    root_key: &[u8; KEY_LENGTH],
+    shared: &[u8],
+) -> ([u8; KEY_LENGTH], [u8; KEY_LENGTH]) {
+    let mut okm = [0; 2 * KEY_LENGTH];
+    Hkdf::<Sha256>::new(Some(root_key), shared)
+        .expand(ROOT_KEY_INFO, &mut okm)
+        .expect("key length is valid");
+
+    let (root_key, chain_key) = okm.split_at(KEY_LENGTH);
+    (root_key.try_into().unwrap(), chain_key.try_into().unwrap())
+}
+
+/// Derive the next chain key and a message key from a chain key.
+fn kdf_chain(
+    chain_key: &[u8; KEY_LENGTH],
+) -> ([u8; KEY_LENGTH], [u8; KEY_LENGTH]) {
+    let derive = |constant: u8| -> [u8; KEY_LENGTH] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(chain_key)
+            .expect("HMAC takes keys of any length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+
+    (derive(0x02), derive(0x01))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let alice_keys = Keypair::generate_ed25519();
+        let bob_keys = Keypair::generate_ed25519();
+        let alice_id = alice_keys.public().to_peer_id();
+        let bob_id = bob_keys.public().to_peer_id();
+
+        let mut alice = Session::initiate(&alice_keys, &bob_id).unwrap();
+        let first = alice.encrypt(b"hello").unwrap();
+        let second = alice.encrypt(b"there").unwrap();
+        assert!(first.0.ephemeral.is_some());
+
+        let ephemeral = first.0.ephemeral.clone().unwrap();
+        let mut bob =
+            Session::respond(&bob_keys, &alice_id, &ephemeral).unwrap();
+        assert!(bob.was_begun_with(&ephemeral));
+
+        // out of order
+        assert_eq!(
+            bob.decrypt(&second.0, &second.1, &second.2).unwrap(),
+            b"there"
+        );
+        assert_eq!(
+            bob.decrypt(&first.0, &first.1, &first.2).unwrap(),
+            b"hello"
+        );
+
+        // bad: replayed
+        assert!(bob.decrypt(&first.0, &first.1, &first.2).is_err());
+
+        let reply = bob.encrypt(b"hi").unwrap();
+        assert!(reply.0.ephemeral.is_none());
+        assert_eq!(alice.decrypt(&reply.0, &reply.1, &reply.2).unwrap(), b"hi");
+        assert!(alice.state().confirmed);
+
+        // once confirmed, the ephemeral key is no longer sent
+        let third = alice.encrypt(b"again").unwrap();
+        assert!(third.0.ephemeral.is_none());
+        assert_eq!(
+            bob.decrypt(&third.0, &third.1, &third.2).unwrap(),
+            b"again"
+        );
+        assert_eq!(bob.state().ratchet_steps, 2);
+
+        // bad: tampered header
+        let mut fourth = alice.encrypt(b"again").unwrap();
+        fourth.0.number += 1;
+        assert!(bob.decrypt(&fourth.0, &fourth.1, &fourth.2).is_err());
+
+        // bad: another peer pretending to be alice
+        let eve_keys = Keypair::generate_ed25519();
+        let mut eve = Session::initiate(&eve_keys, &bob_id).unwrap();
+        let forged = eve.encrypt(b"hello").unwrap();
+        let mut bob = Session::respond(
+            &bob_keys,
+            &alice_id,
+            forged.0.ephemeral.as_ref().unwrap(),
+        )
+        .unwrap();
+        assert!(bob.decrypt(&forged.0, &forged.1, &forged.2).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, time::Instant};
+
+use libp2p::PeerId;
+
+/// Limits how many messages each peer may send, with a token bucket per peer
+/// which refills at a steady rate up to a burst size.
+pub struct RateLimiter {
+    /// How many messages per second each peer may send in the long run.
+    rate: f64,
+    /// How many messages a peer may send at once after being quiet.
+    burst: f64,
+    buckets: HashMap<PeerId, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Whether the last message of the peer was refused.
+    throttled: bool,
+}
+
+/// The verdict of a [`RateLimiter`] on a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateVerdict {
+    Allowed,
+    /// The message was refused, and the peer was not throttled before it.
+    Throttled,
+    /// The message was refused, as was the one before it.
+    StillThrottled,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token from the bucket of a peer for a message it sent.
+    pub fn check(&mut self, peer: PeerId, now: Instant) -> RateVerdict {
+        let burst = self.burst;
+        let bucket = self.buckets.entry(peer).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+            throttled: false,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+            RateVerdict::Allowed
+        } else if bucket.throttled {
+            RateVerdict::StillThrottled
+        } else {
+            bucket.throttled = true;
+            RateVerdict::Throttled
+        }
+    }
+
+    /// Forget the buckets of peers which have been quiet for long enough to
+    /// have refilled them.
+    pub fn expire(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated_at);
+            bucket.tokens + elapsed.as_secs_f64() * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2.0, 3);
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(peer, start), RateVerdict::Allowed);
+        }
+        assert_eq!(limiter.check(peer, start), RateVerdict::Throttled);
+        assert_eq!(limiter.check(peer, start), RateVerdict::StillThrottled);
+
+        // other peers have their own buckets
+        let other = PeerId::random();
+        assert_eq!(limiter.check(other, start), RateVerdict::Allowed);
+
+        // half a second refills one token at two per second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check(peer, later), RateVerdict::Allowed);
+        assert_eq!(limiter.check(peer, later), RateVerdict::Throttled);
+
+        // the other peer has refilled its bucket by now
+        limiter.expire(later);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.expire(start + Duration::from_secs(10));
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{ChannelIdentifier, MessageType};
+use crate::storage::{load_file, write_file, StorageKey};
+
+/// The identifier of a scheduled message, unique within a [`Scheduler`].
+pub type ScheduleId = u64;
+
+/// A message waiting to be published at a later time.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScheduledMessage {
+    pub id: ScheduleId,
+    pub channel: ChannelIdentifier,
+    pub contents: String,
+    pub message_type: MessageType,
+    /// The time at which the message should be sent, in milliseconds since the
+    /// Unix epoch.
+    pub send_at: u64,
+}
+
+/// A set of pending scheduled messages, optionally persisted to disk.
+#[derive(Default, Debug)]
+pub struct Scheduler {
+    pending: BTreeMap<ScheduleId, ScheduledMessage>,
+    next_id: ScheduleId,
+    path: Option<PathBuf>,
+    /// The key the file is encrypted with, if any.
+    key: Option<StorageKey>,
+}
+
+impl Scheduler {
+    /// Load the pending messages stored at a given path, and persist any
+    /// further changes to it.
+    ///
+    /// Messages already scheduled in memory are kept and written to the file.
+    /// If a passphrase is given, the file is encrypted with a key derived from
+    /// it.
+    pub fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        let path = path.as_ref().to_owned();
+
+        let (stored, key): (Option<Vec<ScheduledMessage>>, _) =
+            load_file(&path, passphrase)?;
+        self.key = key;
+        for message in stored.into_iter().flatten() {
+            self.next_id = ScheduleId::max(self.next_id, message.id + 1);
+            self.pending.insert(message.id, message);
+        }
+
+        self.path = Some(path);
+        self.save()
+    }
+
+    /// Add a message to the schedule, returning its identifier.
+    pub fn schedule(
+        &mut self,
+        channel: ChannelIdentifier,
+        contents: String,
+        message_type: MessageType,
+        send_at: u64,
+    ) -> crate::Result<ScheduleId> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.pending.insert(
+            id,
+            ScheduledMessage {
+                id,
+                channel,
+                contents,
+                message_type,
+                send_at,
+            },
+        );
+        self.save()?;
+
+        Ok(id)
+    }
+
+    /// Remove a message from the schedule, returning it if it was pending.
+    pub fn cancel(
+        &mut self,
+        id: ScheduleId,
+    ) -> crate::Result<Option<ScheduledMessage>> {
+        let message = self.pending.remove(&id);
+        if message.is_some() {
+            self.save()?;
+        }
+        Ok(message)
+    }
+
+    /// Get all pending messages, ordered by identifier.
+    pub fn pending(&self) -> impl Iterator<Item = &ScheduledMessage> {
+        self.pending.values()
+    }
+
+    /// Get the messages which are due to be sent at a given time.
+    pub fn due(&self, now: u64) -> Vec<ScheduledMessage> {
+        self.pending
+            .values()
+            .filter(|message| message.send_at <= now)
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(path) = &self.path {
+            let messages = self.pending.values().collect::<Vec<_>>();
+            write_file(path, &messages, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persist_encrypted() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-schedule-{}", std::process::id()));
+
+        let mut scheduler = Scheduler::default();
+        scheduler.persist_to(&path, Some("hunter2")).unwrap();
+        let id = scheduler
+            .schedule("general".into(), "hello".into(), MessageType::Normal, 10)
+            .unwrap();
+
+        // the contents of the message are not kept in plain text
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(5).any(|window| window == b"hello"));
+
+        let mut loaded = Scheduler::default();
+        loaded.persist_to(&path, Some("hunter2")).unwrap();
+        assert_eq!(loaded.due(10)[0].contents, "hello");
+        // identifiers are not reused after loading
+        let next = loaded
+            .schedule("general".into(), "bye".into(), MessageType::Normal, 20)
+            .unwrap();
+        assert!(next > id);
+        assert!(Scheduler::default()
+            .persist_to(&path, Some("hunter3"))
+            .is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::ChannelIdentifier;
+use crate::storage::{load_file, write_file, StorageKey};
+
+/// A channel or direct conversation which was open in a session, to be
+/// resumed in the next one.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum SessionEntry {
+    Channel(ChannelIdentifier),
+    Direct(PeerId),
+}
+
+/// The channels and direct conversations which are open, in the order they
+/// were opened, optionally persisted to disk so that they can be resumed in
+/// later sessions.
+#[derive(Default, Debug)]
+pub struct SessionState {
+    entries: Vec<SessionEntry>,
+    path: Option<PathBuf>,
+    /// The key the file is encrypted with, if any.
+    key: Option<StorageKey>,
+}
+
+impl SessionState {
+    /// Load the entries stored at a given path, and persist any further
+    /// changes to it, returning the entries which were stored there.
+    ///
+    /// Entries already open in memory are kept after the stored ones. If a
+    /// passphrase is given, the file is encrypted with a key derived from it.
+    pub fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<Vec<SessionEntry>> {
+        let path = path.as_ref().to_owned();
+
+        let (stored, key): (Option<Vec<SessionEntry>>, _) =
+            load_file(&path, passphrase)?;
+        self.key = key;
+        let stored = stored.unwrap_or_default();
+
+        let open = std::mem::replace(&mut self.entries, stored.clone());
+        for entry in open {
+            if !self.entries.contains(&entry) {
+                self.entries.push(entry);
+            }
+        }
+
+        self.path = Some(path);
+        self.save()?;
+        Ok(stored)
+    }
+
+    /// Remember that a channel or direct conversation was opened, after those
+    /// opened before it.
+    pub fn open(&mut self, entry: SessionEntry) -> crate::Result<()> {
+        if self.entries.contains(&entry) {
+            return Ok(());
+        }
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /// Forget a channel or direct conversation which was closed.
+    pub fn close(&mut self, entry: &SessionEntry) -> crate::Result<()> {
+        let len = self.entries.len();
+        self.entries.retain(|e| e != entry);
+        if self.entries.len() == len {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Get the open channels and direct conversations, in the order they were
+    /// opened.
+    pub fn entries(&self) -> &[SessionEntry] {
+        &self.entries
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(path) = &self.path {
+            write_file(path, &self.entries, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_state() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-session-{}", std::process::id()));
+        let peer = PeerId::random();
+
+        let mut state = SessionState::default();
+        assert!(state.persist_to(&path, Some("hunter2")).unwrap().is_empty());
+        state.open(SessionEntry::Channel("general".into())).unwrap();
+        state.open(SessionEntry::Direct(peer)).unwrap();
+        state.open(SessionEntry::Channel("random".into())).unwrap();
+        state.open(SessionEntry::Channel("general".into())).unwrap();
+        state
+            .close(&SessionEntry::Channel("random".into()))
+            .unwrap();
+
+        // the entries are resumed in the order they were opened
+        let mut resumed = SessionState::default();
+        resumed
+            .open(SessionEntry::Channel("random".into()))
+            .unwrap();
+        let stored = resumed.persist_to(&path, Some("hunter2")).unwrap();
+        assert_eq!(
+            stored,
+            [
+                SessionEntry::Channel("general".into()),
+                SessionEntry::Direct(peer),
+            ]
+        );
+        assert_eq!(resumed.entries().len(), 3);
+        assert!(SessionState::default()
+            .persist_to(&path, Some("hunter3"))
+            .is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// The settings of the spam filter, which flags messages that look like
+/// spam without refusing them.
+#[derive(Clone, Debug)]
+pub struct SpamConfig {
+    /// How long the contents of each peer's messages are remembered to
+    /// detect duplicates.
+    pub duplicate_window: Duration,
+    /// How many messages with the same contents a peer may send within the
+    /// duplicate window.
+    pub max_duplicates: usize,
+    /// How long each peer's messages are counted to detect bursts.
+    pub burst_window: Duration,
+    /// How many messages a peer may send within the burst window.
+    pub max_burst: usize,
+    /// How many peers a single message may mention.
+    pub max_mentions: usize,
+    /// How much each flagged message lowers the application-specific
+    /// gossipsub score of its sender.
+    pub penalty: f64,
+    /// How long a flagged message counts against the score of its sender.
+    pub penalty_window: Duration,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        SpamConfig {
+            duplicate_window: Duration::from_secs(30),
+            max_duplicates: 3,
+            burst_window: Duration::from_secs(10),
+            max_burst: 15,
+            max_mentions: 8,
+            penalty: 1.0,
+            penalty_window: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Why a message was flagged as spam.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpamKind {
+    /// The sender sent the same contents too many times in a short window.
+    Duplicate,
+    /// The sender sent too many messages in a short window.
+    Burst,
+    /// The message mentioned too many peers.
+    ExcessiveMentions,
+}
+
+/// What has recently been sent by a peer.
+#[derive(Default)]
+struct Activity {
+    /// The times messages were sent, with a hash of their contents.
+    messages: VecDeque<(Instant, u64)>,
+    /// The times messages were flagged.
+    flagged: VecDeque<Instant>,
+}
+
+/// Flags messages which look like spam by the recent activity of their
+/// senders.
+pub(crate) struct SpamFilter {
+    config: SpamConfig,
+    activity: HashMap<PeerId, Activity>,
+}
+
+impl SpamFilter {
+    pub(crate) fn new(config: SpamConfig) -> Self {
+        SpamFilter {
+            config,
+            activity: HashMap::new(),
+        }
+    }
+
+    /// Record a message sent by a peer, returning why it looks like spam, if
+    /// it does.
+    pub(crate) fn check(
+        &mut self,
+        peer: PeerId,
+        contents: &str,
+        mentions: usize,
+        now: Instant,
+    ) -> Option<SpamKind> {
+        let window = self.config.duplicate_window.max(self.config.burst_window);
+        let activity = self.activity.entry(peer).or_default();
+        prune(&mut activity.messages, |(at, _)| *at, window, now);
+
+        let hash = hash_contents(contents);
+        activity.messages.push_back((now, hash));
+
+        let recent = |window: Duration| {
+            activity
+                .messages
+                .iter()
+                .filter(move |(at, _)| now.duration_since(*at) < window)
+        };
+        let kind = if mentions > self.config.max_mentions {
+            Some(SpamKind::ExcessiveMentions)
+        } else if recent(self.config.duplicate_window)
+            .filter(|(_, h)| *h == hash)
+            .count()
+            > self.config.max_duplicates
+        {
+            Some(SpamKind::Duplicate)
+        } else if recent(self.config.burst_window).count()
+            > self.config.max_burst
+        {
+            Some(SpamKind::Burst)
+        } else {
+            None
+        };
+
+        if kind.is_some() {
+            activity.flagged.push_back(now);
+        }
+        kind
+    }
+
+    /// Get the application-specific score of a peer, lowered by each of its
+    /// messages flagged within the penalty window.
+    pub(crate) fn score(&self, peer: &PeerId) -> f64 {
+        self.activity.get(peer).map_or(0.0, |activity| {
+            -self.config.penalty * activity.flagged.len() as f64
+        })
+    }
+
+    /// Forget activity which is too old to count, returning the peers whose
+    /// scores changed.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let window = self.config.duplicate_window.max(self.config.burst_window);
+        let penalty_window = self.config.penalty_window;
+
+        let mut changed = Vec::new();
+        self.activity.retain(|peer, activity| {
+            prune(&mut activity.messages, |(at, _)| *at, window, now);
+            let flagged = activity.flagged.len();
+            prune(&mut activity.flagged, |at| *at, penalty_window, now);
+            if activity.flagged.len() != flagged {
+                changed.push(*peer);
+            }
+            !activity.messages.is_empty() || !activity.flagged.is_empty()
+        });
+        changed
+    }
+}
+
+/// Drop the entries at the front of a queue which are older than a window.
+fn prune<T>(
+    queue: &mut VecDeque<T>,
+    time: impl Fn(&T) -> Instant,
+    window: Duration,
+    now: Instant,
+) {
+    while queue
+        .front()
+        .is_some_and(|entry| now.duration_since(time(entry)) >= window)
+    {
+        queue.pop_front();
+    }
+}
+
+/// Hash the contents of a message, so that they need not be kept.
+fn hash_contents(contents: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spam_filter() {
+        let mut filter = SpamFilter::new(SpamConfig {
+            max_duplicates: 2,
+            max_burst: 4,
+            max_mentions: 1,
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        let check = |filter: &mut SpamFilter, contents, mentions| {
+            filter.check(peer, contents, mentions, start)
+        };
+        assert_eq!(
+            check(&mut filter, "hi", 2),
+            Some(SpamKind::ExcessiveMentions)
+        );
+        assert_eq!(check(&mut filter, "hi", 0), None);
+        assert_eq!(check(&mut filter, "hi", 0), Some(SpamKind::Duplicate));
+        assert_eq!(check(&mut filter, "hello", 0), None);
+        assert_eq!(check(&mut filter, "hey", 0), Some(SpamKind::Burst));
+        assert_eq!(filter.score(&peer), -3.0);
+
+        // other peers are not affected
+        let other = PeerId::random();
+        assert_eq!(filter.check(other, "hi", 0, start), None);
+        assert_eq!(filter.score(&other), 0.0);
+
+        // the penalties wear off with time
+        let later = start + Duration::from_secs(60 * 60);
+        assert_eq!(filter.expire(later), [peer]);
+        assert_eq!(filter.score(&peer), 0.0);
+        assert!(filter.activity.is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{fmt, fs, path::Path};
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::crypto::{
+    decrypt, derive_key, encrypt, random_nonce, KEY_LENGTH, NONCE_LENGTH,
+};
+
+const SALT_LENGTH: usize = 16;
+
+/// A key with which data kept on disk is encrypted, derived from a passphrase
+/// and a salt which is kept alongside the data.
+#[derive(Clone)]
+pub struct StorageKey {
+    salt: Vec<u8>,
+    key: [u8; KEY_LENGTH],
+}
+
+impl StorageKey {
+    /// Derive a key from a passphrase with a new random salt.
+    pub fn generate(passphrase: &str) -> crate::Result<Self> {
+        let mut salt = vec![0; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    /// Derive a key from a passphrase with the salt it was first derived
+    /// with.
+    pub fn derive(passphrase: &str, salt: Vec<u8>) -> crate::Result<Self> {
+        let key = derive_key(passphrase, &salt)?;
+        Ok(StorageKey { salt, key })
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// Encrypt data, returning the nonce followed by the ciphertext.
+    pub fn encrypt(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let mut sealed = random_nonce();
+        sealed.extend(encrypt(&self.key, &sealed, data, &[])?);
+        Ok(sealed)
+    }
+
+    /// Decrypt data encrypted with [`StorageKey::encrypt`], failing if it was
+    /// encrypted with another key.
+    pub fn decrypt(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        if data.len() < NONCE_LENGTH {
+            return Err(crate::Error::EncryptionError);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        decrypt(&self.key, nonce, ciphertext, &[])
+    }
+
+    /// Compute a keyed hash of a name, so that it may be used to look data up
+    /// without revealing the name itself.
+    pub fn hash_name(&self, name: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(name.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the key itself is left out, so that it does not end up in logs
+        f.debug_struct("StorageKey").finish_non_exhaustive()
+    }
+}
+
+/// Data encrypted with a key derived from a passphrase, along with the salt
+/// the key was derived with.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct Sealed {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    /// Encrypt data with a passphrase.
+    pub(crate) fn seal(data: &[u8], passphrase: &str) -> crate::Result<Self> {
+        Self::seal_with(data, &StorageKey::generate(passphrase)?)
+    }
+
+    /// Encrypt data with a key which was already derived, such as when the
+    /// same file is written again.
+    pub(crate) fn seal_with(
+        data: &[u8],
+        key: &StorageKey,
+    ) -> crate::Result<Self> {
+        let nonce = random_nonce();
+        let ciphertext = encrypt(&key.key, &nonce, data, &[])?;
+
+        Ok(Sealed {
+            salt: key.salt.clone(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt data with a passphrase, failing if it is not the one the data
+    /// was sealed with.
+    pub(crate) fn open(&self, passphrase: &str) -> crate::Result<Vec<u8>> {
+        self.open_with(&StorageKey::derive(passphrase, self.salt.clone())?)
+    }
+
+    /// Decrypt data with a key derived from the salt of the data, returned
+    /// by [`Sealed::key`].
+    pub(crate) fn open_with(&self, key: &StorageKey) -> crate::Result<Vec<u8>> {
+        decrypt(&key.key, &self.nonce, &self.ciphertext, &[])
+    }
+
+    /// Derive the key the data was sealed with from a passphrase.
+    pub(crate) fn key(&self, passphrase: &str) -> crate::Result<StorageKey> {
+        StorageKey::derive(passphrase, self.salt.clone())
+    }
+}
+
+/// Read a value kept in a file by [`write_file`], decrypting it with a
+/// passphrase if one is given. The key it was encrypted with is returned
+/// along with it, so that the file may be written again with the same salt.
+pub(crate) fn read_file<T: DeserializeOwned>(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> crate::Result<(T, Option<StorageKey>)> {
+    let file = fs::File::open(path)?;
+    match passphrase {
+        Some(passphrase) => {
+            let sealed: Sealed = rmp_serde::from_read(file)?;
+            let key = sealed.key(passphrase)?;
+            let data = sealed.open_with(&key)?;
+            Ok((rmp_serde::from_slice(&data)?, Some(key)))
+        }
+        None => Ok((rmp_serde::from_read(file)?, None)),
+    }
+}
+
+/// Read a value kept in a file by [`write_file`] if the file exists, along
+/// with the key the file should be written with from then on.
+///
+/// The key is the one the file was encrypted with, or one newly derived from
+/// the passphrase if the file does not exist yet. No key is returned if no
+/// passphrase is given.
+pub(crate) fn load_file<T: DeserializeOwned>(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> crate::Result<(Option<T>, Option<StorageKey>)> {
+    let (stored, key) = if path.exists() {
+        let (stored, key) = read_file(path, passphrase)?;
+        (Some(stored), key)
+    } else {
+        (None, None)
+    };
+    let key = match (key, passphrase) {
+        (None, Some(passphrase)) => Some(StorageKey::generate(passphrase)?),
+        (key, _) => key,
+    };
+    Ok((stored, key))
+}
+
+/// Write a value to a file, encrypting it with a key if one is given.
+pub(crate) fn write_file(
+    path: &Path,
+    value: &impl Serialize,
+    key: Option<&StorageKey>,
+) -> crate::Result<()> {
+    let data = rmp_serde::to_vec(value)?;
+    let data = match key {
+        Some(key) => rmp_serde::to_vec(&Sealed::seal_with(&data, key)?)?,
+        None => data,
+    };
+    fs::write(path, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let sealed = Sealed::seal(b"hello", "hunter2").unwrap();
+        assert_eq!(sealed.open("hunter2").unwrap(), b"hello");
+
+        // sealing again with the same key keeps the salt
+        let key = sealed.key("hunter2").unwrap();
+        let resealed = Sealed::seal_with(b"world", &key).unwrap();
+        assert_eq!(resealed.salt, sealed.salt);
+        assert_eq!(resealed.open("hunter2").unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_open_wrong_passphrase() {
+        let sealed = Sealed::seal(b"hello", "hunter2").unwrap();
+        assert!(sealed.open("hunter3").is_err());
+    }
+
+    #[test]
+    fn test_storage_key() {
+        let key = StorageKey::generate("hunter2").unwrap();
+        let data = key.encrypt(b"hello").unwrap();
+        assert_eq!(key.decrypt(&data).unwrap(), b"hello");
+
+        let same = StorageKey::derive("hunter2", key.salt().to_vec()).unwrap();
+        assert_eq!(same.decrypt(&data).unwrap(), b"hello");
+        assert_eq!(same.hash_name("general"), key.hash_name("general"));
+
+        let other = StorageKey::generate("hunter2").unwrap();
+        assert!(other.decrypt(&data).is_err());
+        assert_ne!(other.hash_name("general"), key.hash_name("general"));
+    }
+}
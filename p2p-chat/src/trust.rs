@@ -0,0 +1,215 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{load_file, write_file, StorageKey};
+
+/// What a [`TrustStore`] made of a nickname being used by a peer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrustVerdict {
+    /// The nickname had not been seen before, and is now trusted to be the
+    /// peer's.
+    New,
+    /// The nickname is trusted to be the peer's.
+    Trusted,
+    /// The nickname was first seen used by another peer, whose key is still
+    /// the one trusted.
+    Changed { trusted: PeerId },
+}
+
+/// The trusted keys, as kept on disk.
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct Records {
+    /// The key trusted for each nickname.
+    keys: HashMap<String, PeerId>,
+    /// The peers whose safety numbers were compared out of band.
+    verified: HashSet<PeerId>,
+    /// When the key trusted for each nickname was first seen using it, in
+    /// milliseconds since the Unix epoch.
+    #[serde(default)]
+    seen_at: HashMap<String, u64>,
+}
+
+/// The key first seen using each nickname, trusted on first use, and the
+/// peers which were verified, optionally persisted to disk so that the trust
+/// outlives the session.
+#[derive(Default, Debug)]
+pub struct TrustStore {
+    records: Records,
+    path: Option<PathBuf>,
+    /// The key the file is encrypted with, if any.
+    key: Option<StorageKey>,
+}
+
+impl TrustStore {
+    /// Load the keys stored at a given path, and persist any further changes
+    /// to it.
+    ///
+    /// Stored keys take precedence over those already seen in memory. If a
+    /// passphrase is given, the file is encrypted with a key derived from it.
+    pub fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> crate::Result<()> {
+        let path = path.as_ref().to_owned();
+
+        let (stored, key): (Option<Records>, _) = load_file(&path, passphrase)?;
+        self.key = key;
+        if let Some(stored) = stored {
+            self.records.keys.extend(stored.keys);
+            self.records.seen_at.extend(stored.seen_at);
+            self.records.verified.extend(stored.verified);
+        }
+
+        self.path = Some(path);
+        self.save()
+    }
+
+    /// Check a peer's use of a nickname at a given time against the key
+    /// trusted for it, trusting the peer if the nickname had not been seen
+    /// before.
+    pub fn observe(
+        &mut self,
+        nick: &str,
+        peer: PeerId,
+        now: u64,
+    ) -> crate::Result<TrustVerdict> {
+        match self.records.keys.get(nick) {
+            Some(trusted) if *trusted == peer => {
+                // keys stored before their times were kept are taken to be
+                // first seen now
+                if !self.records.seen_at.contains_key(nick) {
+                    self.records.seen_at.insert(nick.to_owned(), now);
+                    self.save()?;
+                }
+                Ok(TrustVerdict::Trusted)
+            }
+            Some(trusted) => Ok(TrustVerdict::Changed { trusted: *trusted }),
+            None => {
+                self.trust(nick, peer, now)?;
+                Ok(TrustVerdict::New)
+            }
+        }
+    }
+
+    /// Trust a peer's key for a nickname from a given time, replacing any key
+    /// trusted before, such as after checking its fingerprint out of band.
+    pub fn trust(
+        &mut self,
+        nick: &str,
+        peer: PeerId,
+        now: u64,
+    ) -> crate::Result<()> {
+        self.records.keys.insert(nick.to_owned(), peer);
+        self.records.seen_at.insert(nick.to_owned(), now);
+        self.save()
+    }
+
+    /// Check whether a peer's claim to a nickname, made at a given time by
+    /// its own account, could have been made then.
+    ///
+    /// A peer other than the one trusted for the nickname cannot claim it
+    /// from before the trusted key was first seen using it, since the time of
+    /// a claim is given by the claimant, while the time a key was first seen
+    /// is not.
+    pub fn admits_claim(
+        &self,
+        nick: &str,
+        owner: &PeerId,
+        claimed_at: u64,
+    ) -> bool {
+        match self.records.keys.get(nick) {
+            Some(trusted) if trusted != owner => self
+                .records
+                .seen_at
+                .get(nick)
+                .is_some_and(|seen_at| claimed_at >= *seen_at),
+            _ => true,
+        }
+    }
+
+    /// Get the key trusted for a nickname, if any.
+    pub fn get(&self, nick: &str) -> Option<&PeerId> {
+        self.records.keys.get(nick)
+    }
+
+    /// Mark a peer as verified, returning whether it was not already.
+    pub fn verify(&mut self, peer: PeerId) -> crate::Result<bool> {
+        let added = self.records.verified.insert(peer);
+        if added {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    /// Unmark a peer as verified, returning whether it was.
+    pub fn unverify(&mut self, peer: &PeerId) -> crate::Result<bool> {
+        let removed = self.records.verified.remove(peer);
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn is_verified(&self, peer: &PeerId) -> bool {
+        self.records.verified.contains(peer)
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(path) = &self.path {
+            write_file(path, &self.records, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_store() {
+        let mut store = TrustStore::default();
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+
+        assert_eq!(
+            store.observe("alice", alice, 10).unwrap(),
+            TrustVerdict::New
+        );
+        assert_eq!(
+            store.observe("alice", alice, 20).unwrap(),
+            TrustVerdict::Trusted
+        );
+        assert_eq!(
+            store.observe("alice", mallory, 30).unwrap(),
+            TrustVerdict::Changed { trusted: alice }
+        );
+
+        // claims from others cannot predate the trusted key being first seen
+        assert!(store.admits_claim("alice", &alice, 0));
+        assert!(!store.admits_claim("alice", &mallory, 5));
+        assert!(store.admits_claim("alice", &mallory, 10));
+        assert!(store.admits_claim("bob", &mallory, 0));
+
+        // the first key is kept until another is trusted explicitly
+        assert_eq!(store.get("alice"), Some(&alice));
+        store.trust("alice", mallory, 40).unwrap();
+        assert_eq!(
+            store.observe("alice", alice, 50).unwrap(),
+            TrustVerdict::Changed { trusted: mallory }
+        );
+        assert!(!store.admits_claim("alice", &alice, 30));
+
+        assert!(store.verify(alice).unwrap());
+        assert!(!store.verify(alice).unwrap());
+        assert!(store.is_verified(&alice));
+        assert!(!store.is_verified(&mallory));
+        assert!(store.unverify(&alice).unwrap());
+        assert!(!store.is_verified(&alice));
+    }
+}